@@ -5,8 +5,11 @@ use std::{
     rc::Rc,
 };
 
+use anyhow::{Result, ensure};
+
 use crate::{
-    CodexFsInode, blk_id_to_addr, blk_off_t, blk_size_t, blk_t, sb::get_sb, utils::round_up,
+    CodexFsInode, blk_id_to_addr, blk_off_t, blk_size_t, blk_t, options::get_mkfs_opts, sb::get_sb,
+    utils::round_up,
 };
 
 pub enum BufferType {
@@ -30,6 +33,19 @@ pub fn get_bufmgr_mut() -> &'static mut BufferManager {
     unsafe { BUFFER_MANAGER.get_mut_or_init(BufferManager::new) }
 }
 
+// Fails once the allocations pass the image size limit, so that the data
+// that does not fit is never written.
+pub fn mkfs_check_max_size() -> Result<()> {
+    if let Some(max_size) = get_mkfs_opts().max_size {
+        let allocated = get_bufmgr_mut().allocated();
+        ensure!(
+            allocated <= max_size,
+            "image needs more than {max_size} bytes, {allocated} allocated so far"
+        );
+    }
+    Ok(())
+}
+
 pub struct BufferBlockTable(
     Vec<Vec<Rc<RefCell<BufferBlock>>>>, // index means for unused size
 );
@@ -137,6 +153,24 @@ impl BufferManager {
         addr
     }
 
+    // continues after the blocks of an existing image, nothing is placed
+    // before blk_id
+    pub fn skip_to(&mut self, blk_id: blk_t) {
+        assert!(blk_id > 0);
+        let buf_blk = Rc::new(RefCell::new(BufferBlock {
+            blk_id: blk_id - 1,
+            blk_off: get_sb().blksz(),
+        }));
+        self.table = BufferBlockTable::new();
+        self.tail_blk = buf_blk.clone();
+        self.push_block(buf_blk);
+    }
+
+    // bytes up to the end of the last allocation
+    pub fn allocated(&self) -> u64 {
+        self.tail_blk.borrow().addr()
+    }
+
     pub fn tail_blk_id(&self) -> blk_t {
         self.tail_blk.borrow().blk_id
     }
@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use serde_json::json;
+
+use crate::{
+    buffer::{BufferType, get_bufmgr_mut, mkfs_check_max_size},
+    inode::{InodeHandle, fuse_load_dir},
+    sb::{get_sb, get_sb_mut},
+};
+
+// How an image was built, a JSON object from mkfs that tooling reads back.
+// Only where it is stored is part of the superblock.
+pub fn mkfs_balloc_build_info(buf: &[u8]) -> Result<()> {
+    let addr = get_bufmgr_mut().balloc(buf.len() as _, BufferType::Meta);
+    get_sb_mut().build_info = (addr, buf.len() as _);
+    mkfs_check_max_size()
+}
+
+pub fn mkfs_dump_build_info(buf: &[u8]) -> Result<()> {
+    get_sb().write_all_at(buf, get_sb().build_info.0)
+}
+
+// none in images built before it was recorded
+pub fn fuse_load_build_info() -> Result<Option<Vec<u8>>> {
+    let (addr, size) = get_sb().build_info;
+    if addr == 0 {
+        return Ok(None);
+    }
+    let mut buf = vec![0; size as usize];
+    get_sb().read_exact_at(&mut buf, addr)?;
+    Ok(Some(buf))
+}
+
+// What a mounted image shows about itself: superblock fields, totals of the
+// tree and the build info, as a JSON object.
+pub fn fuse_image_info() -> Result<Vec<u8>> {
+    let mut inos = HashSet::new();
+    let mut data_size = 0;
+    count_tree(get_sb().root(), &mut inos, &mut data_size)?;
+    let image_size = get_sb().img_file.as_ref().unwrap().metadata()?.len();
+    let build: Option<serde_json::Value> = match fuse_load_build_info()? {
+        Some(buf) => Some(serde_json::from_slice(&buf)?),
+        None => None,
+    };
+    let info = json!({
+        "block_size": get_sb().blksz(),
+        "compressed": get_sb().compress,
+        "dict_size": get_sb().dict_size,
+        "max_cluster_size": get_sb().max_cluster_size,
+        "inodes": inos.len(),
+        "data_size": data_size,
+        "image_size": image_size,
+        "ratio": image_size as f64 / data_size.max(1) as f64,
+        "build": build,
+    });
+    Ok(serde_json::to_vec(&info)?)
+}
+
+// hardlinked inodes are counted once, directories not read yet are loaded
+fn count_tree(inode: &InodeHandle, inos: &mut HashSet<u32>, data_size: &mut u64) -> Result<()> {
+    if !inos.insert(inode.meta().ino) {
+        return Ok(());
+    }
+    if let Some(file) = inode.downcast_file_ref() {
+        *data_size += file.itype.size as u64;
+    }
+    if let Some(dir) = inode.downcast_dir_ref() {
+        fuse_load_dir(inode)?;
+        for dentry in dir.itype.inner.borrow().dentries.iter() {
+            count_tree(&dentry.inode, inos, data_size)?;
+        }
+    }
+    Ok(())
+}
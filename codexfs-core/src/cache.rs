@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, Metadata},
+    io::{self, BufReader, BufWriter},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tlsh_fixed::Tlsh;
+
+use crate::compress::ContentHash;
+
+// Fingerprints of source files from an earlier build, reused while a file's
+// path, size and mtime are unchanged. Only files seen by this build are
+// written back, so the cache never outgrows the tree.
+#[derive(Debug, Default)]
+pub struct FingerprintCache {
+    old: BTreeMap<String, CacheEntry>,
+    new: BTreeMap<String, CacheEntry>,
+    pub hits: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    tlsh: Option<String>,
+    sha256: String,
+}
+
+impl CacheEntry {
+    fn matches(&self, metadata: &Metadata) -> bool {
+        self.size == metadata.size()
+            && self.mtime == metadata.mtime()
+            && self.mtime_nsec == metadata.mtime_nsec()
+    }
+}
+
+impl FingerprintCache {
+    // a missing cache file is an empty cache
+    pub fn load(path: &Path) -> Result<Self> {
+        let old = match fs::File::open(path) {
+            Ok(f) => serde_json::from_reader(BufReader::new(f))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            old,
+            ..Default::default()
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let w = BufWriter::new(fs::File::create(path)?);
+        serde_json::to_writer(w, &self.new)?;
+        Ok(())
+    }
+
+    pub fn get(&mut self, path: &Path, metadata: &Metadata) -> Option<(Option<Tlsh>, ContentHash)> {
+        let key = path.to_str()?;
+        let entry = self.old.get(key).filter(|e| e.matches(metadata))?;
+        let tlsh = match &entry.tlsh {
+            Some(tlsh) => Some(tlsh.parse().ok()?),
+            None => None,
+        };
+        let hash = from_hex(&entry.sha256)?;
+        self.new.insert(key.to_owned(), entry.clone());
+        self.hits += 1;
+        log::debug!("fingerprint of {key} from cache");
+        Some((tlsh, hash))
+    }
+
+    // paths that are not valid UTF-8 are never cached
+    pub fn insert(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        tlsh: Option<&Tlsh>,
+        hash: &ContentHash,
+    ) {
+        let Some(key) = path.to_str() else {
+            return;
+        };
+        let entry = CacheEntry {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            tlsh: tlsh.map(Tlsh::hash),
+            sha256: hash.iter().map(|b| format!("{b:02x}")).collect(),
+        };
+        self.new.insert(key.to_owned(), entry);
+    }
+}
+
+fn from_hex(s: &str) -> Option<ContentHash> {
+    let mut hash = [0; 32];
+    if s.len() != hash.len() * 2 {
+        return None;
+    }
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::compress::calc_fingerprint;
+
+    fn fingerprint(path: &Path) -> (Option<Tlsh>, ContentHash) {
+        calc_fingerprint(fs::File::open(path).unwrap()).unwrap()
+    }
+
+    fn hashes(fingerprint: &(Option<Tlsh>, ContentHash)) -> (Option<String>, ContentHash) {
+        (fingerprint.0.as_ref().map(Tlsh::hash), fingerprint.1)
+    }
+
+    #[test]
+    fn check_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (file, cache_path) = (dir.path().join("file"), dir.path().join("cache"));
+        let data: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(&file, &data).unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+        let fp = fingerprint(&file);
+        assert!(fp.0.is_some());
+
+        let mut cache = FingerprintCache::load(&cache_path).unwrap();
+        assert!(cache.get(&file, &metadata).is_none());
+        cache.insert(&file, &metadata, fp.0.as_ref(), &fp.1);
+        cache.save(&cache_path).unwrap();
+
+        let mut cache = FingerprintCache::load(&cache_path).unwrap();
+        let cached = cache.get(&file, &metadata).unwrap();
+        assert_eq!(hashes(&cached), hashes(&fp));
+        assert_eq!(cache.hits, 1);
+    }
+
+    #[test]
+    fn check_stale_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (file, cache_path) = (dir.path().join("file"), dir.path().join("cache"));
+        fs::write(&file, b"old contents").unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+        let fp = fingerprint(&file);
+        let mut cache = FingerprintCache::default();
+        cache.insert(&file, &metadata, fp.0.as_ref(), &fp.1);
+        cache.save(&cache_path).unwrap();
+
+        // same size, new mtime
+        fs::write(&file, b"new contents").unwrap();
+        let f = fs::File::options().write(true).open(&file).unwrap();
+        f.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        let mut cache = FingerprintCache::load(&cache_path).unwrap();
+        assert!(cache.get(&file, &fs::metadata(&file).unwrap()).is_none());
+
+        // same mtime, new size
+        fs::write(&file, b"longer new contents").unwrap();
+        f.set_modified(metadata.modified().unwrap()).unwrap();
+        let new_metadata = fs::metadata(&file).unwrap();
+        assert_eq!(new_metadata.mtime(), metadata.mtime());
+        assert!(cache.get(&file, &new_metadata).is_none());
+        assert_eq!(cache.hits, 0);
+
+        // stale entries are not written back
+        cache.save(&cache_path).unwrap();
+        let mut cache = FingerprintCache::load(&cache_path).unwrap();
+        assert!(cache.get(&file, &metadata).is_none());
+    }
+}
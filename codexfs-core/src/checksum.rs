@@ -0,0 +1,226 @@
+use std::io;
+
+use anyhow::{Result, ensure};
+use bytemuck::{cast_slice, from_bytes};
+
+use crate::{
+    CodexFsChecksum, addr_to_blk_id, blk_id_to_addr,
+    buffer::{BufferType, get_bufmgr_mut, mkfs_check_max_size},
+    sb::{get_sb, get_sb_mut},
+};
+
+// Checksums of file data: a crc32 of every cluster, and of every block sized
+// piece of uncompressed files, recorded by mkfs --checksums. A read checks
+// only the pieces it takes its data from.
+
+fn crc32(data: &[u8]) -> u32 {
+    unsafe { lzma_sys::lzma_crc32(data.as_ptr(), data.len(), 0) }
+}
+
+// the crc32 of data written at addr, if recording them
+pub fn mkfs_record_checksum(addr: u64, data: &[u8]) {
+    if let Some(checksums) = &mut get_sb_mut().checksums {
+        checksums.push(CodexFsChecksum {
+            addr,
+            len: data.len() as _,
+            crc32: crc32(data),
+        });
+    }
+}
+
+// Lays out the checksums recorded, once all file data is written. Nothing if
+// none were.
+pub fn mkfs_balloc_checksums() -> Result<Option<Vec<u8>>> {
+    let Some(checksums) = &mut get_sb_mut().checksums else {
+        return Ok(None);
+    };
+    checksums.sort_by_key(|c| c.addr);
+    let buf = encode(checksums)?;
+    let addr = get_bufmgr_mut().balloc(buf.len() as _, BufferType::ZData);
+    get_sb_mut().checksums_blk = addr_to_blk_id(addr);
+    mkfs_check_max_size()?;
+    Ok(Some(buf))
+}
+
+pub fn mkfs_dump_checksums(buf: &[u8]) -> Result<()> {
+    get_sb().write_all_at(buf, blk_id_to_addr(get_sb().checksums_blk))?;
+    Ok(())
+}
+
+fn encode(checksums: &[CodexFsChecksum]) -> Result<Vec<u8>> {
+    let count = u32::try_from(checksums.len())?;
+    let mut buf = count.to_le_bytes().to_vec();
+    buf.extend(cast_slice(checksums));
+    Ok(buf)
+}
+
+fn decode(buf: &[u8]) -> Result<Vec<CodexFsChecksum>> {
+    let record_size = size_of::<CodexFsChecksum>();
+    let records = buf.get(size_of::<u32>()..).unwrap_or_default();
+    ensure!(
+        records.len() % record_size == 0,
+        "truncated checksum record"
+    );
+    Ok(records
+        .chunks(record_size)
+        .map(|record| *from_bytes(record))
+        .collect())
+}
+
+// Makes reads of the loaded image check the data they take, failing unless
+// the image has checksums.
+pub fn fuse_load_checksums() -> Result<()> {
+    let blk_id = get_sb().checksums_blk;
+    ensure!(
+        blk_id != 0,
+        "the image records no checksums, mkfs --checksums does"
+    );
+    let mut count = [0; size_of::<u32>()];
+    get_sb().read_exact_at(&mut count, blk_id_to_addr(blk_id))?;
+    let size = size_of::<u32>() as u64
+        + u32::from_le_bytes(count) as u64 * size_of::<CodexFsChecksum>() as u64;
+    let img_len = get_sb().img_file.as_ref().unwrap().metadata()?.len();
+    ensure!(
+        size <= img_len.saturating_sub(blk_id_to_addr(blk_id)),
+        "checksum table of {size} bytes runs past the end of the image"
+    );
+    let mut buf = vec![0; size as usize];
+    get_sb().read_exact_at(&mut buf, blk_id_to_addr(blk_id))?;
+    get_sb_mut().checksums = Some(decode(&buf)?);
+    Ok(())
+}
+
+// Reads file data at addr into buf, checking the pieces holding it when
+// verifying. A damaged piece, or data no piece holds, is an InvalidData
+// error, so it reads as EIO.
+pub fn fuse_read_checked(buf: &mut [u8], addr: u64) -> Result<()> {
+    let Some(checksums) = &get_sb().checksums else {
+        return get_sb().read_exact_at(buf, addr);
+    };
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let end = addr + buf.len() as u64;
+    let invalid = |msg| anyhow::Error::from(io::Error::new(io::ErrorKind::InvalidData, msg));
+    let Some(pieces) = pieces(checksums, addr, end) else {
+        return Err(invalid(format!("no checksum covers {addr:#x}..{end:#x}")));
+    };
+    let start = pieces[0].addr;
+    let mut data = vec![0; (piece_end(pieces.last().unwrap()) - start) as usize];
+    get_sb().read_exact_at(&mut data, start)?;
+    for &CodexFsChecksum {
+        addr,
+        len,
+        crc32: sum,
+    } in pieces
+    {
+        let piece = &data[(addr - start) as usize..][..len as usize];
+        if crc32(piece) != sum {
+            return Err(invalid(format!(
+                "data at {addr:#x} does not have its recorded crc32"
+            )));
+        }
+    }
+    buf.copy_from_slice(&data[(addr - start) as usize..][..buf.len()]);
+    Ok(())
+}
+
+fn piece_end(piece: &CodexFsChecksum) -> u64 {
+    piece.addr + piece.len as u64
+}
+
+// the pieces holding addr..end, None unless they hold all of it without a gap
+fn pieces(checksums: &[CodexFsChecksum], addr: u64, end: u64) -> Option<&[CodexFsChecksum]> {
+    let first = checksums.partition_point(|c| piece_end(c) <= addr);
+    let last = checksums.partition_point(|c| c.addr < end);
+    let pieces = checksums.get(first..last)?;
+    let (start, mut covered) = (pieces.first()?.addr, pieces.first()?.addr);
+    for piece in pieces {
+        if piece.addr != covered {
+            return None;
+        }
+        covered = piece_end(piece);
+    }
+    (start <= addr && covered >= end).then_some(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, rc::Rc};
+
+    use super::*;
+    use crate::{
+        image::Image,
+        sb::{SuperBlock, set_sb},
+    };
+
+    #[test]
+    fn check_encode_decode() {
+        let checksums = vec![
+            CodexFsChecksum {
+                addr: 4096,
+                len: 4096,
+                crc32: crc32(b"a"),
+            },
+            CodexFsChecksum {
+                addr: 8192,
+                len: 10,
+                crc32: 7,
+            },
+        ];
+        let buf = encode(&checksums).unwrap();
+        assert_eq!(decode(&buf).unwrap(), checksums);
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn check_load_truncated() {
+        let img_path = Path::new("cargo-test-checksums.tmp");
+        let img_file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(img_path)
+            .unwrap();
+        let _image = Rc::new(Image::default()).enter();
+        set_sb(SuperBlock::new(img_file, 12));
+        get_sb_mut().checksums_blk = 1;
+        // a count of records the image does not hold
+        let mut buf = encode(&[CodexFsChecksum {
+            addr: 0,
+            len: 4096,
+            crc32: 0,
+        }])
+        .unwrap();
+        buf[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        get_sb().write_all_at(&buf, 4096).unwrap();
+        assert!(fuse_load_checksums().is_err());
+        assert!(get_sb().checksums.is_none());
+
+        buf[..4].copy_from_slice(&1_u32.to_le_bytes());
+        get_sb().write_all_at(&buf, 4096).unwrap();
+        fuse_load_checksums().unwrap();
+        assert_eq!(get_sb().checksums.as_ref().unwrap().len(), 1);
+        fs::remove_file(img_path).unwrap();
+    }
+
+    #[test]
+    fn check_pieces() {
+        let piece = |addr, len| CodexFsChecksum {
+            addr,
+            len,
+            crc32: 0,
+        };
+        let checksums = [piece(100, 10), piece(110, 10), piece(200, 10)];
+        assert_eq!(pieces(&checksums, 105, 106), Some(&checksums[..1]));
+        assert_eq!(pieces(&checksums, 105, 115), Some(&checksums[..2]));
+        assert_eq!(pieces(&checksums, 110, 120), Some(&checksums[1..2]));
+        // a gap, or bytes no piece holds
+        assert_eq!(pieces(&checksums, 115, 205), None);
+        assert_eq!(pieces(&checksums, 95, 105), None);
+        assert_eq!(pieces(&checksums, 120, 130), None);
+        assert_eq!(pieces(&checksums, 205, 215), None);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    cell::OnceCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+    sync::mpsc::{Receiver, TryRecvError},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{blk_t, image, nid_t, pool::get_decode_pool};
+
+// Decompressed clusters of the mounted image by the block holding them, and
+// the content of delta files by nid, the least recently used are dropped once
+// they take more than capacity bytes.
+#[derive(Debug)]
+pub struct ClusterCache {
+    capacity: u64,
+    used: u64,
+    tick: u64,
+    entries: HashMap<Key, (Rc<Vec<u8>>, u64)>, // data and last use
+    lru: BTreeMap<u64, Key>,
+    // clusters being decoded in the background ahead of reads
+    pending: HashMap<blk_t, Decode>,
+    pub hits: u64,
+    pub misses: u64,
+    pub decoded: u64, // clusters decompressed, here or ahead of reads
+    pub decode_time: Duration,
+    pub readahead: bool, // reads decode the clusters after theirs ahead
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Cluster(blk_t),
+    Delta(nid_t),
+}
+
+// a cluster decoding in the background, and how long it took once done
+type Decode = Receiver<Result<(Vec<u8>, Duration)>>;
+
+static mut CLUSTER_CACHE: OnceCell<ClusterCache> = OnceCell::new();
+
+// that of the image entered on this thread, the process-wide one otherwise
+fn cluster_cache() -> &'static mut OnceCell<ClusterCache> {
+    match image::entered() {
+        Some(image) => &mut image.cluster_cache,
+        None => unsafe { &mut CLUSTER_CACHE },
+    }
+}
+
+pub fn set_cluster_cache(capacity: u64) {
+    cluster_cache().set(ClusterCache::new(capacity)).unwrap()
+}
+
+// none unless the FUSE driver set one up
+pub fn get_cluster_cache_mut() -> Option<&'static mut ClusterCache> {
+    cluster_cache().get_mut()
+}
+
+impl ClusterCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            pending: HashMap::new(),
+            hits: 0,
+            misses: 0,
+            decoded: 0,
+            decode_time: Duration::ZERO,
+            readahead: true,
+        }
+    }
+
+    pub fn get(&mut self, blk_id: blk_t) -> Option<Rc<Vec<u8>>> {
+        // a failed decode is left to the read, which reports it
+        if let Some(decode) = self.pending.remove(&blk_id)
+            && let Ok(Ok((cluster, time))) = decode.recv()
+        {
+            self.record_decode(1, time);
+            self.insert(blk_id, Rc::new(cluster));
+        }
+        self.get_entry(Key::Cluster(blk_id))
+    }
+
+    pub fn insert(&mut self, blk_id: blk_t, cluster: Rc<Vec<u8>>) {
+        self.insert_entry(Key::Cluster(blk_id), cluster);
+    }
+
+    // the whole content of a delta file, rebuilt from its base
+    pub fn get_delta(&mut self, nid: nid_t) -> Option<Rc<Vec<u8>>> {
+        self.get_entry(Key::Delta(nid))
+    }
+
+    pub fn insert_delta(&mut self, nid: nid_t, content: Rc<Vec<u8>>) {
+        self.insert_entry(Key::Delta(nid), content);
+    }
+
+    fn get_entry(&mut self, key: Key) -> Option<Rc<Vec<u8>>> {
+        let Some((data, last_use)) = self.entries.get_mut(&key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.tick += 1;
+        self.lru.remove(last_use);
+        self.lru.insert(self.tick, key);
+        *last_use = self.tick;
+        Some(data.clone())
+    }
+
+    fn insert_entry(&mut self, key: Key, data: Rc<Vec<u8>>) {
+        let len = data.len() as u64;
+        if len > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        if let Some((old, last_use)) = self.entries.insert(key, (data, self.tick)) {
+            self.used -= old.len() as u64;
+            self.lru.remove(&last_use);
+        }
+        self.lru.insert(self.tick, key);
+        self.used += len;
+        while self.used > self.capacity {
+            let (_, key) = self.lru.pop_first().unwrap();
+            let (old, _) = self.entries.remove(&key).unwrap();
+            self.used -= old.len() as u64;
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    pub fn record_decode(&mut self, clusters: u64, time: Duration) {
+        self.decoded += clusters;
+        self.decode_time += time;
+    }
+
+    pub fn contains(&self, blk_id: blk_t) -> bool {
+        self.entries.contains_key(&Key::Cluster(blk_id)) || self.pending.contains_key(&blk_id)
+    }
+
+    // decodes a cluster on the decode pool, it is cached once done and asked
+    // for, or by a later prefetch
+    pub fn prefetch(
+        &mut self,
+        blk_id: blk_t,
+        decode: impl FnOnce() -> Result<Vec<u8>> + Send + 'static,
+    ) {
+        let mut finished = Vec::new();
+        self.pending
+            .retain(|&blk_id, decode| match decode.try_recv() {
+                Ok(result) => {
+                    finished.push((blk_id, result));
+                    false
+                }
+                Err(TryRecvError::Empty) => true,
+                Err(TryRecvError::Disconnected) => false,
+            });
+        for (blk_id, result) in finished {
+            if let Ok((cluster, time)) = result {
+                self.record_decode(1, time);
+                self.insert(blk_id, Rc::new(cluster));
+            }
+        }
+        if !self.contains(blk_id) {
+            let decode = move || {
+                let start = Instant::now();
+                decode().map(|cluster| (cluster, start.elapsed()))
+            };
+            self.pending.insert(blk_id, get_decode_pool().spawn(decode));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_lru() {
+        let mut cache = ClusterCache::new(10);
+        cache.insert(1, Rc::new(vec![1; 4]));
+        cache.insert(2, Rc::new(vec![2; 4]));
+        assert!(cache.get(1).is_some());
+        // 2 is the least recently used
+        cache.insert(3, Rc::new(vec![3; 4]));
+        assert!(cache.contains(1) && !cache.contains(2) && cache.contains(3));
+        cache.insert(4, Rc::new(vec![4; 11]));
+        assert!(!cache.contains(4));
+        assert_eq!((cache.hits, cache.used), (1, 8));
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.misses, 1);
+        cache.prefetch(5, || Ok(vec![5; 2]));
+        assert!(cache.contains(5));
+        assert_eq!(*cache.get(5).unwrap(), [5; 2]);
+        assert_eq!(cache.decoded, 1);
+        // delta content has keys of its own but shares the capacity, here
+        // pushing out cluster 1
+        cache.insert_delta(1, Rc::new(vec![6; 3]));
+        assert_eq!(*cache.get_delta(1).unwrap(), [6; 3]);
+        assert!(cache.get_delta(2).is_none());
+        assert!(!cache.contains(1) && cache.contains(3) && cache.contains(5));
+    }
+}
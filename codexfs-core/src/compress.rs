@@ -1,12 +1,38 @@
 use std::{
     cell::OnceCell,
-    collections::{HashSet, VecDeque},
+    cmp::min,
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    fmt, fs,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+    mem,
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::{Path, PathBuf},
     rc::Rc,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread::{self, JoinHandle},
+    vec,
 };
 
+use anyhow::{Result, bail, ensure};
+use sha2::{Digest, Sha256};
 use tlsh_fixed::{BucketKind, ChecksumKind, Tlsh, TlshBuilder, Version};
+use xz2::stream::{Action, Check, Filters, LzmaOptions, Status, Stream};
 
-use crate::inode::{File, Inode};
+use crate::{
+    CodexFsCodec, blk_t,
+    cache::FingerprintCache,
+    delta,
+    inode::{Delta, File, Inode},
+    pattern::{PathPatterns, rel_path},
+    scan::mkfs_take_fingerprint,
+    segment::{Segment, split_file},
+};
+
+// bounds of the delta search: files looked at, and bytes of deltas kept
+const DELTA_FILES_MAX: usize = 4096;
+const DELTA_MEMORY_MAX: usize = 256 << 20;
 
 static mut COMPRESS_MANAGER: OnceCell<CompressManager> = OnceCell::new();
 
@@ -26,53 +52,589 @@ pub fn get_cmpr_mgr_mut() -> &'static mut CompressManager {
     unsafe { COMPRESS_MANAGER.get_mut().unwrap() }
 }
 
+// encoder setting tried on every cluster, several settings may map to the
+// same on-disk codec
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Lzma(u32), // microlzma with the given preset
+    Stored,
+    Xz(u32), // standard .xz framing with the given preset
+}
+
+impl Codec {
+    pub fn id(self) -> CodexFsCodec {
+        match self {
+            Codec::Lzma(_) => CodexFsCodec::MicroLzma,
+            Codec::Stored => CodexFsCodec::Stored,
+            Codec::Xz(_) => CodexFsCodec::Xz,
+        }
+    }
+
+    // fills at most output.len() bytes, returns (total_in, total_out)
+    pub fn compress_cluster(self, input: &[u8], output: &mut [u8]) -> Result<(u64, u64)> {
+        match self {
+            Codec::Lzma(preset) => {
+                let mut stream = Stream::new_microlzma_encoder(&LzmaOptions::new_preset(preset)?)?;
+                stream.process(input, output, Action::Finish)?;
+                Ok((stream.total_in(), stream.total_out()))
+            }
+            Codec::Stored => {
+                let n = min(input.len(), output.len());
+                output[..n].copy_from_slice(&input[..n]);
+                Ok((n as _, n as _))
+            }
+            Codec::Xz(preset) => {
+                // an xz stream cannot be cut off at a given output size, so
+                // search for the longest input prefix whose whole stream fits
+                let mut stream = xz_encoder(preset, input.len())?;
+                if let Some(total_out) = xz_compress(&mut stream, input, output)? {
+                    return Ok((input.len() as _, total_out));
+                }
+                // what the encoder consumed before the output filled up
+                let (mut lo, mut hi) = (0, min(stream.total_in() as usize, input.len()));
+                while lo < hi {
+                    let mid = (lo + hi).div_ceil(2);
+                    match xz_compress(&mut xz_encoder(preset, mid)?, &input[..mid], output)? {
+                        Some(_) => lo = mid,
+                        None => hi = mid - 1,
+                    }
+                }
+                let Some(total_out) =
+                    xz_compress(&mut xz_encoder(preset, lo)?, &input[..lo], output)?
+                else {
+                    bail!("empty xz stream does not fit in {} bytes", output.len());
+                };
+                Ok((lo as _, total_out))
+            }
+        }
+    }
+}
+
+// a dictionary larger than the input only costs encoder memory
+fn xz_encoder(preset: u32, input_len: usize) -> Result<Stream> {
+    const LZMA_DICT_SIZE_MIN: u32 = 4096;
+    let dict_size = (input_len as u32)
+        .next_power_of_two()
+        .max(LZMA_DICT_SIZE_MIN);
+    let mut options = LzmaOptions::new_preset(preset)?;
+    options.dict_size(dict_size);
+    Ok(Stream::new_stream_encoder(
+        Filters::new().lzma2(&options),
+        Check::Crc32,
+    )?)
+}
+
+// returns the stream size if the complete stream fits in output
+fn xz_compress(stream: &mut Stream, input: &[u8], output: &mut [u8]) -> Result<Option<u64>> {
+    loop {
+        let status = stream.process(
+            &input[stream.total_in() as usize..],
+            &mut output[stream.total_out() as usize..],
+            Action::Finish,
+        )?;
+        if status == Status::StreamEnd {
+            return Ok(Some(stream.total_out()));
+        }
+        if stream.total_out() as usize == output.len() {
+            return Ok(None);
+        }
+    }
+}
+
+// "lzma" or "xz", either with ":<preset>" and an optional "e" suffix for
+// extreme, or "store"
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "lzma" => Ok(Codec::Lzma(6)),
+            None if s == "xz" => Ok(Codec::Xz(6)),
+            None if s == "store" => Ok(Codec::Stored),
+            Some(("lzma", preset)) => Ok(Codec::Lzma(parse_preset(preset)?)),
+            Some(("xz", preset)) => Ok(Codec::Xz(parse_preset(preset)?)),
+            _ => bail!("unknown codec {s:?}"),
+        }
+    }
+}
+
+const LZMA_PRESET_EXTREME: u32 = 1 << 31;
+
+// same syntax as FromStr
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, preset) = match *self {
+            Codec::Lzma(preset) => ("lzma", preset),
+            Codec::Xz(preset) => ("xz", preset),
+            Codec::Stored => return write!(f, "store"),
+        };
+        let extreme = if preset & LZMA_PRESET_EXTREME != 0 {
+            "e"
+        } else {
+            ""
+        };
+        write!(f, "{name}:{}{extreme}", preset & !LZMA_PRESET_EXTREME)
+    }
+}
+
+fn parse_preset(preset: &str) -> Result<u32> {
+    let (preset, extreme) = match preset.strip_suffix('e') {
+        Some(preset) => (preset, LZMA_PRESET_EXTREME),
+        None => (preset, 0),
+    };
+    let preset: u32 = preset.parse()?;
+    ensure!(preset <= 9, "preset {preset} out of range 0-9");
+    Ok(preset | extreme)
+}
+
+// "PATTERN=CODEC[,CODEC...]", files matching the pattern are compressed
+// with the given codecs instead of the image-wide ones
+#[derive(Clone, Debug)]
+pub struct PolicyRule {
+    pattern: PathPatterns,
+    codecs: Vec<Codec>,
+}
+
+impl FromStr for PolicyRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((pattern, codecs)) = s.rsplit_once('=') else {
+            bail!("policy {s:?} is not PATTERN=CODECS");
+        };
+        Ok(Self {
+            pattern: PathPatterns::new(&[pattern])?,
+            codecs: codecs
+                .split(',')
+                .map(Codec::from_str)
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+// index of the first rule matching the file
+fn policy_of(policies: &[PolicyRule], file: &Inode<File>) -> Option<usize> {
+    let rel_path = rel_path(file.meta.path());
+    policies
+        .iter()
+        .position(|rule| rule.pattern.is_match(rel_path))
+}
+
+// how hard reorder searches for an order of similar files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReorderMode {
+    Off,  // input order
+    Fast, // nearest neighbor only
+    #[default]
+    Full, // nearest neighbor refined by 2-opt
+}
+
+impl FromStr for ReorderMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(ReorderMode::Off),
+            "fast" => Ok(ReorderMode::Fast),
+            "full" => Ok(ReorderMode::Full),
+            _ => bail!("unknown reorder mode {s:?}, expected off, fast or full"),
+        }
+    }
+}
+
+// how data stored uncompressed is laid out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DataOrder {
+    #[default]
+    Input, // the order the source was scanned in
+    Name,          // by path
+    Size,          // smallest first
+    Extension,     // grouped by file extension, then by path
+    AccessProfile, // in first-access order of the access profile
+}
+
+impl FromStr for DataOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "input" => Ok(DataOrder::Input),
+            "name" => Ok(DataOrder::Name),
+            "size" => Ok(DataOrder::Size),
+            "extension" => Ok(DataOrder::Extension),
+            "access-profile" => Ok(DataOrder::AccessProfile),
+            _ => bail!(
+                "unknown data order {s:?}, expected input, name, size, extension or access-profile"
+            ),
+        }
+    }
+}
+
+// one compressed block on disk
+#[derive(Clone, Copy, Debug)]
+pub struct Cluster {
+    pub blk_id: blk_t,
+    pub codec: CodexFsCodec,
+    pub in_size: u32,  // decompressed bytes
+    pub out_size: u32, // compressed bytes
+}
+
 #[derive(Default, Debug)]
 pub struct CompressManager {
-    pub file_data: Vec<u8>,
     pub files: Vec<Rc<Inode<File>>>,
     pub diff_mat: Vec<Vec<usize>>,
-    pub lzma_level: u32,
+    pub codecs: Vec<Codec>,
+    pub clusters: Vec<Cluster>, // sorted by blk_id
+    pub delta_threshold: Option<usize>,
+    pub reorder_cost: Option<(usize, usize)>, // (input order, reordered)
+    pub per_file: bool,                       // no cluster crosses a file boundary
+    pub per_file_patterns: PathPatterns,      // per-file mode for matching paths only
+    pub order: Option<Vec<PathBuf>>,          // fixed layout instead of reordering
+    pub access_profile: Vec<PathBuf>,         // files laid out first, in this order
+    pub segments: Vec<Segment>,               // data layout, set by reorder
+    pub segment_size: Option<u64>,            // average size when splitting large files
+    pub policies: Vec<PolicyRule>,            // per-path codecs, first match wins
+    pub reorder_mode: ReorderMode,
+    pub fingerprint_cache: Option<FingerprintCache>,
+    pub plain_patterns: PathPatterns, // stored uncompressed in a compressed image
+    pub plain_files: Vec<Rc<Inode<File>>>, // set by reorder, not in files
+    pub data_order: DataOrder,        // of files stored uncompressed
 }
 
 impl CompressManager {
     pub fn new(lzma_level: u32) -> Self {
         Self {
-            lzma_level,
+            codecs: vec![Codec::Lzma(lzma_level)],
             ..Default::default()
         }
     }
 
-    pub fn reorder(&mut self) {
-        self.construct_diff_map();
-        self.optimize();
-        for file in self.files.iter() {
-            self.file_data
-                .extend(file.itype.inner.borrow().content.as_ref().unwrap());
+    pub fn reorder(&mut self) -> Result<()> {
+        let order = self.order.take();
+        let patterns = &self.plain_patterns;
+        (self.plain_files, self.files) = mem::take(&mut self.files)
+            .into_iter()
+            .partition(|file| patterns.is_match(rel_path(file.meta.path())));
+        sort_plain(
+            &mut self.plain_files,
+            self.data_order,
+            order.as_deref().unwrap_or_default(),
+            &self.access_profile,
+        );
+        put_profiled_first(&mut self.plain_files, &self.access_profile);
+        let duplicates = self.collapse_duplicates();
+        self.segments = self.files.iter().map(Segment::whole).collect();
+        let optimize = order.is_none() && self.reorder_mode != ReorderMode::Off;
+        if optimize || self.delta_threshold.is_some() {
+            self.construct_diff_map();
+        }
+        if let Some(threshold) = self.delta_threshold {
+            self.select_deltas(threshold)?;
+            // delta files store less data now
+            self.segments = self.files.iter().map(Segment::whole).collect();
+        }
+        match order {
+            Some(order) => self.apply_order(&order),
+            None => {
+                // accessed files go first as they are, the rest is reordered
+                let mut profiled = self.take_profiled(&duplicates);
+                if let Some(avg_size) = self.segment_size {
+                    self.split_segments(avg_size)?;
+                }
+                if optimize && (self.segment_size.is_some() || !profiled.is_empty()) {
+                    self.construct_diff_map();
+                }
+                if optimize {
+                    self.optimize();
+                }
+                // files of one policy together, a cluster never mixes policies
+                let policies = &self.policies;
+                self.segments
+                    .sort_by_key(|segment| policy_of(policies, &segment.file));
+                profiled.append(&mut self.segments);
+                self.segments = profiled;
+            }
+        }
+        self.expand_duplicates(duplicates);
+
+        // files in the order their data first appears
+        let mut seen = HashSet::new();
+        self.files = self
+            .segments
+            .iter()
+            .flat_map(|segment| segment.places())
+            .filter(|(file, _)| seen.insert(Rc::as_ptr(file)))
+            .map(|(file, _)| file.clone())
+            .collect();
+        Ok(())
+    }
+
+    // Keeps only the first file of each set with identical content, so that
+    // the diff matrix and the optimizer only see distinct contents. Returns
+    // the removed files keyed by content hash.
+    fn collapse_duplicates(&mut self) -> HashMap<ContentHash, Vec<Rc<Inode<File>>>> {
+        let mut seen = HashSet::new();
+        let mut duplicates: HashMap<_, Vec<_>> = HashMap::new();
+        self.files.retain(|file| {
+            let Some(hash) = file.itype.inner.borrow().hash else {
+                return true;
+            };
+            if seen.insert(hash) {
+                return true;
+            }
+            duplicates.entry(hash).or_default().push(file.clone());
+            false
+        });
+        let count: usize = duplicates.values().map(Vec::len).sum();
+        if count > 0 {
+            log::info!("{count} files duplicate the content of another file");
+        }
+        duplicates
+    }
+
+    // Lets the removed duplicates share the data of their representative. A
+    // delta-encoded representative stores its delta, so its duplicates are
+    // stored again right after it.
+    fn expand_duplicates(&mut self, duplicates: HashMap<ContentHash, Vec<Rc<Inode<File>>>>) {
+        if duplicates.is_empty() {
+            return;
+        }
+        for mut segment in mem::take(&mut self.segments) {
+            let mut shared = Vec::new();
+            let mut copies = Vec::new();
+            for (file, off) in segment.places() {
+                let hash = file.itype.inner.borrow().hash;
+                let Some(dups) = hash.and_then(|hash| duplicates.get(&hash)) else {
+                    continue;
+                };
+                for dup in dups {
+                    if file.is_delta() {
+                        copies.push(Segment::whole(dup));
+                    } else {
+                        shared.push((dup.clone(), off));
+                    }
+                }
+            }
+            segment.shared.extend(shared);
+            self.segments.push(segment);
+            self.segments.extend(copies);
+        }
+    }
+
+    // Removes the segments of files in the access profile, in access order. A
+    // file is accessed when one of its duplicates is.
+    fn take_profiled(
+        &mut self,
+        duplicates: &HashMap<ContentHash, Vec<Rc<Inode<File>>>>,
+    ) -> Vec<Segment> {
+        let rank_of = profile_rank(&self.access_profile);
+        let segment_rank = |segment: &Segment| {
+            let hash = segment.file.itype.inner.borrow().hash;
+            let dups = hash.and_then(|hash| duplicates.get(&hash));
+            dups.into_iter()
+                .flatten()
+                .map(|dup| rank_of(dup))
+                .fold(rank_of(&segment.file), usize::min)
+        };
+        let (mut profiled, rest): (Vec<_>, Vec<_>) = mem::take(&mut self.segments)
+            .into_iter()
+            .partition(|segment| segment_rank(segment) != usize::MAX);
+        profiled.sort_by_cached_key(segment_rank);
+        self.segments = rest;
+        profiled
+    }
+
+    // Replaces large files by their content-defined segments, storing
+    // segments with identical content only once.
+    fn split_segments(&mut self, avg_size: u64) -> Result<()> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut seen: HashMap<ContentHash, usize> = HashMap::new();
+        let mut deduped = 0;
+        for segment in mem::take(&mut self.segments) {
+            if segment.file.is_delta() || segment.len < 2 * avg_size {
+                segments.push(segment);
+                continue;
+            }
+            for (piece, hash) in split_file(&segment.file, avg_size)? {
+                match seen.get(&hash) {
+                    Some(&i) => {
+                        segments[i].shared.push((piece.file, piece.off));
+                        deduped += 1;
+                    }
+                    None => {
+                        seen.insert(hash, segments.len());
+                        segments.push(piece);
+                    }
+                }
+            }
+        }
+        log::info!(
+            "{} segments after splitting, {deduped} duplicate segments",
+            segments.len()
+        );
+        self.segments = segments;
+        Ok(())
+    }
+
+    // Lays files out in the given order of paths relative to the source root,
+    // files missing from it keep their relative order at the end.
+    pub fn apply_order(&mut self, order: &[PathBuf]) {
+        let rank_of = order_rank(order, &self.files);
+        self.files.sort_by_key(|f| rank_of(f));
+        self.segments.sort_by_key(|segment| rank_of(&segment.file));
+    }
+
+    // layout of an uncompressed image, the order file is followed unless
+    // another data order is given
+    pub fn sort_files(&mut self) {
+        let order = self.order.take().unwrap_or_default();
+        match self.data_order {
+            DataOrder::Input if !order.is_empty() => self.apply_order(&order),
+            data_order => sort_plain(&mut self.files, data_order, &order, &self.access_profile),
+        }
+        put_profiled_first(&mut self.files, &self.access_profile);
+    }
+
+    // one path relative to the source root per line, readable by read_order
+    pub fn write_order(&self, w: &mut dyn Write) -> Result<()> {
+        for file in self.files.iter().chain(self.plain_files.iter()) {
+            w.write_all(rel_path(file.meta.path()).as_os_str().as_bytes())?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    // fingerprint of a source file, from the cache while it is unchanged
+    pub fn fingerprint(
+        &mut self,
+        path: &Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<(Option<Tlsh>, ContentHash)> {
+        if let Some(fingerprint) = mkfs_take_fingerprint(metadata) {
+            return Ok(fingerprint);
+        }
+        let Some(cache) = self.fingerprint_cache.as_mut() else {
+            return calc_fingerprint(fs::File::open(path)?);
+        };
+        if let Some(fingerprint) = cache.get(path, metadata) {
+            return Ok(fingerprint);
+        }
+        let (tlsh, hash) = calc_fingerprint(fs::File::open(path)?)?;
+        cache.insert(path, metadata, tlsh.as_ref(), &hash);
+        Ok((tlsh, hash))
+    }
+
+    // files in per-file mode start a new cluster and end their last one
+    pub fn is_per_file(&self, file: &Inode<File>) -> bool {
+        self.per_file || self.per_file_patterns.is_match(rel_path(file.meta.path()))
+    }
+
+    // codecs tried on clusters holding this file
+    pub fn codecs_for(&self, file: &Inode<File>) -> &[Codec] {
+        match policy_of(&self.policies, file) {
+            Some(i) => &self.policies[i].codecs,
+            None => &self.codecs,
+        }
+    }
+
+    // offsets in the concatenated segment data that no cluster may cross
+    pub fn cluster_boundaries(&self) -> Vec<u64> {
+        let mut boundaries = Vec::new();
+        let mut off = 0;
+        let mut last_policy = None;
+        for segment in self.segments.iter() {
+            let end = off + segment.len;
+            let policy = policy_of(&self.policies, &segment.file);
+            if self.is_per_file(&segment.file) || last_policy.is_some_and(|p| p != policy) {
+                boundaries.push(off);
+            }
+            if self.is_per_file(&segment.file) {
+                boundaries.push(end);
+            }
+            last_policy = Some(policy);
+            off = end;
+        }
+        boundaries.dedup();
+        boundaries
+    }
+
+    // total bytes of file data to be compressed
+    pub fn data_size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.len).sum()
+    }
+
+    // Turns files whose TLSH distance to another file is within threshold into
+    // binary deltas against it. Bases are never deltas themselves, so reading
+    // a delta file needs at most one extra file.
+    pub fn select_deltas(&mut self, threshold: usize) -> Result<()> {
+        // the search for bases is quadratic, files past these are stored whole
+        let len = self.files.len().min(DELTA_FILES_MAX);
+        if len < self.files.len() {
+            log::info!("looking for deltas among the first {len} files only");
+        }
+        let mut delta_bytes = 0;
+        let mut is_base = vec![false; len];
+        let mut is_delta = vec![false; len];
+        for j in 0..len {
+            if is_base[j] {
+                continue;
+            }
+            let Some(i) = (0..len)
+                .filter(|&i| i != j && !is_delta[i])
+                .min_by_key(|&i| self.diff_mat[j][i])
+            else {
+                continue;
+            };
+            if self.diff_mat[j][i] > threshold {
+                continue;
+            }
+            let (base, target) = (&self.files[i], &self.files[j]);
+            let data = delta::encode(&base.mkfs_read()?, &target.mkfs_read()?);
+            if data.len() >= target.itype.size as usize {
+                continue;
+            }
+            // deltas are held in memory until written
+            delta_bytes += data.len();
+            if delta_bytes > DELTA_MEMORY_MAX {
+                log::info!("deltas take over {DELTA_MEMORY_MAX} bytes, storing the rest whole");
+                break;
+            }
+            log::info!(
+                "delta {} against {}: {} -> {} bytes",
+                target.meta.path().display(),
+                base.meta.path().display(),
+                target.itype.size,
+                data.len()
+            );
+            target.itype.inner.borrow_mut().delta = Some(Delta {
+                base: base.clone(),
+                data_size: data.len() as _,
+                data: Some(data),
+            });
+            is_base[i] = true;
+            is_delta[j] = true;
         }
+        Ok(())
     }
 
     pub fn construct_diff_map(&mut self) {
         const DEFAULT_DIFF: usize = 1000;
-        let len = self.files.len();
+        let len = self.segments.len();
         self.diff_mat = vec![vec![0; len]; len];
         for i in 0..len {
             for j in i + 1..len {
-                let inode_pair = (&self.files[i], &self.files[j]);
-                let diff = {
-                    let tlsh_pair = (
-                        &inode_pair.0.itype.inner.borrow().tlsh,
-                        &inode_pair.1.itype.inner.borrow().tlsh,
-                    );
-                    log::debug!("tlsh pair {:?}", tlsh_pair);
-                    match tlsh_pair {
-                        (Some(t0), Some(t1)) => t0.diff(t1, false),
-                        _ => DEFAULT_DIFF,
-                    }
+                let pair = (&self.segments[i], &self.segments[j]);
+                log::debug!("tlsh pair {:?}", (&pair.0.tlsh, &pair.1.tlsh));
+                let diff = match (&pair.0.tlsh, &pair.1.tlsh) {
+                    (Some(t0), Some(t1)) => t0.diff(t1, false),
+                    _ => DEFAULT_DIFF,
                 };
                 log::info!(
-                    "diff of {} and {} is {}",
-                    inode_pair.0.meta.path().display(),
-                    inode_pair.1.meta.path().display(),
+                    "diff of {}@{} and {}@{} is {}",
+                    pair.0.file.meta.path().display(),
+                    pair.0.off,
+                    pair.1.file.meta.path().display(),
+                    pair.1.off,
                     diff
                 );
                 self.diff_mat[i][j] = diff;
@@ -82,39 +644,367 @@ impl CompressManager {
     }
 
     pub fn optimize(&mut self) {
-        // let initial_path = nearest_neighbor(&self.diff_mat);
-        let initial_path = nearest_neighbor_dual_end(&self.diff_mat);
-
-        let optimized_path = two_opt_optimize(initial_path, &self.diff_mat);
+        // e.g. every file is stored uncompressed
+        if self.segments.is_empty() {
+            return;
+        }
+        let optimized_path = match self.reorder_mode {
+            ReorderMode::Off => return,
+            ReorderMode::Fast => nearest_neighbor(&self.diff_mat),
+            ReorderMode::Full => {
+                two_opt_optimize(nearest_neighbor_dual_end(&self.diff_mat), &self.diff_mat)
+            }
+        };
+        let input_path = (0..self.segments.len()).collect::<Vec<_>>();
+        self.reorder_cost = Some((
+            calculate_total_cost(&input_path, &self.diff_mat),
+            calculate_total_cost(&optimized_path, &self.diff_mat),
+        ));
         log::info!(
             "total cost: {}",
             calculate_total_cost(&optimized_path, &self.diff_mat)
         );
 
-        let real_path = optimized_path
+        log::info!("path reordered: ");
+        for idx in optimized_path.iter() {
+            let segment = &self.segments[*idx];
+            log::info!("{}@{}", segment.file.meta.path().display(), segment.off);
+        }
+
+        let mut segments = mem::take(&mut self.segments)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.segments = optimized_path
             .iter()
-            .map(|idx| self.files[*idx].meta.path())
+            .map(|idx| segments[*idx].take().unwrap())
             .collect::<Vec<_>>();
-        log::info!("path reordered: ");
-        for path in real_path.iter() {
-            log::info!("{}", path.display());
+    }
+
+    pub fn push_cluster(
+        &mut self,
+        blk_id: blk_t,
+        codec: CodexFsCodec,
+        in_size: u32,
+        out_size: u32,
+    ) {
+        assert!(self.clusters.last().is_none_or(|c| c.blk_id < blk_id));
+        self.clusters.push(Cluster {
+            blk_id,
+            codec,
+            in_size,
+            out_size,
+        });
+    }
+
+    pub fn cluster(&self, blk_id: blk_t) -> Option<&Cluster> {
+        let i = self.clusters.partition_point(|c| c.blk_id < blk_id);
+        self.clusters.get(i).filter(|c| c.blk_id == blk_id)
+    }
+}
+
+pub type ContentHash = [u8; 32];
+
+// TLSH for similarity and a sha256 of the content for exact matches, computed
+// in a single pass over the data
+pub struct Fingerprinter {
+    tlsh: TlshBuilder,
+    sha: Sha256,
+}
+
+impl Default for Fingerprinter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fingerprinter {
+    pub fn new() -> Self {
+        Self {
+            tlsh: TlshBuilder::new(
+                BucketKind::Bucket256,
+                ChecksumKind::ThreeByte,
+                Version::Version4,
+            ),
+            sha: Sha256::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.tlsh.update(data);
+        self.sha.update(data);
+    }
+
+    pub fn finish(self) -> (Option<Tlsh>, ContentHash) {
+        (self.tlsh.build().ok(), self.sha.finalize().into())
+    }
+}
+
+pub fn calc_fingerprint(mut reader: impl Read) -> io::Result<(Option<Tlsh>, ContentHash)> {
+    let mut fingerprinter = Fingerprinter::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
         }
+        fingerprinter.update(&buf[..n]);
+    }
+    Ok(fingerprinter.finish())
+}
 
-        self.files = optimized_path
+// lays out files stored uncompressed, in input order those in the order file
+// follow it
+fn sort_plain(
+    files: &mut [Rc<Inode<File>>],
+    data_order: DataOrder,
+    order: &[PathBuf],
+    profile: &[PathBuf],
+) {
+    let path_of = |f: &Rc<Inode<File>>| rel_path(f.meta.path()).to_owned();
+    match data_order {
+        DataOrder::Input if !order.is_empty() => {
+            let rank_of = order_rank(order, files);
+            files.sort_by_key(|f| rank_of(f));
+        }
+        DataOrder::Input => (),
+        DataOrder::Name => files.sort_by_cached_key(path_of),
+        DataOrder::Size => files.sort_by_cached_key(|f| (f.itype.size, path_of(f))),
+        DataOrder::Extension => files.sort_by_cached_key(|f| {
+            let path = path_of(f);
+            (path.extension().map(OsStr::to_owned), path)
+        }),
+        DataOrder::AccessProfile => {
+            let rank_of = profile_rank(profile);
+            files.sort_by_key(|f| rank_of(f));
+        }
+    }
+}
+
+// position of a file in order, files missing from it go last
+fn order_rank<'a>(
+    order: &'a [PathBuf],
+    files: &[Rc<Inode<File>>],
+) -> impl Fn(&Inode<File>) -> usize + 'a {
+    let rank: HashMap<&Path, usize> = order
+        .iter()
+        .enumerate()
+        .map(|(i, path)| (path.as_path(), i))
+        .collect();
+    let unknown = files
+        .iter()
+        .filter(|f| !rank.contains_key(rel_path(f.meta.path())))
+        .count();
+    if unknown > 0 {
+        log::warn!("{unknown} files not in the order file, appending them");
+    }
+    move |f: &Inode<File>| {
+        rank.get(rel_path(f.meta.path()))
+            .copied()
+            .unwrap_or(usize::MAX)
+    }
+}
+
+// position of a file in the access profile, files never accessed go last
+fn profile_rank(profile: &[PathBuf]) -> impl Fn(&Inode<File>) -> usize + '_ {
+    let mut rank = HashMap::new();
+    for (i, path) in profile.iter().enumerate() {
+        rank.entry(path.as_path()).or_insert(i);
+    }
+    move |f: &Inode<File>| {
+        rank.get(rel_path(f.meta.path()))
+            .copied()
+            .unwrap_or(usize::MAX)
+    }
+}
+
+// stable, the files not in the profile keep their order after the others
+fn put_profiled_first(files: &mut [Rc<Inode<File>>], profile: &[PathBuf]) {
+    if !profile.is_empty() {
+        let rank_of = profile_rank(profile);
+        files.sort_by_key(|f| rank_of(f));
+    }
+}
+
+// A boot or access trace, one path per line in first-access order, either
+// absolute in the image or relative to its root. Later accesses of a path
+// do not move it.
+pub fn read_access_profile(r: impl BufRead) -> Result<Vec<PathBuf>> {
+    let mut profile = Vec::new();
+    for path in read_order(r)? {
+        let path = path.strip_prefix("/").unwrap_or(&path);
+        let path: PathBuf = path.components().collect();
+        if !path.as_os_str().is_empty() {
+            profile.push(path);
+        }
+    }
+    Ok(profile)
+}
+
+pub fn read_order(r: impl BufRead) -> Result<Vec<PathBuf>> {
+    let mut order = Vec::new();
+    for line in r.split(b'\n') {
+        let line = line?;
+        if !line.is_empty() {
+            order.push(PathBuf::from(OsStr::from_bytes(&line)));
+        }
+    }
+    Ok(order)
+}
+
+// Where the stored bytes of a segment come from. Holds no inodes, so that the
+// data can be read on another thread.
+enum DataSource {
+    Path(PathBuf, u64, u64), // offset and length within the source file
+    Bytes(Vec<u8>),
+}
+
+// Reads the data of segments back to back, opening each source file only when
+// the previous one is exhausted, so that mkfs never holds more than the
+// caller's buffer in memory.
+pub struct FileDataReader {
+    sources: vec::IntoIter<DataSource>,
+    cur: Option<Box<dyn Read + Send>>,
+}
+
+impl FileDataReader {
+    pub fn new(segments: &[Segment]) -> Self {
+        let sources = segments
             .iter()
-            .map(|idx| self.files[*idx].clone())
+            .map(|segment| {
+                let (off, len) = (segment.off as usize, segment.len as usize);
+                match &segment.file.itype.inner.borrow().delta {
+                    Some(delta) => {
+                        DataSource::Bytes(delta.data.as_ref().unwrap()[off..off + len].to_vec())
+                    }
+                    None => match &segment.file.itype.inner.borrow().contents {
+                        Some(contents) => DataSource::Bytes(contents[off..off + len].to_vec()),
+                        None => DataSource::Path(
+                            segment.file.meta.path().into(),
+                            segment.off,
+                            segment.len,
+                        ),
+                    },
+                }
+            })
             .collect::<Vec<_>>();
+        Self {
+            sources: sources.into_iter(),
+            cur: None,
+        }
     }
 }
 
-pub fn calc_tlsh(content: &[u8]) -> Option<Tlsh> {
-    let mut builder = TlshBuilder::new(
-        BucketKind::Bucket256,
-        ChecksumKind::ThreeByte,
-        Version::Version4,
-    );
-    builder.update(content);
-    builder.build().ok()
+impl Read for FileDataReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(cur) = self.cur.as_mut() {
+                let n = cur.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            self.cur = match self.sources.next() {
+                None => return Ok(0),
+                Some(DataSource::Bytes(data)) => Some(Box::new(io::Cursor::new(data))),
+                Some(DataSource::Path(_, _, 0)) => Some(Box::new(io::empty())),
+                Some(DataSource::Path(path, off, len)) => {
+                    let mut f = fs::File::open(path)?;
+                    f.seek(SeekFrom::Start(off))?;
+                    // never read past the size recorded at scan time
+                    Some(Box::new(f.take(len)))
+                }
+            };
+        }
+    }
+}
+
+// chunk size and number of chunks in flight between mkfs pipeline stages
+const PIPELINE_CHUNK_SIZE: u64 = 1 << 20;
+const PIPELINE_DEPTH: usize = 8;
+
+// Runs a FileDataReader on its own thread, so that reading the sources
+// overlaps with compressing them.
+pub struct PipelinedReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    cur: io::Cursor<Vec<u8>>,
+}
+
+impl PipelinedReader {
+    pub fn spawn(mut reader: FileDataReader) -> Self {
+        let (tx, rx) = mpsc::sync_channel(PIPELINE_DEPTH);
+        thread::spawn(move || {
+            loop {
+                let mut chunk = Vec::with_capacity(PIPELINE_CHUNK_SIZE as usize);
+                let res = match (&mut reader)
+                    .take(PIPELINE_CHUNK_SIZE)
+                    .read_to_end(&mut chunk)
+                {
+                    Ok(0) => break,
+                    Ok(_) => Ok(chunk),
+                    Err(e) => Err(e),
+                };
+                let failed = res.is_err();
+                // the receiver is gone when compression has bailed out
+                if tx.send(res).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self {
+            rx,
+            cur: io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl Read for PipelinedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.cur.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.rx.recv() {
+                Ok(chunk) => self.cur = io::Cursor::new(chunk?),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+// Writes compressed clusters to the image on its own thread, so that writing
+// overlaps with compressing the next cluster.
+pub struct ClusterWriter {
+    tx: SyncSender<(u64, Vec<u8>)>,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+impl ClusterWriter {
+    pub fn spawn(img_file: fs::File) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<(u64, Vec<u8>)>(PIPELINE_DEPTH);
+        let handle = thread::spawn(move || {
+            for (addr, buf) in rx {
+                img_file.write_all_at(&buf, addr)?;
+            }
+            Ok(())
+        });
+        Self { tx, handle }
+    }
+
+    pub fn write(&self, buf: Vec<u8>, addr: u64) -> Result<()> {
+        if self.tx.send((addr, buf)).is_err() {
+            bail!("image writer exited early");
+        }
+        Ok(())
+    }
+
+    // waits for all queued clusters to hit the image
+    pub fn finish(self) -> Result<()> {
+        drop(self.tx);
+        self.handle.join().unwrap()?;
+        Ok(())
+    }
 }
 
 fn select_initial_node(diff_mat: &[Vec<usize>]) -> usize {
@@ -246,3 +1136,70 @@ fn two_opt_optimize(mut path: Vec<usize>, diff_matrix: &[Vec<usize>]) -> Vec<usi
     }
     best_path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image::Image,
+        inode::{Dir, PseudoEntry},
+        mode_t,
+        options::init_mkfs_opts,
+        sb::{SuperBlock, get_sb_mut, set_sb},
+    };
+
+    fn names(files: &[Rc<Inode<File>>]) -> Vec<&str> {
+        files
+            .iter()
+            .map(|f| rel_path(f.meta.path()).to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn check_sort_plain() {
+        let _image = Rc::new(Image::default()).enter();
+        set_sb(SuperBlock::new(fs::File::open("/dev/null").unwrap(), 12));
+        init_mkfs_opts();
+        let entry = |mode, size| PseudoEntry {
+            mode,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            target: None,
+            contents: Some(Rc::from(vec![0; size])),
+        };
+        let root = Rc::new(Inode::<Dir>::new_pseudo(
+            Path::new("/src"),
+            &entry(libc::S_IFDIR as mode_t | 0o755, 0),
+        ));
+        get_sb_mut().set_root(root);
+        let files: Vec<_> = [("b.txt", 3), ("c", 1), ("a.so", 2), ("d.txt", 0)]
+            .into_iter()
+            .map(|(name, size)| {
+                let path = Path::new("/src").join(name);
+                Rc::new(Inode::<File>::new_pseudo(
+                    &path,
+                    &entry(libc::S_IFREG as mode_t | 0o644, size),
+                ))
+            })
+            .collect();
+        let order = [PathBuf::from("d.txt"), PathBuf::from("c")];
+        let profile = [PathBuf::from("a.so"), PathBuf::from("d.txt")];
+        let sorted = |data_order, order: &[PathBuf]| {
+            let mut files = files.clone();
+            sort_plain(&mut files, data_order, order, &profile);
+            names(&files).join(" ")
+        };
+
+        assert_eq!(sorted(DataOrder::Input, &[]), "b.txt c a.so d.txt");
+        assert_eq!(sorted(DataOrder::Input, &order), "d.txt c b.txt a.so");
+        assert_eq!(sorted(DataOrder::Name, &order), "a.so b.txt c d.txt");
+        assert_eq!(sorted(DataOrder::Size, &order), "d.txt c a.so b.txt");
+        assert_eq!(sorted(DataOrder::Extension, &order), "c a.so b.txt d.txt");
+        // the profile, not the order file
+        assert_eq!(
+            sorted(DataOrder::AccessProfile, &order),
+            "a.so d.txt b.txt c"
+        );
+    }
+}
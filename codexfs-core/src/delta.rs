@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, bail, ensure};
+
+// Binary delta of a file against a similar base file. The encoding is a
+// sequence of ops:
+//   COPY:   0x00, base offset (u32 le), length (u32 le)
+//   INSERT: 0x01, length (u32 le), literal bytes
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+// granularity of base indexing, matches shorter than this are stored literally
+const DELTA_BLOCK: usize = 16;
+
+fn push_insert(delta: &mut Vec<u8>, lit: &[u8]) {
+    if lit.is_empty() {
+        return;
+    }
+    delta.push(OP_INSERT);
+    delta.extend((lit.len() as u32).to_le_bytes());
+    delta.extend(lit);
+}
+
+fn push_copy(delta: &mut Vec<u8>, off: usize, len: usize) {
+    delta.push(OP_COPY);
+    delta.extend((off as u32).to_le_bytes());
+    delta.extend((len as u32).to_le_bytes());
+}
+
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut index = HashMap::new();
+    for off in (0..base.len().saturating_sub(DELTA_BLOCK - 1)).step_by(DELTA_BLOCK) {
+        index.entry(&base[off..off + DELTA_BLOCK]).or_insert(off);
+    }
+
+    let mut delta = Vec::new();
+    let mut lit_start = 0;
+    let mut pos = 0;
+    while pos + DELTA_BLOCK <= target.len() {
+        let Some(&boff) = index.get(&target[pos..pos + DELTA_BLOCK]) else {
+            pos += 1;
+            continue;
+        };
+        // extend the match backwards into pending literals and then forwards
+        let mut start = pos;
+        let mut bstart = boff;
+        while start > lit_start && bstart > 0 && target[start - 1] == base[bstart - 1] {
+            start -= 1;
+            bstart -= 1;
+        }
+        let mut end = pos + DELTA_BLOCK;
+        let mut bend = boff + DELTA_BLOCK;
+        while end < target.len() && bend < base.len() && target[end] == base[bend] {
+            end += 1;
+            bend += 1;
+        }
+        push_insert(&mut delta, &target[lit_start..start]);
+        push_copy(&mut delta, bstart, end - start);
+        pos = end;
+        lit_start = end;
+    }
+    push_insert(&mut delta, &target[lit_start..]);
+    delta
+}
+
+fn read_u32(delta: &[u8], pos: &mut usize) -> Result<usize> {
+    let Some(bytes) = delta.get(*pos..*pos + 4) else {
+        bail!("truncated delta at {}", *pos);
+    };
+    *pos += 4;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+pub fn apply(base: &[u8], delta: &[u8], size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            OP_COPY => {
+                let off = read_u32(delta, &mut pos)?;
+                let len = read_u32(delta, &mut pos)?;
+                let Some(src) = base.get(off..off + len) else {
+                    bail!("delta copy {off}+{len} out of base range {}", base.len());
+                };
+                out.extend(src);
+            }
+            OP_INSERT => {
+                let len = read_u32(delta, &mut pos)?;
+                let Some(src) = delta.get(pos..pos + len) else {
+                    bail!("truncated delta literal at {pos}");
+                };
+                out.extend(src);
+                pos += len;
+            }
+            _ => bail!("invalid delta op {op:#x} at {}", pos - 1),
+        }
+    }
+    ensure!(
+        out.len() == size,
+        "delta produced {} bytes, expected {size}",
+        out.len()
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_delta_roundtrip() {
+        let base: Vec<u8> = (0..10000u32)
+            .flat_map(|i| (i * 7919).to_le_bytes())
+            .collect();
+        let mut target = base.clone();
+        target[100..110].fill(0xaa);
+        target.splice(5000..5000, b"inserted".iter().copied());
+        target.truncate(30000);
+        target.extend(b"tail");
+
+        let delta = encode(&base, &target);
+        assert!(delta.len() < target.len() / 10);
+        assert_eq!(apply(&base, &delta, target.len()).unwrap(), target);
+
+        assert_eq!(apply(&base, &encode(&base, &[]), 0).unwrap(), b"");
+        assert_eq!(apply(&[], &encode(&[], b"abc"), 3).unwrap(), b"abc");
+        assert!(apply(&base, &delta, target.len() + 1).is_err());
+    }
+}
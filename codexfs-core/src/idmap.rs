@@ -0,0 +1,129 @@
+use std::io::BufRead;
+
+use anyhow::{Context, Result, bail, ensure};
+
+// Ranges of source ids moved to other image ids, ids outside every range are
+// kept as they are.
+#[derive(Clone, Debug, Default)]
+pub struct IdMap {
+    ranges: Vec<(u32, u32, u32)>, // (source start, image start, count)
+}
+
+impl IdMap {
+    pub fn map(&self, id: u32) -> u32 {
+        for &(from, to, count) in self.ranges.iter() {
+            if id >= from && id - from < count {
+                return to + (id - from);
+            }
+        }
+        id
+    }
+
+    // an id as the image stores it, in 16 bits
+    pub fn map_to_image(&self, id: u32) -> Result<u16> {
+        let mapped = self.map(id);
+        u16::try_from(mapped).with_context(|| match mapped == id {
+            true => format!("id {id} does not fit in 16 bits"),
+            false => format!("id {id} maps to {mapped}, which does not fit in 16 bits"),
+        })
+    }
+
+    // later ranges only apply to ids outside the earlier ones, ranges are
+    // checked by parse_id_range
+    pub fn push(&mut self, from: u32, to: u32, count: u32) {
+        self.ranges.push((from, to, count))
+    }
+}
+
+// SRC:DST or SRC:DST:COUNT, as (source start, image start, count)
+pub fn parse_id_range(s: &str) -> Result<(u32, u32, u32)> {
+    let fields: Vec<&str> = s.split(':').collect();
+    let (from, to, count) = match fields[..] {
+        [from, to] => (from, to, "1"),
+        [from, to, count] => (from, to, count),
+        _ => bail!("{s}: expected SRC:DST or SRC:DST:COUNT"),
+    };
+    let num = |f: &str| -> Result<u32> { f.parse().with_context(|| format!("{s}: bad id {f}")) };
+    check_range((num(from)?, num(to)?, num(count)?)).with_context(|| s.to_owned())
+}
+
+// neither end of a range may run past the largest id, so mapping never
+// overflows
+fn check_range(range: (u32, u32, u32)) -> Result<(u32, u32, u32)> {
+    let (from, to, count) = range;
+    ensure!(
+        from.checked_add(count).is_some() && to.checked_add(count).is_some(),
+        "range of {count} ids from {from} to {to} runs past {}",
+        u32::MAX
+    );
+    Ok(range)
+}
+
+// Reads uid and gid maps, one range per line in the order of /proc/*/uid_map
+// prefixed by what it maps, u, g or b for both:
+//   u 100000 0 65536
+// Fields may also be separated by ':', as in "b:100000:0:65536".
+pub fn load_id_maps(r: &mut dyn BufRead) -> Result<(IdMap, IdMap)> {
+    let (mut uid_map, mut gid_map) = (IdMap::default(), IdMap::default());
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ':' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .collect();
+        ensure!(
+            fields.len() == 4,
+            "id map line {}: expected 4 fields",
+            i + 1
+        );
+        let num = |f: &str| -> Result<u32> {
+            f.parse()
+                .with_context(|| format!("id map line {}: bad id {f}", i + 1))
+        };
+        let range = check_range((num(fields[1])?, num(fields[2])?, num(fields[3])?))
+            .with_context(|| format!("id map line {}", i + 1))?;
+        match fields[0] {
+            "u" => uid_map.ranges.push(range),
+            "g" => gid_map.ranges.push(range),
+            "b" => {
+                uid_map.ranges.push(range);
+                gid_map.ranges.push(range);
+            }
+            kind => bail!("id map line {}: unknown kind {kind}", i + 1),
+        }
+    }
+    Ok((uid_map, gid_map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_id_maps() {
+        let input = "# comment\nb:100000:0:65536\nu 5000 1000 1\n";
+        let (uid_map, gid_map) = load_id_maps(&mut input.as_bytes()).unwrap();
+        assert_eq!(uid_map.map(100000), 0);
+        assert_eq!(uid_map.map(101000), 1000);
+        assert_eq!(uid_map.map(165536), 165536);
+        assert_eq!(uid_map.map(5000), 1000);
+        assert_eq!(gid_map.map(5000), 5000);
+        assert!(load_id_maps(&mut "x 1 2 3".as_bytes()).is_err());
+        assert!(load_id_maps(&mut "u 1 2".as_bytes()).is_err());
+        assert_eq!(parse_id_range("1000:0").unwrap(), (1000, 0, 1));
+        assert_eq!(
+            parse_id_range("100000:0:65536").unwrap(),
+            (100000, 0, 65536)
+        );
+        assert!(parse_id_range("1000").is_err());
+        assert!(parse_id_range("0:4294967295:2").is_err());
+        assert!(load_id_maps(&mut "u 4294967295 0 2".as_bytes()).is_err());
+        assert_eq!(uid_map.map_to_image(101000).unwrap(), 1000);
+        assert!(uid_map.map_to_image(165536).is_err());
+        assert!(parse_id_range("a:0").is_err());
+    }
+}
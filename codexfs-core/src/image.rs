@@ -0,0 +1,71 @@
+use std::{
+    cell::{Cell, OnceCell, UnsafeCell},
+    fs::File,
+    ptr,
+    rc::Rc,
+};
+
+use anyhow::Result;
+
+use crate::{
+    cluster_cache::ClusterCache,
+    inode::InodeTable,
+    sb::{self, SuperBlock},
+};
+
+// What reading one image takes: its super block, the inodes loaded from it by
+// ino and the clusters decoded. mkfs and the subcommands reading an image use
+// the process-wide ones, the FUSE driver an Image per image it serves. While
+// an image is entered on a thread, get_sb, get_inode and get_cluster_cache_mut
+// there go to it.
+#[derive(Debug, Default)]
+pub struct Image(UnsafeCell<ImageState>);
+
+#[derive(Debug, Default)]
+pub(crate) struct ImageState {
+    pub(crate) sb: OnceCell<SuperBlock>,
+    pub(crate) inode_table: InodeTable,
+    pub(crate) cluster_cache: OnceCell<ClusterCache>,
+}
+
+thread_local! {
+    static ENTERED: Cell<*const Image> = const { Cell::new(ptr::null()) };
+}
+
+// the image entered on this thread, if any
+pub(crate) fn entered() -> Option<&'static mut ImageState> {
+    let image = ENTERED.get();
+    unsafe { image.as_ref().map(|image| &mut *image.0.get()) }
+}
+
+impl Image {
+    // loads the super block and the root of the image in img_file
+    pub fn open(img_file: File) -> Result<Rc<Self>> {
+        let image = Rc::new(Self::default());
+        let _entered = image.enter();
+        sb::fuse_load_super_block(img_file)?;
+        Ok(image)
+    }
+
+    // Makes this the image of the calling thread until the guard is dropped,
+    // which enters the one entered before again.
+    pub fn enter(self: &Rc<Self>) -> Entered {
+        let prev = ENTERED.replace(Rc::as_ptr(self));
+        Entered {
+            _image: self.clone(),
+            prev,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Entered {
+    _image: Rc<Image>, // kept while entered
+    prev: *const Image,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        ENTERED.set(self.prev);
+    }
+}
@@ -1,42 +1,71 @@
 mod dir;
 mod file;
 mod inode_table;
+mod merge;
+mod special;
 mod symlink;
 
 use std::{
     any::Any,
     cell::RefCell,
-    cmp::min,
+    cmp::{max, min},
+    collections::{BTreeSet, HashMap, hash_map::Entry},
+    ffi::{OsStr, OsString},
     fmt::Debug,
     fs::{self},
-    os::unix::fs::MetadataExt,
+    io::Read,
+    mem,
+    ops::Range,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, MetadataExt},
+    },
     path::{Path, PathBuf},
     rc::{Rc, Weak},
+    str::FromStr,
+    thread,
+    time::Instant,
 };
 
-use anyhow::{Ok, Result};
+use anyhow::{Context, Ok, Result, bail, ensure};
 use bytemuck::{Zeroable, bytes_of, checked::from_bytes};
 pub use dir::*;
 pub use file::*;
 pub use inode_table::*;
+pub use merge::*;
+pub use special::*;
 pub use symlink::*;
-use xz2::stream::{LzmaOptions, Stream};
+use tracing::{debug_span, field};
+use xz2::stream::Stream;
 
 use crate::{
-    CodexFsDirent, CodexFsExtent, CodexFsFileType, CodexFsInode, CodexFsInodeUnion, addr_to_blk_id,
-    addr_to_blk_off, addr_to_nid, blk_id_to_addr, blk_size_t, blk_t,
-    buffer::{BufferType, get_bufmgr_mut},
-    compress::{get_cmpr_mgr, get_cmpr_mgr_mut},
-    gid_t, ino_t, mode_t, nid_to_inode_meta_off, nid_to_inode_off, off_t,
+    CODEXFS_FILEINFO_DELTA, CODEXFS_FILEINFO_PLAIN, CODEXFS_NAME_LEN, CodexFsCodec, CodexFsDelta,
+    CodexFsDirent, CodexFsExtent, CodexFsFileInfo, CodexFsFileType, CodexFsInode,
+    CodexFsInodeFlags, CodexFsInodeUnion, addr_to_blk_id, addr_to_blk_off, addr_to_nid,
+    blk_id_to_addr, blk_size_t, blk_t,
+    buffer::{BufferType, get_align, get_bufmgr_mut, mkfs_check_max_size},
+    checksum::{fuse_read_checked, mkfs_record_checksum},
+    cluster_cache::get_cluster_cache_mut,
+    compress::{
+        ClusterWriter, Codec, FileDataReader, PipelinedReader, get_cmpr_mgr, get_cmpr_mgr_mut,
+    },
+    delta, gid_t, ino_t, mode_t, nid_to_inode_meta_off, nid_to_inode_off,
+    options::{InoMode, get_mkfs_opts, get_mkfs_opts_mut},
+    pattern::{get_path_filter, rel_path},
+    pool::get_decode_pool,
+    progress::get_progress_mut,
     sb::{get_sb, get_sb_mut},
+    scan::{mkfs_dir_entries, mkfs_metadata},
+    segment::Segment,
     uid_t,
-    utils::round_down,
+    utils::{fnv1a, round_down},
+    xattr::{self, Xattrs},
 };
 
 pub type InodeHandle = Rc<dyn InodeOps>;
 
-pub trait InodeFactory {
-    fn from_path(path: &Path) -> Self;
+pub trait InodeFactory: Sized {
+    fn from_path(path: &Path) -> Result<Self>;
     fn from_codexfs_inode(codexfs_inode: &CodexFsInode, nid: u64) -> Self;
     fn fuse_load(codexfs_inode: &CodexFsInode, nid: u64) -> Result<Rc<Self>>;
 }
@@ -45,6 +74,7 @@ pub trait InodeOps: Debug {
     fn meta(&self) -> &InodeMeta;
     fn file_type(&self) -> CodexFsFileType;
     fn as_any(&self) -> &dyn Any;
+    fn as_any_rc(self: Rc<Self>) -> Rc<dyn Any>;
 }
 
 impl dyn InodeOps {
@@ -55,6 +85,14 @@ impl dyn InodeOps {
     pub fn downcast_dir_ref(&self) -> Option<&Inode<Dir>> {
         self.as_any().downcast_ref::<Inode<Dir>>()
     }
+
+    pub fn downcast_special_ref(&self) -> Option<&Inode<Special>> {
+        self.as_any().downcast_ref::<Inode<Special>>()
+    }
+
+    pub fn downcast_symlink_ref(&self) -> Option<&Inode<SymLink>> {
+        self.as_any().downcast_ref::<Inode<SymLink>>()
+    }
 }
 
 impl From<&Rc<dyn InodeOps>> for CodexFsInode {
@@ -65,15 +103,19 @@ impl From<&Rc<dyn InodeOps>> for CodexFsInode {
             0
         };
         let u = if let Some(file) = inode.as_any().downcast_ref::<Inode<File>>() {
-            if get_sb().compress {
+            if file.is_plain() {
                 CodexFsInodeUnion {
-                    blks: file.itype.inner.borrow().extents.len() as _,
+                    blk_off: file.itype.inner.borrow().blk_off.unwrap(),
                 }
             } else {
                 CodexFsInodeUnion {
-                    blk_off: file.itype.inner.borrow().blk_off.unwrap(),
+                    blks: file.itype.inner.borrow().extents.len() as _,
                 }
             }
+        } else if let Some(special) = inode.downcast_special_ref() {
+            CodexFsInodeUnion {
+                rdev: special.itype.rdev,
+            }
         } else {
             CodexFsInodeUnion::zeroed()
         };
@@ -82,6 +124,14 @@ impl From<&Rc<dyn InodeOps>> for CodexFsInode {
         } else {
             inode.meta().meta_size()
         };
+        let mut flags = CodexFsInodeFlags::empty();
+        if let Some(file) = inode.downcast_file_ref() {
+            flags.set(CodexFsInodeFlags::CODEXFS_INODE_DELTA, file.is_delta());
+            flags.set(
+                CodexFsInodeFlags::CODEXFS_INODE_PLAIN,
+                get_sb().compress && file.is_plain(),
+            );
+        }
         Self {
             mode: inode.meta().mode,
             nlink: inode.meta().inner.borrow().nlink,
@@ -91,6 +141,8 @@ impl From<&Rc<dyn InodeOps>> for CodexFsInode {
             uid: inode.meta().uid,
             gid: inode.meta().gid,
             u,
+            flags,
+            xattr_size: 0,
             reserved: [0; _],
         }
     }
@@ -149,23 +201,23 @@ impl InodeMeta {
 #[derive(Debug)]
 pub struct Dentry {
     pub path: Option<PathBuf>,
-    pub file_name: String,
+    pub file_name: OsString, // bytes as in the source, need not be UTF-8
     pub file_type: CodexFsFileType,
     pub inode: InodeHandle,
 }
 
 impl Dentry {
     fn new_path(path: &Path, inode: InodeHandle) -> Self {
-        let metadata = path.symlink_metadata().unwrap();
+        let metadata = mkfs_metadata(path).unwrap();
         Dentry {
             path: Some(path.into()),
-            file_name: path.file_name().unwrap().to_string_lossy().to_string(),
+            file_name: path.file_name().unwrap().to_os_string(),
             file_type: metadata.file_type().into(),
             inode,
         }
     }
 
-    fn new_name(file_name: String, inode: InodeHandle) -> Self {
+    fn new_name(file_name: OsString, inode: InodeHandle) -> Self {
         Dentry {
             path: None,
             file_name,
@@ -180,20 +232,240 @@ impl From<&Dentry> for CodexFsDirent {
         Self {
             nid: dentry.inode.meta().inner.borrow().nid,
             nameoff: 0,
-            file_type: dentry.file_type,
+            file_type: dentry.file_type as u8,
             reserved: 0,
         }
     }
 }
 
-fn mkfs_load_inode_dir(path: &Path) -> Result<Rc<Inode<Dir>>> {
-    assert!(path.is_dir());
+// an entry that does not exist in the source tree, e.g. from a device table
+#[derive(Clone, Debug)]
+pub struct PseudoEntry {
+    pub mode: mode_t, // includes the file type, a directory, symlink or special file
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub rdev: u32,
+    pub target: Option<PathBuf>,    // what a symlink points to
+    pub contents: Option<Rc<[u8]>>, // of a regular file, shared by the entries hardlinked to it
+}
 
-    let dir = Rc::new(Inode::<Dir>::from_path(path));
+// replaces source metadata of a path, unset fields keep the source's
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AttrOverride {
+    pub mode: Option<mode_t>, // permission bits, the file type stays
+    pub uid: Option<uid_t>,
+    pub gid: Option<gid_t>,
+}
 
-    for entry in fs::read_dir(path)? {
-        let entry_path = entry?.path();
+// --owner and --all-root apply to pseudo entries as well
+fn mkfs_owned(entry: &PseudoEntry) -> PseudoEntry {
+    let mut entry = entry.clone();
+    if let Some((uid, gid)) = get_mkfs_opts().owner {
+        (entry.uid, entry.gid) = (uid, gid);
+    }
+    entry
+}
 
+// adds the pseudo entries that belong in dir, and their own children
+fn mkfs_add_pseudo_entries(dir: &Rc<Inode<Dir>>, path: &Path) -> Result<()> {
+    for (entry_path, entry) in get_mkfs_opts().pseudo_entries.iter() {
+        if entry_path.parent() != Some(path) {
+            continue;
+        }
+        let file_name = entry_path.file_name().unwrap().to_os_string();
+        if dir
+            .itype
+            .inner
+            .borrow()
+            .dentries
+            .iter()
+            .any(|d| d.file_name == file_name)
+        {
+            continue;
+        }
+        let entry = &mkfs_owned(entry);
+        let file_type = CodexFsFileType::try_from(entry.mode)
+            .with_context(|| entry_path.display().to_string())?;
+        let inode: InodeHandle = if file_type.is_file() {
+            let contents = entry.contents.clone().unwrap_or_else(|| Rc::from([]));
+            let (inode, new) = match get_pseudo_file_table_mut().get(&Rc::as_ptr(&contents)) {
+                Some(inode) => (inode.clone(), false),
+                None => mkfs_add_file(Rc::new(Inode::<File>::new_pseudo(entry_path, entry))),
+            };
+            get_pseudo_file_table_mut().insert(Rc::as_ptr(&contents), inode.clone());
+            inode.meta().inc_nlink();
+            if new {
+                get_inode_vec_mut().push(inode.clone());
+            }
+            dir.add_dentry(Dentry {
+                path: Some(entry_path.clone()),
+                file_name,
+                file_type,
+                inode,
+            });
+            continue;
+        } else if file_type.is_dir() {
+            let child = Rc::new(Inode::<Dir>::new_pseudo(entry_path, entry));
+            child.set_parent(Rc::downgrade(dir));
+            mkfs_add_pseudo_entries(&child, entry_path)?;
+            child.update_meta_size();
+            dir.meta.inc_nlink();
+            child
+        } else if file_type.is_symlink() {
+            let child = Inode::<SymLink>::new_pseudo(entry_path, entry);
+            child.meta.inc_nlink();
+            Rc::new(child)
+        } else {
+            let child = Inode::<Special>::new_pseudo(entry_path, entry);
+            child.meta.inc_nlink();
+            Rc::new(child)
+        };
+        log::info!("pseudo entry {}", entry_path.display());
+        get_inode_vec_mut().push(inode.clone());
+        dir.add_dentry(Dentry {
+            path: Some(entry_path.clone()),
+            file_name,
+            file_type,
+            inode,
+        });
+    }
+    Ok(())
+}
+
+// mode and owner recorded for a source entry, fails for ids the image can
+// not hold
+pub(crate) fn mkfs_attrs(path: &Path, metadata: &fs::Metadata) -> Result<(mode_t, uid_t, gid_t)> {
+    let (mode, uid, gid) = match get_mkfs_opts().attrs.get(path) {
+        Some(&attrs) => attrs,
+        None if let Some((uid, gid)) = get_mkfs_opts().owner => (metadata.mode() as _, uid, gid),
+        None => {
+            let context = |what| format!("{}: {what}", path.display());
+            let uid = get_mkfs_opts()
+                .uid_map
+                .map_to_image(metadata.uid())
+                .with_context(|| context("uid"))?;
+            let gid = get_mkfs_opts()
+                .gid_map
+                .map_to_image(metadata.gid())
+                .with_context(|| context("gid"))?;
+            (metadata.mode() as _, uid, gid)
+        }
+    };
+    let (uid, gid) = get_mkfs_opts().owner.unwrap_or((uid, gid));
+    Ok(match get_mkfs_opts().overrides.get(path) {
+        Some(o) => (
+            o.mode.map_or(mode, |bits| mode & 0o170000 | bits),
+            o.uid.unwrap_or(uid),
+            o.gid.unwrap_or(gid),
+        ),
+        None => (mode, uid, gid),
+    })
+}
+
+// A new inode number for path. Unless counting, a number given out already
+// moves the inode to the next free one.
+pub(crate) fn mkfs_alloc_ino(path: &Path) -> ino_t {
+    let count = get_sb_mut().get_ino_and_inc();
+    let mut ino = match get_mkfs_opts().ino_mode {
+        InoMode::Counter => return count,
+        // pseudo entries are not in the source
+        InoMode::Source => mkfs_metadata(path).map_or_else(|_| path_ino(path), |m| m.ino() as _),
+        InoMode::Path => path_ino(path),
+    };
+    while !get_mkfs_opts_mut().used_inos.insert(ino) {
+        ino = ino.wrapping_add(1);
+    }
+    ino
+}
+
+fn path_ino(path: &Path) -> ino_t {
+    let root = get_path_filter().map(|filter| filter.root.as_path());
+    let rel_path = root
+        .and_then(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path);
+    fnv1a(rel_path.as_os_str().as_encoded_bytes())
+}
+
+// A new file inode, or with --hardlink-dedupe an earlier one with the same
+// content and attributes, which is then also the inode of ino.
+fn mkfs_load_file(path: &Path, ino: ino_t) -> Result<InodeHandle> {
+    let (inode, new) = mkfs_add_file(Rc::new(Inode::<File>::from_path(path)?));
+    if !new {
+        insert_inode(ino, inode.clone());
+    }
+    Ok(inode)
+}
+
+// Queues file for compression, unless --hardlink-dedupe finds an earlier
+// file with the same content and attributes, which is returned instead along
+// with false.
+fn mkfs_add_file(file: Rc<Inode<File>>) -> (InodeHandle, bool) {
+    let path = file.meta.path();
+    if get_mkfs_opts().hardlink_dedupe {
+        let key = (
+            file.itype.inner.borrow().hash.unwrap(),
+            file.meta.mode,
+            file.meta.uid,
+            file.meta.gid,
+            get_mkfs_opts().xattrs.get(path).cloned(),
+        );
+        match get_content_table_mut().entry(key) {
+            Entry::Occupied(entry) => {
+                let inode: InodeHandle = entry.get().clone();
+                log::info!(
+                    "{} hardlinked to {}",
+                    path.display(),
+                    inode.meta().path().display()
+                );
+                return (inode, false);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(file.clone());
+            }
+        }
+    }
+    get_cmpr_mgr_mut().files.push(file.clone());
+    (file, true)
+}
+
+// the root's mode and owner from the command line win over everything else
+fn mkfs_override_root(meta: &mut InodeMeta) {
+    if let Some(mode) = get_mkfs_opts().root_mode {
+        meta.mode = meta.mode & 0o170000 | mode & 0o7777;
+    }
+    if let Some((uid, gid)) = get_mkfs_opts().root_owner {
+        (meta.uid, meta.gid) = (uid, gid);
+    }
+}
+
+fn mkfs_load_inode_dir(path: &Path, is_root: bool) -> Result<Rc<Inode<Dir>>> {
+    assert!(path.is_dir());
+
+    let mut dir = Inode::<Dir>::from_path(path)?;
+    if is_root {
+        mkfs_override_root(&mut dir.meta);
+    }
+    let dir = Rc::new(dir);
+
+    let entry_paths = match mkfs_dir_entries(path) {
+        Some(entry_paths) => entry_paths.to_vec(),
+        None => {
+            let mut entry_paths = Vec::new();
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let entry_path = entry.path();
+                if let Some(filter) = get_path_filter()
+                    && filter.is_excluded(&entry_path, entry.file_type()?.is_dir())
+                {
+                    log::info!("exclude {}", entry_path.display());
+                    continue;
+                }
+                entry_paths.push(entry_path);
+            }
+            entry_paths
+        }
+    };
+    for entry_path in entry_paths {
         let child = mkfs_load_inode(&entry_path, Some(Rc::downgrade(&dir)))?;
         let child_dentry = Dentry::new_path(&entry_path, child);
 
@@ -202,203 +474,436 @@ fn mkfs_load_inode_dir(path: &Path) -> Result<Rc<Inode<Dir>>> {
         }
         dir.add_dentry(child_dentry);
     }
+    mkfs_add_pseudo_entries(&dir, path)?;
 
     Ok(dir)
 }
 
+// The root of a tree made of pseudo entries only, e.g. from an archive, with
+// the mode and owner of the entry for path if there is one.
+pub fn mkfs_load_pseudo_root(path: &Path) -> Result<InodeHandle> {
+    let entry = get_mkfs_opts().pseudo_entries.get(path).cloned();
+    let entry = entry.unwrap_or(PseudoEntry {
+        mode: libc::S_IFDIR as mode_t | 0o755,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        target: None,
+        contents: None,
+    });
+    let mut dir = Inode::<Dir>::new_pseudo(path, &mkfs_owned(&entry));
+    mkfs_override_root(&mut dir.meta);
+    let dir = Rc::new(dir);
+    mkfs_add_pseudo_entries(&dir, path)?;
+    dir.set_parent(Rc::downgrade(&dir));
+    dir.update_meta_size();
+    get_inode_vec_mut().push(dir.clone());
+    Ok(dir)
+}
+
 pub fn mkfs_load_inode(path: &Path, parent: Option<Weak<Inode<Dir>>>) -> Result<InodeHandle> {
-    let metadata = path.symlink_metadata()?;
+    get_progress_mut().advance(1);
+    let metadata = mkfs_metadata(path)?;
     let ino = metadata.ino() as _;
 
     let file_type = metadata.file_type().into();
     let inode = match file_type {
         CodexFsFileType::File => {
-            let inode = get_inode(ino).cloned().unwrap_or_else(|| {
-                let child = Inode::<File>::from_path(path);
-                let inode = Rc::new(child);
-                get_cmpr_mgr_mut().files.push(inode.clone());
-                inode
-            });
+            let inode = match get_inode(ino) {
+                Some(inode) => inode.clone(),
+                None => mkfs_load_file(path, ino)?,
+            };
             inode.meta().inc_nlink();
             inode
         }
         CodexFsFileType::Dir => {
-            let inode = mkfs_load_inode_dir(path)?;
+            let inode = mkfs_load_inode_dir(path, parent.is_none())?;
             let parent = parent.unwrap_or_else(|| Rc::downgrade(&inode));
             inode.set_parent(parent);
-            let total_dirents_size =
-                (inode.itype.inner.borrow().dentries.len() + 2) * size_of::<CodexFsDirent>();
-            let total_name_size: usize = 1
-                + 2
-                + inode
-                    .itype
-                    .inner
-                    .borrow()
-                    .dentries
-                    .iter()
-                    .map(|d| d.file_name.len())
-                    .sum::<usize>();
-            inode
-                .meta
-                .set_meta_size((total_dirents_size + total_name_size) as _);
+            inode.update_meta_size();
             inode as _
         }
-        CodexFsFileType::CharDevice => todo!(),
-        CodexFsFileType::BlockDevice => todo!(),
-        CodexFsFileType::Fifo => todo!(),
-        CodexFsFileType::Socket => todo!(),
+        CodexFsFileType::CharDevice
+        | CodexFsFileType::BlockDevice
+        | CodexFsFileType::Fifo
+        | CodexFsFileType::Socket => {
+            let inode = match get_inode(ino) {
+                Some(inode) => inode.clone(),
+                None => Rc::new(Inode::<Special>::from_path(path)?),
+            };
+            inode.meta().inc_nlink();
+            inode
+        }
         CodexFsFileType::Symlink => {
-            let inode = get_inode(ino).cloned().unwrap_or_else(|| {
-                let child = Inode::<SymLink>::from_path(path);
-                Rc::new(child)
-            });
+            let inode = match get_inode(ino) {
+                Some(inode) => inode.clone(),
+                None => Rc::new(Inode::<SymLink>::from_path(path)?),
+            };
             inode.meta().inc_nlink();
             inode
         }
-        CodexFsFileType::Unknown => todo!(),
+        CodexFsFileType::Unknown => bail!("{}: unsupported file type", path.display()),
     };
 
-    if get_inode(ino).is_none() {
-        get_inode_vec_mut().push(inode.clone());
-        insert_inode(ino, inode.clone());
+    // a directory reached twice through followed symlinks is stored twice
+    match get_inode(ino) {
+        None => {
+            get_inode_vec_mut().push(inode.clone());
+            insert_inode(ino, inode.clone());
+        }
+        Some(old) if !Rc::ptr_eq(old, &inode) => get_inode_vec_mut().push(inode.clone()),
+        Some(_) => (),
     }
 
     Ok(inode)
 }
 
-pub fn mkfs_balloc_inode() {
-    let buf_mgr = get_bufmgr_mut();
+// bytes between the inode and its xattrs
+fn meta_body_size(inode: &InodeHandle) -> u64 {
+    match inode.file_type() {
+        CodexFsFileType::File => {
+            let inode = inode.downcast_file_ref().unwrap();
+            let delta_size = if inode.is_delta() {
+                size_of::<CodexFsDelta>()
+            } else {
+                0
+            };
+            (delta_size + inode.itype.inner.borrow().extents.len() * size_of::<CodexFsExtent>())
+                as _
+        }
+        CodexFsFileType::Dir | CodexFsFileType::Symlink => inode.meta().meta_size() as _,
+        CodexFsFileType::CharDevice
+        | CodexFsFileType::BlockDevice
+        | CodexFsFileType::Fifo
+        | CodexFsFileType::Socket => 0,
+        CodexFsFileType::Unknown => todo!(),
+    }
+}
+
+// encoded xattrs given to the source path of an inode
+fn mkfs_xattrs(inode: &InodeHandle) -> Result<Vec<u8>> {
+    match get_mkfs_opts().xattrs.get(inode.meta().path()) {
+        Some(xattrs) => xattr::encode(xattrs),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Limits of the format a source tree can exceed, checked after loading it so
+// that nothing is compressed or written for an image that can not be built.
+pub fn mkfs_check_limits() -> Result<()> {
     for inode in get_inode_vec_mut().iter() {
-        let file_type = inode.file_type();
-        match file_type {
-            CodexFsFileType::File => {
-                let inode = inode.downcast_file_ref().unwrap();
-                let addr = buf_mgr.balloc(
-                    (size_of::<CodexFsInode>()
-                        + inode.itype.inner.borrow().extents.len() * size_of::<CodexFsExtent>())
-                        as _,
-                    BufferType::Inode,
-                );
-                inode.meta().inner.borrow_mut().nid = addr_to_nid(addr);
-            }
-            CodexFsFileType::Dir => {
-                let addr = buf_mgr.balloc(
-                    size_of::<CodexFsInode>() as u64 + inode.meta().meta_size() as u64,
-                    BufferType::Inode,
-                );
-                inode.meta().inner.borrow_mut().nid = addr_to_nid(addr);
-            }
-            CodexFsFileType::CharDevice => todo!(),
-            CodexFsFileType::BlockDevice => todo!(),
-            CodexFsFileType::Fifo => todo!(),
-            CodexFsFileType::Socket => todo!(),
-            CodexFsFileType::Symlink => {
-                let addr = buf_mgr.balloc(
-                    size_of::<CodexFsInode>() as u64 + inode.meta().meta_size() as u64,
-                    BufferType::Inode,
-                );
-                inode.meta().inner.borrow_mut().nid = addr_to_nid(addr);
+        if let Some(file) = inode.downcast_file_ref() {
+            mkfs_warn_extents(file);
+        }
+        let Some(dir) = inode.downcast_dir_ref() else {
+            continue;
+        };
+        let inner = dir.itype.inner.borrow();
+        for dentry in inner.dentries.iter() {
+            ensure!(
+                dentry.file_name.len() <= CODEXFS_NAME_LEN,
+                "{}: file name longer than {CODEXFS_NAME_LEN} bytes",
+                dir.meta.path().join(&dentry.file_name).display()
+            );
+        }
+        // names follow all dirents, the last one must start within a u16
+        let last_name_len = inner.dentries.last().map_or(2, |d| d.file_name.len());
+        ensure!(
+            dir.meta.meta_size() as usize - last_name_len <= u16::MAX as usize,
+            "{}: {} entries with {} bytes of dirents and names do not fit in a directory",
+            dir.meta.path().display(),
+            inner.dentries.len(),
+            dir.meta.meta_size()
+        );
+    }
+    Ok(())
+}
+
+// Every cluster fills one block, so data that does not compress takes an
+// extent per block, plus one for a cluster shared with the file before. A
+// file that could need more extents than an inode holds is only warned about,
+// mkfs_balloc_inode fails on the extents it does take.
+fn mkfs_warn_extents(file: &Inode<File>) {
+    let path = file.meta.path();
+    if !get_sb().compress || get_cmpr_mgr().plain_patterns.is_match(rel_path(path)) {
+        return;
+    }
+    let size = file.itype.size as u64;
+    let extents = size.div_ceil(get_sb().blksz() as u64) + 1;
+    if extents > u16::MAX as u64 {
+        log::warn!(
+            "{}: {size} bytes may take {extents} extents, more than an inode holds, unless they \
+             compress",
+            path.display()
+        );
+    }
+}
+
+// what mkfs does about names that are equal under case folding
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaseCollisions {
+    #[default]
+    Ignore,
+    Warn,
+    Fail,
+}
+
+impl FromStr for CaseCollisions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ignore" => Ok(CaseCollisions::Ignore),
+            "warn" => Ok(CaseCollisions::Warn),
+            "fail" => Ok(CaseCollisions::Fail),
+            _ => bail!("unknown case collision check {s:?}, expected ignore, warn or fail"),
+        }
+    }
+}
+
+// pairs of names equal to an earlier one once lowercased
+fn case_collisions<'a>(names: impl Iterator<Item = &'a str>) -> Vec<(&'a str, &'a str)> {
+    let mut folded = HashMap::new();
+    let mut collisions = Vec::new();
+    for name in names {
+        match folded.entry(name.to_lowercase()) {
+            Entry::Occupied(first) => collisions.push((*first.get(), name)),
+            Entry::Vacant(e) => {
+                e.insert(name);
             }
-            CodexFsFileType::Unknown => todo!(),
         }
     }
+    collisions
 }
 
-fn mkfs_dump_codexfs_inode(inode: &InodeHandle) -> Result<()> {
-    log::info!(
-        "path: {}, nid: {}",
-        inode.meta().path().display(),
-        inode.meta().inner.borrow().nid
+// Names one directory has twice on a casefold mount or a case-insensitive
+// filesystem the tree is copied to.
+pub fn mkfs_check_case_collisions(mode: CaseCollisions) -> Result<()> {
+    if mode == CaseCollisions::Ignore {
+        return Ok(());
+    }
+    let mut count = 0;
+    for inode in get_inode_vec_mut().iter() {
+        let Some(dir) = inode.downcast_dir_ref() else {
+            continue;
+        };
+        let inner = dir.itype.inner.borrow();
+        let names = inner
+            .dentries
+            .iter()
+            .filter_map(|d| d.file_name.to_str())
+            .filter(|&name| name != "." && name != "..");
+        for (first, name) in case_collisions(names) {
+            let path = dir.meta.path();
+            log::warn!(
+                "{} collides with {} under case folding",
+                path.join(name).display(),
+                path.join(first).display()
+            );
+            count += 1;
+        }
+    }
+    ensure!(
+        mode == CaseCollisions::Warn || count == 0,
+        "{count} file names collide under case folding"
     );
-    let codexfs_inode = CodexFsInode::from(inode);
-    get_sb().write_all_at(
-        bytes_of(&codexfs_inode),
-        nid_to_inode_off(inode.meta().inner.borrow().nid),
-    )?;
     Ok(())
 }
 
+// returns the bytes taken by the inodes with their metadata
+pub fn mkfs_balloc_inode() -> Result<u64> {
+    let buf_mgr = get_bufmgr_mut();
+    let align = get_align(BufferType::Inode) as u64;
+    let mut meta_size = 0;
+    for inode in get_inode_vec_mut().iter() {
+        // the extents the file data took once compressed
+        if let Some(file) = inode.downcast_file_ref() {
+            let extents = file.itype.inner.borrow().extents.len();
+            ensure!(
+                extents <= u16::MAX as usize,
+                "{}: {extents} extents, more than an inode holds, try a larger block size",
+                inode.meta().path().display()
+            );
+        }
+        let size = size_of::<CodexFsInode>() as u64
+            + meta_body_size(inode)
+            + mkfs_xattrs(inode)?.len() as u64;
+        let addr = buf_mgr.balloc(size, BufferType::Inode);
+        inode.meta().inner.borrow_mut().nid = addr_to_nid(addr);
+        meta_size += size.next_multiple_of(align);
+    }
+    mkfs_check_max_size()?;
+    Ok(meta_size)
+}
+
+// upper bound of source data buffered while compressing, a cluster can never
+// consume more input than this
+const DATA_WINDOW_SIZE: usize = 4 << 20;
+
+// Source files are read and clusters written on helper threads while the
+// main thread compresses, the cluster layout itself stays sequential.
 pub fn mkfs_dump_inode_file_data_z() -> Result<()> {
+    let writer = ClusterWriter::spawn(get_sb().img_file.as_ref().unwrap().try_clone()?);
+    let res = mkfs_compress_file_data(&writer);
+    // a failed write also fails the send, report the write error first
+    writer.finish()?;
+    res
+}
+
+fn mkfs_compress_file_data(writer: &ClusterWriter) -> Result<()> {
     let mut goff = 0;
+    let data_size = get_cmpr_mgr().data_size();
 
     let mut output = vec![0; get_sb().blksz() as usize];
-    let mut it = get_cmpr_mgr().files.iter();
-    let (mut off, mut inode) = {
-        if let Some(next) = it.next() {
-            (0, next)
-        } else {
-            panic!("no files to dump");
+    let mut trial = vec![0; get_sb().blksz() as usize];
+    let mut reader = PipelinedReader::spawn(FileDataReader::new(&get_cmpr_mgr().segments));
+    let mut window = Vec::with_capacity(DATA_WINDOW_SIZE);
+    let boundaries = get_cmpr_mgr().cluster_boundaries();
+    let mut it = get_cmpr_mgr().segments.iter();
+    // the segment being dumped and its offset in the concatenated data
+    let (mut off, mut segment) = (0, it.next());
+
+    while goff < data_size {
+        // the cluster starts in the first segment with data left
+        while let Some(cur) = segment
+            && goff == off + cur.len
+        {
+            (off, segment) = (goff, it.next());
         }
-    };
-
-    while (goff as usize) < get_cmpr_mgr().file_data.len() {
-        let mut stream = Stream::new_microlzma_encoder(
-            &LzmaOptions::new_preset(get_cmpr_mgr().lzma_level).unwrap(),
-        )?;
-        let status = stream
-            .process(
-                &get_cmpr_mgr().file_data[(goff) as usize..],
-                &mut output,
-                xz2::stream::Action::Finish,
-            )
-            .unwrap();
-        log::debug!(
-            "off {}, total_in {}, total_out {}",
-            goff,
-            stream.total_in(),
-            stream.total_out(),
-        );
+        let want = DATA_WINDOW_SIZE - window.len();
+        (&mut reader).take(want as u64).read_to_end(&mut window)?;
+        let limit = match boundaries.get(boundaries.partition_point(|&b| b <= goff)) {
+            Some(&boundary) => min(window.len() as u64, boundary - goff),
+            None => window.len() as u64,
+        };
+        let input = &window[..limit as usize];
+        // the block size is fixed, so the best codec is the one that packs the
+        // most input into it
+        let mut best: Option<(Codec, u64, u64)> = None;
+        for &codec in get_cmpr_mgr().codecs_for(&segment.unwrap().file) {
+            let (total_in, total_out) = codec.compress_cluster(input, &mut trial)?;
+            log::debug!("off {goff}, codec {codec:?}, total_in {total_in}, total_out {total_out}");
+            if best.is_none_or(|(_, best_in, _)| total_in > best_in) {
+                best = Some((codec, total_in, total_out));
+                mem::swap(&mut output, &mut trial);
+            }
+        }
+        let (codec, total_in, total_out) = best.unwrap();
+        if total_in == 0 {
+            bail!("source data ended early at offset {goff}, was a file truncated?");
+        }
+        window.drain(..total_in as usize);
+        get_progress_mut().advance(total_in);
         let woff = get_bufmgr_mut().balloc(get_sb().blksz() as u64, BufferType::ZData);
+        mkfs_check_max_size()?;
         assert_eq!(woff, round_down(woff, get_sb().blksz() as _));
-        let input_margin = get_sb().blksz() - (stream.total_out() as blk_size_t);
+        let input_margin = match codec.id() {
+            CodexFsCodec::MicroLzma => get_sb().blksz() - (total_out as blk_size_t),
+            CodexFsCodec::Stored | CodexFsCodec::Xz => 0,
+        };
         log::debug!("input margin {}", input_margin);
-        get_sb()
-            .write_all_at(&output, woff + input_margin as u64)
-            .unwrap();
+        if get_sb().checksums.is_some() {
+            // as the block reads, zeros around the compressed data
+            let mut block = vec![0; get_sb().blksz() as usize];
+            block[input_margin as usize..][..total_out as usize]
+                .copy_from_slice(&output[..total_out as usize]);
+            mkfs_record_checksum(woff, &block);
+        }
+        writer.write(
+            output[..total_out as usize].to_vec(),
+            woff + input_margin as u64,
+        )?;
+        get_cmpr_mgr_mut().push_cluster(
+            addr_to_blk_id(woff),
+            codec.id(),
+            total_in as _,
+            total_out as _,
+        );
 
+        let blk_id = addr_to_blk_id(woff);
         let mut frag_off = 0;
-        while frag_off < stream.total_in() {
-            inode
-                .itype
-                .inner
-                .borrow_mut()
-                .blk_id
-                .get_or_insert(addr_to_blk_id(woff));
-            log::info!(
-                "path {}, blk_id {:?}",
-                inode.meta.path().display(),
-                inode.itype.inner.borrow().blk_id
-            );
-            let len = min(
-                stream.total_in() - frag_off,
-                off + inode.itype.size as u64 - goff,
-            );
-            if inode
-                .push_extent((goff - off) as _, len as _, frag_off as _)
-                .is_none()
-            {
-                let Some(next) = it.next() else {
-                    goff += len;
-                    break;
-                };
-                (off, inode) = (off + inode.itype.size as off_t, next);
-            };
+        while frag_off < total_in {
+            let cur = segment.unwrap();
+            let len = min(total_in - frag_off, off + cur.len - goff);
+            if len > 0 {
+                for (file, file_off) in cur.places() {
+                    log::info!("path {}, blk_id {blk_id}", file.meta.path().display());
+                    file.push_extent(
+                        (file_off + goff - off) as _,
+                        frag_off as _,
+                        blk_id,
+                        codec.id(),
+                    );
+                }
+            }
             goff += len;
             frag_off += len;
+            if goff == off + cur.len {
+                (off, segment) = (goff, it.next());
+            }
         }
+    }
 
-        output.fill(0);
+    for file in get_cmpr_mgr().files.iter() {
+        file.sort_extents();
     }
+    Ok(())
+}
 
+// Decodes every cluster back from the image and compares it with the source
+// data it was compressed from. Needs the decoder limits in the superblock.
+pub fn mkfs_verify_file_data_z() -> Result<()> {
+    let mut reader = PipelinedReader::spawn(FileDataReader::new(&get_cmpr_mgr().segments));
+    let mut input = vec![0; get_sb().blksz() as usize];
+    let mut expected = Vec::new();
+    for cluster in get_cmpr_mgr().clusters.iter() {
+        expected.resize(cluster.in_size as usize, 0);
+        reader.read_exact(&mut expected)?;
+        // the image is not padded to a whole block yet, so only read up to
+        // the end of the compressed data
+        let len = match cluster.codec {
+            CodexFsCodec::MicroLzma => input.len(),
+            CodexFsCodec::Stored | CodexFsCodec::Xz => cluster.out_size as usize,
+        };
+        get_sb().read_exact_at(&mut input[..len], blk_id_to_addr(cluster.blk_id))?;
+        let output = decode_cluster(
+            &input[..len],
+            cluster.codec,
+            get_sb().max_cluster_size,
+            get_sb().dict_size,
+        )?;
+        ensure!(
+            output.get(..expected.len()) == Some(&expected[..]),
+            "block {} does not decode to the data it was compressed from",
+            cluster.blk_id
+        );
+    }
     Ok(())
 }
 
-pub fn mkfs_dump_inode_file_data() -> Result<()> {
-    for file in get_cmpr_mgr().files.iter() {
-        let len = file.itype.inner.borrow().content.as_ref().unwrap().len();
+// stores files uncompressed, each one contiguous
+pub fn mkfs_dump_inode_file_data(files: &[Rc<Inode<File>>]) -> Result<()> {
+    let mut buf = vec![0; DATA_WINDOW_SIZE];
+    for file in files.iter() {
+        let len = file.data_size();
         let addr = get_bufmgr_mut().balloc(len as _, BufferType::Data);
+        mkfs_check_max_size().with_context(|| format!("store {}", file.meta.path().display()))?;
         log::debug!("addr {addr:#x}");
-        get_sb().write_all_at(file.itype.inner.borrow().content.as_ref().unwrap(), addr)?;
+        let mut src = FileDataReader::new(&[Segment::whole(file)]);
+        let mut done = 0;
+        while done < len as usize {
+            let n = min(buf.len(), len as usize - done);
+            src.read_exact(&mut buf[..n])?;
+            get_sb().write_all_at(&buf[..n], addr + done as u64)?;
+            // pieces start every block from the start of the file, as
+            // DATA_WINDOW_SIZE is a multiple of the block size
+            for (i, piece) in buf[..n].chunks(get_sb().blksz() as usize).enumerate() {
+                let piece_addr = addr + (done + i * get_sb().blksz() as usize) as u64;
+                mkfs_record_checksum(piece_addr, piece);
+            }
+            done += n;
+            get_progress_mut().advance(n as _);
+        }
         file.itype
             .inner
             .borrow_mut()
@@ -413,91 +918,123 @@ pub fn mkfs_dump_inode_file_data() -> Result<()> {
     Ok(())
 }
 
-pub fn mkfs_dump_inode() -> Result<()> {
+// Writes every inode laid out by mkfs_balloc_inode. The inodes are encoded
+// here, the writes are spread over threads since each one has its own offset.
+pub fn mkfs_dump_inode(threads: usize) -> Result<()> {
+    let mut writes = Vec::new();
     for inode in get_inode_vec_mut().iter() {
-        match inode.file_type() {
-            CodexFsFileType::File => {
-                let inode_file = inode.downcast_file_ref().unwrap();
-                let mut extents_off = inode_file.meta.inode_meta_off();
-                for codexfs_extent in inode_file.itype.inner.borrow().extents.iter() {
-                    get_sb().write_all_at(bytes_of(codexfs_extent), extents_off)?;
-                    extents_off += size_of::<CodexFsExtent>() as u64;
-                }
-                mkfs_dump_codexfs_inode(inode)?;
-            }
-            CodexFsFileType::Dir => {
-                let inode_dir = inode.downcast_dir_ref().unwrap();
-                let mut dirents = Vec::new();
-                let mut names = Vec::new();
-                let mut nameoff = (size_of::<CodexFsDirent>()
-                    * (inode_dir.itype.inner.borrow().dentries.len() + 2))
-                    as u16;
-
-                let dot_dirent = CodexFsDirent {
-                    nid: inode_dir.meta.inner.borrow().nid,
-                    nameoff,
-                    file_type: CodexFsFileType::Dir,
-                    reserved: 0,
-                };
-                dirents.push(dot_dirent);
-                names.push(".");
-                nameoff += 1;
-
-                let dotdot_dirent = CodexFsDirent {
-                    nid: inode_dir.parent().meta.inner.borrow().nid,
-                    nameoff,
-                    file_type: CodexFsFileType::Dir,
-                    reserved: 0,
-                };
-                dirents.push(dotdot_dirent);
-                names.push("..");
-                nameoff += 2;
-
-                {
-                    let guard = inode_dir.itype.inner.borrow();
-                    for dentry in guard.dentries.iter() {
-                        let mut codexfs_dirent = CodexFsDirent::from(dentry);
-                        codexfs_dirent.nameoff = nameoff;
-                        dirents.push(codexfs_dirent);
-                        names.push(&dentry.file_name);
-                        nameoff += u16::try_from(dentry.file_name.len())?;
-                    }
-
-                    let mut dirent_off = inode_dir.meta.inode_meta_off();
-                    for dirent in dirents {
-                        get_sb().write_all_at(bytes_of(&dirent), dirent_off)?;
-                        dirent_off += size_of::<CodexFsDirent>() as u64;
-                    }
-                    let mut name_off = dirent_off;
-                    for name in names {
-                        get_sb().write_all_at(name.as_bytes(), name_off)?;
-                        name_off += name.len() as u64;
+        log::info!(
+            "path: {}, nid: {}",
+            inode.meta().path().display(),
+            inode.meta().inner.borrow().nid
+        );
+        writes.push((inode.meta().inode_off(), mkfs_encode_inode(inode)?));
+    }
+    let img_file = get_sb().img_file.as_ref().unwrap();
+    let chunk_len = writes.len().div_ceil(threads.max(1)).max(1);
+    thread::scope(|s| {
+        let workers: Vec<_> = writes
+            .chunks(chunk_len)
+            .map(|writes| {
+                s.spawn(move || -> Result<()> {
+                    for (off, buf) in writes {
+                        img_file.write_all_at(buf, *off)?;
                     }
-                    assert_eq!(
-                        inode_dir.meta.inode_meta_off() + inode_dir.meta.meta_size() as u64,
-                        name_off
-                    );
-                }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+    Ok(())
+}
 
-                mkfs_dump_codexfs_inode(inode)?;
+// the inode followed by its metadata and xattrs, as stored at its nid
+fn mkfs_encode_inode(inode: &InodeHandle) -> Result<Vec<u8>> {
+    let xattrs = mkfs_xattrs(inode)?;
+    let mut codexfs_inode = CodexFsInode::from(inode);
+    codexfs_inode.xattr_size = xattrs.len() as _;
+    let mut buf = bytes_of(&codexfs_inode).to_vec();
+    match inode.file_type() {
+        CodexFsFileType::File => {
+            let inode_file = inode.downcast_file_ref().unwrap();
+            if let Some(delta) = &inode_file.itype.inner.borrow().delta {
+                let codexfs_delta = CodexFsDelta {
+                    base_nid: delta.base.meta().inner.borrow().nid,
+                    data_size: delta.data_size,
+                    reserved: 0,
+                };
+                buf.extend(bytes_of(&codexfs_delta));
             }
-            CodexFsFileType::CharDevice => todo!(),
-            CodexFsFileType::BlockDevice => todo!(),
-            CodexFsFileType::Fifo => todo!(),
-            CodexFsFileType::Socket => todo!(),
-            CodexFsFileType::Symlink => {
-                let link = fs::read_link(inode.meta().path())?;
-                get_sb().write_all_at(
-                    link.to_string_lossy().as_bytes(),
-                    inode.meta().inode_meta_off(),
-                )?;
-                mkfs_dump_codexfs_inode(inode)?;
+            for extent in inode_file.itype.inner.borrow().extents.iter() {
+                buf.extend(bytes_of(&CodexFsExtent::from(extent)));
             }
-            CodexFsFileType::Unknown => todo!(),
         }
+        CodexFsFileType::Dir => {
+            let inode_dir = inode.downcast_dir_ref().unwrap();
+            let mut dirents = Vec::new();
+            let mut names = Vec::new();
+            // the end of the last name need not fit in a u16, only its start
+            let mut nameoff =
+                size_of::<CodexFsDirent>() * (inode_dir.itype.inner.borrow().dentries.len() + 2);
+
+            let dot_dirent = CodexFsDirent {
+                nid: inode_dir.meta.inner.borrow().nid,
+                nameoff: u16::try_from(nameoff)?,
+                file_type: CodexFsFileType::Dir as u8,
+                reserved: 0,
+            };
+            dirents.push(dot_dirent);
+            names.push(OsStr::new("."));
+            nameoff += 1;
+
+            let dotdot_dirent = CodexFsDirent {
+                nid: inode_dir.parent().meta.inner.borrow().nid,
+                nameoff: u16::try_from(nameoff)?,
+                file_type: CodexFsFileType::Dir as u8,
+                reserved: 0,
+            };
+            dirents.push(dotdot_dirent);
+            names.push(OsStr::new(".."));
+            nameoff += 2;
+
+            let guard = inode_dir.itype.inner.borrow();
+            for dentry in guard.dentries.iter() {
+                let mut codexfs_dirent = CodexFsDirent::from(dentry);
+                codexfs_dirent.nameoff = u16::try_from(nameoff)?;
+                dirents.push(codexfs_dirent);
+                names.push(&dentry.file_name);
+                nameoff += dentry.file_name.len();
+            }
+            for dirent in dirents {
+                buf.extend(bytes_of(&dirent));
+            }
+            for name in names {
+                buf.extend(name.as_bytes());
+            }
+        }
+        CodexFsFileType::CharDevice
+        | CodexFsFileType::BlockDevice
+        | CodexFsFileType::Fifo
+        | CodexFsFileType::Socket => (),
+        CodexFsFileType::Symlink => {
+            let symlink = inode.as_any().downcast_ref::<Inode<SymLink>>().unwrap();
+            let link = match &symlink.itype.target {
+                Some(target) => target.clone(),
+                None => fs::read_link(inode.meta().path())?,
+            };
+            buf.extend(link.as_os_str().as_encoded_bytes());
+        }
+        CodexFsFileType::Unknown => todo!(),
     }
-
-    Ok(())
+    assert_eq!(
+        buf.len() as u64,
+        size_of::<CodexFsInode>() as u64 + meta_body_size(inode)
+    );
+    buf.extend(xattrs);
+    Ok(buf)
 }
 
 pub fn fuse_load_inode(nid: u64) -> Result<InodeHandle> {
@@ -506,8 +1043,10 @@ pub fn fuse_load_inode(nid: u64) -> Result<InodeHandle> {
     get_sb().read_exact_at(&mut inode_buf, nid_to_inode_off(nid))?;
     let codexfs_inode: &CodexFsInode = from_bytes(&inode_buf);
 
-    let file_type: CodexFsFileType = codexfs_inode.mode.into();
-    // TODO: this check seems only for root inode
+    let file_type =
+        CodexFsFileType::try_from(codexfs_inode.mode).with_context(|| format!("nid {nid}"))?;
+    // Every path to a hardlinked inode gets the one loaded first, so they
+    // share nlink and, through FUSE, st_ino. Directories are never linked.
     if !file_type.is_dir() {
         if let Some(inode) = get_inode(codexfs_inode.ino) {
             return Ok(inode.clone());
@@ -516,24 +1055,49 @@ pub fn fuse_load_inode(nid: u64) -> Result<InodeHandle> {
     let inode: InodeHandle = match file_type {
         CodexFsFileType::File => Inode::<File>::fuse_load(codexfs_inode, nid)? as _,
         CodexFsFileType::Dir => Inode::<Dir>::fuse_load(codexfs_inode, nid)? as _,
-        CodexFsFileType::CharDevice => todo!(),
-        CodexFsFileType::BlockDevice => todo!(),
-        CodexFsFileType::Fifo => todo!(),
-        CodexFsFileType::Socket => todo!(),
+        CodexFsFileType::CharDevice
+        | CodexFsFileType::BlockDevice
+        | CodexFsFileType::Fifo
+        | CodexFsFileType::Socket => Inode::<Special>::fuse_load(codexfs_inode, nid)? as _,
         CodexFsFileType::Symlink => Inode::<SymLink>::fuse_load(codexfs_inode, nid)? as _,
-        CodexFsFileType::Unknown => todo!(),
+        CodexFsFileType::Unknown => bail!("nid {nid}: unknown file type"),
     };
     insert_inode(inode.meta().ino, inode.clone());
 
     Ok(inode)
 }
 
+// reads the dentries of a directory loaded lazily, once
+pub fn fuse_load_dir(inode: &InodeHandle) -> Result<()> {
+    if let Some(dir) = inode.downcast_dir_ref()
+        && dir.itype.inner.borrow().unloaded
+    {
+        let dir: Rc<Inode<Dir>> = inode.clone().as_any_rc().downcast().unwrap();
+        dir.fuse_load_dentries()?;
+    }
+    Ok(())
+}
+
+// extended attributes stored after the metadata of an image inode
+pub fn fuse_read_xattrs(inode: &InodeHandle) -> Result<Xattrs> {
+    let mut inode_buf = [0; size_of::<CodexFsInode>()];
+    get_sb().read_exact_at(&mut inode_buf, inode.meta().inode_off())?;
+    let codexfs_inode: &CodexFsInode = from_bytes(&inode_buf);
+    let mut buf = vec![0; codexfs_inode.xattr_size as usize];
+    get_sb().read_exact_at(
+        &mut buf,
+        inode.meta().inode_meta_off() + meta_body_size(inode),
+    )?;
+    xattr::decode(&buf)
+}
+
 pub fn fuse_read_inode_file(inode: &Inode<File>, off: u32, len: u32) -> Result<Vec<u8>> {
     log::info!("inode size {}, off {}, len {}", inode.itype.size, off, len);
     let file = &inode.itype;
-    let len_left = min(len, file.size - off);
+    let len_left = min(len, inode.data_size() - off);
     let mut buf = vec![0; len_left as _];
-    get_sb().read_exact_at(
+    let _span = debug_span!("read_image", len = len_left).entered();
+    fuse_read_checked(
         &mut buf,
         blk_id_to_addr(file.inner.borrow().blk_id.unwrap())
             + file.inner.borrow().blk_off.unwrap() as u64
@@ -542,93 +1106,331 @@ pub fn fuse_read_inode_file(inode: &Inode<File>, off: u32, len: u32) -> Result<V
     Ok(buf)
 }
 
-pub fn fixup_insize(buf: &[u8]) -> usize {
-    buf.iter().position(|&x| x != 0).unwrap()
+// Reads file content, reconstructing delta-encoded files from their base.
+pub fn fuse_read_inode_file_data(inode: &Inode<File>, off: u32, len: u32) -> Result<Vec<u8>> {
+    if off >= inode.itype.size {
+        return Ok(Vec::new());
+    }
+    let read_stored = |inode: &Inode<File>, off, len| {
+        if inode.is_plain() {
+            fuse_read_inode_file(inode, off, len)
+        } else {
+            fuse_read_inode_file_z(inode, off, len)
+        }
+    };
+
+    let Some((base, data_size)) = inode
+        .itype
+        .inner
+        .borrow()
+        .delta
+        .as_ref()
+        .map(|d| (d.base.clone(), d.data_size))
+    else {
+        let mut buf = read_stored(inode, off, len)?;
+        buf.truncate(min(len, inode.itype.size.saturating_sub(off)) as _);
+        return Ok(buf);
+    };
+    // the content is rebuilt whole, and kept for the reads after this one
+    let nid = inode.meta.inner.borrow().nid;
+    let cached = get_cluster_cache_mut().and_then(|cache| cache.get_delta(nid));
+    let content = match cached {
+        Some(content) => content,
+        None => {
+            let base = base
+                .downcast_file_ref()
+                .with_context(|| format!("nid {nid}: delta base is not a file"))?;
+            let mut data = read_stored(inode, 0, data_size)?;
+            data.truncate(data_size as _);
+            let mut base_data = read_stored(base, 0, base.itype.size)?;
+            base_data.truncate(base.itype.size as _);
+            let content = debug_span!("apply_delta", size = inode.itype.size)
+                .in_scope(|| delta::apply(&base_data, &data, inode.itype.size as _))?;
+            let content = Rc::new(content);
+            if let Some(cache) = get_cluster_cache_mut() {
+                cache.insert_delta(nid, content.clone());
+            }
+            content
+        }
+    };
+    let start = min(off, inode.itype.size) as usize;
+    let end = min(off as usize + len as usize, content.len());
+    Ok(content[start..end].to_vec())
 }
 
-pub fn fuse_read_inode_file_z(inode: &Inode<File>, off: u32, len: u32) -> Result<Vec<u8>> {
-    const MEM_LIMIT: usize = 32 * 1024;
-    const DICT_SIZE: usize = 32 * 1024;
+// layout of a file as reported through CODEXFS_IOC_GET_FILEINFO
+pub fn fuse_file_info(inode: &Inode<File>) -> CodexFsFileInfo {
+    let inner = inode.itype.inner.borrow();
+    let blocks: BTreeSet<_> = inner.extents.iter().map(|e| e.blk_id).collect();
+    let mut info = CodexFsFileInfo {
+        size: inode.itype.size as _,
+        data_addr: match inner.blk_id {
+            Some(blk_id) => blk_id_to_addr(blk_id) + inner.blk_off.unwrap_or(0) as u64,
+            None => 0,
+        },
+        extents: inner.extents.len() as _,
+        ..Default::default()
+    };
+    drop(inner);
+    if inode.is_plain() {
+        info.compressed_size = inode.data_size() as _;
+        info.flags |= CODEXFS_FILEINFO_PLAIN;
+    } else {
+        info.compressed_size = blocks.len() as u64 * get_sb().blksz() as u64;
+    }
+    if inode.is_delta() {
+        info.flags |= CODEXFS_FILEINFO_DELTA;
+    }
+    for e in inode.itype.inner.borrow().extents.iter() {
+        info.codecs |= 1 << e.codec as u16;
+    }
+    info
+}
 
-    log::info!("inode size {}, off {}, len {}", inode.itype.size, off, len);
+// none if the block is all zeros
+pub fn fixup_insize(buf: &[u8]) -> Option<usize> {
+    buf.iter().position(|&x| x != 0)
+}
 
-    let file = &inode.itype;
-    let mut len_left = min(len, file.size - off);
-    let mut buf = vec![0; len as _];
-    let mut input = vec![0; get_sb().blksz() as usize];
-    let mut output = Vec::with_capacity(MEM_LIMIT);
+// reads spanning at least this many clusters decode them on several threads
+const PARALLEL_DECODE_MIN_CLUSTERS: usize = 4;
+const PARALLEL_DECODE_CHUNKS: usize = 4;
+// clusters past the end of a read decoded ahead of the next one, at least
+// as many as the read spanned
+const READAHEAD_CLUSTERS: usize = 2;
+
+// memory an xz decoder needs besides its dictionary
+const XZ_DECODER_STATE_SIZE: u64 = 1 << 20;
+
+// decompresses one cluster block, callable from any thread
+fn decode_cluster(
+    input: &[u8],
+    codec: CodexFsCodec,
+    max_cluster_size: u32,
+    dict_size: u32,
+) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(max_cluster_size as _);
+    match codec {
+        CodexFsCodec::Stored => output.extend_from_slice(input),
+        CodexFsCodec::MicroLzma => {
+            let input_margin = fixup_insize(input).context("empty compressed cluster")?;
+            let comp_size = input.len() as u64 - input_margin as u64;
+            log::debug!("comp_size {}, input_margin {}", comp_size, input_margin);
+            let mut stream =
+                Stream::new_microlzma_decoder(comp_size, max_cluster_size as _, false, dict_size)?;
+            stream.process_vec(
+                &input[input_margin..],
+                &mut output,
+                xz2::stream::Action::Finish,
+            )?;
+            // WARN: output may contain one extra byte so that we can not
+            // depend on the length of output
+            log::debug!("output len {}", output.len());
+        }
+        CodexFsCodec::Xz => {
+            // the zero padding after the stream is never reached; a stream
+            // asking for a larger dictionary than recorded is refused
+            let memlimit = dict_size as u64 + XZ_DECODER_STATE_SIZE;
+            let mut stream = Stream::new_stream_decoder(memlimit, 0)?;
+            stream.process_vec(input, &mut output, xz2::stream::Action::Finish)?;
+        }
+    }
+    Ok(output)
+}
 
-    let i = file
-        .inner
-        .borrow()
-        .extents
-        .partition_point(|&e| e.off <= off);
-    for (i, e) in file.inner.borrow().extents.iter().enumerate().skip(i - 1) {
-        log::debug!("i {i}, e {:?}", e);
-        let blk_id = file.inner.borrow().blk_id.unwrap() + i as blk_t;
-        get_sb().read_exact_at(&mut input, blk_id_to_addr(blk_id))?;
-        let input_margin = fixup_insize(&input);
-        let comp_size = get_sb().blksz() as u64 - input_margin as u64;
-        log::debug!(
-            "blk_id {}, comp_size {}, input_margin {}",
-            blk_id,
-            comp_size,
-            input_margin
-        );
-        let mut stream =
-            Stream::new_microlzma_decoder(comp_size, MEM_LIMIT as _, false, DICT_SIZE as _)?;
-        let status = stream.process_vec(
-            &input[input_margin..],
-            &mut output,
-            xz2::stream::Action::Finish,
-        )?;
-        // WARN: output may contain one extra byte so that we can not depend on the
-        // length of output
-        log::debug!("output len {}", output.len());
-        // log::debug!("output {:?}", output.len());
-
-        for i in 0..(output.len() / 8) + 1 {
-            print!("{:#x}:\t", i);
-            for j in 0..8 {
-                if 8 * i + j < output.len() {
-                    print!("{:#x}:{}\t", output[8 * i + j], output[8 * i + j] as char);
-                }
+// decodes the blocks in order, splitting them among the decode pool when
+// there are enough of them
+fn decode_clusters(blocks: Vec<(Vec<u8>, CodexFsCodec)>) -> Result<Vec<Vec<u8>>> {
+    let (max_cluster_size, dict_size) = (get_sb().max_cluster_size, get_sb().dict_size);
+    let decode = move |blocks: Vec<(Vec<u8>, CodexFsCodec)>| {
+        blocks
+            .iter()
+            .map(|(input, codec)| decode_cluster(input, *codec, max_cluster_size, dict_size))
+            .collect::<Result<Vec<_>>>()
+    };
+    if blocks.len() < PARALLEL_DECODE_MIN_CLUSTERS {
+        return decode(blocks);
+    }
+    let len = blocks.len();
+    let chunk_size = len.div_ceil(PARALLEL_DECODE_CHUNKS);
+    let mut blocks = blocks.into_iter();
+    let mut receivers = Vec::new();
+    loop {
+        let chunk: Vec<_> = blocks.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        receivers.push(get_decode_pool().spawn(move || decode(chunk)));
+    }
+    let mut outputs = Vec::with_capacity(len);
+    for receiver in receivers {
+        outputs.extend(receiver.recv().context("cluster decoder panicked")??);
+    }
+    Ok(outputs)
+}
+
+// Decompressed contents of the blocks, taken from the cluster cache where
+// there is one and read and decoded otherwise.
+fn fuse_decode_blocks(blocks: &[(blk_t, CodexFsCodec)]) -> Result<Vec<Rc<Vec<u8>>>> {
+    let mut cache = get_cluster_cache_mut();
+    let mut outputs: Vec<Option<Rc<Vec<u8>>>> = blocks
+        .iter()
+        .map(|&(blk_id, _)| cache.as_mut().and_then(|c| c.get(blk_id)))
+        .collect();
+    let mut inputs = Vec::new();
+    let span = debug_span!("read_image", clusters = field::Empty).entered();
+    for (&(blk_id, codec), output) in blocks.iter().zip(&outputs) {
+        if output.is_none() {
+            let mut input = vec![0; get_sb().blksz() as usize];
+            fuse_read_checked(&mut input, blk_id_to_addr(blk_id))?;
+            inputs.push((input, codec));
+        }
+    }
+    span.record("clusters", inputs.len());
+    span.exit();
+    let start = Instant::now();
+    let clusters = inputs.len();
+    let mut decoded = debug_span!("decode", clusters)
+        .in_scope(|| decode_clusters(inputs))?
+        .into_iter();
+    if let Some(cache) = cache.as_mut() {
+        cache.record_decode(clusters as _, start.elapsed());
+    }
+    for (&(blk_id, _), output) in blocks.iter().zip(outputs.iter_mut()) {
+        if output.is_none() {
+            let cluster = Rc::new(decoded.next().unwrap());
+            if let Some(cache) = cache.as_mut() {
+                cache.insert(blk_id, cluster.clone());
             }
-            println!();
+            *output = Some(cluster);
         }
+    }
+    Ok(outputs.into_iter().map(Option::unwrap).collect())
+}
 
-        let needed_output_len = if i + 1 < file.inner.borrow().extents.len() {
-            file.inner.borrow().extents[i + 1].off - file.inner.borrow().extents[i].off
-        } else {
-            file.size - file.inner.borrow().extents[i].off
-        };
-        let len_consumed = if off >= e.off {
-            min(len_left, needed_output_len - (off - e.off))
-        } else {
-            min(len_left, needed_output_len)
-        };
-        log::debug!(
-            "needed_output_len {}, len_consumed {}, len_left {}",
-            needed_output_len,
-            len_consumed,
-            len_left
-        );
-        if off >= e.off {
-            buf[..len_consumed as _].copy_from_slice(
-                &output[(e.frag_off + off - e.off) as _
-                    ..(e.frag_off + off - e.off + len_consumed) as _],
-            );
-        } else {
-            assert!(e.frag_off == 0);
-            buf[(e.off - off) as _..(e.off - off + len_consumed) as _]
-                .copy_from_slice(&output[..len_consumed as _]);
+// Starts decoding the first count of blocks into the cluster cache, so a
+// read going on does not wait for them.
+fn fuse_readahead(blocks: impl Iterator<Item = (blk_t, CodexFsCodec)>, count: usize) {
+    if get_cluster_cache_mut().is_some_and(|c| c.readahead) {
+        fuse_prefetch(blocks, count);
+    }
+}
+
+fn fuse_prefetch(blocks: impl Iterator<Item = (blk_t, CodexFsCodec)>, count: usize) {
+    let Some(cache) = get_cluster_cache_mut() else {
+        return;
+    };
+    let (max_cluster_size, dict_size) = (get_sb().max_cluster_size, get_sb().dict_size);
+    let mut prev_blk_id = None;
+    let blocks = blocks.filter(|&(blk_id, _)| prev_blk_id.replace(blk_id) != Some(blk_id));
+    for (blk_id, codec) in blocks.take(count) {
+        if cache.contains(blk_id) {
+            continue;
         }
-        assert!(e.off == 0 || e.frag_off == 0);
-        len_left -= len_consumed;
-        if len_left == 0 {
-            break;
+        let mut input = vec![0; get_sb().blksz() as usize];
+        if fuse_read_checked(&mut input, blk_id_to_addr(blk_id)).is_err() {
+            return;
+        }
+        cache.prefetch(blk_id, move || {
+            decode_cluster(&input, codec, max_cluster_size, dict_size)
+        });
+    }
+}
+
+// Starts decoding every cluster of a compressed file, for small files whose
+// opening is nearly always followed by reading them whole.
+pub fn fuse_prefetch_file(inode: &Inode<File>) {
+    if inode.is_plain() || inode.is_delta() || inode.itype.size == 0 {
+        return;
+    }
+    let extents = &inode.itype.inner.borrow().extents;
+    fuse_prefetch(extents.iter().map(|e| (e.blk_id, e.codec)), extents.len());
+}
+
+// The cached cluster holding a read and where in it, for reads of a
+// compressed file within one extent whose cluster is cached, which can be
+// replied from the cache without copying. None sends the read the usual way.
+pub fn fuse_read_cached(
+    inode: &Inode<File>,
+    off: u32,
+    len: u32,
+) -> Option<(Rc<Vec<u8>>, Range<usize>)> {
+    if inode.is_plain() || inode.is_delta() || off >= inode.itype.size {
+        return None;
+    }
+    let end = min(off.saturating_add(len), inode.itype.size);
+    let extents = &inode.itype.inner.borrow().extents;
+    let i = extents.partition_point(|&e| e.off <= off).checked_sub(1)?;
+    let e = extents[i];
+    if end > e.off + inode.extent_len(i) {
+        return None;
+    }
+    let cache = get_cluster_cache_mut()?;
+    if !cache.contains(e.blk_id) {
+        return None;
+    }
+    let cluster = cache.get(e.blk_id)?;
+    let range = (e.frag_off + off - e.off) as usize..(e.frag_off + end - e.off) as usize;
+    if range.end > cluster.len() {
+        return None;
+    }
+    if end < inode.data_size() {
+        let next = extents[i + 1..].iter().map(|e| (e.blk_id, e.codec));
+        fuse_readahead(
+            next.filter(|&(blk_id, _)| blk_id != e.blk_id),
+            READAHEAD_CLUSTERS,
+        );
+    }
+    Some((cluster, range))
+}
+
+pub fn fuse_read_inode_file_z(inode: &Inode<File>, off: u32, len: u32) -> Result<Vec<u8>> {
+    log::info!("inode size {}, off {}, len {}", inode.itype.size, off, len);
+
+    let file = &inode.itype;
+    let len_left = min(len, inode.data_size() - off);
+    let end = off + len_left;
+    let mut buf = vec![0; len as _];
+    if len_left == 0 {
+        return Ok(buf);
+    }
+    let extents = &file.inner.borrow().extents;
+
+    // extents overlapping the read, and the blocks holding them; extents of a
+    // split file may share a block, which is decoded only once
+    let Some(first) = extents.partition_point(|&e| e.off <= off).checked_sub(1) else {
+        bail!("no extent holds offset {off}");
+    };
+    let last = extents.partition_point(|&e| e.off < end);
+    let mut blocks = Vec::new();
+    let mut block_of = Vec::with_capacity(last - first);
+    for e in extents[first..last].iter() {
+        if blocks.last().map(|&(blk_id, _)| blk_id) != Some(e.blk_id) {
+            blocks.push((e.blk_id, e.codec));
         }
-        output.clear();
+        block_of.push(blocks.len() - 1);
+    }
+    let outputs = fuse_decode_blocks(&blocks)?;
+    if end < inode.data_size() {
+        let next = extents[last..].iter().map(|e| (e.blk_id, e.codec));
+        fuse_readahead(
+            next.filter(|&b| Some(&b) != blocks.last()),
+            max(READAHEAD_CLUSTERS, blocks.len()),
+        );
+    }
+
+    let _span = debug_span!("copy", len = len_left).entered();
+    for (i, e) in extents.iter().enumerate().take(last).skip(first) {
+        log::debug!("i {i}, e {:?}", e);
+        let output = &outputs[block_of[i - first]];
+        // the part of the read this extent covers
+        let from = max(off, e.off);
+        let to = min(end, e.off + inode.extent_len(i));
+        log::debug!("from {from}, to {to}");
+        let data = output
+            .get((e.frag_off + from - e.off) as _..(e.frag_off + to - e.off) as _)
+            .with_context(|| format!("extent {i} lies past its decoded cluster"))?;
+        buf[(from - off) as _..(to - off) as _].copy_from_slice(data);
     }
 
     Ok(buf)
@@ -640,16 +1442,27 @@ mod test {
         fs::{self, File},
         path::Path,
         rc::Rc,
+        sync::Once,
     };
 
     use anyhow::{Ok, Result};
 
     use crate::{
         compress::set_cmpr_mgr,
-        inode::{InodeHandle, get_inode_by_path, mkfs_load_inode},
+        image::Image,
+        inode::{
+            InodeHandle, get_inode_by_path, mkfs_check_limits, mkfs_encode_inode, mkfs_load_inode,
+        },
+        options::init_mkfs_opts,
         sb::{SuperBlock, set_sb},
     };
 
+    // the compress manager is process-wide, set by the first test to want it
+    fn init_cmpr_mgr() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| set_cmpr_mgr(6));
+    }
+
     #[test]
     fn check_mkfs_load_inode() -> Result<()> {
         // .
@@ -674,7 +1487,8 @@ mod test {
 
         {
             set_sb(SuperBlock::new(File::create(img_path)?, 12));
-            set_cmpr_mgr(6);
+            init_cmpr_mgr();
+            init_mkfs_opts();
             let root_inode = mkfs_load_inode(root, None)?;
             let subdir_inode = get_inode_by_path(&subdir).unwrap();
             let hello_inode = get_inode_by_path(&hello).unwrap();
@@ -694,4 +1508,46 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn check_dir_names_past_u16() -> Result<()> {
+        // 246 dirents with names of 255 bytes take 65709 bytes, the last name
+        // starts at 65454
+        let root = Path::new("cargo-test-names.tmp");
+        let img_path = Path::new("cargo-test-names-img.tmp");
+        if root.exists() {
+            fs::remove_dir_all(root)?;
+        }
+        fs::create_dir(root)?;
+        for i in 0..246 {
+            File::create(root.join(format!("{i:03}{}", "x".repeat(252))))?;
+        }
+
+        {
+            // the process-wide super block is check_mkfs_load_inode's
+            let _image = Rc::new(Image::default()).enter();
+            set_sb(SuperBlock::new(File::create(img_path)?, 12));
+            init_cmpr_mgr();
+            init_mkfs_opts();
+            let root_inode = mkfs_load_inode(root, None)?;
+            assert_eq!(root_inode.meta().meta_size(), 65709);
+            mkfs_check_limits()?;
+            mkfs_encode_inode(&root_inode)?;
+        }
+
+        fs::remove_dir_all(root)?;
+        fs::remove_file(img_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_case_collisions() {
+        let names = ["Makefile", "README", "makefile", "readme.md", "MAKEFILE"];
+        assert_eq!(
+            super::case_collisions(names.into_iter()),
+            [("Makefile", "makefile"), ("Makefile", "MAKEFILE")]
+        );
+        assert!(super::case_collisions(["a", "b"].into_iter()).is_empty());
+    }
 }
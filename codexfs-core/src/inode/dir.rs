@@ -1,20 +1,22 @@
 use std::{
     any::Any,
     cell::RefCell,
-    os::unix::fs::MetadataExt,
+    ffi::OsString,
+    os::unix::ffi::OsStringExt,
     path::Path,
     rc::{Rc, Weak},
 };
 
-use anyhow::Result;
+use anyhow::{Result, ensure};
 use bytemuck::from_bytes;
 
-use super::{Dentry, Inode, InodeFactory, InodeOps, insert_inode};
+use super::{Dentry, Inode, InodeFactory, InodeOps, insert_inode, mkfs_alloc_ino, mkfs_attrs};
 use crate::{
     CodexFsDirent, CodexFsFileType, CodexFsInode,
-    inode::{InodeMeta, InodeMetaInner, fuse_load_inode},
+    inode::{InodeMeta, InodeMetaInner, PseudoEntry, fuse_load_inode},
     nid_to_inode_meta_off, nid_to_inode_off,
-    sb::{get_sb, get_sb_mut},
+    sb::get_sb,
+    scan::mkfs_metadata,
     utils::is_dot_or_dotdot,
 };
 
@@ -27,19 +29,21 @@ pub struct Dir {
 pub struct DirInner {
     pub parent: Option<Weak<Inode<Dir>>>, // root points to itself
     pub dentries: Vec<Dentry>,            // child dentries
+    pub unloaded: bool,                   // fuse: dentries not read yet
 }
 
 impl InodeFactory for Inode<Dir> {
-    fn from_path(path: &Path) -> Self {
-        let metadata = path.symlink_metadata().unwrap();
+    fn from_path(path: &Path) -> Result<Self> {
+        let metadata = mkfs_metadata(path)?;
+        let (mode, uid, gid) = mkfs_attrs(path, &metadata)?;
         log::info!("{}, size {}", path.display(), metadata.len());
-        Self {
+        Ok(Self {
             meta: InodeMeta {
                 path: Some(path.into()),
-                ino: get_sb_mut().get_ino_and_inc(),
-                gid: metadata.gid() as _,
-                uid: metadata.uid() as _,
-                mode: metadata.mode() as _,
+                ino: mkfs_alloc_ino(path),
+                gid,
+                uid,
+                mode,
                 inner: RefCell::new(InodeMetaInner {
                     nlink: 2,
                     nid: 0,
@@ -47,7 +51,7 @@ impl InodeFactory for Inode<Dir> {
                 }),
             },
             itype: Dir::default(),
-        }
+        })
     }
 
     fn from_codexfs_inode(codexfs_inode: &CodexFsInode, nid: u64) -> Self {
@@ -72,6 +76,46 @@ impl InodeFactory for Inode<Dir> {
 
     fn fuse_load(codexfs_inode: &CodexFsInode, nid: u64) -> Result<Rc<Self>> {
         let inode = Rc::new(Inode::<Dir>::from_codexfs_inode(codexfs_inode, nid));
+        if get_sb().lazy_dirs {
+            inode.itype.inner.borrow_mut().unloaded = true;
+        } else {
+            inode.fuse_load_dentries()?;
+        }
+        Ok(inode)
+    }
+}
+
+impl InodeOps for Inode<Dir> {
+    fn meta(&self) -> &InodeMeta {
+        &self.meta
+    }
+
+    fn file_type(&self) -> CodexFsFileType {
+        CodexFsFileType::Dir
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_rc(self: Rc<Self>) -> Rc<dyn Any> {
+        self
+    }
+}
+
+impl Inode<Dir> {
+    pub fn load_from_nid(nid: u64) -> Result<Rc<Self>> {
+        let mut inode_buf = [0; size_of::<CodexFsInode>()];
+        get_sb().read_exact_at(&mut inode_buf, nid_to_inode_off(nid))?;
+        let codexfs_inode: &CodexFsInode = from_bytes(&inode_buf);
+        let inode = Rc::new(Self::from_codexfs_inode(codexfs_inode, nid));
+        insert_inode(inode.meta.ino, inode.clone());
+        Ok(inode)
+    }
+
+    // reads the dentries of an image directory, loading the children
+    pub(crate) fn fuse_load_dentries(self: &Rc<Self>) -> Result<()> {
+        let nid = self.meta.inner.borrow().nid;
         let dirents_off = nid_to_inode_meta_off(nid);
         let mut dirent_buf = [0; size_of::<CodexFsDirent>()];
         let ndir = {
@@ -89,57 +133,42 @@ impl InodeFactory for Inode<Dir> {
             let codexfs_dirent: CodexFsDirent = *from_bytes(&dirent_buf);
             dirents.push(codexfs_dirent);
         }
+        // added once all are read, a damaged directory stays unloaded
+        let mut dentries = Vec::new();
         for i in 0..ndir {
             let file_name = {
                 let endoff = if i != ndir - 1 {
                     dirents[(i + 1) as usize].nameoff
                 } else {
-                    inode.meta.meta_size() as _
+                    self.meta.meta_size() as _
                 };
                 let startoff = dirents[(i) as usize].nameoff;
+                ensure!(startoff <= endoff, "nid {nid}: bad dirent name offset");
                 let mut name_buf = vec![0; (endoff - startoff) as usize];
                 get_sb().read_exact_at(&mut name_buf, dirents_off + startoff as u64)?;
-                String::from_utf8(name_buf)?
+                OsString::from_vec(name_buf)
             };
-            log::debug!("{}", file_name);
+            log::debug!("{}", file_name.display());
             if is_dot_or_dotdot(&file_name) {
                 continue;
             }
             let child_inode = fuse_load_inode(dirents[i as usize].nid)?;
-            assert_eq!(dirents[i as usize].file_type, child_inode.file_type());
+            ensure!(
+                dirents[i as usize].file_type == child_inode.file_type() as u8,
+                "nid {nid}: dirent type of {} differs from its inode",
+                file_name.display()
+            );
             if let Some(child_dir) = child_inode.downcast_dir_ref() {
-                child_dir.set_parent(Rc::downgrade(&inode));
+                child_dir.set_parent(Rc::downgrade(self));
             }
-            let child_dentry = Dentry::new_name(file_name, child_inode);
-            inode.add_dentry(child_dentry);
+            dentries.push(Dentry::new_name(file_name, child_inode));
+        }
+        for dentry in dentries {
+            self.add_dentry(dentry);
         }
 
-        Ok(inode)
-    }
-}
-
-impl InodeOps for Inode<Dir> {
-    fn meta(&self) -> &InodeMeta {
-        &self.meta
-    }
-
-    fn file_type(&self) -> CodexFsFileType {
-        CodexFsFileType::Dir
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-}
-
-impl Inode<Dir> {
-    pub fn load_from_nid(nid: u64) -> Result<Rc<Self>> {
-        let mut inode_buf = [0; size_of::<CodexFsInode>()];
-        get_sb().read_exact_at(&mut inode_buf, nid_to_inode_off(nid))?;
-        let codexfs_inode: &CodexFsInode = from_bytes(&inode_buf);
-        let inode = Rc::new(Self::from_codexfs_inode(codexfs_inode, nid));
-        insert_inode(inode.meta.ino, inode.clone());
-        Ok(inode)
+        self.itype.inner.borrow_mut().unloaded = false;
+        Ok(())
     }
 
     pub(crate) fn parent(&self) -> Rc<Inode<Dir>> {
@@ -160,4 +189,38 @@ impl Inode<Dir> {
     pub(crate) fn add_dentry(&self, dentry: Dentry) {
         self.itype.inner.borrow_mut().dentries.push(dentry)
     }
+
+    // path need not exist
+    pub(crate) fn new_pseudo(path: &Path, entry: &PseudoEntry) -> Self {
+        Self {
+            meta: InodeMeta {
+                path: Some(path.into()),
+                ino: mkfs_alloc_ino(path),
+                gid: entry.gid,
+                uid: entry.uid,
+                mode: entry.mode,
+                inner: RefCell::new(InodeMetaInner {
+                    nlink: 2,
+                    nid: 0,
+                    meta_size: None,
+                }),
+            },
+            itype: Dir::default(),
+        }
+    }
+
+    // dirents and names, call again after adding dentries
+    pub(crate) fn update_meta_size(&self) {
+        let inner = self.itype.inner.borrow();
+        let total_dirents_size = (inner.dentries.len() + 2) * size_of::<CodexFsDirent>();
+        let total_name_size: usize = 1
+            + 2
+            + inner
+                .dentries
+                .iter()
+                .map(|d| d.file_name.len())
+                .sum::<usize>();
+        self.meta
+            .set_meta_size((total_dirents_size + total_name_size) as _);
+    }
 }
@@ -1,24 +1,27 @@
 use std::{
     any::Any,
     cell::RefCell,
-    cmp::Ordering,
-    io::Read,
-    os::unix::fs::MetadataExt,
+    fs,
+    io::{self, Read},
     path::Path,
     rc::Rc,
 };
 
-use anyhow::{Ok, Result};
+use anyhow::{Context, Ok, Result};
 use bytemuck::from_bytes;
 use tlsh_fixed::Tlsh;
 
-use super::{Inode, InodeFactory, InodeMeta, InodeOps};
+use super::{
+    Inode, InodeFactory, InodeHandle, InodeMeta, InodeOps, PseudoEntry, mkfs_alloc_ino, mkfs_attrs,
+};
 use crate::{
-    CodexFsExtent, CodexFsFileType, CodexFsInode, blk_off_t, blk_t,
-    compress::calc_tlsh,
-    inode::InodeMetaInner,
+    CodexFsCodec, CodexFsDelta, CodexFsExtent, CodexFsFileType, CodexFsInode, CodexFsInodeFlags,
+    blk_off_t, blk_t,
+    compress::{ContentHash, calc_fingerprint, get_cmpr_mgr, get_cmpr_mgr_mut},
+    inode::{InodeMetaInner, fuse_load_inode},
     nid_to_inode_meta_off,
-    sb::{get_sb, get_sb_mut},
+    sb::get_sb,
+    scan::{mkfs_is_unreadable, mkfs_metadata},
     size_t,
 };
 
@@ -32,26 +35,71 @@ pub struct File {
 pub struct FileInner {
     pub blk_id: Option<blk_t>,
     pub blk_off: Option<blk_off_t>,
-    pub extents: Vec<CodexFsExtent>,
-    pub content: Option<Vec<u8>>,
+    pub extents: Vec<Extent>,
     pub tlsh: Option<Tlsh>,
+    pub hash: Option<ContentHash>, // only kept by mkfs
+    pub delta: Option<Delta>,
+    pub contents: Option<Rc<[u8]>>, // mkfs: of a pseudo file, read from the source otherwise
+}
+
+// CodexFsExtent with its codec checked
+#[derive(Clone, Copy, Debug)]
+pub struct Extent {
+    pub off: u32,
+    pub frag_off: u32,
+    pub blk_id: blk_t,
+    pub codec: CodexFsCodec,
+}
+
+impl TryFrom<&CodexFsExtent> for Extent {
+    type Error = anyhow::Error;
+
+    fn try_from(codexfs_extent: &CodexFsExtent) -> Result<Self> {
+        Ok(Self {
+            off: codexfs_extent.off,
+            frag_off: codexfs_extent.frag_off,
+            blk_id: codexfs_extent.blk_id,
+            codec: codexfs_extent.codec.try_into()?,
+        })
+    }
+}
+
+impl From<&Extent> for CodexFsExtent {
+    fn from(extent: &Extent) -> Self {
+        Self {
+            off: extent.off,
+            frag_off: extent.frag_off,
+            blk_id: extent.blk_id,
+            codec: extent.codec as _,
+            reserved: [0; _],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Delta {
+    pub base: InodeHandle, // always a non-delta file
+    pub data_size: size_t,
+    pub data: Option<Vec<u8>>, // only kept by mkfs
 }
 
 impl InodeFactory for Inode<File> {
-    fn from_path(path: &Path) -> Self {
-        let metadata = path.symlink_metadata().unwrap();
+    fn from_path(path: &Path) -> Result<Self> {
+        let metadata = mkfs_metadata(path)?;
+        let (mode, uid, gid) = mkfs_attrs(path, &metadata)?;
         log::info!("{}, size {}", path.display(), metadata.len());
-        let mut file = std::fs::File::open(path).unwrap();
-        let mut content = Vec::new();
-        file.read_to_end(&mut content).unwrap();
-        let tlsh = calc_tlsh(&content);
-        Self {
+        let unreadable = mkfs_is_unreadable(&metadata);
+        let (tlsh, hash) = match unreadable {
+            true => calc_fingerprint(io::empty())?,
+            false => get_cmpr_mgr_mut().fingerprint(path, &metadata)?,
+        };
+        Ok(Self {
             meta: InodeMeta {
                 path: Some(path.into()),
-                ino: get_sb_mut().get_ino_and_inc(),
-                gid: metadata.gid() as _,
-                uid: metadata.uid() as _,
-                mode: metadata.mode() as _,
+                ino: mkfs_alloc_ino(path),
+                gid,
+                uid,
+                mode,
                 inner: RefCell::new(InodeMetaInner {
                     nlink: 0,
                     nid: 0,
@@ -59,14 +107,14 @@ impl InodeFactory for Inode<File> {
                 }),
             },
             itype: File {
-                size: metadata.len() as _,
+                size: if unreadable { 0 } else { metadata.len() as _ },
                 inner: RefCell::new(FileInner {
-                    content: Some(content),
                     tlsh,
+                    hash: Some(hash),
                     ..Default::default()
                 }),
             },
-        }
+        })
     }
 
     fn from_codexfs_inode(codexfs_inode: &CodexFsInode, nid: u64) -> Self {
@@ -87,7 +135,11 @@ impl InodeFactory for Inode<File> {
                 size: codexfs_inode.size,
                 inner: RefCell::new(FileInner {
                     blk_id: Some(codexfs_inode.blk_id),
-                    blk_off: if !get_sb().compress {
+                    blk_off: if !get_sb().compress
+                        || codexfs_inode
+                            .flags
+                            .contains(CodexFsInodeFlags::CODEXFS_INODE_PLAIN)
+                    {
                         Some(unsafe { codexfs_inode.u.blk_off })
                     } else {
                         None
@@ -100,10 +152,25 @@ impl InodeFactory for Inode<File> {
 
     fn fuse_load(codexfs_inode: &CodexFsInode, nid: u64) -> Result<Rc<Self>> {
         let inode = Self::from_codexfs_inode(codexfs_inode, nid);
-        let extents_off = nid_to_inode_meta_off(nid);
+        let mut extents_off = nid_to_inode_meta_off(nid);
         let mut extent_buf = [0; size_of::<CodexFsExtent>()];
 
-        if get_sb().compress {
+        if codexfs_inode
+            .flags
+            .contains(CodexFsInodeFlags::CODEXFS_INODE_DELTA)
+        {
+            let mut delta_buf = [0; size_of::<CodexFsDelta>()];
+            get_sb().read_exact_at(&mut delta_buf, extents_off)?;
+            let codexfs_delta: CodexFsDelta = *from_bytes(&delta_buf);
+            inode.itype.inner.borrow_mut().delta = Some(Delta {
+                base: fuse_load_inode(codexfs_delta.base_nid)?,
+                data_size: codexfs_delta.data_size,
+                data: None,
+            });
+            extents_off += size_of::<CodexFsDelta>() as u64;
+        }
+
+        if !inode.is_plain() {
             let blks = unsafe { codexfs_inode.u.blks };
             log::info!("nid {nid} blks {}", blks);
             for i in 0..blks {
@@ -111,7 +178,8 @@ impl InodeFactory for Inode<File> {
                     &mut extent_buf,
                     extents_off + (i as usize * size_of::<CodexFsExtent>()) as u64,
                 )?;
-                let extent: CodexFsExtent = *from_bytes::<CodexFsExtent>(&extent_buf);
+                let extent = Extent::try_from(from_bytes::<CodexFsExtent>(&extent_buf))
+                    .with_context(|| format!("nid {nid}: extent {i}"))?;
                 log::info!("nid {nid} push extent");
                 inode.itype.inner.borrow_mut().extents.push(extent);
             }
@@ -133,17 +201,126 @@ impl InodeOps for Inode<File> {
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn as_any_rc(self: Rc<Self>) -> Rc<dyn Any> {
+        self
+    }
 }
 
 impl Inode<File> {
-    pub(crate) fn push_extent(&self, off: u32, len: u32, frag_off: u32) -> Option<()> {
-        let codexfs_extent = CodexFsExtent { off, frag_off };
-        log::info!("push extent {codexfs_extent:?}");
-        self.itype.inner.borrow_mut().extents.push(codexfs_extent);
-        match (off + len).cmp(&self.itype.size) {
-            Ordering::Less => Some(()),
-            Ordering::Equal => None,
-            Ordering::Greater => panic!(),
+    // path need not exist
+    pub(crate) fn new_pseudo(path: &Path, entry: &PseudoEntry) -> Self {
+        let contents = entry.contents.clone().unwrap_or_else(|| Rc::from([]));
+        log::info!("{}, size {}", path.display(), contents.len());
+        let (tlsh, hash) = calc_fingerprint(&contents[..]).unwrap();
+        Self {
+            meta: InodeMeta {
+                path: Some(path.into()),
+                ino: mkfs_alloc_ino(path),
+                gid: entry.gid,
+                uid: entry.uid,
+                mode: entry.mode,
+                inner: RefCell::new(InodeMetaInner {
+                    nlink: 0,
+                    nid: 0,
+                    meta_size: None,
+                }),
+            },
+            itype: File {
+                size: contents.len() as _,
+                inner: RefCell::new(FileInner {
+                    tlsh,
+                    hash: Some(hash),
+                    contents: Some(contents),
+                    ..Default::default()
+                }),
+            },
+        }
+    }
+
+    // mkfs: the source data of the file
+    pub(crate) fn mkfs_open(&self) -> io::Result<Box<dyn Read>> {
+        let reader: Box<dyn Read> = match &self.itype.inner.borrow().contents {
+            Some(contents) => Box::new(io::Cursor::new(contents.clone())),
+            None => Box::new(fs::File::open(self.meta.path())?),
+        };
+        io::Result::Ok(reader)
+    }
+
+    pub(crate) fn mkfs_read(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.mkfs_open()?.read_to_end(&mut data)?;
+        io::Result::Ok(data)
+    }
+
+    // extents of a file split into segments are pushed in layout order, not
+    // in file order, see sort_extents
+    pub(crate) fn push_extent(&self, off: u32, frag_off: u32, blk_id: blk_t, codec: CodexFsCodec) {
+        let mut inner = self.itype.inner.borrow_mut();
+        inner.blk_id.get_or_insert(blk_id);
+        // continues the previous extent within the same fragment
+        if let Some(last) = inner.extents.last()
+            && last.blk_id == blk_id
+            && off >= last.off
+            && frag_off >= last.frag_off
+            && off - last.off == frag_off - last.frag_off
+        {
+            return;
+        }
+        let extent = Extent {
+            off,
+            frag_off,
+            blk_id,
+            codec,
+        };
+        log::info!("push extent {extent:?}");
+        inner.extents.push(extent);
+    }
+
+    pub(crate) fn sort_extents(&self) {
+        self.itype.inner.borrow_mut().extents.sort_by_key(|e| e.off);
+    }
+
+    // data stored as is at blk_id and blk_off rather than in clusters, known
+    // once the data is dumped
+    pub fn is_plain(&self) -> bool {
+        !get_sb().compress || self.itype.inner.borrow().blk_off.is_some()
+    }
+
+    pub fn is_delta(&self) -> bool {
+        self.itype.inner.borrow().delta.is_some()
+    }
+
+    // bytes actually stored for this file, smaller than its size when the file
+    // is delta-encoded
+    pub fn data_size(&self) -> size_t {
+        match &self.itype.inner.borrow().delta {
+            Some(delta) => delta.data_size,
+            None => self.itype.size,
+        }
+    }
+
+    // decompressed length covered by the i-th extent
+    pub fn extent_len(&self, i: usize) -> u32 {
+        let extents = &self.itype.inner.borrow().extents;
+        match extents.get(i + 1) {
+            Some(next) => next.off - extents[i].off,
+            None => self.data_size() - extents[i].off,
+        }
+    }
+
+    // compressed bytes attributable to this file, a cluster shared by several
+    // files is split in proportion to the decompressed bytes each one owns
+    pub fn compressed_size(&self) -> u64 {
+        if self.is_plain() {
+            return self.data_size() as _;
         }
+        let extents = &self.itype.inner.borrow().extents;
+        (0..extents.len())
+            .map(|i| {
+                let cluster = get_cmpr_mgr().cluster(extents[i].blk_id).unwrap();
+                self.extent_len(i) as u64 * cluster.out_size as u64 / cluster.in_size as u64
+            })
+            .sum()
     }
 }
@@ -1,12 +1,22 @@
-use std::{cell::OnceCell, collections::HashMap, os::unix::fs::MetadataExt, path::Path};
+use std::{cell::OnceCell, collections::HashMap, os::unix::fs::MetadataExt, path::Path, rc::Rc};
 
-use crate::{ino_t, inode::InodeHandle};
+use crate::{
+    compress::ContentHash,
+    gid_t, image, ino_t,
+    inode::{File, Inode, InodeHandle},
+    mode_t, uid_t,
+    xattr::Xattrs,
+};
 
 pub(crate) type InodeTable = HashMap<ino_t, InodeHandle>;
 
-fn get_inode_table_mut() -> &'static mut InodeTable {
+// that of the image entered on this thread, if any
+pub(crate) fn get_inode_table_mut() -> &'static mut InodeTable {
     static mut INODE_TABLE: OnceCell<InodeTable> = OnceCell::new();
-    unsafe { INODE_TABLE.get_mut_or_init(HashMap::new) }
+    match image::entered() {
+        Some(image) => &mut image.inode_table,
+        None => unsafe { INODE_TABLE.get_mut_or_init(HashMap::new) },
+    }
 }
 
 pub fn get_inode(ino: ino_t) -> Option<&'static InodeHandle> {
@@ -22,6 +32,24 @@ pub(crate) fn insert_inode(ino: ino_t, inode: InodeHandle) {
     get_inode_table_mut().insert(ino, inode);
 }
 
+// files by content and everything stored in their inode, for hardlinking
+// identical ones
+pub(crate) type ContentKey = (ContentHash, mode_t, uid_t, gid_t, Option<Xattrs>);
+pub(crate) type ContentTable = HashMap<ContentKey, Rc<Inode<File>>>;
+
+pub(crate) fn get_content_table_mut() -> &'static mut ContentTable {
+    static mut CONTENT_TABLE: OnceCell<ContentTable> = OnceCell::new();
+    unsafe { CONTENT_TABLE.get_mut_or_init(HashMap::new) }
+}
+
+// pseudo files by their contents, which hardlinked entries share
+pub(crate) type PseudoFileTable = HashMap<*const [u8], InodeHandle>;
+
+pub(crate) fn get_pseudo_file_table_mut() -> &'static mut PseudoFileTable {
+    static mut PSEUDO_FILE_TABLE: OnceCell<PseudoFileTable> = OnceCell::new();
+    unsafe { PSEUDO_FILE_TABLE.get_mut_or_init(HashMap::new) }
+}
+
 pub type InodeVec = Vec<InodeHandle>;
 
 pub fn get_inode_vec_mut() -> &'static mut InodeVec {
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use bytemuck::{bytes_of, from_bytes};
+
+use super::{Dentry, InodeHandle, get_inode_table_mut};
+use crate::{CodexFsDirent, CodexFsInode, nid_to_inode_off, sb::get_sb};
+
+// Fixes for entries of an existing image that stay where they are when a
+// source tree is merged into it, applied after the new metadata is dumped.
+#[derive(Debug, Default)]
+pub struct Merge {
+    adopted_dirs: Vec<(InodeHandle, InodeHandle)>, // (image dir, new parent)
+    nlinks: HashMap<u64, u16>,                     // image inodes that lost names
+}
+
+// Merges the image tree at old into the source tree at dir. Source entries
+// replace image entries of the same name, directories in both are merged and
+// image entries missing from the source are kept with their data. Every
+// source directory is written again, the rest of the image is not touched.
+pub fn mkfs_merge_tree(dir: &InodeHandle, old: &InodeHandle) -> Merge {
+    let mut merge = Merge::default();
+    mkfs_merge_dir(dir, old, &mut merge);
+    merge
+}
+
+fn mkfs_merge_dir(dir: &InodeHandle, old: &InodeHandle, merge: &mut Merge) {
+    let new_dir = dir.downcast_dir_ref().unwrap();
+    let old_dir = old.downcast_dir_ref().unwrap();
+    for old_dentry in old_dir.itype.inner.borrow().dentries.iter() {
+        let new_child = new_dir
+            .itype
+            .inner
+            .borrow()
+            .dentries
+            .iter()
+            .find(|d| d.file_name == old_dentry.file_name)
+            .map(|d| d.inode.clone());
+        let old_child = &old_dentry.inode;
+        match new_child {
+            Some(new_child) if new_child.file_type().is_dir() && old_dentry.file_type.is_dir() => {
+                mkfs_merge_dir(&new_child, old_child, merge);
+            }
+            Some(_) => {
+                if !old_dentry.file_type.is_dir() {
+                    let nid = old_child.meta().inner.borrow().nid;
+                    let nlink = merge
+                        .nlinks
+                        .entry(nid)
+                        .or_insert(old_child.meta().inner.borrow().nlink);
+                    *nlink -= 1;
+                }
+            }
+            None => {
+                log::info!("keep {} from the image", old_dentry.file_name.display());
+                if old_dentry.file_type.is_dir() {
+                    new_dir.meta.inc_nlink();
+                    merge.adopted_dirs.push((old_child.clone(), dir.clone()));
+                }
+                new_dir.add_dentry(Dentry {
+                    path: Some(new_dir.meta.path().join(&old_dentry.file_name)),
+                    file_name: old_dentry.file_name.clone(),
+                    file_type: old_dentry.file_type,
+                    inode: old_child.clone(),
+                });
+            }
+        }
+    }
+    new_dir.update_meta_size();
+}
+
+impl Merge {
+    pub fn mkfs_dump(&self) -> Result<()> {
+        // ".." of a kept directory points to the parent written again
+        for (old_dir, parent) in self.adopted_dirs.iter() {
+            let parent_nid = parent.meta().inner.borrow().nid;
+            let dotdot_off = old_dir.meta().inode_meta_off() + size_of::<CodexFsDirent>() as u64;
+            get_sb().write_all_at(bytes_of(&parent_nid), dotdot_off)?;
+        }
+        for (&nid, &nlink) in self.nlinks.iter() {
+            let mut inode_buf = [0; size_of::<CodexFsInode>()];
+            get_sb().read_exact_at(&mut inode_buf, nid_to_inode_off(nid))?;
+            let mut codexfs_inode: CodexFsInode = *from_bytes(&inode_buf);
+            codexfs_inode.nlink = nlink;
+            get_sb().write_all_at(bytes_of(&codexfs_inode), nid_to_inode_off(nid))?;
+        }
+        Ok(())
+    }
+}
+
+// The image's inodes are in the table by image inode number, while a source
+// tree is loaded by source inode number to find hardlinks.
+pub fn mkfs_forget_image_inodes() {
+    get_inode_table_mut().clear();
+}
@@ -0,0 +1,98 @@
+use std::{any::Any, cell::RefCell, os::unix::fs::MetadataExt, path::Path, rc::Rc};
+
+use anyhow::Result;
+
+use super::{Inode, InodeFactory, InodeMeta, InodeOps, PseudoEntry, mkfs_alloc_ino, mkfs_attrs};
+use crate::{
+    CodexFsFileType, CodexFsInode, inode::InodeMetaInner, new_encode_dev, scan::mkfs_metadata,
+};
+
+// character and block devices, fifos and sockets, nothing but an inode
+#[derive(Debug, Default)]
+pub struct Special {
+    pub rdev: u32, // new_encode_dev format, 0 for fifos and sockets
+}
+
+impl InodeFactory for Inode<Special> {
+    fn from_path(path: &Path) -> Result<Self> {
+        let metadata = mkfs_metadata(path)?;
+        let (mode, uid, gid) = mkfs_attrs(path, &metadata)?;
+        log::info!("{}, rdev {:#x}", path.display(), metadata.rdev());
+        let (major, minor) =
+            unsafe { (libc::major(metadata.rdev()), libc::minor(metadata.rdev())) };
+        let entry = PseudoEntry {
+            mode,
+            uid,
+            gid,
+            rdev: new_encode_dev(major, minor),
+            target: None,
+            contents: None,
+        };
+        Ok(Self::new_pseudo(path, &entry))
+    }
+
+    fn from_codexfs_inode(codexfs_inode: &CodexFsInode, nid: u64) -> Self {
+        Self {
+            meta: InodeMeta {
+                path: None,
+                ino: codexfs_inode.ino,
+                uid: codexfs_inode.uid,
+                gid: codexfs_inode.gid,
+                mode: codexfs_inode.mode,
+                inner: RefCell::new(InodeMetaInner {
+                    nid,
+                    nlink: codexfs_inode.nlink,
+                    meta_size: Some(0),
+                }),
+            },
+            itype: Special {
+                rdev: unsafe { codexfs_inode.u.rdev },
+            },
+        }
+    }
+
+    fn fuse_load(codexfs_inode: &CodexFsInode, nid: u64) -> Result<Rc<Self>> {
+        let inode = Inode::<Special>::from_codexfs_inode(codexfs_inode, nid);
+        Ok(Rc::new(inode))
+    }
+}
+
+impl Inode<Special> {
+    // path need not exist
+    pub(crate) fn new_pseudo(path: &Path, entry: &PseudoEntry) -> Self {
+        Self {
+            meta: InodeMeta {
+                path: Some(path.into()),
+                ino: mkfs_alloc_ino(path),
+                gid: entry.gid,
+                uid: entry.uid,
+                mode: entry.mode,
+                inner: RefCell::new(InodeMetaInner {
+                    nlink: 0,
+                    nid: 0,
+                    meta_size: Some(0),
+                }),
+            },
+            itype: Special { rdev: entry.rdev },
+        }
+    }
+}
+
+impl InodeOps for Inode<Special> {
+    fn meta(&self) -> &InodeMeta {
+        &self.meta
+    }
+
+    fn file_type(&self) -> CodexFsFileType {
+        // checked when the inode was made
+        CodexFsFileType::try_from(self.meta.mode).unwrap_or(CodexFsFileType::Unknown)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_rc(self: Rc<Self>) -> Rc<dyn Any> {
+        self
+    }
+}
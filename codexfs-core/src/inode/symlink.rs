@@ -1,24 +1,37 @@
-use std::{any::Any, cell::RefCell, os::unix::fs::MetadataExt, path::Path, rc::Rc};
+use std::{
+    any::Any,
+    cell::RefCell,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use anyhow::Result;
 
-use super::{Inode, InodeFactory, InodeMeta, InodeOps};
-use crate::{CodexFsFileType, CodexFsInode, inode::InodeMetaInner, sb::get_sb_mut};
+use super::{Inode, InodeFactory, InodeMeta, InodeOps, PseudoEntry, mkfs_alloc_ino, mkfs_attrs};
+use crate::{
+    CodexFsFileType, CodexFsInode, inode::InodeMetaInner, sb::get_sb, scan::mkfs_metadata,
+};
 
 #[derive(Debug, Default)]
-pub struct SymLink {}
+pub struct SymLink {
+    pub target: Option<PathBuf>, /* mkfs: of a pseudo entry, read from the source otherwise;
+                                  * fuse: read at load */
+}
 
 impl InodeFactory for Inode<SymLink> {
-    fn from_path(path: &Path) -> Self {
-        let metadata = path.symlink_metadata().unwrap();
+    fn from_path(path: &Path) -> Result<Self> {
+        let metadata = mkfs_metadata(path)?;
+        let (mode, uid, gid) = mkfs_attrs(path, &metadata)?;
         log::info!("{}, size {}", path.display(), metadata.len());
-        Self {
+        Ok(Self {
             meta: InodeMeta {
                 path: Some(path.into()),
-                ino: get_sb_mut().get_ino_and_inc(),
-                gid: metadata.gid() as _,
-                uid: metadata.uid() as _,
-                mode: metadata.mode() as _,
+                ino: mkfs_alloc_ino(path),
+                gid,
+                uid,
+                mode,
                 inner: RefCell::new(InodeMetaInner {
                     nlink: 0,
                     nid: 0,
@@ -26,7 +39,7 @@ impl InodeFactory for Inode<SymLink> {
                 }),
             },
             itype: SymLink::default(),
-        }
+        })
     }
 
     fn from_codexfs_inode(codexfs_inode: &CodexFsInode, nid: u64) -> Self {
@@ -48,11 +61,39 @@ impl InodeFactory for Inode<SymLink> {
     }
 
     fn fuse_load(codexfs_inode: &CodexFsInode, nid: u64) -> Result<Rc<Self>> {
-        let inode = Inode::<SymLink>::from_codexfs_inode(codexfs_inode, nid);
+        let mut inode = Inode::<SymLink>::from_codexfs_inode(codexfs_inode, nid);
+        // the target is stored inline after the inode
+        let mut target = vec![0; inode.meta.meta_size() as usize];
+        get_sb().read_exact_at(&mut target, inode.meta.inode_meta_off())?;
+        inode.itype.target = Some(OsStr::from_bytes(&target).into());
         Ok(Rc::new(inode))
     }
 }
 
+impl Inode<SymLink> {
+    // path need not exist
+    pub(crate) fn new_pseudo(path: &Path, entry: &PseudoEntry) -> Self {
+        let target = entry.target.clone().unwrap();
+        Self {
+            meta: InodeMeta {
+                path: Some(path.into()),
+                ino: mkfs_alloc_ino(path),
+                gid: entry.gid,
+                uid: entry.uid,
+                mode: entry.mode,
+                inner: RefCell::new(InodeMetaInner {
+                    nlink: 0,
+                    nid: 0,
+                    meta_size: Some(target.as_os_str().as_bytes().len() as _),
+                }),
+            },
+            itype: SymLink {
+                target: Some(target),
+            },
+        }
+    }
+}
+
 impl InodeOps for Inode<SymLink> {
     fn meta(&self) -> &InodeMeta {
         &self.meta
@@ -65,4 +106,8 @@ impl InodeOps for Inode<SymLink> {
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn as_any_rc(self: Rc<Self>) -> Rc<dyn Any> {
+        self
+    }
 }
@@ -6,16 +6,32 @@
 #![allow(non_camel_case_types)]
 
 pub mod buffer;
+pub mod buildinfo;
+pub mod cache;
+pub mod checksum;
+pub mod cluster_cache;
 pub mod compress;
+pub mod delta;
+pub mod idmap;
+pub mod image;
 pub mod inode;
+pub mod options;
+pub mod pattern;
+pub mod pool;
+pub mod progress;
+pub mod provenance;
+pub mod report;
 pub mod sb;
+pub mod scan;
+pub mod segment;
 pub mod utils;
+pub mod xattr;
 
 use std::{fmt::Debug, os::unix::fs::FileTypeExt};
 
 use bitflags::bitflags;
 use bytemuck::{Pod, Zeroable};
-use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
+use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
 use sb::get_sb;
 use utils::round_up;
 
@@ -31,6 +47,8 @@ pub type off_t = u64; // offset in block device
 pub type size_t = u32; // size of a file
 
 pub const CODEXFS_MAGIC: u32 = 114514;
+pub const CODEXFS_VERSION: u8 = 1; // bumped on any incompatible change of the layout
+pub const CODEXFS_NAME_LEN: usize = 255; // longest file name
 pub const CODEXFS_SUPERBLK_OFF: u64 = 0;
 
 pub fn addr_to_blk_id(addr: u64) -> blk_t {
@@ -54,6 +72,17 @@ pub fn nid_to_inode_off(nid: nid_t) -> u64 {
     nid << get_sb().islot_bits
 }
 
+// 32-bit device number as stored in the image, the encoding Linux uses for
+// on-disk inodes and FUSE attributes
+pub fn new_encode_dev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+// (major, minor) of a device number encoded by new_encode_dev
+pub fn new_decode_dev(dev: u32) -> (u32, u32) {
+    ((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00))
+}
+
 pub fn nid_to_inode_meta_off(nid: nid_t) -> u64 {
     (nid + 1) << get_sb().islot_bits
 }
@@ -68,6 +97,17 @@ bitflags! {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Pod, Zeroable)]
+#[repr(transparent)]
+pub struct CodexFsInodeFlags(u8);
+
+bitflags! {
+    impl CodexFsInodeFlags: u8 {
+        const CODEXFS_INODE_DELTA = 1 << 0; // a CodexFsDelta follows the inode
+        const CODEXFS_INODE_PLAIN = 1 << 1; // data not compressed in a compressed image
+    }
+}
+
 // codexfs on-disk super block (currently 128 bytes)
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
 #[repr(C, packed)]
@@ -81,7 +121,37 @@ pub struct CodexFsSuperBlock {
 
     pub blocks: u32, // used for statfs
     pub flags: CodexFsFlags,
-    pub reserved: [u8; 101],
+    pub dict_size: u32,         // lzma dictionary needed to decode any cluster
+    pub max_cluster_size: u32,  // max decompressed bytes of a cluster
+    pub provenance_addr: u64,   // CodexFsProvenance records, 0 if not recorded
+    pub provenance_size: u32,   // bytes of the records
+    pub input_digest: [u8; 32], // sha256 of the source tree and options, 0 if appended to
+    pub build_info_addr: u64,   // JSON record of how the image was built, 0 if none
+    pub build_info_size: u32,
+    pub label: [u8; 16],      // NUL padded, empty if none
+    pub uuid: [u8; 16],       // 0 if none
+    pub version: u8,          // CODEXFS_VERSION, images of other versions are refused
+    pub checksums_blk: blk_t, // CodexFsChecksum records, 0 if not recorded
+}
+
+// crc32 of len bytes of file data at addr, a cluster or a block sized piece
+// of an uncompressed file, preceded in the image by their u32 count
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct CodexFsChecksum {
+    pub addr: u64,
+    pub len: u32,
+    pub crc32: u32,
+}
+
+// where the data of a file came from, followed by path_len bytes of its
+// source path relative to the source root
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct CodexFsProvenance {
+    pub nid: nid_t,
+    pub sha256: [u8; 32], // of the file content
+    pub path_len: u16,
 }
 
 #[derive(Clone, Copy, Zeroable)]
@@ -89,6 +159,7 @@ pub struct CodexFsSuperBlock {
 pub union CodexFsInodeUnion {
     blks: u16,
     blk_off: blk_off_t,
+    rdev: u32, // device number of a device inode
 }
 
 unsafe impl Pod for CodexFsInodeUnion {}
@@ -110,7 +181,9 @@ pub struct CodexFsInode {
     pub gid: gid_t,
     pub blk_id: blk_t,
     pub u: CodexFsInodeUnion,
-    pub reserved: [u8; 8],
+    pub flags: CodexFsInodeFlags,
+    pub xattr_size: u16, // bytes of CodexFsXattrEntry records after the metadata
+    pub reserved: [u8; 5],
 }
 
 #[derive(Clone, Copy, Debug, Zeroable, PartialEq, Eq)]
@@ -126,8 +199,6 @@ pub enum CodexFsFileType {
     Symlink,
 }
 
-unsafe impl Pod for CodexFsFileType {}
-
 impl CodexFsFileType {
     pub const fn is_file(self) -> bool {
         matches!(self, Self::File)
@@ -180,38 +251,104 @@ impl From<std::fs::FileType> for CodexFsFileType {
     }
 }
 
-impl From<mode_t> for CodexFsFileType {
-    fn from(val: mode_t) -> Self {
-        match (val as u32) & S_IFMT {
+// the type bits of a mode, which in a damaged image may be none of these
+impl TryFrom<mode_t> for CodexFsFileType {
+    type Error = anyhow::Error;
+
+    fn try_from(val: mode_t) -> anyhow::Result<Self> {
+        Ok(match (val as u32) & S_IFMT {
             S_IFREG => CodexFsFileType::File,
             S_IFDIR => CodexFsFileType::Dir,
             S_IFCHR => CodexFsFileType::CharDevice,
             S_IFBLK => CodexFsFileType::BlockDevice,
+            S_IFIFO => CodexFsFileType::Fifo,
             S_IFSOCK => CodexFsFileType::Socket,
             S_IFLNK => CodexFsFileType::Symlink,
-            _ => panic!(),
-        }
+            _ => anyhow::bail!("unknown file type in mode {val:o}"),
+        })
     }
 }
 
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
 #[repr(C, packed)]
 pub struct CodexFsDirent {
-    pub nid: nid_t,                 // node number
-    pub nameoff: u16,               // start offset of file name
-    pub file_type: CodexFsFileType, // file type
-    pub reserved: u8,               // reserved
+    pub nid: nid_t,    // node number
+    pub nameoff: u16,  // start offset of file name
+    pub file_type: u8, // CodexFsFileType, as read from the image
+    pub reserved: u8,  // reserved
+}
+
+// data of a delta-encoded file is a binary delta against the file at base_nid,
+// stored (and compressed) like ordinary file data of data_size bytes
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct CodexFsDelta {
+    pub base_nid: nid_t,
+    pub data_size: size_t,
+    pub reserved: u32,
+}
+
+// an extended attribute, followed by name_len bytes of the full name and
+// value_size bytes of the value
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct CodexFsXattrEntry {
+    pub name_len: u8,
+    pub reserved: u8,
+    pub value_size: u16,
 }
 
-// TODO: off and frag_off may be compressed depending on the following condition
-// assert!(e.off == 0 || e.frag_off == 0);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CodexFsCodec {
+    MicroLzma, // right-aligned in the block, preceded by zeros
+    Stored,    // raw bytes from the start of the block
+    Xz,        // a complete .xz stream from the start of the block
+}
+
+impl TryFrom<u8> for CodexFsCodec {
+    type Error = anyhow::Error;
+
+    fn try_from(codec: u8) -> anyhow::Result<Self> {
+        Ok(match codec {
+            0 => Self::MicroLzma,
+            1 => Self::Stored,
+            2 => Self::Xz,
+            _ => anyhow::bail!("unknown codec {codec}"),
+        })
+    }
+}
+
+// extents are sorted by off, each one covers the file up to the next one
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
 #[repr(C)]
 pub struct CodexFsExtent {
     off: u32,      // offset in file
     frag_off: u32, // offset in decompressed fragment
+    blk_id: blk_t, // block holding the fragment
+    codec: u8,     // CodexFsCodec of the fragment's block
+    reserved: [u8; 3],
+}
+
+// what CODEXFS_IOC_GET_FILEINFO returns for a regular file of a mounted image
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+#[repr(C)]
+pub struct CodexFsFileInfo {
+    pub size: u64,
+    pub compressed_size: u64, // bytes stored, blocks shared with other files count whole
+    pub data_addr: u64,       // first byte of the data in the image, 0 for none
+    pub extents: u32,
+    pub codecs: u16, // 1 << codec for every codec of the file's blocks
+    pub flags: u16,  // CODEXFS_FILEINFO_*
 }
 
+pub const CODEXFS_FILEINFO_PLAIN: u16 = 1 << 0; // stored uncompressed
+pub const CODEXFS_FILEINFO_DELTA: u16 = 1 << 1; // stored as a delta to another file
+
+// _IOR('C', 1, CodexFsFileInfo)
+pub const CODEXFS_IOC_GET_FILEINFO: u32 =
+    (2 << 30) | ((size_of::<CodexFsFileInfo>() as u32) << 16) | ((b'C' as u32) << 8) | 1;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -221,6 +358,36 @@ mod tests {
         assert_eq!(size_of::<CodexFsSuperBlock>(), 128);
         assert_eq!(size_of::<CodexFsInode>(), 32);
         assert_eq!(size_of::<CodexFsDirent>(), 12);
-        assert_eq!(size_of::<CodexFsExtent>(), 8);
+        assert_eq!(size_of::<CodexFsDelta>(), 16);
+        assert_eq!(size_of::<CodexFsExtent>(), 16);
+        assert_eq!(size_of::<CodexFsFileInfo>(), 32);
+        assert_eq!(CODEXFS_IOC_GET_FILEINFO, 0x80204301);
+    }
+
+    #[test]
+    fn check_codec_conversion() {
+        for codec in [
+            CodexFsCodec::MicroLzma,
+            CodexFsCodec::Stored,
+            CodexFsCodec::Xz,
+        ] {
+            assert_eq!(CodexFsCodec::try_from(codec as u8).unwrap(), codec);
+        }
+        assert!(CodexFsCodec::try_from(3).is_err());
+    }
+
+    #[test]
+    fn check_file_type_conversion() {
+        let file_type = CodexFsFileType::try_from(S_IFLNK as mode_t | 0o777).unwrap();
+        assert_eq!(file_type, CodexFsFileType::Symlink);
+        assert!(CodexFsFileType::try_from(0o644).is_err());
+        assert!(CodexFsFileType::try_from(0o170644).is_err());
+    }
+
+    #[test]
+    fn check_dev_encoding() {
+        for (major, minor) in [(1, 3), (8, 17), (259, 0x12345)] {
+            assert_eq!(new_decode_dev(new_encode_dev(major, minor)), (major, minor));
+        }
     }
 }
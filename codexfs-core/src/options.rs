@@ -0,0 +1,82 @@
+use std::{
+    cell::OnceCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{Result, bail};
+
+use crate::{
+    gid_t,
+    idmap::IdMap,
+    ino_t,
+    inode::{AttrOverride, PseudoEntry},
+    mode_t, uid_t,
+    xattr::{XattrFilter, Xattrs},
+};
+
+// what mkfs is told to store besides the source tree, and how
+#[derive(Debug, Default)]
+pub struct MkfsOptions {
+    pub owner: Option<(uid_t, gid_t)>, // owner of every inode instead of the source's
+    pub attrs: HashMap<PathBuf, (mode_t, uid_t, gid_t)>, // metadata of these source paths
+    pub pseudo_entries: BTreeMap<PathBuf, PseudoEntry>, // entries missing from the source
+    pub overrides: HashMap<PathBuf, AttrOverride>, // metadata fields of these paths
+    pub uid_map: IdMap,                // source uid to image uid
+    pub gid_map: IdMap,
+    pub xattrs: HashMap<PathBuf, Xattrs>, // extended attributes of these source paths
+    pub root_mode: Option<mode_t>,        // permission bits of the image root
+    pub root_owner: Option<(uid_t, gid_t)>, // owner of the image root
+    pub hardlink_dedupe: bool,            // identical files share one inode
+    pub skip_errors: bool,                // pass over unreadable source entries
+    pub placeholder_unreadable: bool,     // store files denied to us empty
+    pub one_file_system: bool,            // store other mounts as empty directories
+    pub source_xattrs: Option<XattrFilter>, // which xattrs to read from the source
+    pub max_size: Option<u64>,            // fail once the image grows past this
+    pub ino_mode: InoMode,                // how inode numbers are given out
+    pub used_inos: HashSet<ino_t>,        // given out unless counting
+}
+
+// how mkfs numbers inodes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InoMode {
+    #[default]
+    Counter, // in the order the tree is loaded
+    Path,   // a hash of the path below the source root
+    Source, // the source's inode number, truncated to 32 bits
+}
+
+impl FromStr for InoMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "counter" => Ok(InoMode::Counter),
+            "path" => Ok(InoMode::Path),
+            "source" => Ok(InoMode::Source),
+            _ => bail!("unknown inode numbering {s:?}, expected counter, path or source"),
+        }
+    }
+}
+
+static mut MKFS_OPTIONS: OnceCell<MkfsOptions> = OnceCell::new();
+
+pub fn set_mkfs_opts(opts: MkfsOptions) {
+    unsafe { MKFS_OPTIONS.set(opts).unwrap() }
+}
+
+pub fn get_mkfs_opts() -> &'static MkfsOptions {
+    unsafe { MKFS_OPTIONS.get().unwrap() }
+}
+
+pub fn get_mkfs_opts_mut() -> &'static mut MkfsOptions {
+    unsafe { MKFS_OPTIONS.get_mut().unwrap() }
+}
+
+// the options are process-wide, set by the first test to want them
+#[cfg(test)]
+pub(crate) fn init_mkfs_opts() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| set_mkfs_opts(MkfsOptions::default()));
+}
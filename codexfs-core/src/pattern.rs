@@ -0,0 +1,277 @@
+use std::{
+    cell::OnceCell,
+    collections::HashSet,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Result, bail};
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+
+use crate::sb::get_sb;
+
+// Shell-style path patterns. A pattern without '/' matches the file name at
+// any depth, otherwise it matches the path relative to the source root.
+#[derive(Clone, Debug, Default)]
+pub struct PathPatterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl PathPatterns {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for pattern in patterns.iter().map(AsRef::as_ref) {
+            match pattern.contains('/') {
+                true => paths.add(Glob::new(pattern.trim_start_matches('/'))?),
+                false => names.add(Glob::new(pattern)?),
+            };
+        }
+        Ok(Self {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty()
+    }
+
+    // rel_path is relative to the source root
+    pub fn is_match(&self, rel_path: &Path) -> bool {
+        rel_path
+            .file_name()
+            .is_some_and(|name| self.names.is_match(name))
+            || self.paths.is_match(rel_path)
+    }
+}
+
+// Entries left out of the image, applied while loading the source tree. An
+// excluded directory is skipped with everything below it. Include patterns
+// win over exclude ones and, when given, only matching files are kept, though
+// directories are still descended into. With a file list, anything not on it
+// is left out before the patterns are looked at.
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    pub root: PathBuf,
+    pub exclude: PathPatterns,
+    pub include: PathPatterns,
+    pub ignore_files: bool, // honor IGNORE_FILE in every directory
+    pub listed: Option<HashSet<PathBuf>>, // relative paths, from parse_file_list
+}
+
+impl PathFilter {
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let rel_path = path.strip_prefix(&self.root).unwrap_or(path);
+        if let Some(listed) = &self.listed
+            && !listed.contains(rel_path)
+        {
+            return true;
+        }
+        if self.include.is_match(rel_path) {
+            return false;
+        }
+        self.exclude.is_match(rel_path) || (!is_dir && !self.include.is_empty())
+    }
+
+    pub fn is_included(&self, path: &Path) -> bool {
+        let rel_path = path.strip_prefix(&self.root).unwrap_or(path);
+        self.include.is_match(rel_path)
+    }
+}
+
+// Parses a NUL-delimited list of paths relative to the source root, as
+// printed by "find . -print0", adding the directories leading to each path.
+pub fn parse_file_list(list: &[u8]) -> Result<HashSet<PathBuf>> {
+    let mut listed = HashSet::new();
+    for entry in list.split(|&b| b == 0).filter(|entry| !entry.is_empty()) {
+        let entry = Path::new(OsStr::from_bytes(entry));
+        let mut path = PathBuf::new();
+        for component in entry.components() {
+            match component {
+                Component::Normal(name) => {
+                    path.push(name);
+                    listed.insert(path.clone());
+                }
+                Component::CurDir => {}
+                _ => bail!("{}: not a path below the source root", entry.display()),
+            }
+        }
+    }
+    Ok(listed)
+}
+
+pub const IGNORE_FILE: &str = ".codexfsignore";
+
+// One ignore file in gitignore syntax, for the directory holding it and
+// everything below. The last matching line wins and "!" keeps what earlier
+// lines ignored, a pattern with a '/' other than a trailing one is relative to
+// the directory and one ending in '/' only matches directories.
+#[derive(Debug)]
+pub struct IgnoreRules {
+    base: PathBuf,
+    rules: Vec<(GlobMatcher, bool, bool)>, // (glob, negated, directories only)
+}
+
+impl IgnoreRules {
+    pub fn parse(base: &Path, text: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(line) => (true, line),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let pattern = match line.contains('/') {
+                true => line.trim_start_matches('/').to_owned(),
+                false => format!("**/{line}"),
+            };
+            let glob = GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()?
+                .compile_matcher();
+            rules.push((glob, negated, dir_only));
+        }
+        Ok(Self {
+            base: base.into(),
+            rules,
+        })
+    }
+
+    // Some(true) if a line ignores path, Some(false) if a negated one keeps it
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let rel_path = path.strip_prefix(&self.base).ok()?;
+        self.rules
+            .iter()
+            .rev()
+            .find(|(glob, _, dir_only)| (is_dir || !dir_only) && glob.is_match(rel_path))
+            .map(|(_, negated, _)| !negated)
+    }
+}
+
+// rules of the ignore files above path, outermost first, deeper files win
+pub fn is_ignored(rules: &[Arc<IgnoreRules>], path: &Path, is_dir: bool) -> bool {
+    rules
+        .iter()
+        .rev()
+        .find_map(|rules| rules.matched(path, is_dir))
+        .unwrap_or(false)
+}
+
+static mut PATH_FILTER: OnceCell<PathFilter> = OnceCell::new();
+
+pub fn set_path_filter(filter: PathFilter) {
+    unsafe { PATH_FILTER.set(filter).unwrap() }
+}
+
+pub fn get_path_filter() -> Option<&'static PathFilter> {
+    unsafe { PATH_FILTER.get() }
+}
+
+// path relative to the root of the source tree
+pub fn rel_path(path: &Path) -> &Path {
+    path.strip_prefix(get_sb().root().meta().path())
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_path_patterns() {
+        let patterns = PathPatterns::new(&["*.png", "/boot/**", "var/cache"]).unwrap();
+        assert!(patterns.is_match(Path::new("a.png")));
+        assert!(patterns.is_match(Path::new("usr/share/a.png")));
+        assert!(patterns.is_match(Path::new("boot/vmlinuz")));
+        assert!(patterns.is_match(Path::new("var/cache")));
+        assert!(!patterns.is_match(Path::new("var/cache/x")));
+        assert!(!patterns.is_match(Path::new("usr/boot/vmlinuz")));
+        assert!(!patterns.is_match(Path::new("a.png.txt")));
+        assert!(
+            !PathPatterns::new::<&str>(&[])
+                .unwrap()
+                .is_match(Path::new("a"))
+        );
+    }
+
+    #[test]
+    fn check_path_filter() {
+        let mut filter = PathFilter {
+            root: "/src".into(),
+            exclude: PathPatterns::new(&[".git", "*.o", "/target"]).unwrap(),
+            ..Default::default()
+        };
+        assert!(filter.is_excluded(Path::new("/src/a/.git"), true));
+        assert!(filter.is_excluded(Path::new("/src/target"), true));
+        assert!(!filter.is_excluded(Path::new("/src/a/target"), true));
+        assert!(filter.is_excluded(Path::new("/src/a.o"), false));
+        assert!(!filter.is_excluded(Path::new("/src/a.c"), false));
+
+        filter.include = PathPatterns::new(&["keep.o", "*.c"]).unwrap();
+        assert!(!filter.is_excluded(Path::new("/src/keep.o"), false));
+        assert!(!filter.is_excluded(Path::new("/src/a.c"), false));
+        assert!(filter.is_excluded(Path::new("/src/a.h"), false));
+        assert!(!filter.is_excluded(Path::new("/src/include"), true));
+    }
+
+    #[test]
+    fn check_file_list() {
+        let listed = parse_file_list(b".\0./a/b.c\0./d\0\0e/f\0").unwrap();
+        let filter = PathFilter {
+            root: "/src".into(),
+            listed: Some(listed),
+            ..Default::default()
+        };
+        assert!(!filter.is_excluded(Path::new("/src/a"), true));
+        assert!(!filter.is_excluded(Path::new("/src/a/b.c"), false));
+        assert!(filter.is_excluded(Path::new("/src/a/b.h"), false));
+        assert!(!filter.is_excluded(Path::new("/src/d"), true));
+        assert!(filter.is_excluded(Path::new("/src/d/x"), false));
+        assert!(!filter.is_excluded(Path::new("/src/e/f"), false));
+        assert!(parse_file_list(b"../etc/passwd").is_err());
+        assert!(parse_file_list(b"/etc/passwd").is_err());
+
+        // names are bytes, as the source stores them
+        let listed = parse_file_list(b"./caf\xe9/x\0").unwrap();
+        assert!(listed.contains(Path::new(OsStr::from_bytes(b"caf\xe9/x"))));
+    }
+
+    #[test]
+    fn check_ignore_rules() {
+        let text = "# comment\n*.o\n!keep.o\n/build/\ndoc/*.html\n\\#hash\n";
+        let rules = Arc::new(IgnoreRules::parse(Path::new("/src"), text).unwrap());
+        let sub = Arc::new(IgnoreRules::parse(Path::new("/src/a"), "!*.o\n").unwrap());
+        let ignored =
+            |path: &str, is_dir| is_ignored(std::slice::from_ref(&rules), Path::new(path), is_dir);
+        assert!(ignored("/src/x/y.o", false));
+        assert!(!ignored("/src/x/keep.o", false));
+        assert!(ignored("/src/build", true));
+        assert!(!ignored("/src/build", false));
+        assert!(!ignored("/src/x/build", true));
+        assert!(ignored("/src/doc/a.html", false));
+        assert!(!ignored("/src/doc/x/a.html", false));
+        assert!(ignored("/src/#hash", false));
+        assert!(!ignored("/src/a.c", false));
+        assert!(!is_ignored(
+            &[rules.clone(), sub.clone()],
+            Path::new("/src/a/y.o"),
+            false
+        ));
+        assert!(is_ignored(
+            &[rules.clone(), sub.clone()],
+            Path::new("/src/b/y.o"),
+            false
+        ));
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex, OnceLock,
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// A fixed set of threads running jobs in the order they are spawned, so reads
+// decoding clusters in the background do not start threads of their own.
+#[derive(Debug)]
+pub struct WorkerPool {
+    sender: Sender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("codexfs-worker-{i}"))
+                .spawn(move || {
+                    loop {
+                        let Ok(job) = receiver.lock().unwrap().recv() else {
+                            return;
+                        };
+                        // a panicking job only loses its result
+                        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                    }
+                })
+                .unwrap();
+        }
+        Self { sender }
+    }
+
+    // the result is sent once the job is done, the receiver is disconnected
+    // without one if it panicked
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Receiver<T> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let job = move || {
+            let _ = sender.send(job());
+        };
+        self.sender.send(Box::new(job)).unwrap();
+        receiver
+    }
+}
+
+const DECODE_THREADS: usize = 4;
+
+// threads decoding clusters for every mounted image, started on first use
+pub fn get_decode_pool() -> &'static WorkerPool {
+    static DECODE_POOL: OnceLock<WorkerPool> = OnceLock::new();
+    DECODE_POOL.get_or_init(|| WorkerPool::new(DECODE_THREADS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_worker_pool() {
+        let pool = WorkerPool::new(2);
+        let receivers: Vec<_> = (0..8).map(|i| pool.spawn(move || i * i)).collect();
+        let results: Vec<_> = receivers.into_iter().map(|r| r.recv().unwrap()).collect();
+        assert_eq!(results, (0..8).map(|i| i * i).collect::<Vec<_>>());
+
+        // a panic neither kills the worker nor hangs the caller
+        let receiver = pool.spawn(|| -> u32 { panic!("job failed") });
+        assert!(receiver.recv().is_err());
+        assert_eq!(pool.spawn(|| 1).recv().unwrap(), 1);
+        assert_eq!(pool.spawn(|| 2).recv().unwrap(), 2);
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    cell::OnceCell,
+    io::{IsTerminal, Write, stderr},
+    time::{Duration, Instant},
+};
+
+// Status of the running mkfs phase on stderr, redrawn in place on a terminal
+// and printed as a line every few seconds otherwise.
+#[derive(Debug)]
+pub struct Progress {
+    pub enabled: bool,
+    tty: bool,
+    phase: &'static str,
+    unit: Unit,
+    done: u64,
+    total: Option<u64>,
+    start: Instant,
+    last: Instant,
+    pub phases: Vec<(&'static str, Duration)>, // finished phases with their wall-clock time
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Entries,
+    Bytes,
+}
+
+static mut PROGRESS: OnceCell<Progress> = OnceCell::new();
+
+pub fn get_progress_mut() -> &'static mut Progress {
+    unsafe {
+        PROGRESS.get_mut_or_init(|| Progress {
+            enabled: false,
+            tty: stderr().is_terminal(),
+            phase: "",
+            unit: Unit::Entries,
+            done: 0,
+            total: None,
+            start: Instant::now(),
+            last: Instant::now(),
+            phases: Vec::new(),
+        })
+    }
+}
+
+impl Progress {
+    pub fn begin(&mut self, phase: &'static str, unit: Unit, total: Option<u64>) {
+        self.phase = phase;
+        self.unit = unit;
+        self.done = 0;
+        self.total = total;
+        self.start = Instant::now();
+        self.last = self.start;
+    }
+
+    pub fn advance(&mut self, n: u64) {
+        self.done += n;
+        let interval = match self.tty {
+            true => Duration::from_millis(200),
+            false => Duration::from_secs(5),
+        };
+        if self.enabled && self.last.elapsed() >= interval {
+            self.last = Instant::now();
+            self.print();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.phases.push((self.phase, self.start.elapsed()));
+        if self.enabled {
+            self.print();
+            if self.tty {
+                eprintln!();
+            }
+        }
+    }
+
+    fn print(&self) {
+        let mut line = format!("{}: {}", self.phase, self.amount(self.done));
+        if let Some(total) = self.total.filter(|&t| t > 0) {
+            let percent = self.done as f64 * 100.0 / total as f64;
+            line += &format!(" of {} ({percent:.0}%)", self.amount(total));
+            let secs = self.start.elapsed().as_secs_f64();
+            if self.done > 0 && self.done < total {
+                let eta = (secs * (total - self.done) as f64 / self.done as f64) as u64;
+                line += &format!(", eta {}:{:02}", eta / 60, eta % 60);
+            }
+        }
+        let mut err = stderr().lock();
+        match self.tty {
+            true => write!(err, "\r{line}\x1b[K"),
+            false => writeln!(err, "{line}"),
+        }
+        .ok();
+    }
+
+    fn amount(&self, n: u64) -> String {
+        match self.unit {
+            Unit::Entries => format!("{n} entries"),
+            Unit::Bytes => format!("{:.1} MiB", n as f64 / (1 << 20) as f64),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    ffi::OsStr,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow, ensure};
+use bytemuck::{bytes_of, from_bytes};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    CodexFsProvenance,
+    buffer::{BufferType, get_bufmgr_mut, mkfs_check_max_size},
+    inode::{InodeHandle, fuse_load_inode, fuse_read_inode_file_data},
+    nid_t,
+    options::get_mkfs_opts,
+    pattern::rel_path,
+    sb::{get_sb, get_sb_mut},
+};
+
+// one file of the image, as many records as it has paths
+#[derive(Debug, PartialEq, Eq)]
+pub struct Provenance {
+    pub nid: nid_t,
+    pub sha256: [u8; 32],
+    pub path: PathBuf,
+}
+
+// Records the digest and source path of every file below root. Has to run
+// after the inodes got their nids.
+pub fn mkfs_balloc_provenance(root: &InodeHandle) -> Result<Vec<u8>> {
+    let mut records = Vec::new();
+    collect(root, &mut records);
+    let buf = encode(&records)?;
+    let addr = get_bufmgr_mut().balloc(buf.len() as _, BufferType::Meta);
+    get_sb_mut().provenance = (addr, buf.len() as _);
+    mkfs_check_max_size()?;
+    Ok(buf)
+}
+
+pub fn mkfs_dump_provenance(buf: &[u8]) -> Result<()> {
+    get_sb().write_all_at(buf, get_sb().provenance.0)?;
+    Ok(())
+}
+
+// Digest of everything a build stores from the source tree below root, and
+// of the options it was given. Two builds with the same one make the same
+// image, though not always byte for byte.
+pub fn mkfs_input_digest(root: &InodeHandle, options: &str) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hash_field(&mut hasher, options.as_bytes());
+    hash_inode(&mut hasher, root)?;
+    Ok(hasher.finalize().into())
+}
+
+// digest of a file read by the build besides the source tree, such as an
+// order file, for the options hashed by mkfs_input_digest
+pub fn mkfs_file_digest(path: &Path) -> io::Result<[u8; 32]> {
+    Ok(Sha256::digest(fs::read(path)?).into())
+}
+
+fn hash_field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+fn hash_inode(hasher: &mut Sha256, inode: &InodeHandle) -> Result<()> {
+    let meta = inode.meta();
+    hasher.update(meta.mode.to_le_bytes());
+    hasher.update(meta.uid.to_le_bytes());
+    hasher.update(meta.gid.to_le_bytes());
+    hasher.update(meta.inner.borrow().nlink.to_le_bytes());
+    if let Some(xattrs) = get_mkfs_opts().xattrs.get(meta.path()) {
+        for (name, value) in xattrs.iter() {
+            hash_field(hasher, name.as_bytes());
+            hash_field(hasher, value);
+        }
+    }
+    if let Some(file) = inode.downcast_file_ref() {
+        hasher.update(file.itype.size.to_le_bytes());
+        hasher.update(file.itype.inner.borrow().hash.unwrap_or_default());
+    } else if let Some(special) = inode.downcast_special_ref() {
+        hasher.update(special.itype.rdev.to_le_bytes());
+    } else if let Some(dir) = inode.downcast_dir_ref() {
+        for dentry in dir.itype.inner.borrow().dentries.iter() {
+            hash_field(hasher, dentry.file_name.as_bytes());
+            hash_inode(hasher, &dentry.inode)?;
+        }
+    } else if let Some(symlink) = inode.downcast_symlink_ref() {
+        let target = match &symlink.itype.target {
+            Some(target) => target.clone(),
+            None => fs::read_link(meta.path())?,
+        };
+        hash_field(hasher, target.as_os_str().as_bytes());
+    }
+    Ok(())
+}
+
+// files reached from dir in tree order, under their source paths
+fn collect(dir: &InodeHandle, records: &mut Vec<Provenance>) {
+    let Some(dir) = dir.downcast_dir_ref() else {
+        return;
+    };
+    for dentry in dir.itype.inner.borrow().dentries.iter() {
+        if let Some(file) = dentry.inode.downcast_file_ref()
+            && let Some(sha256) = file.itype.inner.borrow().hash
+        {
+            let path = dentry.path.as_deref().unwrap_or(file.meta.path());
+            records.push(Provenance {
+                nid: file.meta.inner.borrow().nid,
+                sha256,
+                path: rel_path(path).into(),
+            });
+        }
+        collect(&dentry.inode, records);
+    }
+}
+
+fn encode(records: &[Provenance]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for record in records {
+        let path = record.path.as_os_str().as_bytes();
+        let entry = CodexFsProvenance {
+            nid: record.nid,
+            sha256: record.sha256,
+            path_len: u16::try_from(path.len())?,
+        };
+        buf.extend(bytes_of(&entry));
+        buf.extend(path);
+    }
+    ensure!(
+        buf.len() <= u32::MAX as usize,
+        "provenance records take {} bytes",
+        buf.len()
+    );
+    Ok(buf)
+}
+
+fn decode(mut buf: &[u8]) -> Result<Vec<Provenance>> {
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        let header_size = size_of::<CodexFsProvenance>();
+        ensure!(buf.len() >= header_size, "truncated provenance record");
+        let entry: CodexFsProvenance = *from_bytes(&buf[..header_size]);
+        let end = header_size + entry.path_len as usize;
+        ensure!(buf.len() >= end, "truncated provenance record");
+        records.push(Provenance {
+            nid: entry.nid,
+            sha256: entry.sha256,
+            path: OsStr::from_bytes(&buf[header_size..end]).into(),
+        });
+        buf = &buf[end..];
+    }
+    Ok(records)
+}
+
+// the records of a loaded image, none if it has no provenance region
+pub fn fuse_load_provenance() -> Result<Vec<Provenance>> {
+    let (addr, size) = get_sb().provenance;
+    if addr == 0 {
+        return Ok(Vec::new());
+    }
+    let mut buf = vec![0; size as usize];
+    get_sb().read_exact_at(&mut buf, addr)?;
+    decode(&buf)
+}
+
+// whether the file in the image still has the recorded content
+pub fn fuse_verify(record: &Provenance) -> Result<bool> {
+    let inode = fuse_load_inode(record.nid)?;
+    let file = inode
+        .downcast_file_ref()
+        .ok_or_else(|| anyhow!("nid {} is not a regular file", record.nid))?;
+    let data = fuse_read_inode_file_data(file, 0, file.itype.size)?;
+    let sha256: [u8; 32] = Sha256::digest(&data[..file.itype.size as usize]).into();
+    Ok(sha256 == record.sha256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_encode_decode() {
+        let records = vec![
+            Provenance {
+                nid: 3,
+                sha256: [7; 32],
+                path: "usr/bin/sh".into(),
+            },
+            Provenance {
+                nid: 9,
+                sha256: [0; 32],
+                path: "empty".into(),
+            },
+        ];
+        let buf = encode(&records).unwrap();
+        assert_eq!(decode(&buf).unwrap(), records);
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+    }
+}
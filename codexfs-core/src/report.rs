@@ -0,0 +1,257 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Ok, Result};
+use serde::Serialize;
+
+use crate::{
+    CodexFsFileType, blk_id_to_addr,
+    compress::get_cmpr_mgr,
+    inode::{InodeHandle, get_inode_vec_mut},
+    nid_to_inode_off,
+    pattern::rel_path,
+    sb::get_sb,
+};
+
+// du-like report of original vs compressed bytes, children before parents
+pub fn mkfs_report(w: &mut dyn Write) -> Result<()> {
+    writeln!(w, "compressed\tsize\tratio\tpath")?;
+    let root = get_sb().root();
+    mkfs_report_inode(root, root.meta().path(), w)?;
+    Ok(())
+}
+
+fn mkfs_report_inode(inode: &InodeHandle, path: &Path, w: &mut dyn Write) -> Result<(u64, u64)> {
+    let (size, zsize) = if let Some(file) = inode.downcast_file_ref() {
+        (file.itype.size as u64, file.compressed_size())
+    } else if let Some(dir) = inode.downcast_dir_ref() {
+        let mut total = (0, 0);
+        for dentry in dir.itype.inner.borrow().dentries.iter() {
+            let (size, zsize) = mkfs_report_inode(&dentry.inode, dentry.path.as_ref().unwrap(), w)?;
+            total.0 += size;
+            total.1 += zsize;
+        }
+        total
+    } else {
+        return Ok((0, 0));
+    };
+
+    let ratio = if size == 0 {
+        100.0
+    } else {
+        zsize as f64 * 100.0 / size as f64
+    };
+    writeln!(w, "{zsize}\t{size}\t{ratio:.1}%\t{}", path.display())?;
+    Ok((size, zsize))
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub total_in: u64,
+    pub total_out: u64,
+    pub clusters: usize,
+    pub plain: u64, // bytes stored uncompressed, not in total_in
+    pub codecs: BTreeMap<String, usize>,
+    // decompressed bytes per cluster, rounded up to a power of two
+    pub cluster_histogram: BTreeMap<u32, usize>,
+    pub reorder_cost_before: Option<usize>,
+    pub reorder_cost_after: Option<usize>,
+}
+
+impl Stats {
+    pub fn collect() -> Self {
+        let cmpr_mgr = get_cmpr_mgr();
+        let mut stats = Stats {
+            total_in: cmpr_mgr.data_size(),
+            reorder_cost_before: cmpr_mgr.reorder_cost.map(|c| c.0),
+            reorder_cost_after: cmpr_mgr.reorder_cost.map(|c| c.1),
+            ..Default::default()
+        };
+        if !get_sb().compress {
+            stats.total_out = stats.total_in;
+            return stats;
+        }
+        stats.plain = cmpr_mgr
+            .plain_files
+            .iter()
+            .map(|file| file.data_size() as u64)
+            .sum();
+        for cluster in cmpr_mgr.clusters.iter() {
+            stats.total_out += cluster.out_size as u64;
+            stats.clusters += 1;
+            *stats
+                .codecs
+                .entry(format!("{:?}", cluster.codec).to_lowercase())
+                .or_default() += 1;
+            *stats
+                .cluster_histogram
+                .entry(cluster.in_size.next_power_of_two())
+                .or_default() += 1;
+        }
+        stats
+    }
+}
+
+pub fn mkfs_dump_stats(w: &mut dyn Write) -> Result<()> {
+    serde_json::to_writer_pretty(&mut *w, &Stats::collect())?;
+    writeln!(w)?;
+    Ok(())
+}
+
+// Summary printed at the end of mkfs, meta_size is what mkfs_balloc_inode
+// returned and phases the finished progress phases.
+pub fn mkfs_summary(
+    w: &mut dyn Write,
+    meta_size: u64,
+    phases: &[(&'static str, Duration)],
+) -> Result<()> {
+    let (mut files, mut dirs, mut symlinks, mut others) = (0, 0, 0, 0);
+    let mut size = 0;
+    for inode in get_inode_vec_mut().iter() {
+        match inode.file_type() {
+            CodexFsFileType::File => {
+                files += 1;
+                size += inode.downcast_file_ref().unwrap().itype.size as u64;
+            }
+            CodexFsFileType::Dir => dirs += 1,
+            CodexFsFileType::Symlink => symlinks += 1,
+            _ => others += 1,
+        }
+    }
+    let percent = |n: u64| match size {
+        0 => 100.0,
+        _ => n as f64 * 100.0 / size as f64,
+    };
+    writeln!(
+        w,
+        "{files} files, {dirs} directories, {symlinks} symlinks, {others} other inodes"
+    )?;
+    writeln!(w, "file data   {size} bytes")?;
+    let cmpr_mgr = get_cmpr_mgr();
+    if get_sb().compress {
+        let stats = Stats::collect();
+        let zdata_size = stats.clusters as u64 * get_sb().blksz() as u64;
+        writeln!(
+            w,
+            "compressed  {} bytes in {} clusters ({:.1}%), {zdata_size} bytes on disk",
+            stats.total_in,
+            stats.clusters,
+            stats.total_out as f64 * 100.0 / stats.total_in.max(1) as f64,
+        )?;
+        writeln!(
+            w,
+            "plain       {} bytes in {} files",
+            stats.plain,
+            cmpr_mgr.plain_files.len()
+        )?;
+    } else {
+        let plain: u64 = cmpr_mgr.files.iter().map(|f| f.data_size() as u64).sum();
+        writeln!(
+            w,
+            "plain       {plain} bytes in {} files",
+            cmpr_mgr.files.len()
+        )?;
+    }
+    writeln!(w, "metadata    {meta_size} bytes")?;
+    let img_size = get_sb().img_file.as_ref().unwrap().metadata()?.len();
+    writeln!(
+        w,
+        "image       {img_size} bytes, {:.1}% of the file data",
+        percent(img_size)
+    )?;
+    let total: Duration = phases.iter().map(|(_, time)| *time).sum();
+    let phases: Vec<String> = phases
+        .iter()
+        .map(|(phase, time)| format!("{phase} {:.2}s", time.as_secs_f64()))
+        .collect();
+    writeln!(w, "took {:.2}s: {}", total.as_secs_f64(), phases.join(", "))?;
+    Ok(())
+}
+
+// where an image path ended up, every name of a hardlinked inode is listed
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf, // in the image, starting with /
+    pub nid: u64,
+    pub ino: u32,
+    pub mode: u16,
+    pub inode_off: u64,
+    pub data_off: Option<u64>, // plain data, or the first block of compressed data
+    pub extents: usize,
+    pub size: u64,
+    pub compressed_size: u64,
+}
+
+pub fn mkfs_manifest() -> Vec<ManifestEntry> {
+    let mut entries = Vec::new();
+    let root = get_sb().root();
+    mkfs_manifest_inode(root, root.meta().path(), &mut entries);
+    entries
+}
+
+fn mkfs_manifest_inode(inode: &InodeHandle, path: &Path, entries: &mut Vec<ManifestEntry>) {
+    let nid = inode.meta().inner.borrow().nid;
+    let mut entry = ManifestEntry {
+        path: Path::new("/").join(rel_path(path)),
+        nid,
+        ino: inode.meta().ino,
+        mode: inode.meta().mode,
+        inode_off: nid_to_inode_off(nid),
+        data_off: None,
+        extents: 0,
+        size: 0,
+        compressed_size: 0,
+    };
+    if let Some(file) = inode.downcast_file_ref() {
+        let inner = file.itype.inner.borrow();
+        entry.data_off = inner
+            .blk_id
+            .map(|blk_id| blk_id_to_addr(blk_id) + inner.blk_off.unwrap_or(0) as u64);
+        entry.extents = inner.extents.len();
+        entry.size = file.itype.size as _;
+        drop(inner);
+        entry.compressed_size = file.compressed_size();
+    }
+    entries.push(entry);
+    if let Some(dir) = inode.downcast_dir_ref() {
+        for dentry in dir.itype.inner.borrow().dentries.iter() {
+            mkfs_manifest_inode(&dentry.inode, dentry.path.as_ref().unwrap(), entries);
+        }
+    }
+}
+
+pub fn mkfs_dump_manifest(w: &mut dyn Write) -> Result<()> {
+    serde_json::to_writer_pretty(&mut *w, &mkfs_manifest())?;
+    writeln!(w)?;
+    Ok(())
+}
+
+pub fn mkfs_dump_manifest_csv(w: &mut dyn Write) -> Result<()> {
+    writeln!(
+        w,
+        "path,nid,ino,mode,inode_off,data_off,extents,size,compressed_size"
+    )?;
+    for entry in mkfs_manifest() {
+        let path = entry.path.to_string_lossy().replace('"', "\"\"");
+        let data_off = entry
+            .data_off
+            .map(|off| off.to_string())
+            .unwrap_or_default();
+        writeln!(
+            w,
+            "\"{path}\",{},{},{:o},{},{data_off},{},{},{}",
+            entry.nid,
+            entry.ino,
+            entry.mode,
+            entry.inode_off,
+            entry.extents,
+            entry.size,
+            entry.compressed_size
+        )?;
+    }
+    Ok(())
+}
@@ -1,12 +1,14 @@
 use std::{cell::OnceCell, fs::File, os::unix::fs::FileExt};
 
-use anyhow::{Ok, Result};
+use anyhow::{Context, Ok, Result, ensure};
 use bytemuck::{bytes_of, from_bytes};
 
 use crate::{
-    CODEXFS_MAGIC, CODEXFS_SUPERBLK_OFF, CodexFsFlags, CodexFsInode, CodexFsSuperBlock, blk_size_t,
+    CODEXFS_MAGIC, CODEXFS_SUPERBLK_OFF, CODEXFS_VERSION, CodexFsChecksum, CodexFsFlags,
+    CodexFsInode, CodexFsSuperBlock, addr_to_blk_id, blk_size_t, blk_t,
     buffer::{BufferType, get_bufmgr_mut},
-    ino_t,
+    compress::get_cmpr_mgr,
+    image, ino_t,
     inode::{Inode, InodeHandle},
     utils::round_up,
 };
@@ -19,6 +21,18 @@ pub struct SuperBlock {
     pub img_file: Option<File>,
     root: Option<InodeHandle>,
     pub compress: bool,
+    pub dict_size: u32,
+    pub max_cluster_size: u32,
+    pub provenance: (u64, u32), // address and size of the provenance records
+    pub input_digest: [u8; 32], // mkfs: what the image was built from
+    pub build_info: (u64, u32), // address and size of the build info record
+    pub checksums_blk: blk_t,   // where the checksums of file data start
+    // mkfs: the checksums of the data written, if recording them; fuse: those
+    // reads are checked against, if verifying
+    pub checksums: Option<Vec<CodexFsChecksum>>,
+    pub label: [u8; 16],
+    pub uuid: [u8; 16],
+    pub lazy_dirs: bool, // fuse: read dentries on first use
 }
 
 impl SuperBlock {
@@ -42,7 +56,15 @@ impl SuperBlock {
         self.set_root(root);
         self.islot_bits = codexfs_sb.islot_bits;
         self.blksz_bits = codexfs_sb.blksz_bits;
+        self.ino = codexfs_sb.inos;
         self.compress = codexfs_sb.flags.contains(CodexFsFlags::CODEXFS_COMPRESSED);
+        self.dict_size = codexfs_sb.dict_size;
+        self.max_cluster_size = codexfs_sb.max_cluster_size;
+        self.provenance = (codexfs_sb.provenance_addr, codexfs_sb.provenance_size);
+        self.build_info = (codexfs_sb.build_info_addr, codexfs_sb.build_info_size);
+        self.checksums_blk = codexfs_sb.checksums_blk;
+        self.label = codexfs_sb.label;
+        self.uuid = codexfs_sb.uuid;
         Ok(())
     }
 
@@ -94,35 +116,125 @@ impl From<&SuperBlock> for CodexFsSuperBlock {
             root_nid: sb.root().meta().inner.borrow().nid,
             inos: sb.ino,
             blocks: 0,
-            reserved: [0; _],
             islot_bits: sb.islot_bits,
             flags,
+            dict_size: sb.dict_size,
+            max_cluster_size: sb.max_cluster_size,
+            provenance_addr: sb.provenance.0,
+            provenance_size: sb.provenance.1,
+            input_digest: sb.input_digest,
+            build_info_addr: sb.build_info.0,
+            build_info_size: sb.build_info.1,
+            label: sb.label,
+            uuid: sb.uuid,
+            version: CODEXFS_VERSION,
+            checksums_blk: sb.checksums_blk,
         }
     }
 }
 
 static mut SUPER_BLOCK: OnceCell<SuperBlock> = OnceCell::new();
 
+// the super block of the image entered on this thread, the process-wide one
+// otherwise
+fn super_block() -> &'static mut OnceCell<SuperBlock> {
+    match image::entered() {
+        Some(image) => &mut image.sb,
+        None => unsafe { &mut SUPER_BLOCK },
+    }
+}
+
 pub fn set_sb(sb: SuperBlock) {
-    unsafe { SUPER_BLOCK.set(sb).unwrap() }
+    super_block().set(sb).unwrap()
 }
 
 pub fn get_sb() -> &'static SuperBlock {
-    unsafe { SUPER_BLOCK.get().unwrap() }
+    super_block().get().unwrap()
 }
 
 pub fn get_sb_mut() -> &'static mut SuperBlock {
-    unsafe { SUPER_BLOCK.get_mut().unwrap() }
+    super_block().get_mut().unwrap()
 }
 
 pub fn fuse_load_super_block(img_file: File) -> Result<()> {
+    let codexfs_sb = read_super_block(&img_file)?;
     set_sb(SuperBlock::new(img_file, 0));
+    get_sb_mut().from_codexfs_sb(&codexfs_sb)?;
+    Ok(())
+}
+
+// The superblock of an image, without loading it
+pub fn read_super_block(img_file: &File) -> Result<CodexFsSuperBlock> {
     let mut sb_buf = [0; size_of::<CodexFsSuperBlock>()];
-    get_sb().read_exact_at(&mut sb_buf, CODEXFS_SUPERBLK_OFF)?;
-    let codexfs_sb: &CodexFsSuperBlock = from_bytes(&sb_buf);
+    img_file.read_exact_at(&mut sb_buf, CODEXFS_SUPERBLK_OFF)?;
+    let codexfs_sb: CodexFsSuperBlock = *from_bytes(&sb_buf);
     let magic = codexfs_sb.magic;
-    assert_eq!(magic, CODEXFS_MAGIC);
-    get_sb_mut().from_codexfs_sb(codexfs_sb)?;
+    ensure!(magic == CODEXFS_MAGIC, "not a codexfs image");
+    ensure!(
+        codexfs_sb.version == CODEXFS_VERSION,
+        "image format version {}, this build reads version {CODEXFS_VERSION}",
+        codexfs_sb.version
+    );
+    Ok(codexfs_sb)
+}
+
+// Input digest recorded in the superblock of an image, without loading it.
+pub fn read_input_digest(img_file: &File) -> Result<[u8; 32]> {
+    Ok(read_super_block(img_file)?.input_digest)
+}
+
+// up to 16 bytes, stored NUL padded
+pub fn parse_label(s: &str) -> Result<[u8; 16]> {
+    let mut label = [0; 16];
+    ensure!(s.len() <= label.len(), "label {s} is longer than 16 bytes");
+    ensure!(!s.contains('\0'), "label {s} contains NUL");
+    label[..s.len()].copy_from_slice(s.as_bytes());
+    Ok(label)
+}
+
+pub fn label_to_string(label: &[u8; 16]) -> String {
+    let len = label.iter().position(|&b| b == 0).unwrap_or(label.len());
+    String::from_utf8_lossy(&label[..len]).into_owned()
+}
+
+// 32 hex digits, dashes anywhere ignored as in 8-4-4-4-12
+pub fn parse_uuid(s: &str) -> Result<[u8; 16]> {
+    let hex: Vec<u8> = s.bytes().filter(|&b| b != b'-').collect();
+    ensure!(hex.len() == 32, "{s}: expected a UUID of 32 hex digits");
+    let mut uuid = [0; 16];
+    for (byte, pair) in uuid.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok();
+        *byte = pair
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .with_context(|| format!("{s}: expected a UUID of 32 hex digits"))?;
+    }
+    Ok(uuid)
+}
+
+pub fn uuid_to_string(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// Loads an existing image to add to. New data and metadata go after its end,
+// the superblock is rewritten in place.
+pub fn mkfs_load_image_for_append(img_file: File) -> Result<()> {
+    let len = img_file.metadata()?.len();
+    fuse_load_super_block(img_file)?;
+    // they would not cover what is added
+    get_sb_mut().checksums_blk = 0;
+    ensure!(
+        len % get_sb().blksz() as u64 == 0,
+        "image size {len} is not a multiple of the block size"
+    );
+    get_bufmgr_mut().skip_to(addr_to_blk_id(len));
     Ok(())
 }
 
@@ -131,6 +243,21 @@ pub fn mkfs_balloc_super_block() {
     assert_eq!(pos, CODEXFS_SUPERBLK_OFF);
 }
 
+// size the decoder for the largest cluster actually written, or already in
+// the image when appending
+pub fn mkfs_set_decoder_limits() {
+    const LZMA_DICT_SIZE_MIN: u32 = 4096;
+    let max_cluster_size = get_cmpr_mgr()
+        .clusters
+        .iter()
+        .map(|c| c.in_size)
+        .max()
+        .unwrap_or(0)
+        .max(get_sb().max_cluster_size);
+    get_sb_mut().max_cluster_size = max_cluster_size;
+    get_sb_mut().dict_size = max_cluster_size.next_power_of_two().max(LZMA_DICT_SIZE_MIN);
+}
+
 pub fn mkfs_dump_super_block() -> Result<()> {
     let codexfs_sb = CodexFsSuperBlock::from(get_sb());
     get_sb().write_all_at(bytes_of(&codexfs_sb), CODEXFS_SUPERBLK_OFF)?;
@@ -143,3 +270,37 @@ pub fn mkfs_align_block_size() -> Result<()> {
     get_sb().img_file.as_ref().unwrap().set_len(aligned_len)?;
     Ok(())
 }
+
+// Grows the image with zeros to the next multiple of align, then to size, for
+// partitions of a fixed size or flash erased in large blocks.
+pub fn mkfs_pad_image(size: Option<u64>, align: Option<u64>) -> Result<()> {
+    let img_file = get_sb().img_file.as_ref().unwrap();
+    let mut len = img_file.metadata()?.len();
+    if let Some(align) = align {
+        len = len.next_multiple_of(align);
+    }
+    if let Some(size) = size {
+        ensure!(len <= size, "image needs {len} bytes, more than {size}");
+        len = size;
+    }
+    img_file.set_len(len)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_label_and_uuid() {
+        let label = parse_label("rootfs").unwrap();
+        assert_eq!(label_to_string(&label), "rootfs");
+        assert!(parse_label("a-label-of-17-bytes").is_err());
+        let s = "0123abcd-4567-89ef-0123-456789abcdef";
+        let uuid = parse_uuid(s).unwrap();
+        assert_eq!(uuid_to_string(&uuid), s);
+        assert_eq!(parse_uuid(&s.to_uppercase()).unwrap(), uuid);
+        assert!(parse_uuid("0123abcd").is_err());
+        assert!(parse_uuid("0123abcd-4567-89ef-0123-456789abcdeg").is_err());
+    }
+}
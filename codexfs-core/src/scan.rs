@@ -0,0 +1,505 @@
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    fs::{self, Metadata},
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use tlsh_fixed::Tlsh;
+
+use crate::{
+    CodexFsFileType,
+    compress::{ContentHash, calc_fingerprint, get_cmpr_mgr_mut},
+    options::{get_mkfs_opts, get_mkfs_opts_mut},
+    pattern::{IGNORE_FILE, IgnoreRules, PathFilter, PathPatterns, get_path_filter, is_ignored},
+    progress::{Unit, get_progress_mut},
+    size_t,
+    xattr::{self, XattrFilter, Xattrs},
+};
+
+// Metadata, directory listings and file fingerprints of the source tree,
+// gathered by several threads before mkfs_load_inode builds the inodes from
+// them serially. Files that could not be fingerprinted are missing and are
+// read again by the loader, which reports the error.
+#[derive(Debug, Default)]
+pub struct Scan {
+    metadata: HashMap<PathBuf, Metadata>,
+    dir_entries: HashMap<PathBuf, Vec<PathBuf>>, // in read_dir order, not excluded
+    fingerprints: HashMap<(u64, u64), (Option<Tlsh>, ContentHash)>, // by (dev, ino)
+    unreadable: HashSet<(u64, u64)>,             // files stored empty
+    skipped: Option<Vec<(PathBuf, String)>>,     // errors and what was done instead, if skipped
+}
+
+static mut SCAN: OnceCell<Scan> = OnceCell::new();
+
+fn get_scan() -> Option<&'static Scan> {
+    unsafe { SCAN.get() }
+}
+
+fn get_scan_mut() -> Option<&'static mut Scan> {
+    unsafe { SCAN.get_mut() }
+}
+
+pub(crate) fn mkfs_metadata(path: &Path) -> Result<Metadata> {
+    match get_scan().and_then(|scan| scan.metadata.get(path)) {
+        Some(metadata) => Ok(metadata.clone()),
+        None => get_symlink_policy().metadata(path),
+    }
+}
+
+// What to do with symlinks in the source tree. Followed ones are stored as
+// the file or directory they point to, resolved on the host.
+#[derive(Debug, Default)]
+pub struct SymlinkPolicy {
+    pub root: PathBuf,
+    pub follow_all: bool,
+    pub follow: PathPatterns,
+    pub reject_dangling: bool,
+    pub reject_absolute: bool,
+}
+
+impl SymlinkPolicy {
+    // metadata of path, or of its target if it is a symlink to follow
+    pub fn metadata(&self, path: &Path) -> Result<Metadata> {
+        let metadata = path
+            .symlink_metadata()
+            .with_context(|| format!("stat {}", path.display()))?;
+        if !metadata.is_symlink() {
+            return Ok(metadata);
+        }
+        let target = path.metadata();
+        let rel_path = path.strip_prefix(&self.root).unwrap_or(path);
+        if let Ok(target) = target.as_ref()
+            && (self.follow_all || self.follow.is_match(rel_path))
+        {
+            return Ok(target.clone());
+        }
+        ensure!(
+            !self.reject_dangling || target.is_ok(),
+            "dangling symlink {}",
+            path.display()
+        );
+        ensure!(
+            !self.reject_absolute || !fs::read_link(path)?.is_absolute(),
+            "absolute symlink {}",
+            path.display()
+        );
+        Ok(metadata)
+    }
+}
+
+static mut SYMLINK_POLICY: OnceCell<SymlinkPolicy> = OnceCell::new();
+
+pub fn set_symlink_policy(policy: SymlinkPolicy) {
+    unsafe { SYMLINK_POLICY.set(policy).unwrap() }
+}
+
+fn get_symlink_policy() -> &'static SymlinkPolicy {
+    unsafe { SYMLINK_POLICY.get_or_init(SymlinkPolicy::default) }
+}
+
+pub(crate) fn mkfs_dir_entries(path: &Path) -> Option<&'static [PathBuf]> {
+    get_scan()?.dir_entries.get(path).map(Vec::as_slice)
+}
+
+// source errors passed over with --skip-errors
+pub fn mkfs_skipped() -> &'static [(PathBuf, String)] {
+    get_scan()
+        .and_then(|scan| scan.skipped.as_deref())
+        .unwrap_or_default()
+}
+
+pub(crate) fn mkfs_is_unreadable(metadata: &Metadata) -> bool {
+    get_scan().is_some_and(|scan| scan.unreadable.contains(&dev_ino(metadata)))
+}
+
+// records e unless errors abort the build
+fn skip(
+    skipped: &mut Option<Vec<(PathBuf, String)>>,
+    path: &Path,
+    e: anyhow::Error,
+    instead: &str,
+) -> Result<()> {
+    let Some(skipped) = skipped else {
+        return Err(e);
+    };
+    log::warn!("{e:#}, {instead}");
+    skipped.push((path.into(), format!("{e:#}, {instead}")));
+    Ok(())
+}
+
+pub(crate) fn mkfs_take_fingerprint(metadata: &Metadata) -> Option<(Option<Tlsh>, ContentHash)> {
+    get_scan_mut()?.fingerprints.remove(&dev_ino(metadata))
+}
+
+#[derive(Default)]
+struct Queue {
+    dirs: Vec<QueuedDir>,
+    root_dev: Option<u64>, // directories on other devices are stored empty
+    busy: usize,
+    error: Option<anyhow::Error>,
+    skipped: Vec<(PathBuf, String)>,
+}
+
+#[derive(Clone)]
+struct QueuedDir {
+    path: PathBuf,
+    ancestors: Vec<(u64, u64)>, // (dev, ino) of it and the directories above
+    ignores: Vec<Arc<IgnoreRules>>, // ignore files of the directories above
+}
+
+fn dev_ino(metadata: &Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+type Listing = (PathBuf, Vec<(PathBuf, Metadata, Xattrs)>);
+
+// Walks the tree below root and fingerprints its regular files with the given
+// number of threads. Has to run after the path filter, symlink policy and
+// fingerprint cache are set up.
+pub fn mkfs_scan(root: &Path, threads: usize) -> Result<()> {
+    let threads = threads.max(1);
+    let mut scan = Scan {
+        skipped: get_mkfs_opts().skip_errors.then(Vec::new),
+        ..Default::default()
+    };
+    let root_metadata = root.metadata()?;
+    let root_dev_ino = dev_ino(&root_metadata);
+    scan.metadata.insert(root.into(), root_metadata);
+    let xattr_filter = get_mkfs_opts().source_xattrs.as_ref();
+    let root_xattrs = read_xattrs(root, true, xattr_filter, &mut scan.skipped)?;
+    mkfs_add_xattrs(root.into(), root_xattrs);
+
+    // directory walk
+    let queue = Mutex::new(Queue {
+        dirs: vec![QueuedDir {
+            path: root.into(),
+            ancestors: vec![root_dev_ino],
+            ignores: Vec::new(),
+        }],
+        root_dev: get_mkfs_opts().one_file_system.then_some(root_dev_ino.0),
+        ..Default::default()
+    });
+    let cvar = Condvar::new();
+    let scanned = AtomicU64::new(0);
+    let (filter, policy) = (get_path_filter(), get_symlink_policy());
+    let skip_errors = get_mkfs_opts().skip_errors;
+    let listings: Vec<Listing> = thread::scope(|scope| {
+        let handles = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    scan_dirs(
+                        &queue,
+                        &cvar,
+                        &scanned,
+                        filter,
+                        policy,
+                        xattr_filter,
+                        skip_errors,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        poll_progress(&handles, &scanned);
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    let queue = queue.into_inner().unwrap();
+    if let Some(e) = queue.error {
+        return Err(e);
+    }
+    if let Some(skipped) = scan.skipped.as_mut() {
+        skipped.extend(queue.skipped);
+    }
+    for (dir, entries) in listings {
+        let mut paths = Vec::with_capacity(entries.len());
+        for (path, metadata, xattrs) in entries {
+            paths.push(path.clone());
+            mkfs_add_xattrs(path.clone(), xattrs);
+            scan.metadata.insert(path, metadata);
+        }
+        scan.dir_entries.insert(dir, paths);
+    }
+    get_progress_mut().finish();
+
+    // regular files in the order the loader meets them, each inode once so
+    // cache lookups use the same path as without the scan
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(path) = stack.pop() {
+        let metadata = &scan.metadata[&path];
+        if metadata.is_file() && seen.insert(dev_ino(metadata)) {
+            files.push(path);
+        } else if let Some(entries) = scan.dir_entries.get(&path) {
+            stack.extend(entries.iter().rev().cloned());
+        }
+    }
+    let mut pending = Vec::new();
+    for path in files {
+        let metadata = &scan.metadata[&path];
+        let key = dev_ino(metadata);
+        let cached = get_cmpr_mgr_mut()
+            .fingerprint_cache
+            .as_mut()
+            .and_then(|cache| cache.get(&path, metadata));
+        match cached {
+            Some(fingerprint) => {
+                scan.fingerprints.insert(key, fingerprint);
+            }
+            None => pending.push(path),
+        }
+    }
+
+    // fingerprints
+    let total = pending.iter().map(|p| scan.metadata[p].len()).sum();
+    get_progress_mut().begin("fingerprinting", Unit::Bytes, Some(total));
+    let done = AtomicU64::new(0);
+    let chunk_size = pending.len().div_ceil(threads).max(1);
+    let results = thread::scope(|scope| {
+        let handles = pending
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let (metadata, done) = (&scan.metadata, &done);
+                scope.spawn(move || {
+                    let mut results = Vec::with_capacity(chunk.len());
+                    for path in chunk {
+                        let fingerprint = fs::File::open(path).and_then(calc_fingerprint);
+                        results.push((path, fingerprint));
+                        done.fetch_add(metadata[path].len(), Ordering::Relaxed);
+                    }
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+        poll_progress(&handles, &done);
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    // with placeholders, files denied to us are passed over even if other
+    // errors are not
+    let mut placeholders = get_mkfs_opts().placeholder_unreadable.then(Vec::new);
+    for (path, fingerprint) in results {
+        let metadata = &scan.metadata[path];
+        let (tlsh, hash) = match fingerprint {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => {
+                let denied = e.kind() == io::ErrorKind::PermissionDenied;
+                let e = anyhow::Error::new(e).context(format!("read {}", path.display()));
+                match placeholders.is_some() && denied {
+                    true => skip(&mut placeholders, path, e, "stored as an empty placeholder")?,
+                    false => skip(&mut scan.skipped, path, e, "stored empty")?,
+                }
+                scan.unreadable.insert(dev_ino(metadata));
+                continue;
+            }
+        };
+        if let Some(cache) = get_cmpr_mgr_mut().fingerprint_cache.as_mut() {
+            cache.insert(path, metadata, tlsh.as_ref(), &hash);
+        }
+        scan.fingerprints.insert(dev_ino(metadata), (tlsh, hash));
+    }
+    get_progress_mut().finish();
+    if let Some(placeholders) = placeholders
+        && !placeholders.is_empty()
+    {
+        scan.skipped.get_or_insert_default().extend(placeholders);
+    }
+
+    unsafe { SCAN.set(scan).unwrap() }
+    Ok(())
+}
+
+fn scan_dirs(
+    queue: &Mutex<Queue>,
+    cvar: &Condvar,
+    scanned: &AtomicU64,
+    filter: Option<&PathFilter>,
+    policy: &SymlinkPolicy,
+    xattr_filter: Option<&XattrFilter>,
+    skip_errors: bool,
+) -> Vec<Listing> {
+    let mut listings = Vec::new();
+    loop {
+        let mut dir = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if let Some(dir) = queue.dirs.pop() {
+                    queue.busy += 1;
+                    break dir;
+                }
+                if queue.busy == 0 {
+                    return listings;
+                }
+                queue = cvar.wait(queue).unwrap();
+            }
+        };
+        let mut skipped = skip_errors.then(Vec::new);
+        let entries = scan_dir(&mut dir, filter, policy, xattr_filter, &mut skipped);
+        let mut queue = queue.lock().unwrap();
+        queue.skipped.extend(skipped.into_iter().flatten());
+        match entries {
+            Ok(entries) => {
+                scanned.fetch_add(entries.len() as u64, Ordering::Relaxed);
+                // only a followed symlink can lead back to an ancestor
+                for (path, metadata, _) in entries.iter().filter(|(_, m, _)| m.is_dir()) {
+                    if dir.ancestors.contains(&dev_ino(metadata)) {
+                        let e = anyhow!("symlink loop at {}", path.display());
+                        queue.error.get_or_insert(e);
+                    } else if queue.root_dev.is_some_and(|dev| dev != metadata.dev()) {
+                        log::info!("not crossing into mount point {}", path.display());
+                        listings.push((path.clone(), Vec::new()));
+                    } else {
+                        let mut child = dir.clone();
+                        child.path = path.clone();
+                        child.ancestors.push(dev_ino(metadata));
+                        queue.dirs.push(child);
+                    }
+                }
+                listings.push((dir.path, entries));
+            }
+            Err(e) => {
+                queue.error.get_or_insert(e);
+            }
+        }
+        if queue.error.is_some() {
+            queue.dirs.clear();
+        }
+        queue.busy -= 1;
+        cvar.notify_all();
+    }
+}
+
+// Adds the ignore file of dir to the rules its subdirectories inherit. With
+// skipped errors, unreadable directories are stored empty and entries that
+// can not be stat'ed left out.
+fn scan_dir(
+    dir: &mut QueuedDir,
+    filter: Option<&PathFilter>,
+    policy: &SymlinkPolicy,
+    xattr_filter: Option<&XattrFilter>,
+    skipped: &mut Option<Vec<(PathBuf, String)>>,
+) -> Result<Vec<(PathBuf, Metadata, Xattrs)>> {
+    let mut entries = Vec::new();
+    let read_dir = match fs::read_dir(&dir.path) {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            let e = anyhow::Error::new(e).context(format!("read {}", dir.path.display()));
+            skip(skipped, &dir.path, e, "stored empty")?;
+            return Ok(Vec::new());
+        }
+    };
+    if let Some(filter) = filter
+        && filter.ignore_files
+    {
+        let ignore_path = dir.path.join(IGNORE_FILE);
+        match fs::read_to_string(&ignore_path) {
+            Ok(text) => {
+                let rules = IgnoreRules::parse(&dir.path, &text)
+                    .with_context(|| format!("parse {}", ignore_path.display()))?;
+                dir.ignores.push(Arc::new(rules));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                let e = anyhow::Error::new(e).context(format!("read {}", ignore_path.display()));
+                skip(skipped, &ignore_path, e, "not applied")?;
+            }
+        }
+    }
+    for entry in read_dir {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                let e = anyhow::Error::new(e).context(format!("read {}", dir.path.display()));
+                skip(skipped, &dir.path, e, "entries left out")?;
+                continue;
+            }
+        };
+        let metadata = match policy.metadata(&path) {
+            Ok(metadata)
+                if CodexFsFileType::from(metadata.file_type()) == CodexFsFileType::Unknown =>
+            {
+                let e = anyhow!("{}: unsupported file type", path.display());
+                skip(skipped, &path, e, "left out")?;
+                continue;
+            }
+            Ok(metadata) if metadata.is_file() && metadata.len() > size_t::MAX as u64 => {
+                let e = anyhow!("{}: larger than {} bytes", path.display(), size_t::MAX);
+                skip(skipped, &path, e, "left out")?;
+                continue;
+            }
+            Ok(metadata) => metadata,
+            Err(e) => {
+                skip(skipped, &path, e, "left out")?;
+                continue;
+            }
+        };
+        if let Some(filter) = filter
+            && (filter.is_excluded(&path, metadata.is_dir())
+                || is_ignored(&dir.ignores, &path, metadata.is_dir()) && !filter.is_included(&path))
+        {
+            log::info!("exclude {}", path.display());
+            continue;
+        }
+        let xattrs = read_xattrs(&path, !metadata.is_symlink(), xattr_filter, skipped)?;
+        entries.push((path, metadata, xattrs));
+    }
+    Ok(entries)
+}
+
+// the xattrs of path the filter keeps, none without a filter
+fn read_xattrs(
+    path: &Path,
+    follow: bool,
+    filter: Option<&XattrFilter>,
+    skipped: &mut Option<Vec<(PathBuf, String)>>,
+) -> Result<Xattrs> {
+    let Some(filter) = filter else {
+        return Ok(Vec::new());
+    };
+    match xattr::read(path, follow) {
+        Ok(mut xattrs) => {
+            xattrs.retain(|(name, _)| filter.is_kept(name));
+            Ok(xattrs)
+        }
+        Err(e) => {
+            let e = anyhow::Error::new(e).context(format!("read xattrs of {}", path.display()));
+            skip(skipped, path, e, "xattrs left out")?;
+            Ok(Vec::new())
+        }
+    }
+}
+
+// xattrs already given for the path, e.g. by an fs_config file, win
+fn mkfs_add_xattrs(path: PathBuf, xattrs: Xattrs) {
+    if xattrs.is_empty() {
+        return;
+    }
+    let stored = get_mkfs_opts_mut().xattrs.entry(path).or_default();
+    for (name, value) in xattrs {
+        if !stored.iter().any(|(stored_name, _)| *stored_name == name) {
+            stored.push((name, value));
+        }
+    }
+}
+
+fn poll_progress<T>(handles: &[thread::ScopedJoinHandle<T>], counter: &AtomicU64) {
+    while !handles.iter().all(|handle| handle.is_finished()) {
+        thread::sleep(Duration::from_millis(50));
+        get_progress_mut().advance(counter.swap(0, Ordering::Relaxed));
+    }
+    get_progress_mut().advance(counter.swap(0, Ordering::Relaxed));
+}
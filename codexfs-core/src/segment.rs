@@ -0,0 +1,180 @@
+use std::{
+    io::{self, Read},
+    rc::Rc,
+};
+
+use tlsh_fixed::Tlsh;
+
+use crate::{
+    compress::{ContentHash, Fingerprinter},
+    inode::{File, Inode},
+};
+
+// A contiguous piece of a file's stored data, the unit of reordering and
+// deduplication. Files are a single segment unless split into content-defined
+// chunks, identical segments are stored once.
+#[derive(Debug)]
+pub struct Segment {
+    pub file: Rc<Inode<File>>,
+    pub off: u64,
+    pub len: u64,
+    pub tlsh: Option<Tlsh>,
+    pub shared: Vec<(Rc<Inode<File>>, u64)>, // same bytes at these offsets of other files
+}
+
+impl Segment {
+    pub fn whole(file: &Rc<Inode<File>>) -> Self {
+        Self {
+            file: file.clone(),
+            off: 0,
+            len: file.data_size() as _,
+            tlsh: file.itype.inner.borrow().tlsh.clone(),
+            shared: Vec::new(),
+        }
+    }
+
+    // every file and offset whose data this segment provides
+    pub fn places(&self) -> impl Iterator<Item = (&Rc<Inode<File>>, u64)> {
+        [(&self.file, self.off)]
+            .into_iter()
+            .chain(self.shared.iter().map(|(file, off)| (file, *off)))
+    }
+}
+
+// gear hash table, any fixed random values do
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut x: u64 = 0x9e3779b97f4a7c15;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        x = x.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = x;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+// Gear-hash content-defined chunking. A cut depends only on the bytes just
+// before it, so regions shared by different files are cut at the same places
+// wherever they start.
+struct Chunker {
+    hash: u64,
+    len: u64,
+    min: u64,
+    max: u64,
+    mask: u64,
+}
+
+impl Chunker {
+    fn new(avg_size: u64) -> Self {
+        Self {
+            hash: 0,
+            len: 0,
+            min: avg_size / 4,
+            max: avg_size * 4,
+            mask: avg_size.next_power_of_two() - 1,
+        }
+    }
+
+    // length of the prefix of data that ends the current chunk, if any
+    fn find_cut(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, &byte) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+            self.len += 1;
+            if self.len >= self.max || (self.len >= self.min && self.hash & self.mask == 0) {
+                self.hash = 0;
+                self.len = 0;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
+
+// splits a non-delta file into segments of about avg_size bytes
+pub fn split_file(
+    file: &Rc<Inode<File>>,
+    avg_size: u64,
+) -> io::Result<Vec<(Segment, ContentHash)>> {
+    let mut reader = file.mkfs_open()?.take(file.itype.size as u64);
+    let mut chunker = Chunker::new(avg_size);
+    let mut fingerprinter = Fingerprinter::new();
+    let mut segments = Vec::new();
+    let mut push = |off: u64, len: u64, fingerprinter: Fingerprinter| {
+        let (tlsh, hash) = fingerprinter.finish();
+        let segment = Segment {
+            file: file.clone(),
+            off,
+            len,
+            tlsh,
+            shared: Vec::new(),
+        };
+        segments.push((segment, hash));
+    };
+
+    let mut buf = vec![0; 64 * 1024];
+    let (mut start, mut off) = (0, 0);
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let mut data = &buf[..n];
+        while let Some(cut) = chunker.find_cut(data) {
+            fingerprinter.update(&data[..cut]);
+            off += cut as u64;
+            push(start, off - start, std::mem::take(&mut fingerprinter));
+            start = off;
+            data = &data[cut..];
+        }
+        fingerprinter.update(data);
+        off += data.len() as u64;
+    }
+    if off > start {
+        push(start, off - start, fingerprinter);
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cuts(data: &[u8], avg_size: u64) -> Vec<usize> {
+        let mut chunker = Chunker::new(avg_size);
+        let mut cuts = Vec::new();
+        let mut pos = 0;
+        while let Some(cut) = chunker.find_cut(&data[pos..]) {
+            pos += cut;
+            cuts.push(pos);
+        }
+        cuts
+    }
+
+    #[test]
+    fn check_chunker_resyncs() {
+        let mut x = 1u32;
+        let data: Vec<u8> = (0..200000)
+            .map(|_| {
+                // xorshift32
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let base = cuts(&data, 4096);
+        assert!(base.len() > 10);
+        assert!(base.windows(2).all(|w| w[1] - w[0] <= 4 * 4096));
+
+        // a prefix shifts every offset, but later cuts land on the same bytes
+        let mut shifted = b"some prefix".to_vec();
+        shifted.extend(&data);
+        let shifted: Vec<_> = cuts(&shifted, 4096).iter().map(|c| c - 11).collect();
+        assert_eq!(base[base.len() - 5..], shifted[shifted.len() - 5..]);
+    }
+}
@@ -1,3 +1,5 @@
+use std::ffi::OsStr;
+
 use num_traits::PrimInt;
 
 pub fn round_up<T: PrimInt>(value: T, align: T) -> T {
@@ -8,10 +10,33 @@ pub fn round_down<T: PrimInt>(value: T, align: T) -> T {
     value & !(align - T::one())
 }
 
-pub fn is_dot_or_dotdot(s: &str) -> bool {
+pub fn is_dot_or_dotdot(s: impl AsRef<OsStr>) -> bool {
+    let s = s.as_ref();
     s == "." || s == ".."
 }
 
+// 32-bit FNV-1a, a hash that stays the same across builds and hosts
+pub fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// bytes with an optional binary K, M or G suffix
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    let size: u64 = num.parse()?;
+    anyhow::ensure!(size > 0, "size must not be 0");
+    size.checked_shl(shift)
+        .filter(|n| n >> shift == size)
+        .ok_or_else(|| anyhow::anyhow!("size {s} is too large"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -37,4 +62,11 @@ mod tests {
         assert!(!is_dot_or_dotdot("..."));
         assert!(!is_dot_or_dotdot("not dot"));
     }
+
+    #[test]
+    fn check_fnv1a() {
+        assert_eq!(fnv1a(b""), 0x811c9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c292c);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9cf968);
+    }
 }
@@ -0,0 +1,197 @@
+use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+use anyhow::{Result, ensure};
+use bytemuck::{bytes_of, from_bytes};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::CodexFsXattrEntry;
+
+pub type Xattrs = Vec<(String, Vec<u8>)>; // (full name, value)
+
+pub const XATTR_SECURITY_CAPABILITY: &str = "security.capability";
+pub const XATTR_SECURITY_SELINUX: &str = "security.selinux";
+
+const VFS_CAP_REVISION_2: u32 = 0x02000000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x000001;
+
+// the records stored after the metadata of an inode
+pub fn encode(xattrs: &Xattrs) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for (name, value) in xattrs.iter() {
+        let entry = CodexFsXattrEntry {
+            name_len: u8::try_from(name.len())?,
+            reserved: 0,
+            value_size: u16::try_from(value.len())?,
+        };
+        buf.extend(bytes_of(&entry));
+        buf.extend(name.as_bytes());
+        buf.extend(value);
+    }
+    ensure!(
+        buf.len() <= u16::MAX as usize,
+        "xattrs take {} bytes",
+        buf.len()
+    );
+    Ok(buf)
+}
+
+pub fn decode(mut buf: &[u8]) -> Result<Xattrs> {
+    let mut xattrs = Vec::new();
+    while !buf.is_empty() {
+        let header_size = size_of::<CodexFsXattrEntry>();
+        ensure!(buf.len() >= header_size, "truncated xattr entry");
+        let entry: CodexFsXattrEntry = *from_bytes(&buf[..header_size]);
+        let name_end = header_size + entry.name_len as usize;
+        let value_end = name_end + entry.value_size as usize;
+        ensure!(buf.len() >= value_end, "truncated xattr entry");
+        let name = String::from_utf8(buf[header_size..name_end].to_vec())?;
+        xattrs.push((name, buf[name_end..value_end].to_vec()));
+        buf = &buf[value_end..];
+    }
+    Ok(xattrs)
+}
+
+// Which extended attributes of the source tree to store, by name patterns
+// such as "user.*". Include patterns win over exclude ones.
+#[derive(Debug, Default)]
+pub struct XattrFilter {
+    exclude: GlobSet,
+    include: GlobSet,
+}
+
+impl XattrFilter {
+    pub fn new<S: AsRef<str>>(exclude: &[S], include: &[S]) -> Result<Self> {
+        let build = |patterns: &[S]| -> Result<GlobSet> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern.as_ref())?);
+            }
+            Ok(builder.build()?)
+        };
+        Ok(Self {
+            exclude: build(exclude)?,
+            include: build(include)?,
+        })
+    }
+
+    pub fn is_kept(&self, name: &str) -> bool {
+        self.include.is_match(name) || !self.exclude.is_match(name)
+    }
+}
+
+// Extended attributes of path, or of the symlink itself unless follow is set,
+// in the order the filesystem lists them. A filesystem without xattr support
+// has none.
+pub fn read(path: &Path, follow: bool) -> io::Result<Xattrs> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let list = read_buf(|buf, size| unsafe {
+        if follow {
+            libc::listxattr(c_path.as_ptr(), buf.cast(), size)
+        } else {
+            libc::llistxattr(c_path.as_ptr(), buf.cast(), size)
+        }
+    });
+    let list = match list {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        list => list?,
+    };
+    let mut xattrs = Vec::new();
+    for name in list.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name)?;
+        let value = read_buf(|buf, size| unsafe {
+            if follow {
+                libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), buf.cast(), size)
+            } else {
+                libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf.cast(), size)
+            }
+        });
+        let value = match value {
+            // removed since it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+            value => value?,
+        };
+        let name = String::from_utf8(name.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        xattrs.push((name, value));
+    }
+    Ok(xattrs)
+}
+
+// Sets extended attributes on path itself, not on what a symlink points to.
+pub fn write(path: &Path, xattrs: &Xattrs) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    for (name, value) in xattrs.iter() {
+        let c_name = CString::new(name.as_str())?;
+        let ret = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// asks for the size first, again if it grew in between
+fn read_buf(f: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0; size as usize];
+        let len = f(buf.as_mut_ptr(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+// security.capability value granting the permitted set as effective, the way
+// setcap and Android's fs_config do
+pub fn capability(permitted: u64) -> Vec<u8> {
+    let mut value = Vec::new();
+    value.extend((VFS_CAP_REVISION_2 | VFS_CAP_FLAGS_EFFECTIVE).to_le_bytes());
+    value.extend((permitted as u32).to_le_bytes());
+    value.extend(0u32.to_le_bytes()); // inheritable
+    value.extend(((permitted >> 32) as u32).to_le_bytes());
+    value.extend(0u32.to_le_bytes());
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_encode_decode() {
+        let xattrs = vec![
+            (XATTR_SECURITY_CAPABILITY.to_owned(), capability(1 << 12)),
+            ("user.empty".to_owned(), Vec::new()),
+        ];
+        let buf = encode(&xattrs).unwrap();
+        assert_eq!(decode(&buf).unwrap(), xattrs);
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+        assert_eq!(capability(1 << 12)[..8], [1, 0, 0, 2, 0, 0x10, 0, 0]);
+    }
+
+    #[test]
+    fn check_xattr_filter() {
+        let filter = XattrFilter::new(&["user.*", "trusted.*"], &["user.keep"]).unwrap();
+        assert!(!filter.is_kept("user.mime_type"));
+        assert!(filter.is_kept("user.keep"));
+        assert!(!filter.is_kept("trusted.overlay.opaque"));
+        assert!(filter.is_kept("security.selinux"));
+    }
+}
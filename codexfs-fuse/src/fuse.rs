@@ -1,49 +1,44 @@
 use std::{
-    ffi::OsStr,
-    os::unix::fs::FileExt,
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    str::FromStr,
+    sync::atomic::Ordering,
     time::{Duration, SystemTime},
 };
 
-use bytemuck::from_bytes;
+use anyhow::{Result, bail};
+use bytemuck::bytes_of;
 use codexfs_core::{
-    CodexFsFileType, CodexFsInode,
-    inode::{
-        File, Inode, InodeHandle, InodeOps, fuse_load_inode, fuse_read_inode_file,
-        fuse_read_inode_file_z, get_inode,
-    },
-    nid_to_inode_off,
+    CODEXFS_IOC_GET_FILEINFO, CodexFsFileType,
+    buildinfo::fuse_image_info,
+    idmap::IdMap,
+    image::Entered,
+    inode::{File, Inode, InodeHandle, fuse_file_info, fuse_read_xattrs},
     sb::get_sb,
     utils::round_up,
+    xattr::Xattrs,
 };
-use fuser::{FUSE_ROOT_ID, FileAttr, Filesystem, Request};
-use log::{debug, info};
-
-fn codexfsfuse_get_inode(ino: u64) -> Option<&'static InodeHandle> {
-    let nid = codexfsfuse_ino_to_nid(ino);
-    let mut codexfs_inode_buf = vec![0; size_of::<CodexFsInode>()];
-    get_sb()
-        .read_exact_at(&mut codexfs_inode_buf, nid_to_inode_off(nid))
-        .unwrap();
-    let codexfs_inode: &CodexFsInode = from_bytes(&codexfs_inode_buf);
-    get_inode(codexfs_inode.ino)
-}
-
-fn codexfsfuse_ino_to_nid(ino: u64) -> u64 {
-    if ino == FUSE_ROOT_ID {
-        return get_sb().root().meta().inner.borrow().nid;
-    }
-    ino - FUSE_ROOT_ID
-}
+use fuser::{
+    FUSE_ROOT_ID, FileAttr, Filesystem, Request,
+    consts::{
+        FOPEN_CACHE_DIR, FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE, FUSE_DO_READDIRPLUS,
+        FUSE_READDIRPLUS_AUTO,
+    },
+};
+use log::{debug, error, info};
+use serde_json::json;
+use tracing::{Span, field, instrument};
 
-fn codexfsfuse_nid_to_ino(nid: u64) -> u64 {
-    if nid == get_sb().root().meta().inner.borrow().nid {
-        return FUSE_ROOT_ID;
-    }
-    nid + FUSE_ROOT_ID
-}
+use crate::{
+    node::{InoTable, Layers, Node},
+    reader::{Read, Readers},
+};
 
-fn codexfsfuse_codexfsfiletype_cast(file_type: CodexFsFileType) -> fuser::FileType {
-    match file_type {
+fn codexfsfuse_codexfsfiletype_cast(file_type: CodexFsFileType) -> Result<fuser::FileType> {
+    Ok(match file_type {
         CodexFsFileType::File => fuser::FileType::RegularFile,
         CodexFsFileType::Dir => fuser::FileType::Directory,
         CodexFsFileType::CharDevice => fuser::FileType::CharDevice,
@@ -51,83 +46,353 @@ fn codexfsfuse_codexfsfiletype_cast(file_type: CodexFsFileType) -> fuser::FileTy
         CodexFsFileType::Fifo => fuser::FileType::NamedPipe,
         CodexFsFileType::Socket => fuser::FileType::Socket,
         CodexFsFileType::Symlink => fuser::FileType::Symlink,
-        CodexFsFileType::Unknown => panic!(),
-    }
+        CodexFsFileType::Unknown => bail!("unknown file type"),
+    })
 }
 
-fn codexfsfuse_inode_attr(inode: &InodeHandle) -> FileAttr {
+fn codexfsfuse_inode_attr(inode: &InodeHandle, ino: u64) -> Result<FileAttr> {
     let size = if let Some(i) = inode.as_any().downcast_ref::<Inode<File>>() {
         i.itype.size as _
     } else {
         0
     };
-    let blocks = if let Some(i) = inode.as_any().downcast_ref::<Inode<File>>() {
-        (round_up(i.itype.size, get_sb().blksz() as _) / (get_sb().blksz() as u32)) as _
-    } else {
-        0
-    };
-    FileAttr {
-        ino: codexfsfuse_nid_to_ino(inode.meta().inner.borrow().nid),
+    // st_blocks counts 512-byte units of what the data takes in the image
+    let blksz = get_sb().blksz() as u64;
+    let blocks = inode.downcast_file_ref().map_or(0, |file| {
+        round_up(fuse_file_info(file).compressed_size, blksz) / 512
+    });
+    Ok(FileAttr {
+        ino,
         size,
         blocks,
         atime: SystemTime::now(),
         mtime: SystemTime::now(),
         ctime: SystemTime::now(),
         crtime: SystemTime::now(),
-        kind: codexfsfuse_codexfsfiletype_cast(inode.file_type()),
+        kind: codexfsfuse_codexfsfiletype_cast(inode.file_type())?,
         perm: inode.meta().mode as _,
         nlink: inode.meta().inner.borrow().nlink as _,
         uid: inode.meta().uid as _,
         gid: inode.meta().gid as _,
-        rdev: 0,
-        blksize: 0,
+        rdev: inode.downcast_special_ref().map_or(0, |i| i.itype.rdev),
+        blksize: blksz as _,
         flags: 0,
+    })
+}
+
+// What a request failing on a damaged image replies, the mount stays up:
+// EIO where reading the image failed, EUCLEAN where what it holds makes no
+// sense.
+pub(crate) fn codexfsfuse_errno(err: anyhow::Error) -> i32 {
+    error!("{err:#}");
+    match err.downcast_ref::<io::Error>() {
+        Some(err) => err.raw_os_error().unwrap_or(libc::EIO),
+        None => libc::EUCLEAN,
+    }
+}
+
+// Checks mask against the owner, group or other bits of the mode, whichever
+// apply to uid and the groups in_group accepts. Root may do anything but
+// execute a file no one can.
+fn codexfsfuse_permitted(
+    attr: &FileAttr,
+    uid: u32,
+    in_group: impl Fn(u32) -> bool,
+    mask: i32,
+) -> bool {
+    let mode = attr.perm as i32;
+    if uid == 0 {
+        return mask & libc::X_OK == 0
+            || attr.kind == fuser::FileType::Directory
+            || mode & 0o111 != 0;
     }
+    let bits = if uid == attr.uid {
+        mode >> 6
+    } else if in_group(attr.gid) {
+        mode >> 3
+    } else {
+        mode
+    };
+    mask & !bits & 0o7 == 0
 }
 
-pub struct CodexFs;
+// The supplementary groups of a process, which FUSE requests do not carry,
+// as /proc lists them. None if it exited meanwhile.
+fn codexfsfuse_groups(pid: u32) -> Option<Vec<u32>> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let groups = status.lines().find_map(|l| l.strip_prefix("Groups:"))?;
+    Some(
+        groups
+            .split_whitespace()
+            .filter_map(|g| g.parse().ok())
+            .collect(),
+    )
+}
+
+// a size of 0 asks for the length only, a smaller buffer than the data is
+// ERANGE
+fn codexfsfuse_reply_xattr(data: &[u8], size: u32, reply: fuser::ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as _);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+// who checks requests against the modes of the image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Permissions {
+    Kernel, // the kernel, as with default_permissions
+    #[default]
+    Fs, // access(), open() and opendir() here
+    All,    // nobody, any user may read anything, to inspect restrictive images
+}
+
+impl FromStr for Permissions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kernel" => Ok(Permissions::Kernel),
+            "fs" => Ok(Permissions::Fs),
+            "all" => Ok(Permissions::All),
+            _ => bail!("unknown permission checks {s:?}, expected kernel, fs or all"),
+        }
+    }
+}
+
+// what an open file or directory keeps until released
+pub(crate) enum Handle {
+    File { next_off: u64 }, // where the last read ended
+    Dir(Vec<(OsString, u64)>),
+}
+
+pub struct CodexFs {
+    // the images served, entered while their inodes are used
+    pub(crate) layers: Layers,
+    pub(crate) inos: RefCell<InoTable>,
+    // how long the kernel may remember that a name does not exist
+    pub negative_ttl: Duration,
+    // reads bypass the page cache
+    pub direct_io: bool,
+    // JSON shown as IMAGE_INFO_XATTR of the root, made when first asked for
+    // as it walks the whole tree
+    pub image_info: OnceCell<Vec<u8>>,
+    // owners as presented: every inode owned by squash_owner if set, image
+    // ids mapped otherwise
+    pub squash_owner: Option<(u32, u32)>,
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    pub permissions: Permissions,
+    // largest read the kernel is asked to send at once
+    pub max_read: u32,
+    // compressed files smaller than this are decoded whole when opened
+    pub prefetch_below: u32,
+    // no data, directories or failed lookups cached by the kernel
+    pub no_cache: bool,
+    // the threads file data is read on
+    pub(crate) readers: Readers,
+    pub(crate) handles: HashMap<u64, Handle>,
+    pub(crate) next_fh: u64,
+}
+
+const IMAGE_INFO_XATTR: &str = "user.codexfs.info";
+const STATS_XATTR: &str = "user.codexfs.stats";
+const COMPRESSED_SIZE_XATTR: &str = "user.codexfs.compressed_size";
+const EXTENTS_XATTR: &str = "user.codexfs.extents";
+
+impl CodexFs {
+    fn open_handle(&mut self, handle: Handle) -> u64 {
+        self.next_fh += 1;
+        self.handles.insert(self.next_fh, handle);
+        self.next_fh
+    }
+
+    // the kernel checked already unless left to the filesystem, the groups of
+    // the caller are only read if the file's group is not its primary one
+    fn permitted(&self, req: &Request<'_>, ino: u64, mask: i32) -> bool {
+        let in_group = |gid| {
+            gid == req.gid() || codexfsfuse_groups(req.pid()).is_some_and(|g| g.contains(&gid))
+        };
+        self.permissions != Permissions::Fs
+            || self
+                .attr(ino)
+                .is_ok_and(|attr| codexfsfuse_permitted(&attr, req.uid(), in_group, mask))
+    }
+
+    // what a FUSE inode number stands for
+    pub(crate) fn node(&self, ino: u64) -> Option<Node> {
+        self.inos.borrow().get(ino).cloned()
+    }
+
+    // the inode a FUSE inode number stands for, with its image entered
+    fn inode(&self, ino: u64) -> Option<(Entered, InodeHandle)> {
+        let node = self.node(ino)?;
+        Some((self.layers.enter(node.layer), node.inode))
+    }
+
+    fn attr(&self, ino: u64) -> Result<FileAttr> {
+        let Some((_image, inode)) = &self.inode(ino) else {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE).into());
+        };
+        let mut attr = codexfsfuse_inode_attr(inode, ino)?;
+        (attr.uid, attr.gid) = match self.squash_owner {
+            Some(owner) => owner,
+            None => (self.uid_map.map(attr.uid), self.gid_map.map(attr.gid)),
+        };
+        Ok(attr)
+    }
+
+    // Names and inode numbers of the entries of a directory as readdir lists
+    // them, "." and ".." first. opendir keeps them with its handle and offsets
+    // are positions in them.
+    pub(crate) fn dir_entries(&self, ino: u64) -> Result<Vec<(OsString, u64)>> {
+        let Some(node) = self.node(ino) else {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE).into());
+        };
+        if !node.inode.file_type().is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR).into());
+        }
+        let mut entries = vec![(".".into(), ino), ("..".into(), node.parent)];
+        for (name, child) in self.layers.entries(&node, ino)? {
+            entries.push((name, self.inos.borrow_mut().ino(child)));
+        }
+        Ok(entries)
+    }
+
+    // the stored xattrs, the image info on the root and the on-disk cost of
+    // files, the last as decimal text
+    fn xattrs(&self, ino: u64) -> Result<Xattrs> {
+        let Some((_image, inode)) = &self.inode(ino) else {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE).into());
+        };
+        let mut xattrs = fuse_read_xattrs(inode)?;
+        if let Some(file) = inode.downcast_file_ref() {
+            let info = fuse_file_info(file);
+            xattrs.push((
+                COMPRESSED_SIZE_XATTR.to_string(),
+                info.compressed_size.to_string().into_bytes(),
+            ));
+            xattrs.push((
+                EXTENTS_XATTR.to_string(),
+                info.extents.to_string().into_bytes(),
+            ));
+        }
+        if ino == FUSE_ROOT_ID {
+            if self.image_info.get().is_none() {
+                self.image_info.set(fuse_image_info()?).unwrap();
+            }
+            let image_info = self.image_info.get().unwrap().clone();
+            xattrs.push((IMAGE_INFO_XATTR.to_string(), image_info));
+            xattrs.push((STATS_XATTR.to_string(), self.stats()?));
+        }
+        Ok(xattrs)
+    }
+
+    // counters since mount, as a JSON object, to tune the block and cache
+    // sizes by
+    fn stats(&self) -> Result<Vec<u8>> {
+        let mut cache = self.readers.cache_stats();
+        for layer in 0..self.layers.0.len() {
+            let _image = self.layers.enter(layer);
+            cache.add_entered();
+        }
+        let stats = json!({
+            "reads": self.readers.reads.load(Ordering::Relaxed),
+            "read_bytes": self.readers.read_bytes.load(Ordering::Relaxed),
+            "clusters_decoded": cache.decoded,
+            "decode_seconds": cache.decode_time.as_secs_f64(),
+            "cache_hits": cache.hits,
+            "cache_misses": cache.misses,
+            "cache_used": cache.used,
+        });
+        Ok(serde_json::to_vec(&stats)?)
+    }
+}
 
 impl Filesystem for CodexFs {
     fn init(
         &mut self,
         _req: &Request<'_>,
-        _config: &mut fuser::KernelConfig,
+        config: &mut fuser::KernelConfig,
     ) -> Result<(), libc::c_int> {
         info!("Using FUSE protocol");
+        // entries come with their attributes, the kernel picks readdirplus
+        // when lookups follow a readdir, e.g. for ls -l
+        if let Err(missing) = config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO) {
+            debug!("kernel lacks readdirplus capabilities {:#x}", missing);
+        }
+        // fuser sizes max_pages, the longest request, after the larger of
+        // max_write and max_readahead; the kernel caps readahead at what it
+        // offered and both at its own page limit
+        let readahead = config
+            .set_max_readahead(self.max_read)
+            .or_else(|nearest| config.set_max_readahead(nearest));
+        let _ = config
+            .set_max_write(self.max_read)
+            .or_else(|nearest| config.set_max_write(nearest));
+        debug!("max_read {}, readahead was {:?}", self.max_read, readahead);
         Ok(())
     }
 
     fn destroy(&mut self) {}
 
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+    #[instrument(skip_all, fields(parent, name = ?name))]
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
         info!("lookup(parent: {:#x?}, name {:?})", parent, name);
-        let parent = codexfsfuse_get_inode(parent).unwrap();
-        for dentry in parent
-            .downcast_dir_ref()
-            .unwrap()
-            .itype
-            .inner
-            .borrow()
-            .dentries
-            .iter()
-        {
-            if *dentry.file_name == *name {
-                reply.entry(
-                    &Duration::new(0, 0),
-                    &codexfsfuse_inode_attr(&dentry.inode),
-                    0,
-                );
+        let Some(dir) = self.node(parent) else {
+            reply.error(libc::ESTALE);
+            return;
+        };
+        if !dir.inode.file_type().is_dir() {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+        // names are looked up in directories the caller may search
+        if !self.permitted(req, parent, libc::X_OK) {
+            reply.error(libc::EACCES);
+            return;
+        }
+        let node = match self.layers.lookup(&dir, parent, name) {
+            Ok(node) => node,
+            Err(err) => {
+                reply.error(codexfsfuse_errno(err));
                 return;
             }
+        };
+        if let Some(node) = node {
+            let ino = self.inos.borrow_mut().ino(node);
+            match self.attr(ino) {
+                Ok(attr) => reply.entry(&Duration::new(0, 0), &attr, 0),
+                Err(err) => reply.error(codexfsfuse_errno(err)),
+            }
+            return;
+        }
+        // an entry with inode 0 is cached by the kernel as a negative entry
+        match self.attr(parent) {
+            Ok(mut attr) if !self.negative_ttl.is_zero() && !self.no_cache => {
+                attr.ino = 0;
+                reply.entry(&self.negative_ttl, &attr, 0);
+            }
+            _ => reply.error(libc::ENOENT),
         }
     }
 
     fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
 
+    #[instrument(skip_all, fields(ino))]
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, fh: Option<u64>, reply: fuser::ReplyAttr) {
         info!("getattr(ino: {:#x?}, fh: {:#x?})", ino, fh);
-        let inode = fuse_load_inode(codexfsfuse_ino_to_nid(ino)).unwrap();
-        reply.attr(&Duration::new(0, 0), &codexfsfuse_inode_attr(&inode));
+        if self.node(ino).is_none() {
+            reply.error(libc::ESTALE);
+            return;
+        }
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&Duration::new(0, 0), &attr),
+            Err(err) => reply.error(codexfsfuse_errno(err)),
+        }
     }
 
     fn setattr(
@@ -149,22 +414,26 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyAttr,
     ) {
         debug!(
-            "[Not Implemented] setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
+            "[Read-only] setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
             gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
             ino, mode, uid, gid, size, fh, flags
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
+    #[instrument(skip_all, fields(ino))]
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyData) {
         info!("readlink(ino: {:#x?})", ino);
-        let inode = codexfsfuse_get_inode(ino).unwrap();
+        let Some((_image, inode)) = &self.inode(ino) else {
+            reply.error(libc::ESTALE);
+            return;
+        };
 
-        let mut buf = vec![0; inode.meta().meta_size() as usize];
-        get_sb()
-            .read_exact_at(&mut buf, inode.meta().inode_meta_off())
-            .unwrap();
-        reply.data(&buf);
+        // targets are read with the inode
+        match inode.downcast_symlink_ref() {
+            Some(link) => reply.data(link.itype.target.as_ref().unwrap().as_os_str().as_bytes()),
+            None => reply.error(libc::EINVAL),
+        }
     }
 
     fn mknod(
@@ -178,11 +447,11 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyEntry,
     ) {
         debug!(
-            "[Not Implemented] mknod(parent: {:#x?}, name: {:?}, mode: {}, \
+            "[Read-only] mknod(parent: {:#x?}, name: {:?}, mode: {}, \
             umask: {:#x?}, rdev: {})",
             parent, name, mode, umask, rdev
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
     fn mkdir(
@@ -195,26 +464,26 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyEntry,
     ) {
         debug!(
-            "[Not Implemented] mkdir(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?})",
+            "[Read-only] mkdir(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?})",
             parent, name, mode, umask
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
         debug!(
-            "[Not Implemented] unlink(parent: {:#x?}, name: {:?})",
+            "[Read-only] unlink(parent: {:#x?}, name: {:?})",
             parent, name,
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
         debug!(
-            "[Not Implemented] rmdir(parent: {:#x?}, name: {:?})",
+            "[Read-only] rmdir(parent: {:#x?}, name: {:?})",
             parent, name,
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
     fn symlink(
@@ -226,10 +495,10 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyEntry,
     ) {
         debug!(
-            "[Not Implemented] symlink(parent: {:#x?}, link_name: {:?}, target: {:?})",
+            "[Read-only] symlink(parent: {:#x?}, link_name: {:?}, target: {:?})",
             parent, link_name, target,
         );
-        reply.error(libc::EPERM);
+        reply.error(libc::EROFS);
     }
 
     fn rename(
@@ -243,11 +512,11 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyEmpty,
     ) {
         debug!(
-            "[Not Implemented] rename(parent: {:#x?}, name: {:?}, newparent: {:#x?}, \
+            "[Read-only] rename(parent: {:#x?}, name: {:?}, newparent: {:#x?}, \
             newname: {:?}, flags: {})",
             parent, name, newparent, newname, flags,
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
     fn link(
@@ -259,16 +528,45 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyEntry,
     ) {
         debug!(
-            "[Not Implemented] link(ino: {:#x?}, newparent: {:#x?}, newname: {:?})",
+            "[Read-only] link(ino: {:#x?}, newparent: {:#x?}, newname: {:?})",
             ino, newparent, newname
         );
-        reply.error(libc::EPERM);
+        reply.error(libc::EROFS);
     }
 
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        reply.opened(0, 0);
+    #[instrument(skip_all, fields(ino))]
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        info!("open(ino: {:#x?}, flags: {:#x})", ino, flags);
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let Some(node) = self.node(ino) else {
+            reply.error(libc::ESTALE);
+            return;
+        };
+        if !self.permitted(req, ino, libc::R_OK) {
+            reply.error(libc::EACCES);
+            return;
+        }
+        // decoded on the thread its reads go to
+        if let Some(file) = node.inode.downcast_file_ref()
+            && file.itype.size < self.prefetch_below
+        {
+            self.readers
+                .prefetch(node.layer, file.meta.inner.borrow().nid);
+        }
+        // the image never changes, so pages cached by an earlier open are
+        // still good
+        let open_flags = match self.direct_io || self.no_cache {
+            true => FOPEN_DIRECT_IO,
+            false => FOPEN_KEEP_CACHE,
+        };
+        let fh = self.open_handle(Handle::File { next_off: 0 });
+        reply.opened(fh, open_flags);
     }
 
+    #[instrument(skip_all, fields(ino, offset, size, cache_hit = field::Empty))]
     fn read(
         &mut self,
         _req: &Request<'_>,
@@ -285,17 +583,33 @@ impl Filesystem for CodexFs {
             flags: {:#x?}, lock_owner: {:?})",
             ino, fh, offset, size, flags, lock_owner
         );
-        assert!(offset >= 0);
-
-        let inode = codexfsfuse_get_inode(ino).unwrap();
-        let buf = if get_sb().compress {
-            fuse_read_inode_file_z(inode.downcast_file_ref().unwrap(), offset as _, size as _)
-                .unwrap()
-        } else {
-            fuse_read_inode_file(inode.downcast_file_ref().unwrap(), offset as _, size as _)
-                .unwrap()
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let Some(node) = self.node(ino) else {
+            reply.error(libc::ESTALE);
+            return;
+        };
+        let Some(file) = node.inode.downcast_file_ref() else {
+            reply.error(libc::EISDIR);
+            return;
+        };
+        let Some(Handle::File { next_off }) = self.handles.get_mut(&fh) else {
+            reply.error(libc::EBADF);
+            return;
         };
-        reply.data(&buf);
+        let readahead = *next_off == offset as u64;
+        *next_off = offset as u64 + size as u64;
+        self.readers.read(Read {
+            layer: node.layer,
+            nid: file.meta.inner.borrow().nid,
+            offset: offset as u64,
+            size,
+            readahead,
+            span: Span::current(),
+            reply: Box::new(reply),
+        });
     }
 
     fn write(
@@ -311,7 +625,7 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyWrite,
     ) {
         debug!(
-            "[Not Implemented] write(ino: {:#x?}, fh: {}, offset: {}, data.len(): {}, \
+            "[Read-only] write(ino: {:#x?}, fh: {}, offset: {}, data.len(): {}, \
             write_flags: {:#x?}, flags: {:#x?}, lock_owner: {:?})",
             ino,
             fh,
@@ -321,7 +635,7 @@ impl Filesystem for CodexFs {
             flags,
             lock_owner
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
     fn flush(
@@ -339,16 +653,18 @@ impl Filesystem for CodexFs {
         reply.error(libc::ENOSYS);
     }
 
+    #[instrument(skip_all, fields(fh))]
     fn release(
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        self.handles.remove(&fh);
         reply.ok();
     }
 
@@ -367,10 +683,33 @@ impl Filesystem for CodexFs {
         reply.error(libc::ENOSYS);
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        reply.opened(0, 0);
+    #[instrument(skip_all, fields(ino))]
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        if self.node(ino).is_none() {
+            reply.error(libc::ESTALE);
+            return;
+        }
+        if !self.permitted(req, ino, libc::R_OK) {
+            reply.error(libc::EACCES);
+            return;
+        }
+        // listed from this snapshot until released
+        let entries = match self.dir_entries(ino) {
+            Ok(entries) => entries,
+            Err(err) => {
+                reply.error(codexfsfuse_errno(err));
+                return;
+            }
+        };
+        let fh = self.open_handle(Handle::Dir(entries));
+        let open_flags = match self.no_cache {
+            true => 0,
+            false => FOPEN_KEEP_CACHE | FOPEN_CACHE_DIR,
+        };
+        reply.opened(fh, open_flags);
     }
 
+    #[instrument(skip_all, fields(ino, offset))]
     fn readdir(
         &mut self,
         _req: &Request<'_>,
@@ -381,56 +720,77 @@ impl Filesystem for CodexFs {
     ) {
         info!("readdir(ino: {:#x?}, fh: {}, offset: {})", ino, fh, offset);
 
-        let inode = codexfsfuse_get_inode(ino).unwrap();
-        log::info!("inode {:?}", inode);
-        for (index, dentry) in inode
-            .downcast_dir_ref()
-            .unwrap()
-            .itype
-            .inner
-            .borrow()
-            .dentries
-            .iter()
-            .skip(offset as usize)
-            .enumerate()
-        {
-            let buffer_full = reply.add(
-                codexfsfuse_nid_to_ino(dentry.inode.meta().inner.borrow().nid),
-                offset + index as i64 + 1,
-                codexfsfuse_codexfsfiletype_cast(dentry.file_type),
-                &dentry.file_name,
-            );
-            if buffer_full {
+        let Some(Handle::Dir(entries)) = self.handles.get(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        // the offset passed with each entry is that of the next one
+        for (index, (name, ino)) in entries.iter().enumerate().skip(offset as usize) {
+            let entry = self.node(*ino).unwrap();
+            let kind = match codexfsfuse_codexfsfiletype_cast(entry.inode.file_type()) {
+                Ok(kind) => kind,
+                Err(err) => {
+                    reply.error(codexfsfuse_errno(err));
+                    return;
+                }
+            };
+            if reply.add(*ino, index as i64 + 1, kind, name) {
                 break;
             }
         }
-
         reply.ok();
     }
 
+    #[instrument(skip_all, fields(ino, offset))]
     fn readdirplus(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
-        reply: fuser::ReplyDirectoryPlus,
+        mut reply: fuser::ReplyDirectoryPlus,
     ) {
-        debug!(
-            "[Not Implemented] readdirplus(ino: {:#x?}, fh: {}, offset: {})",
+        info!(
+            "readdirplus(ino: {:#x?}, fh: {}, offset: {})",
             ino, fh, offset
         );
-        reply.error(libc::ENOSYS);
+
+        let Some(Handle::Dir(entries)) = self.handles.get(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        for (index, (name, ino)) in entries.iter().enumerate().skip(offset as usize) {
+            let attr = match self.attr(*ino) {
+                Ok(attr) => attr,
+                Err(err) => {
+                    reply.error(codexfsfuse_errno(err));
+                    return;
+                }
+            };
+            if reply.add(
+                attr.ino,
+                index as i64 + 1,
+                name,
+                &Duration::new(0, 0),
+                &attr,
+                0,
+            ) {
+                break;
+            }
+        }
+        reply.ok();
     }
 
+    #[instrument(skip_all, fields(fh))]
     fn releasedir(
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
+        self.handles.remove(&fh);
         reply.ok();
     }
 
@@ -449,6 +809,7 @@ impl Filesystem for CodexFs {
         reply.error(libc::ENOSYS);
     }
 
+    #[instrument(skip_all)]
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
         reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
     }
@@ -464,12 +825,13 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyEmpty,
     ) {
         debug!(
-            "[Not Implemented] setxattr(ino: {:#x?}, name: {:?}, flags: {:#x?}, position: {})",
+            "[Read-only] setxattr(ino: {:#x?}, name: {:?}, flags: {:#x?}, position: {})",
             ino, name, flags, position
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
+    #[instrument(skip_all, fields(ino, name = ?name, size))]
     fn getxattr(
         &mut self,
         _req: &Request<'_>,
@@ -478,19 +840,40 @@ impl Filesystem for CodexFs {
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        debug!(
-            "[Not Implemented] getxattr(ino: {:#x?}, name: {:?}, size: {})",
+        info!(
+            "getxattr(ino: {:#x?}, name: {:?}, size: {})",
             ino, name, size
         );
-        reply.error(libc::ENOSYS);
+        let xattrs = match self.xattrs(ino) {
+            Ok(xattrs) => xattrs,
+            Err(err) => {
+                reply.error(codexfsfuse_errno(err));
+                return;
+            }
+        };
+        match xattrs.iter().find(|(n, _)| *name == **n) {
+            Some((_, value)) => codexfsfuse_reply_xattr(value, size, reply),
+            None => reply.error(libc::ENODATA),
+        }
     }
 
+    #[instrument(skip_all, fields(ino, size))]
     fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
-        debug!(
-            "[Not Implemented] listxattr(ino: {:#x?}, size: {})",
-            ino, size
-        );
-        reply.error(libc::ENOSYS);
+        info!("listxattr(ino: {:#x?}, size: {})", ino, size);
+        let xattrs = match self.xattrs(ino) {
+            Ok(xattrs) => xattrs,
+            Err(err) => {
+                reply.error(codexfsfuse_errno(err));
+                return;
+            }
+        };
+        // every name ends with a nul
+        let mut names = Vec::new();
+        for (name, _) in xattrs.iter() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        codexfsfuse_reply_xattr(&names, size, reply);
     }
 
     fn removexattr(
@@ -501,15 +884,26 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyEmpty,
     ) {
         debug!(
-            "[Not Implemented] removexattr(ino: {:#x?}, name: {:?})",
+            "[Read-only] removexattr(ino: {:#x?}, name: {:?})",
             ino, name
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
-    fn access(&mut self, _req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
-        debug!("[Not Implemented] access(ino: {:#x?}, mask: {})", ino, mask);
-        reply.error(libc::ENOSYS);
+    #[instrument(skip_all, fields(ino, mask))]
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        info!("access(ino: {:#x?}, mask: {})", ino, mask);
+        if self.node(ino).is_none() {
+            reply.error(libc::ESTALE);
+            return;
+        }
+        if mask & libc::W_OK != 0 {
+            reply.error(libc::EROFS);
+        } else if self.permitted(req, ino, mask) {
+            reply.ok();
+        } else {
+            reply.error(libc::EACCES);
+        }
     }
 
     fn create(
@@ -523,11 +917,11 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyCreate,
     ) {
         debug!(
-            "[Not Implemented] create(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?}, \
+            "[Read-only] create(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?}, \
             flags: {:#x?})",
             parent, name, mode, umask, flags
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
     fn getlk(
@@ -586,6 +980,7 @@ impl Filesystem for CodexFs {
         reply.error(libc::ENOSYS);
     }
 
+    #[instrument(skip_all, fields(ino, cmd))]
     fn ioctl(
         &mut self,
         _req: &Request<'_>,
@@ -597,9 +992,8 @@ impl Filesystem for CodexFs {
         out_size: u32,
         reply: fuser::ReplyIoctl,
     ) {
-        debug!(
-            "[Not Implemented] ioctl(ino: {:#x?}, fh: {}, flags: {}, cmd: {}, \
-            in_data.len(): {}, out_size: {})",
+        info!(
+            "ioctl(ino: {:#x?}, fh: {}, flags: {}, cmd: {:#x}, in_data.len(): {}, out_size: {})",
             ino,
             fh,
             flags,
@@ -607,7 +1001,19 @@ impl Filesystem for CodexFs {
             in_data.len(),
             out_size,
         );
-        reply.error(libc::ENOSYS);
+        if cmd != CODEXFS_IOC_GET_FILEINFO {
+            reply.error(libc::ENOTTY);
+            return;
+        }
+        let Some((_image, inode)) = &self.inode(ino) else {
+            reply.error(libc::ESTALE);
+            return;
+        };
+        let Some(file) = inode.downcast_file_ref() else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        reply.ioctl(0, bytes_of(&fuse_file_info(file)));
     }
 
     fn fallocate(
@@ -621,13 +1027,14 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyEmpty,
     ) {
         debug!(
-            "[Not Implemented] fallocate(ino: {:#x?}, fh: {}, offset: {}, \
+            "[Read-only] fallocate(ino: {:#x?}, fh: {}, offset: {}, \
             length: {}, mode: {})",
             ino, fh, offset, length, mode
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
     }
 
+    #[instrument(skip_all, fields(ino, offset, whence))]
     fn lseek(
         &mut self,
         _req: &Request<'_>,
@@ -658,11 +1065,222 @@ impl Filesystem for CodexFs {
         reply: fuser::ReplyWrite,
     ) {
         debug!(
-            "[Not Implemented] copy_file_range(ino_in: {:#x?}, fh_in: {}, \
+            "[Read-only] copy_file_range(ino_in: {:#x?}, fh_in: {}, \
             offset_in: {}, ino_out: {:#x?}, fh_out: {}, offset_out: {}, \
             len: {}, flags: {})",
             ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags
         );
-        reply.error(libc::ENOSYS);
+        reply.error(libc::EROFS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, rc::Rc};
+
+    use codexfs_core::{inode::fuse_read_inode_file_data, nid_to_inode_off};
+
+    use super::*;
+
+    fn attr(kind: fuser::FileType, perm: u16, uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
+            ino: FUSE_ROOT_ID,
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn check_permitted() {
+        let file = attr(fuser::FileType::RegularFile, 0o640, 1000, 100);
+        let no_groups = |_| false;
+        let in_100 = |gid| gid == 100;
+        // owner, group and others
+        assert!(codexfsfuse_permitted(
+            &file,
+            1000,
+            no_groups,
+            libc::R_OK | libc::W_OK
+        ));
+        assert!(!codexfsfuse_permitted(&file, 1000, no_groups, libc::X_OK));
+        assert!(codexfsfuse_permitted(&file, 1001, in_100, libc::R_OK));
+        assert!(!codexfsfuse_permitted(&file, 1001, in_100, libc::W_OK));
+        assert!(!codexfsfuse_permitted(&file, 1001, no_groups, libc::R_OK));
+        // a supplementary group counts as much as the primary one
+        let in_200_or_100 = |gid| gid == 200 || gid == 100;
+        assert!(codexfsfuse_permitted(
+            &file,
+            1001,
+            in_200_or_100,
+            libc::R_OK
+        ));
+        // the owner bits apply to the owner even if the group ones allow more
+        let group_only = attr(fuser::FileType::RegularFile, 0o040, 1000, 100);
+        assert!(!codexfsfuse_permitted(
+            &group_only,
+            1000,
+            in_100,
+            libc::R_OK
+        ));
+        // root executes what anyone may, and searches any directory
+        assert!(codexfsfuse_permitted(
+            &file,
+            0,
+            no_groups,
+            libc::R_OK | libc::W_OK
+        ));
+        assert!(!codexfsfuse_permitted(&file, 0, no_groups, libc::X_OK));
+        let dir = attr(fuser::FileType::Directory, 0o700, 1000, 100);
+        assert!(codexfsfuse_permitted(&dir, 0, no_groups, libc::X_OK));
+        assert!(!codexfsfuse_permitted(&dir, 1001, in_100, libc::X_OK));
+    }
+
+    #[test]
+    fn check_groups() {
+        let groups = codexfsfuse_groups(std::process::id()).unwrap();
+        let mut expected = vec![0; 256];
+        let n = unsafe { libc::getgroups(expected.len() as _, expected.as_mut_ptr()) };
+        expected.truncate(n as usize);
+        assert_eq!(groups, expected);
+    }
+
+    // an image codexfs-fuse/testdata holds, loaded as for a mount
+    fn load_testdata(name: &str) -> CodexFs {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name);
+        crate::load(&path, &crate::MountConfig::default()).unwrap()
+    }
+
+    fn root_names(fs: &CodexFs) -> Vec<OsString> {
+        let entries = fs.dir_entries(FUSE_ROOT_ID).unwrap();
+        let mut names: Vec<_> = entries.into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        names
+    }
+
+    // the inode number of entry name of the directory numbered dir
+    fn child(fs: &CodexFs, dir: u64, name: &str) -> u64 {
+        let entries = fs.dir_entries(dir).unwrap();
+        entries.into_iter().find(|(n, _)| n == name).unwrap().1
+    }
+
+    // what the file numbered ino holds
+    fn read_all(fs: &CodexFs, ino: u64) -> Vec<u8> {
+        let (_image, inode) = fs.inode(ino).unwrap();
+        fuse_read_inode_file_data(inode.downcast_file_ref().unwrap(), 0, 4096).unwrap()
+    }
+
+    #[test]
+    fn check_two_images() {
+        let base = load_testdata("base.img");
+        let upper = load_testdata("upper.img");
+        assert_eq!(
+            root_names(&base),
+            [".", "..", "dir", "hello", "opaque", "removed"]
+        );
+        assert_eq!(
+            root_names(&upper),
+            [".", "..", "extra", "hello", "opaque", "removed"]
+        );
+        // each reads its own data
+        for (fs, data) in [(&base, b"hello\n"), (&upper, b"upper\n")] {
+            let hello = child(fs, FUSE_ROOT_ID, "hello");
+            assert_eq!(read_all(fs, hello), data);
+        }
+    }
+
+    #[test]
+    fn check_layers() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let config = crate::MountConfig {
+            lower: vec![path.join("base.img")],
+            ..Default::default()
+        };
+        let fs = crate::load(&path.join("upper.img"), &config).unwrap();
+        // removed is whited out, the rest of both roots is merged
+        assert_eq!(
+            root_names(&fs),
+            [".", "..", "dir", "extra", "hello", "opaque"]
+        );
+        assert_eq!(read_all(&fs, child(&fs, FUSE_ROOT_ID, "hello")), b"upper\n");
+        assert_eq!(read_all(&fs, child(&fs, FUSE_ROOT_ID, "extra")), b"extra\n");
+        let dir = child(&fs, FUSE_ROOT_ID, "dir");
+        assert_eq!(read_all(&fs, child(&fs, dir, "file")), b"lower\n");
+        assert_eq!(child(&fs, dir, ".."), FUSE_ROOT_ID);
+        // the upper opaque directory hides the lower one's entries
+        let opaque = child(&fs, FUSE_ROOT_ID, "opaque");
+        let names: Vec<_> = fs
+            .dir_entries(opaque)
+            .unwrap()
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(names, [".", "..", "new"]);
+    }
+
+    #[test]
+    fn check_ino_table() {
+        let fs = load_testdata("base.img");
+        {
+            let (_image, root) = fs.inode(FUSE_ROOT_ID).unwrap();
+            assert!(Rc::ptr_eq(&root, get_sb().root()));
+        }
+        assert!(fs.node(0).is_none() && fs.node(FUSE_ROOT_ID + 1).is_none());
+        // given out in the order the entries are first listed
+        let entries = fs.dir_entries(FUSE_ROOT_ID).unwrap();
+        let inos: Vec<_> = entries.iter().map(|&(_, ino)| ino).collect();
+        assert_eq!(inos, [1, 1, 2, 3, 4, 5].map(|i| FUSE_ROOT_ID + i - 1));
+        for &(_, ino) in &entries[2..] {
+            assert_eq!(fs.attr(ino).unwrap().ino, ino);
+        }
+        // and kept
+        assert_eq!(fs.dir_entries(FUSE_ROOT_ID).unwrap(), entries);
+    }
+
+    #[test]
+    fn check_hardlinks() {
+        let fs = load_testdata("base.img");
+        let dir = child(&fs, FUSE_ROOT_ID, "dir");
+        let link = |name| child(&fs, dir, name);
+        let (link1, link2, file) = (link("link1"), link("link2"), link("file"));
+        let inode = |ino| fs.node(ino).unwrap().inode;
+        assert!(Rc::ptr_eq(&inode(link1), &inode(link2)));
+        let (attr1, attr2) = (fs.attr(link1).unwrap(), fs.attr(link2).unwrap());
+        assert_eq!((attr1.ino, attr1.nlink), (attr2.ino, 2));
+        let attr = fs.attr(file).unwrap();
+        assert_eq!((attr.nlink, attr.ino == attr1.ino), (1, false));
+    }
+
+    #[test]
+    fn check_damaged_image() {
+        let fs = load_testdata("base.img");
+        let hello_off = {
+            let (_image, hello) = fs.inode(child(&fs, FUSE_ROOT_ID, "hello")).unwrap();
+            nid_to_inode_off(hello.meta().inner.borrow().nid)
+        };
+        // the inode of hello with a mode of no known type
+        let mut img =
+            std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/base.img")).unwrap();
+        img[hello_off as usize..][..2].copy_from_slice(&0o644u16.to_le_bytes());
+        let damaged = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(damaged.path(), img).unwrap();
+        let fs = crate::load(damaged.path(), &crate::MountConfig::default()).unwrap();
+        let err = fs.dir_entries(FUSE_ROOT_ID).unwrap_err();
+        assert_eq!(codexfsfuse_errno(err), libc::EUCLEAN);
+        // the rest of the mount stays up
+        assert!(fs.attr(FUSE_ROOT_ID).is_ok());
     }
 }
@@ -0,0 +1,318 @@
+#![allow(static_mut_refs)]
+
+mod fuse;
+mod node;
+mod reader;
+
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    fs::File,
+    io, iter,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, ensure};
+use codexfs_core::{
+    CodexFsSuperBlock, cluster_cache,
+    idmap::IdMap,
+    image::Image,
+    inode,
+    sb::{self, get_sb, get_sb_mut},
+};
+pub use fuse::{CodexFs, Permissions};
+pub use fuser::{FUSE_ROOT_ID, MountOption, Notifier, Session, SessionUnmounter};
+use node::{InoTable, Layers};
+use reader::Readers;
+
+// How an image is served, what the codexfsfuse options set
+#[derive(Clone, Debug)]
+pub struct MountConfig {
+    pub negative_ttl: Duration,
+    pub cache_size: u64,
+    pub preload_metadata: bool,
+    pub squash_owner: Option<(u32, u32)>,
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    pub direct_io: bool,
+    pub permissions: Permissions,
+    pub max_read: u32,
+    pub prefetch_below: u32,
+    // reads checked against the checksums mkfs --checksums records
+    pub verify: bool,
+    // nothing cached by the kernel, for debugging
+    pub no_cache: bool,
+    // read-only, named after the image and of subtype codexfs unless given
+    pub options: Vec<MountOption>,
+    // images merged below, the topmost first, as overlayfs lowerdir
+    pub lower: Vec<PathBuf>,
+    // threads reading file data, each with caches of its own
+    pub threads: usize,
+}
+
+impl Default for MountConfig {
+    fn default() -> Self {
+        Self {
+            negative_ttl: Duration::from_secs(60),
+            cache_size: 32 << 20,
+            preload_metadata: false,
+            squash_owner: None,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            direct_io: false,
+            permissions: Permissions::default(),
+            max_read: 1 << 20,
+            prefetch_below: 64 << 10,
+            verify: false,
+            no_cache: false,
+            options: Vec::new(),
+            lower: Vec::new(),
+            threads: 4,
+        }
+    }
+}
+
+// The image an argument names: LABEL=NAME and UUID=UUID are looked up among
+// the files of the search path directories as mount(8) does among devices,
+// anything else is a path.
+pub fn find_image(spec: &str, search_path: &[PathBuf]) -> Result<PathBuf> {
+    let (label, uuid) = if let Some(label) = spec.strip_prefix("LABEL=") {
+        (Some(sb::parse_label(label)?), None)
+    } else if let Some(uuid) = spec.strip_prefix("UUID=") {
+        (None, Some(sb::parse_uuid(uuid)?))
+    } else {
+        return Ok(PathBuf::from(spec));
+    };
+    let mut found = Vec::new();
+    for dir in search_path {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("{}: {e}", dir.display());
+                continue;
+            }
+        };
+        for entry in entries {
+            let path = entry?.path();
+            // anything not a readable image is passed over
+            let Ok(codexfs_sb) = image_super_block(&path) else {
+                continue;
+            };
+            if label.is_none_or(|label| label == codexfs_sb.label)
+                && uuid.is_none_or(|uuid| uuid == codexfs_sb.uuid)
+            {
+                found.push(path);
+            }
+        }
+    }
+    ensure!(found.len() < 2, "{spec} names several images: {found:?}");
+    found
+        .pop()
+        .with_context(|| format!("no image with {spec} in {search_path:?}"))
+}
+
+fn image_super_block(path: &Path) -> Result<CodexFsSuperBlock> {
+    let file = File::open(path)?;
+    ensure!(file.metadata()?.is_file(), "not a regular file");
+    sb::read_super_block(&file)
+}
+
+// Opens the image, and the lower ones of config, and sets up the filesystem
+// serving them, with super blocks, inodes and caches of its own. The cache
+// size is shared out among the images and the threads reading them.
+pub fn load(img_path: &Path, config: &MountConfig) -> Result<CodexFs> {
+    let img_paths: Vec<_> = iter::once(img_path.to_path_buf())
+        .chain(config.lower.iter().cloned())
+        .collect();
+    // shared out among the images on the reader threads and this one
+    let cache_size = config.cache_size / (img_paths.len() * (config.threads.max(1) + 1)) as u64;
+    let mut images = Vec::new();
+    for img_path in &img_paths {
+        let image =
+            Image::open(File::open(img_path)?).with_context(|| img_path.display().to_string())?;
+        let _image = image.enter();
+        get_sb_mut().lazy_dirs = !config.preload_metadata;
+        let nid = get_sb().root().meta().inner.borrow().nid;
+        get_sb_mut().set_root(inode::fuse_load_inode(nid)?);
+        cluster_cache::set_cluster_cache(cache_size);
+        images.push(image.clone());
+    }
+    let layers = Layers(images);
+    let readers = Readers::new(&img_paths, config.threads, cache_size, config.verify)?;
+    Ok(CodexFs {
+        inos: RefCell::new(InoTable::new(layers.root()?)),
+        layers,
+        negative_ttl: config.negative_ttl,
+        direct_io: config.direct_io,
+        image_info: OnceCell::new(),
+        squash_owner: config.squash_owner,
+        uid_map: config.uid_map.clone(),
+        gid_map: config.gid_map.clone(),
+        permissions: config.permissions,
+        max_read: config.max_read,
+        prefetch_below: config.prefetch_below,
+        no_cache: config.no_cache,
+        readers,
+        handles: HashMap::new(),
+        next_fh: 0,
+    })
+}
+
+fn mount_options(img_path: &Path, config: &MountConfig) -> Vec<MountOption> {
+    let mut options = config.options.clone();
+    if config.permissions == Permissions::Kernel
+        && !options.contains(&MountOption::DefaultPermissions)
+    {
+        options.push(MountOption::DefaultPermissions);
+    }
+    if !options.contains(&MountOption::RW) && !options.contains(&MountOption::RO) {
+        options.push(MountOption::RO);
+    }
+    if !options.iter().any(|o| matches!(o, MountOption::FSName(_))) {
+        options.push(MountOption::FSName(img_path.display().to_string()));
+    }
+    if !options.iter().any(|o| matches!(o, MountOption::Subtype(_))) {
+        options.push(MountOption::Subtype("codexfs".to_string()));
+    }
+    options
+}
+
+// Mounts the image, the caller runs the session
+pub fn session(img_path: &Path, mnt_path: &Path, config: &MountConfig) -> Result<Session<CodexFs>> {
+    let fs = load(img_path, config)?;
+    Ok(Session::new(
+        fs,
+        mnt_path,
+        &mount_options(img_path, config),
+    )?)
+}
+
+// An image served from a thread of its own, unmounted when dropped
+#[derive(Debug)]
+pub struct MountHandle {
+    unmounter: Arc<Mutex<SessionUnmounter>>,
+    notifier: Notifier,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl MountHandle {
+    // Makes the kernel drop the attributes and data it cached of an inode.
+    // Inode numbers are those stat reports, FUSE_ROOT_ID for the root.
+    pub fn invalidate_inode(&self, ino: u64) -> io::Result<()> {
+        self.notifier.inval_inode(ino, 0, 0)
+    }
+
+    // makes the kernel look name up in parent again on its next use
+    pub fn invalidate_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        self.notifier.inval_entry(parent, name)
+    }
+
+    // unmounts the image from any thread, which ends the session
+    pub fn unmounter(&self) -> Arc<Mutex<SessionUnmounter>> {
+        self.unmounter.clone()
+    }
+
+    // waits for the session to end, as it does once the image is unmounted
+    pub fn join(mut self) -> io::Result<()> {
+        let thread = self.thread.take().unwrap();
+        thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("session panicked")))
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        if let Err(e) = self.unmounter.lock().unwrap().unmount() {
+            log::warn!("unmounting failed: {e}");
+        }
+        let _ = thread.join();
+    }
+}
+
+// Mounts the image and serves it from a thread of its own until the returned
+// handle is dropped or the image is unmounted. The filesystem is created on
+// that thread and stays there.
+pub fn mount(img_path: &Path, mnt_path: &Path, config: &MountConfig) -> Result<MountHandle> {
+    let (img_path, mnt_path) = (img_path.to_owned(), mnt_path.to_owned());
+    let config = config.clone();
+    let (sender, receiver) = mpsc::channel();
+    let thread = thread::Builder::new()
+        .name("codexfs-session".to_string())
+        .spawn(move || {
+            let mut session = match session(&img_path, &mnt_path, &config) {
+                Ok(session) => session,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return Ok(());
+                }
+            };
+            let _ = sender.send(Ok((session.unmount_callable(), session.notifier())));
+            session.run()
+        })?;
+    let (unmounter, notifier) = receiver.recv().context("mounting panicked")??;
+    Ok(MountHandle {
+        unmounter: Arc::new(Mutex::new(unmounter)),
+        notifier,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn check_failed_mount() {
+        let missing = env::temp_dir().join(format!("codexfs-missing-{}", process::id()));
+        let config = MountConfig::default();
+        assert!(mount(&testdata("base.img"), &missing, &config).is_err());
+        assert!(mount(&testdata("missing.img"), &env::temp_dir(), &config).is_err());
+        // nothing is left loaded
+        load(&testdata("base.img"), &config).unwrap();
+    }
+
+    #[test]
+    fn check_mount() {
+        let config = MountConfig::default();
+        let dir = env::temp_dir().join(format!("codexfs-mount-{}", process::id()));
+        let (base_mnt, upper_mnt) = (dir.join("base"), dir.join("upper"));
+        fs::create_dir_all(&base_mnt).unwrap();
+        fs::create_dir_all(&upper_mnt).unwrap();
+        let base = match mount(&testdata("base.img"), &base_mnt, &config) {
+            Ok(base) => base,
+            Err(e) => {
+                // FUSE can not be mounted everywhere the tests run
+                eprintln!("skipped: {e:#}");
+                return;
+            }
+        };
+        let upper = mount(&testdata("upper.img"), &upper_mnt, &config).unwrap();
+        assert_eq!(fs::read(base_mnt.join("hello")).unwrap(), b"hello\n");
+        assert_eq!(fs::read(upper_mnt.join("hello")).unwrap(), b"upper\n");
+        drop(base);
+        assert!(!base_mnt.join("hello").exists());
+        assert_eq!(fs::read(upper_mnt.join("extra")).unwrap(), b"extra\n");
+        // mounted again once dropped
+        let base = mount(&testdata("base.img"), &base_mnt, &config).unwrap();
+        assert_eq!(fs::read(base_mnt.join("dir/file")).unwrap(), b"lower\n");
+        drop((base, upper));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
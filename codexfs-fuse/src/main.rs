@@ -1,23 +1,109 @@
 #![feature(once_cell_get_mut)]
 #![allow(static_mut_refs)]
 
-mod fuse;
-
-use std::{cell::OnceCell, fs::File};
+use std::{
+    cell::OnceCell,
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    os::fd::FromRawFd,
+    path::{self, Path, PathBuf},
+    process, ptr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
+use anyhow::{Context, Result};
 use clap::Parser;
-use codexfs_core::sb;
-use fuse::CodexFs;
-use fuser::MountOption;
+use codexfs_core::{
+    idmap::{IdMap, parse_id_range},
+    utils::parse_size,
+};
+use codexfs_fuse::{MountConfig, MountHandle, MountOption, Permissions, SessionUnmounter};
+use log::info;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::layer::SubscriberExt;
 
 #[derive(Debug, Parser)]
 #[command(name = "codexfsfuse")]
 #[command(version("1.0"))]
 struct Args {
-    #[arg(index(1))]
-    pub img_path: String,
-    #[arg(index(2))]
-    pub mnt_path: String,
+    /// An image and its mountpoint, or several IMG=MNT pairs to mount
+    /// with one command. IMG may be LABEL=NAME or UUID=UUID of an image in
+    /// the search path, or several images, the topmost first, separated by
+    /// colons to merge them as overlayfs does
+    #[arg(required = true, value_name = "IMG MNT | IMG=MNT")]
+    pub mounts: Vec<String>,
+    /// Look for LABEL= and UUID= images among the files of DIR, may be
+    /// repeated [default: the current directory]
+    #[arg(long, value_name = "DIR")]
+    pub search_path: Vec<PathBuf>,
+    /// Seconds the kernel may cache a failed lookup, 0 to ask again every
+    /// time. The image never changes, so a missing name stays missing
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub negative_ttl: u64,
+    /// Keep up to SIZE bytes of decompressed clusters for later reads
+    /// (K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", default_value = "32M", value_parser = parse_size)]
+    pub cache_size: u64,
+    /// Let the kernel send reads and read ahead up to SIZE bytes at once,
+    /// as far as it allows (K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = parse_size)]
+    pub max_read: u64,
+    /// Decode compressed files smaller than SIZE whole as soon as they are
+    /// opened, as their reads nearly always follow, 0 to leave it to reads
+    /// (K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", default_value = "64K", value_parser = parse_size)]
+    pub prefetch_below: u64,
+    /// Read file data on N threads, so a slow decompression holds up only
+    /// the reads sent to its thread. Each has caches of its own, the cache
+    /// size is shared out among them
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    pub threads: usize,
+    /// Check the data of every read against the crc32 mkfs --checksums
+    /// recorded for it before replying, failing reads of damaged data with
+    /// EIO, for images on unreliable storage
+    #[arg(long)]
+    pub verify: bool,
+    /// Read every directory of the image at mount instead of on first use,
+    /// so the first walk of the tree (find, ls -R) need not wait on it
+    #[arg(long)]
+    pub preload_metadata: bool,
+    /// Present every inode as owned by the user and group mounting the image
+    #[arg(long, conflicts_with_all = ["map_uid", "map_gid"])]
+    pub squash_uids: bool,
+    /// Present image uid SRC as DST, or COUNT uids from SRC on as those from
+    /// DST on, may be repeated
+    #[arg(long, value_name = "SRC:DST[:COUNT]", value_parser = parse_id_range)]
+    pub map_uid: Vec<(u32, u32, u32)>,
+    /// Present image gid SRC as DST, as --map-uid does uids
+    #[arg(long, value_name = "SRC:DST[:COUNT]", value_parser = parse_id_range)]
+    pub map_gid: Vec<(u32, u32, u32)>,
+    /// Let the kernel cache no file data, directories or failed lookups, so
+    /// every access reaches the driver, for debugging
+    #[arg(long)]
+    pub no_cache: bool,
+    /// Who checks requests against the modes of the image: kernel, fs (the
+    /// driver) or all to let any user read anything, for inspecting images
+    /// with restrictive modes
+    #[arg(long, value_name = "MODE", default_value = "fs")]
+    pub permissions: Permissions,
+    /// Mount options separated by commas: ro, allow_other, allow_root,
+    /// default_permissions, auto_unmount, direct_io, fsname=NAME, subtype=NAME
+    /// and the usual flags such as nodev or noexec; others go to the kernel
+    /// as they are
+    #[arg(short = 'o', value_name = "OPTIONS", value_delimiter = ',')]
+    pub options: Vec<String>,
+    /// Write a Chrome trace of every request to FILE, with the time spent
+    /// reading the image, decoding and copying, for chrome://tracing or
+    /// Perfetto. Takes a single mount
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<PathBuf>,
+    /// Stay in the foreground instead of detaching once mounted, logging to
+    /// stderr
+    #[arg(short, long)]
+    pub foreground: bool,
 }
 
 static mut ARGS: OnceCell<Args> = OnceCell::new();
@@ -38,13 +124,260 @@ fn parse_args() -> &'static Args {
     get_args()
 }
 
+fn parse_mount_option(s: &str) -> MountOption {
+    match s {
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        _ => match s.split_once('=') {
+            Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+            Some(("subtype", name)) => MountOption::Subtype(name.to_string()),
+            _ => MountOption::CUSTOM(s.to_string()),
+        },
+    }
+}
+
+fn mount_config(args: &Args) -> MountConfig {
+    MountConfig {
+        negative_ttl: Duration::from_secs(args.negative_ttl),
+        cache_size: args.cache_size,
+        preload_metadata: args.preload_metadata,
+        squash_owner: args
+            .squash_uids
+            .then(|| unsafe { (libc::getuid(), libc::getgid()) }),
+        uid_map: id_map(&args.map_uid),
+        gid_map: id_map(&args.map_gid),
+        // served by the driver, not a kernel mount option
+        direct_io: args.options.iter().any(|s| s == "direct_io"),
+        permissions: args.permissions,
+        max_read: args.max_read.try_into().unwrap_or(u32::MAX),
+        prefetch_below: args.prefetch_below.try_into().unwrap_or(u32::MAX),
+        verify: args.verify,
+        no_cache: args.no_cache,
+        options: args
+            .options
+            .iter()
+            .filter(|s| *s != "direct_io")
+            .map(|s| parse_mount_option(s))
+            .collect(),
+        lower: Vec::new(),
+        threads: args.threads,
+    }
+}
+
+// SIGINT and SIGTERM are taken by the thread unmount_on_signal starts, every
+// thread started after this inherits the mask
+fn block_signals() -> libc::sigset_t {
+    let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+    }
+    set
+}
+
+// SIGINT and SIGTERM unmount every image, which ends the sessions, instead of
+// killing the process with dead mountpoints left behind
+fn unmount_on_signal(set: libc::sigset_t, unmounters: Vec<Arc<Mutex<SessionUnmounter>>>) {
+    thread::spawn(move || {
+        let mut sig = 0;
+        unsafe { libc::sigwait(&set, &mut sig) };
+        info!("signal {sig}, unmounting");
+        for unmounter in unmounters {
+            if let Err(e) = unmounter.lock().unwrap().unmount() {
+                log::warn!("unmounting failed: {e}");
+            }
+        }
+    });
+}
+
+// Forks, the parent exiting with the status the child reports through the
+// returned pipe, 1 if it exits without one, e.g. as mounting failed. This
+// happens before any thread is started, which a fork would lose.
+fn detach() -> File {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        panic!("detaching failed: {}", io::Error::last_os_error());
+    }
+    let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+        0 => {
+            drop(reader);
+            unsafe { libc::setsid() };
+            writer
+        }
+        _ => {
+            drop(writer);
+            let mut status = [1];
+            let _ = (&reader).read_exact(&mut status);
+            process::exit(status[0].into());
+        }
+    }
+}
+
+// once everything is mounted the parent exits, what is left goes nowhere
+fn finish_detach(mut status: File) {
+    let _ = status.write_all(&[0]);
+    unsafe {
+        libc::chdir(c"/".as_ptr());
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        for fd in 0..3 {
+            libc::dup2(null, fd);
+        }
+        libc::close(null);
+    }
+}
+
+// IMG=MNT split after the image, which may be LABEL=NAME or UUID=UUID
+fn split_pair(m: &str) -> Option<(&str, &str)> {
+    let start = ["LABEL=", "UUID="]
+        .iter()
+        .find(|prefix| m.starts_with(*prefix))
+        .map_or(0, |prefix| prefix.len());
+    let i = start + m[start..].find('=')?;
+    Some((&m[..i], &m[i + 1..]))
+}
+
+// (image, mountpoint) of every IMG MNT or IMG=MNT argument
+fn mount_pairs(mounts: &[String]) -> Result<Vec<(&str, &str)>> {
+    if let [img_path, mnt_path] = mounts
+        && split_pair(img_path).is_none()
+        && !mnt_path.contains('=')
+    {
+        return Ok(vec![(img_path, mnt_path)]);
+    }
+    mounts
+        .iter()
+        .map(|m| split_pair(m).with_context(|| format!("{m}: expected IMG=MNT")))
+        .collect()
+}
+
+fn id_map(ranges: &[(u32, u32, u32)]) -> IdMap {
+    let mut map = IdMap::default();
+    for &(from, to, count) in ranges {
+        map.push(from, to, count);
+    }
+    map
+}
+
 fn main() {
     env_logger::init();
 
     let args = parse_args();
-    let img_file = File::open(&args.img_path).unwrap();
-    sb::fuse_load_super_block(img_file).unwrap();
+    let pairs = mount_pairs(&args.mounts).unwrap();
+    assert!(
+        args.trace.is_none() || pairs.len() == 1,
+        "--trace takes a single mount"
+    );
+    // detaching moves to /
+    let trace_path = args.trace.as_deref().map(|p| path::absolute(p).unwrap());
+    let signals = block_signals();
+    // mount errors are reported before the parent exits
+    let status = (!args.foreground).then(detach);
+    // the trace is written by a thread of its own, which must be started
+    // after detaching, and completed once the sessions end
+    let _trace = trace_path.map(|p| trace_to(&p));
+    // every image is served by a thread of this process
+    let mounts: Vec<_> = pairs
+        .iter()
+        .map(|&(img_path, mnt_path)| mount(img_path, mnt_path))
+        .collect();
+    if let Some(status) = status {
+        finish_detach(status);
+    }
+    unmount_on_signal(signals, mounts.iter().map(|m| m.unmounter()).collect());
+    for mount in mounts {
+        mount.join().unwrap();
+    }
+}
 
-    let options = vec![MountOption::FSName("fuser".to_string())];
-    fuser::mount2(CodexFs, &args.mnt_path, &options).unwrap();
+fn mount(img_path: &str, mnt_path: &str) -> MountHandle {
+    let args = get_args();
+    let search_path = if args.search_path.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        args.search_path.clone()
+    };
+    let mut img_paths = img_path
+        .split(':')
+        .map(|spec| codexfs_fuse::find_image(spec, &search_path).unwrap());
+    let img_path = img_paths.next().unwrap();
+    let config = MountConfig {
+        lower: img_paths.collect(),
+        ..mount_config(args)
+    };
+    codexfs_fuse::mount(&img_path, Path::new(mnt_path), &config).unwrap()
+}
+
+fn trace_to(path: &Path) -> FlushGuard {
+    let (layer, guard) = ChromeLayerBuilder::new()
+        .file(path)
+        .include_args(true)
+        .build();
+    // log records still go to env_logger
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer)).unwrap();
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_split_pair() {
+        assert_eq!(split_pair("a.img=/mnt/a"), Some(("a.img", "/mnt/a")));
+        assert_eq!(split_pair("LABEL=root=/mnt"), Some(("LABEL=root", "/mnt")));
+        assert_eq!(split_pair("UUID=1234=/mnt"), Some(("UUID=1234", "/mnt")));
+        assert_eq!(split_pair("LABEL=root"), None);
+        assert_eq!(split_pair("a.img"), None);
+    }
+
+    #[test]
+    fn check_mount_pairs() {
+        let mounts = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let single = mounts(&["a.img", "/mnt/a"]);
+        assert_eq!(mount_pairs(&single).unwrap(), [("a.img", "/mnt/a")]);
+        let labeled = mounts(&["LABEL=root", "/mnt"]);
+        assert_eq!(mount_pairs(&labeled).unwrap(), [("LABEL=root", "/mnt")]);
+        let pairs = mounts(&["a.img=/mnt/a", "LABEL=b=/mnt/b"]);
+        assert_eq!(
+            mount_pairs(&pairs).unwrap(),
+            [("a.img", "/mnt/a"), ("LABEL=b", "/mnt/b")]
+        );
+        assert!(mount_pairs(&mounts(&["a.img=/mnt/a", "b.img"])).is_err());
+        assert!(mount_pairs(&mounts(&["a.img"])).is_err());
+    }
+
+    #[test]
+    fn check_parse_mount_option() {
+        assert_eq!(parse_mount_option("ro"), MountOption::RO);
+        assert_eq!(parse_mount_option("allow_other"), MountOption::AllowOther);
+        assert_eq!(parse_mount_option("noexec"), MountOption::NoExec);
+        assert_eq!(
+            parse_mount_option("fsname=image"),
+            MountOption::FSName("image".to_string())
+        );
+        assert_eq!(
+            parse_mount_option("subtype=codexfs"),
+            MountOption::Subtype("codexfs".to_string())
+        );
+        assert_eq!(
+            parse_mount_option("max_read=4096"),
+            MountOption::CUSTOM("max_read=4096".to_string())
+        );
+    }
 }
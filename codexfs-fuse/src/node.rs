@@ -0,0 +1,216 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    iter,
+    rc::Rc,
+};
+
+use anyhow::Result;
+use codexfs_core::{
+    image::{Entered, Image},
+    ino_t,
+    inode::{InodeHandle, fuse_load_dir, fuse_read_xattrs},
+    sb::get_sb,
+};
+use fuser::FUSE_ROOT_ID;
+
+const OPAQUE_XATTRS: [&str; 2] = ["trusted.overlay.opaque", "user.overlay.opaque"];
+
+// An inode as the mount shows it: an inode of the image of layer, 0 being the
+// top one, and for a directory the directories at its path in the layers
+// below, down to the first opaque one, whose entries show through.
+#[derive(Clone, Debug)]
+pub(crate) struct Node {
+    pub(crate) layer: usize,
+    pub(crate) inode: InodeHandle,
+    pub(crate) lower: Vec<(usize, InodeHandle)>,
+    pub(crate) parent: u64, // FUSE inode number of the directory holding it
+}
+
+impl Node {
+    // the directories merged into this one, the top one first
+    fn dirs(&self) -> impl Iterator<Item = (usize, &InodeHandle)> {
+        iter::once((self.layer, &self.inode)).chain(self.lower.iter().map(|(l, i)| (*l, i)))
+    }
+}
+
+// FUSE inode numbers, given out from FUSE_ROOT_ID on as the kernel first
+// sees an inode and kept for the life of the mount, so they depend neither on
+// where inodes sit in the image nor on how mkfs numbered them. Hardlinks share
+// the image's inode and so one number.
+#[derive(Debug, Default)]
+pub(crate) struct InoTable {
+    nodes: Vec<Node>,                   // by FUSE inode number
+    inos: HashMap<(usize, ino_t), u64>, // by layer and the image's inode number
+}
+
+impl InoTable {
+    pub(crate) fn new(root: Node) -> Self {
+        let mut table = Self::default();
+        table.ino(root);
+        table
+    }
+
+    pub(crate) fn get(&self, ino: u64) -> Option<&Node> {
+        self.nodes
+            .get(usize::try_from(ino.checked_sub(FUSE_ROOT_ID)?).ok()?)
+    }
+
+    pub(crate) fn ino(&mut self, node: Node) -> u64 {
+        let next = FUSE_ROOT_ID + self.nodes.len() as u64;
+        let key = (node.layer, node.inode.meta().ino);
+        let ino = *self.inos.entry(key).or_insert(next);
+        if ino == next {
+            self.nodes.push(node);
+        }
+        ino
+    }
+}
+
+// The images of a mount, the top one first, merged as overlayfs merges its
+// layers: a path an upper image has hides it in the ones below, directories
+// are merged. A 0:0 character device in an upper image deletes the path
+// below, and a directory marked opaque hides what is below it. The bottom
+// image is shown as it is.
+#[derive(Debug)]
+pub(crate) struct Layers(pub(crate) Vec<Rc<Image>>);
+
+impl Layers {
+    // makes the image of layer the one codexfs-core reads
+    pub(crate) fn enter(&self, layer: usize) -> Entered {
+        self.0[layer].enter()
+    }
+
+    fn is_upper(&self, layer: usize) -> bool {
+        layer + 1 < self.0.len()
+    }
+
+    // both with the image of layer entered
+    fn is_whiteout(&self, layer: usize, inode: &InodeHandle) -> bool {
+        self.is_upper(layer)
+            && inode
+                .downcast_special_ref()
+                .is_some_and(|i| inode.file_type().is_char_device() && i.itype.rdev == 0)
+    }
+
+    fn is_opaque(&self, layer: usize, inode: &InodeHandle) -> Result<bool> {
+        if !self.is_upper(layer) {
+            return Ok(false);
+        }
+        let xattrs = fuse_read_xattrs(inode)?;
+        Ok(xattrs
+            .iter()
+            .any(|(name, value)| OPAQUE_XATTRS.contains(&name.as_str()) && value == b"y"))
+    }
+
+    // the roots of the images, merged
+    pub(crate) fn root(&self) -> Result<Node> {
+        let mut dirs = Vec::new();
+        for layer in 0..self.0.len() {
+            let _image = self.enter(layer);
+            let root = get_sb().root().clone();
+            let opaque = self.is_opaque(layer, &root)?;
+            dirs.push((layer, root));
+            if opaque {
+                break;
+            }
+        }
+        let (layer, inode) = dirs.remove(0);
+        Ok(Node {
+            layer,
+            inode,
+            lower: dirs,
+            parent: FUSE_ROOT_ID,
+        })
+    }
+
+    // What name in dir, numbered dir_ino, stands for. None if no image has it
+    // or one above those that do deletes it.
+    pub(crate) fn lookup(&self, dir: &Node, dir_ino: u64, name: &OsStr) -> Result<Option<Node>> {
+        self.merge(dir, dir_ino, |_, dir| codexfsfuse_child(dir, name))
+    }
+
+    // the entries of dir, numbered dir_ino, those of upper images first
+    pub(crate) fn entries(&self, dir: &Node, dir_ino: u64) -> Result<Vec<(OsString, Node)>> {
+        let mut names = Vec::new();
+        let mut seen = HashSet::new();
+        let mut children = Vec::new(); // of the directory of each layer, by name
+        for (layer, inode) in dir.dirs() {
+            let _image = self.enter(layer);
+            fuse_load_dir(inode)?;
+            let mut by_name = HashMap::new();
+            if let Some(merged) = inode.downcast_dir_ref() {
+                for dentry in merged.itype.inner.borrow().dentries.iter() {
+                    if seen.insert(dentry.file_name.clone()) {
+                        names.push(dentry.file_name.clone());
+                    }
+                    by_name
+                        .entry(dentry.file_name.clone())
+                        .or_insert_with(|| dentry.inode.clone());
+                }
+            }
+            children.push(by_name);
+        }
+        let mut entries = Vec::new();
+        for name in names {
+            let node = self.merge(dir, dir_ino, |i, _| Ok(children[i].get(&name).cloned()))?;
+            if let Some(node) = node {
+                entries.push((name, node));
+            }
+        }
+        Ok(entries)
+    }
+
+    // The node of an entry of dir, numbered dir_ino, from child giving the
+    // entry in the i-th directory merged into dir, with its image entered.
+    fn merge(
+        &self,
+        dir: &Node,
+        dir_ino: u64,
+        mut child: impl FnMut(usize, &InodeHandle) -> Result<Option<InodeHandle>>,
+    ) -> Result<Option<Node>> {
+        let mut found: Vec<(usize, InodeHandle)> = Vec::new();
+        for (i, (layer, dir)) in dir.dirs().enumerate() {
+            let _image = self.enter(layer);
+            let Some(child) = child(i, dir)? else {
+                continue;
+            };
+            if self.is_whiteout(layer, &child) {
+                break;
+            }
+            // a file hides what is below it, and a directory a file below it
+            if !child.file_type().is_dir() {
+                if found.is_empty() {
+                    found.push((layer, child));
+                }
+                break;
+            }
+            let opaque = self.is_opaque(layer, &child)?;
+            found.push((layer, child));
+            if opaque {
+                break;
+            }
+        }
+        if found.is_empty() {
+            return Ok(None);
+        }
+        let (layer, inode) = found.remove(0);
+        Ok(Some(Node {
+            layer,
+            inode,
+            lower: found,
+            parent: dir_ino,
+        }))
+    }
+}
+
+// the entry name of dir in the image entered, None if there is none
+fn codexfsfuse_child(dir: &InodeHandle, name: &OsStr) -> Result<Option<InodeHandle>> {
+    fuse_load_dir(dir)?;
+    let Some(dir) = dir.downcast_dir_ref() else {
+        return Ok(None);
+    };
+    let inner = dir.itype.inner.borrow();
+    let child = inner.dentries.iter().find(|d| d.file_name == name);
+    Ok(child.map(|d| d.inode.clone()))
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::PathBuf,
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SendError, Sender, SyncSender},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use codexfs_core::{
+    checksum::fuse_load_checksums,
+    cluster_cache::{get_cluster_cache_mut, set_cluster_cache},
+    image::Image,
+    inode::{
+        InodeHandle, fuse_load_inode, fuse_prefetch_file, fuse_read_cached,
+        fuse_read_inode_file_data,
+    },
+    nid_t,
+};
+use tracing::Span;
+
+use crate::fuse::codexfsfuse_errno;
+
+// Where a reader sends what it read: the kernel when serving, a channel in
+// tests, as fuser replies can not be made outside a session.
+pub(crate) trait ReadReply: Send + 'static {
+    fn data(self: Box<Self>, data: &[u8]);
+    fn error(self: Box<Self>, errno: i32);
+}
+
+impl ReadReply for fuser::ReplyData {
+    fn data(self: Box<Self>, data: &[u8]) {
+        fuser::ReplyData::data(*self, data)
+    }
+
+    fn error(self: Box<Self>, errno: i32) {
+        fuser::ReplyData::error(*self, errno)
+    }
+}
+
+// a read of the file nid in the image of layer
+pub(crate) struct Read {
+    pub(crate) layer: usize,
+    pub(crate) nid: nid_t,
+    pub(crate) offset: u64,
+    pub(crate) size: u32,
+    pub(crate) readahead: bool, // going on from the last read of the handle
+    pub(crate) span: Span,      // of the request, cache hits are recorded in
+    pub(crate) reply: Box<dyn ReadReply>,
+}
+
+enum Job {
+    Read(Read),
+    Prefetch(usize, nid_t),
+    Stats(SyncSender<CacheStats>),
+}
+
+// counters of cluster caches, summed over images and threads
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CacheStats {
+    pub(crate) decoded: u64,
+    pub(crate) decode_time: Duration,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) used: u64,
+}
+
+impl CacheStats {
+    // adds those of the cache of the image entered
+    pub(crate) fn add_entered(&mut self) {
+        let cache = get_cluster_cache_mut().unwrap();
+        self.decoded += cache.decoded;
+        self.decode_time += cache.decode_time;
+        self.hits += cache.hits;
+        self.misses += cache.misses;
+        self.used += cache.used();
+    }
+
+    fn add(&mut self, other: CacheStats) {
+        self.decoded += other.decoded;
+        self.decode_time += other.decode_time;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.used += other.used;
+    }
+}
+
+// File data is read on these threads, so a slow decompression holds up the
+// reads sent to its thread only, while lookups and the rest are answered by
+// the session. As codexfs-core state is per thread, each thread opens the
+// images again, with inodes and cluster caches of its own, and a file's reads
+// all go to one thread so its clusters are cached once.
+#[derive(Debug)]
+pub(crate) struct Readers {
+    senders: Vec<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+    pub(crate) reads: Arc<AtomicU64>,
+    pub(crate) read_bytes: Arc<AtomicU64>,
+}
+
+// what a reader thread keeps
+struct Reader {
+    layers: Vec<Rc<Image>>,
+    files: HashMap<(usize, nid_t), InodeHandle>,
+    reads: Arc<AtomicU64>,
+    read_bytes: Arc<AtomicU64>,
+}
+
+impl Readers {
+    // Threads with a cluster cache of cache_size bytes for each image,
+    // checking the data they read against the checksums of the images if
+    // verify is set.
+    pub(crate) fn new(
+        img_paths: &[PathBuf],
+        threads: usize,
+        cache_size: u64,
+        verify: bool,
+    ) -> Result<Self> {
+        let reads = Arc::new(AtomicU64::new(0));
+        let read_bytes = Arc::new(AtomicU64::new(0));
+        let (mut senders, mut handles, mut opened) = (Vec::new(), Vec::new(), Vec::new());
+        for i in 0..threads.max(1) {
+            let (sender, jobs) = mpsc::channel();
+            let (done, open) = mpsc::sync_channel(1);
+            let img_paths = img_paths.to_vec();
+            let (reads, read_bytes) = (reads.clone(), read_bytes.clone());
+            let thread = thread::Builder::new()
+                .name(format!("codexfs-reader-{i}"))
+                .spawn(move || {
+                    let layers = match codexfsfuse_open_images(&img_paths, cache_size, verify) {
+                        Ok(layers) => layers,
+                        Err(err) => {
+                            let _ = done.send(Err(err));
+                            return;
+                        }
+                    };
+                    let _ = done.send(Ok(()));
+                    let reader = Reader {
+                        layers,
+                        files: HashMap::new(),
+                        reads,
+                        read_bytes,
+                    };
+                    reader.run(jobs);
+                })?;
+            senders.push(sender);
+            handles.push(thread);
+            opened.push(open);
+        }
+        let readers = Self {
+            senders,
+            threads: handles,
+            reads,
+            read_bytes,
+        };
+        for open in opened {
+            open.recv().context("reader thread died")??;
+        }
+        Ok(readers)
+    }
+
+    fn send(&self, layer: usize, nid: nid_t, job: Job) {
+        let thread = (nid as usize).wrapping_add(layer) % self.senders.len();
+        if let Err(SendError(Job::Read(read))) = self.senders[thread].send(job) {
+            read.reply.error(libc::EIO);
+        }
+    }
+
+    // replied to by the thread of the file
+    pub(crate) fn read(&self, read: Read) {
+        self.send(read.layer, read.nid, Job::Read(read));
+    }
+
+    // starts decoding the file whole on its thread
+    pub(crate) fn prefetch(&self, layer: usize, nid: nid_t) {
+        self.send(layer, nid, Job::Prefetch(layer, nid));
+    }
+
+    // those of every thread, once it is done with the reads sent before
+    pub(crate) fn cache_stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for sender in &self.senders {
+            let (reply, stats_of) = mpsc::sync_channel(1);
+            if sender.send(Job::Stats(reply)).is_ok()
+                && let Ok(thread_stats) = stats_of.recv()
+            {
+                stats.add(thread_stats);
+            }
+        }
+        stats
+    }
+}
+
+impl Drop for Readers {
+    // the reads sent are served first
+    fn drop(&mut self) {
+        self.senders.clear();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+// the images, topmost first, opened on the calling thread
+fn codexfsfuse_open_images(
+    img_paths: &[PathBuf],
+    cache_size: u64,
+    verify: bool,
+) -> Result<Vec<Rc<Image>>> {
+    let mut layers = Vec::new();
+    for img_path in img_paths {
+        let image =
+            Image::open(File::open(img_path)?).with_context(|| img_path.display().to_string())?;
+        let _image = image.enter();
+        set_cluster_cache(cache_size);
+        if verify {
+            fuse_load_checksums().with_context(|| img_path.display().to_string())?;
+        }
+        layers.push(image.clone());
+    }
+    Ok(layers)
+}
+
+impl Reader {
+    fn run(mut self, jobs: Receiver<Job>) {
+        for job in jobs {
+            match job {
+                Job::Read(read) => self.read(read),
+                Job::Prefetch(layer, nid) => {
+                    let _image = self.layers[layer].enter();
+                    if let Ok(inode) = self.file(layer, nid)
+                        && let Some(file) = inode.downcast_file_ref()
+                    {
+                        fuse_prefetch_file(file);
+                    }
+                }
+                Job::Stats(reply) => {
+                    let mut stats = CacheStats::default();
+                    for image in &self.layers {
+                        let _image = image.enter();
+                        stats.add_entered();
+                    }
+                    let _ = reply.send(stats);
+                }
+            }
+        }
+    }
+
+    // the inode of file nid, with the image of layer entered
+    fn file(&mut self, layer: usize, nid: nid_t) -> Result<InodeHandle> {
+        if let Some(inode) = self.files.get(&(layer, nid)) {
+            return Ok(inode.clone());
+        }
+        let inode = fuse_load_inode(nid)?;
+        self.files.insert((layer, nid), inode.clone());
+        Ok(inode)
+    }
+
+    fn read(&mut self, read: Read) {
+        let Read {
+            layer,
+            nid,
+            offset,
+            size,
+            readahead,
+            span,
+            reply,
+        } = read;
+        let _span = span.enter();
+        let _image = self.layers[layer].enter();
+        let inode = match self.file(layer, nid) {
+            Ok(inode) => inode,
+            Err(err) => return reply.error(codexfsfuse_errno(err)),
+        };
+        let Some(file) = inode.downcast_file_ref() else {
+            return reply.error(libc::EISDIR);
+        };
+        // clusters are decoded ahead only for reads going on from the last
+        get_cluster_cache_mut().unwrap().readahead = readahead;
+        let (offset, size) = (offset.min(u32::MAX as u64) as u32, size);
+        // reads within a cached cluster are replied from it as they are
+        let cached = fuse_read_cached(file, offset, size);
+        span.record("cache_hit", cached.is_some());
+        let read = match cached {
+            Some(_) => Vec::new(),
+            None => match fuse_read_inode_file_data(file, offset, size) {
+                Ok(buf) => buf,
+                Err(err) => return reply.error(codexfsfuse_errno(err)),
+            },
+        };
+        let buf = match &cached {
+            Some((cluster, range)) => &cluster[range.clone()],
+            None => &read[..],
+        };
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        reply.data(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, path::Path};
+
+    use codexfs_core::{blk_id_to_addr, sb::get_sb};
+    use fuser::FUSE_ROOT_ID;
+
+    use super::*;
+    use crate::CodexFs;
+
+    struct Reply(Sender<Result<Vec<u8>, i32>>);
+
+    impl ReadReply for Reply {
+        fn data(self: Box<Self>, data: &[u8]) {
+            let _ = self.0.send(Ok(data.to_vec()));
+        }
+
+        fn error(self: Box<Self>, errno: i32) {
+            let _ = self.0.send(Err(errno));
+        }
+    }
+
+    // sends a read, the reply comes back on the receiver
+    fn send_read(readers: &Readers, nid: nid_t, offset: u64) -> Receiver<Result<Vec<u8>, i32>> {
+        let (sender, receiver) = mpsc::channel();
+        readers.read(Read {
+            layer: 0,
+            nid,
+            offset,
+            size: 4096,
+            readahead: false,
+            span: Span::none(),
+            reply: Box::new(Reply(sender)),
+        });
+        receiver
+    }
+
+    // names and nids of the files in the root
+    fn root_files(fs: &CodexFs) -> Vec<(OsString, nid_t)> {
+        let mut files = Vec::new();
+        for (name, ino) in fs.dir_entries(FUSE_ROOT_ID).unwrap() {
+            let node = fs.node(ino).unwrap();
+            if node.inode.file_type().is_file() {
+                files.push((name, node.inode.meta().inner.borrow().nid));
+            }
+        }
+        files
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn check_readers() {
+        let fs = crate::load(&testdata("base.img"), &crate::MountConfig::default()).unwrap();
+        let files = root_files(&fs);
+        assert_eq!(files.len(), 2);
+        // sent all at once, to threads of their own or not
+        let replies: Vec<_> = (0..16)
+            .map(|i| send_read(&fs.readers, files[i % 2].1, 0))
+            .collect();
+        for (i, reply) in replies.into_iter().enumerate() {
+            let expected: &[u8] = match files[i % 2].0.to_str().unwrap() {
+                "hello" => b"hello\n",
+                _ => b"gone\n",
+            };
+            assert_eq!(reply.recv().unwrap().unwrap(), expected);
+        }
+        assert_eq!(
+            send_read(&fs.readers, files[0].1, 1 << 20)
+                .recv()
+                .unwrap()
+                .unwrap(),
+            b""
+        );
+        assert_eq!(fs.readers.reads.load(Ordering::Relaxed), 17);
+        // a nid that is no inode fails the read only
+        assert!(send_read(&fs.readers, 1 << 30, 0).recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn check_verify() {
+        let config = crate::MountConfig {
+            verify: true,
+            ..Default::default()
+        };
+        let fs = crate::load(&testdata("base.img"), &config).unwrap();
+        let hello = root_files(&fs)
+            .into_iter()
+            .find(|(n, _)| n == "hello")
+            .unwrap()
+            .1;
+        assert_eq!(
+            send_read(&fs.readers, hello, 0).recv().unwrap().unwrap(),
+            b"hello\n"
+        );
+        // the piece holding the data of hello damaged
+        let piece = {
+            let _image = fs.layers.enter(0);
+            fuse_load_checksums().unwrap();
+            let inode = fuse_load_inode(hello).unwrap();
+            let inner = inode.downcast_file_ref().unwrap().itype.inner.borrow();
+            let addr = match inner.extents.first() {
+                Some(extent) => blk_id_to_addr(extent.blk_id),
+                None => blk_id_to_addr(inner.blk_id.unwrap()) + inner.blk_off.unwrap() as u64,
+            };
+            let checksums = get_sb().checksums.as_ref().unwrap();
+            *checksums
+                .iter()
+                .find(|c| c.addr <= addr && addr < c.addr + c.len as u64)
+                .unwrap()
+        };
+        let mut img = std::fs::read(testdata("base.img")).unwrap();
+        img[(piece.addr + piece.len as u64 - 1) as usize] ^= 1;
+        let damaged = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(damaged.path(), img).unwrap();
+        let fs = crate::load(damaged.path(), &config).unwrap();
+        assert_eq!(
+            send_read(&fs.readers, hello, 0).recv().unwrap(),
+            Err(libc::EIO)
+        );
+        // images without checksums are refused
+        assert!(crate::load(&testdata("upper.img"), &config).is_err());
+    }
+}
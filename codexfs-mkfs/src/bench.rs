@@ -0,0 +1,124 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::Result;
+use clap::Args;
+use codexfs_core::{blk_size_t, compress::Codec};
+
+const DEFAULT_CODECS: &[&str] = &[
+    "store", "lzma:0", "lzma:1", "lzma:2", "lzma:3", "lzma:4", "lzma:5", "lzma:6", "lzma:7",
+    "lzma:8", "lzma:9", "lzma:9e", "xz:6",
+];
+
+/// Compress a sample of a source tree with each codec and print the ratio and
+/// throughput, to pick settings before a long build
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Codecs to compare, defaults to store and every lzma preset
+    #[arg(long, value_delimiter = ',')]
+    pub codecs: Vec<Codec>,
+    #[arg(short, long, default_value_t = 4096)]
+    pub blksz: blk_size_t,
+    /// Stop sampling files once this many bytes are read
+    #[arg(long, value_name = "BYTES", default_value_t = 64 << 20)]
+    pub sample: u64,
+    pub src_path: String,
+}
+
+pub fn bench(args: &BenchArgs) -> Result<()> {
+    let mut sample = Vec::new();
+    read_sample(Path::new(&args.src_path), args.sample, &mut sample)?;
+    let codecs = match args.codecs.is_empty() {
+        true => DEFAULT_CODECS
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_>>()?,
+        false => args.codecs.clone(),
+    };
+
+    println!("sample {} bytes, blksz {}", sample.len(), args.blksz);
+    println!("codec\tclusters\tratio\tMB/s");
+    for codec in codecs {
+        let start = Instant::now();
+        let clusters = compress_sample(codec, &sample, args.blksz)?;
+        let secs = start.elapsed().as_secs_f64();
+        let ratio = match sample.len() {
+            0 => 100.0,
+            len => (clusters * args.blksz as usize) as f64 * 100.0 / len as f64,
+        };
+        println!(
+            "{codec}\t{clusters}\t{ratio:.1}%\t{:.1}",
+            sample.len() as f64 / secs / 1e6
+        );
+    }
+    Ok(())
+}
+
+// number of blocks the sample compresses into
+fn compress_sample(codec: Codec, sample: &[u8], blksz: blk_size_t) -> Result<usize> {
+    let mut output = vec![0; blksz as usize];
+    let mut clusters = 0;
+    let mut off = 0;
+    while off < sample.len() {
+        let (total_in, _) = codec.compress_cluster(&sample[off..], &mut output)?;
+        off += total_in as usize;
+        clusters += 1;
+    }
+    Ok(clusters)
+}
+
+const AUTO_BLKSZ_CANDIDATES: &[blk_size_t] = &[4096, 8192, 16384, 32768, 65536];
+const AUTO_BLKSZ_SAMPLE: u64 = 8 << 20;
+// a random read decodes a whole cluster, so a larger block has to shrink the
+// data by at least this fraction to be worth it
+const AUTO_BLKSZ_MIN_GAIN: f64 = 0.05;
+
+// the smallest block size after which doubling stops paying off
+pub fn auto_blksz(src_path: &Path, codec: Codec) -> Result<blk_size_t> {
+    let mut sample = Vec::new();
+    read_sample(src_path, AUTO_BLKSZ_SAMPLE, &mut sample)?;
+    let size_with = |blksz: blk_size_t| -> Result<u64> {
+        let size = compress_sample(codec, &sample, blksz)? as u64 * blksz as u64;
+        log::info!(
+            "blksz {blksz}: sample of {} bytes takes {size}",
+            sample.len()
+        );
+        Ok(size)
+    };
+    let mut best = AUTO_BLKSZ_CANDIDATES[0];
+    let mut best_size = size_with(best)?;
+    for &blksz in AUTO_BLKSZ_CANDIDATES[1..].iter() {
+        let size = size_with(blksz)?;
+        if (size as f64) > best_size as f64 * (1.0 - AUTO_BLKSZ_MIN_GAIN) {
+            break;
+        }
+        (best, best_size) = (blksz, size);
+    }
+    log::info!("using blksz {best}");
+    Ok(best)
+}
+
+// regular files in name order, depth first, until limit bytes are read
+fn read_sample(path: &Path, limit: u64, sample: &mut Vec<u8>) -> io::Result<()> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.is_file() {
+        let want = limit.saturating_sub(sample.len() as u64);
+        fs::File::open(path)?.take(want).read_to_end(sample)?;
+    } else if metadata.is_dir() {
+        let mut entries = fs::read_dir(path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            if sample.len() as u64 >= limit {
+                break;
+            }
+            read_sample(&entry, limit, sample)?;
+        }
+    }
+    Ok(())
+}
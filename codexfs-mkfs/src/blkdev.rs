@@ -0,0 +1,56 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{FileTypeExt, OpenOptionsExt},
+};
+
+use anyhow::{Result, ensure};
+
+// a multiple of every logical sector size, so O_DIRECT takes each write
+const ALIGN: usize = 4096;
+const CHUNK_BLOCKS: usize = 256;
+
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; ALIGN]);
+
+pub fn is_block_device(path: &str) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.file_type().is_block_device())
+}
+
+// bytes a block device holds, its metadata reports none
+pub fn device_size(path: &str) -> io::Result<u64> {
+    File::open(path)?.seek(SeekFrom::End(0))
+}
+
+// Copies the image built in img to the start of the device at path, with
+// O_DIRECT and sector-aligned buffers. The last sector is padded with zeros.
+pub fn write_image(mut img: &File, path: &str) -> Result<()> {
+    let len = img.metadata()?.len();
+    let dev_size = device_size(path)?;
+    ensure!(
+        len.next_multiple_of(ALIGN as u64) <= dev_size,
+        "image is {len} bytes, {path} holds {dev_size}"
+    );
+    let mut dev = File::options()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)?;
+    let mut blocks: Vec<AlignedBlock> = (0..CHUNK_BLOCKS)
+        .map(|_| AlignedBlock([0; ALIGN]))
+        .collect();
+    let buf = unsafe {
+        std::slice::from_raw_parts_mut(blocks.as_mut_ptr().cast::<u8>(), CHUNK_BLOCKS * ALIGN)
+    };
+    img.seek(SeekFrom::Start(0))?;
+    let mut left = len as usize;
+    while left > 0 {
+        let n = left.min(buf.len());
+        img.read_exact(&mut buf[..n])?;
+        let padded = n.next_multiple_of(ALIGN);
+        buf[n..padded].fill(0);
+        dev.write_all(&buf[..padded])?;
+        left -= n;
+    }
+    dev.sync_all()?;
+    Ok(())
+}
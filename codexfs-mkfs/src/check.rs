@@ -0,0 +1,152 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use anyhow::{Result, bail};
+use clap::Args;
+use codexfs_core::{
+    inode::{self, InodeHandle},
+    new_encode_dev,
+    pattern::IGNORE_FILE,
+    sb::{self, get_sb},
+};
+
+/// Load an image the way the FUSE driver does and compare its tree with the
+/// source directory
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// Skip mode and owner and accept entries missing from the source, for
+    /// images built with attribute overrides or a device table
+    #[arg(long)]
+    pub relaxed: bool,
+    /// Accept source entries missing from the image, for images built from
+    /// part of the source with filters, -x or --skip-errors
+    #[arg(long)]
+    pub partial: bool,
+    /// Do not accept source entries missing from the image below a
+    /// directory with a .codexfsignore file
+    #[arg(long)]
+    pub no_ignore_files: bool,
+    pub img_path: String,
+    pub src_path: String,
+}
+
+pub fn check(args: &CheckArgs) -> Result<()> {
+    sb::fuse_load_super_block(File::open(&args.img_path)?)?;
+    let nid = get_sb().root().meta().inner.borrow().nid;
+    let root = inode::fuse_load_inode(nid)?;
+    let mut mismatches = 0;
+    check_inode(
+        &root,
+        Path::new(&args.src_path),
+        args,
+        false,
+        &mut mismatches,
+    )?;
+    if mismatches > 0 {
+        bail!("{mismatches} entries differ from the source");
+    }
+    eprintln!("{} matches {}", args.img_path, args.src_path);
+    Ok(())
+}
+
+// Compares inode with path and what is below both, in both directions.
+// Below a directory with an ignore file, source entries missing from the
+// image may have been ignored.
+fn check_inode(
+    inode: &InodeHandle,
+    path: &Path,
+    args: &CheckArgs,
+    ignored: bool,
+    mismatches: &mut usize,
+) -> Result<()> {
+    let mut report = |what: &str| {
+        eprintln!("{}: {what}", path.display());
+        *mismatches += 1;
+    };
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(_) if args.relaxed => return Ok(()),
+        Err(_) => {
+            report("missing from the source");
+            return Ok(());
+        }
+    };
+    let meta = inode.meta();
+    // a symlink followed by -L or --follow is compared as what it points to
+    let metadata = match metadata.is_symlink() && !inode.file_type().is_symlink() {
+        true => path.metadata()?,
+        false => metadata,
+    };
+    if meta.mode as u32 & 0o170000 != metadata.mode() & 0o170000 {
+        report("file type differs");
+        return Ok(());
+    }
+    if !args.relaxed {
+        if meta.mode != metadata.mode() as u16 {
+            report("mode differs");
+        }
+        if (meta.uid as u32, meta.gid as u32) != (metadata.uid(), metadata.gid()) {
+            report("owner differs");
+        }
+    }
+
+    if let Some(file) = inode.downcast_file_ref() {
+        // an unreadable file is a difference, a placeholder with --relaxed
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) if args.relaxed => return Ok(()),
+            Err(e) => {
+                report(&format!("can not be read: {e}"));
+                return Ok(());
+            }
+        };
+        let size = file.itype.size;
+        if size as usize != data.len()
+            || inode::fuse_read_inode_file_data(file, 0, size)?[..size as usize] != data[..]
+        {
+            report("content differs");
+        }
+    } else if let Some(dir) = inode.downcast_dir_ref() {
+        let dentries = &dir.itype.inner.borrow().dentries;
+        let ignored = ignored || (!args.no_ignore_files && path.join(IGNORE_FILE).exists());
+        for dentry in dentries.iter() {
+            let path = path.join(&dentry.file_name);
+            check_inode(&dentry.inode, &path, args, ignored, mismatches)?;
+        }
+        if args.partial || ignored {
+            return Ok(());
+        }
+        let names: HashSet<_> = dentries.iter().map(|d| d.file_name.as_os_str()).collect();
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(_) if args.relaxed => return Ok(()),
+            Err(e) => {
+                eprintln!("{}: can not be listed: {e}", path.display());
+                *mismatches += 1;
+                return Ok(());
+            }
+        };
+        for entry in entries {
+            let name = entry?.file_name();
+            if !names.contains(name.as_os_str()) {
+                eprintln!("{}: missing from the image", path.join(name).display());
+                *mismatches += 1;
+            }
+        }
+    } else if let Some(special) = inode.downcast_special_ref() {
+        let rdev = metadata.rdev();
+        let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+        if special.itype.rdev != new_encode_dev(major, minor) {
+            report("device number differs");
+        }
+    } else if let Some(link) = inode.downcast_symlink_ref()
+        && link.itype.target.as_ref() != Some(&fs::read_link(path)?)
+    {
+        report("link target differs");
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use anyhow::{Result, bail};
+use toml::{Table, Value};
+
+// Turns a TOML config into the equivalent command-line options, one key per
+// long option without the dashes:
+//   codecs = ["lzma:9e", "store"]
+//   exclude = [".git", "/build/**"]
+//   reorder = "fast"
+//   per-file = true
+// Options given on the command line come after these and take precedence.
+pub fn load_args(text: &str) -> Result<Vec<String>> {
+    let table: Table = text.parse()?;
+    let mut args = Vec::new();
+    for (key, value) in table.iter() {
+        if key == "config" {
+            bail!("config files can not include other config files");
+        }
+        let values = match value {
+            Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            let value = match value {
+                Value::Boolean(true) => None,
+                Value::Boolean(false) => continue,
+                Value::String(s) => Some(s.clone()),
+                Value::Integer(n) => Some(n.to_string()),
+                Value::Float(f) => Some(f.to_string()),
+                _ => bail!("unsupported value for {key}"),
+            };
+            args.push(format!("--{key}"));
+            args.extend(value);
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_load_args() {
+        let text = "codecs = [\"lzma:9e\", \"store\"]\nper-file = true\nuncompress = false\nsegment-size = 65536\n";
+        assert_eq!(
+            load_args(text).unwrap(),
+            [
+                "--codecs",
+                "lzma:9e",
+                "--codecs",
+                "store",
+                "--per-file",
+                "--segment-size",
+                "65536"
+            ]
+        );
+        assert!(load_args("[table]\nkey = 1").is_err());
+        assert!(load_args("config = \"other.toml\"").is_err());
+    }
+}
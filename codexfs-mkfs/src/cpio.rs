@@ -0,0 +1,270 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    io::{self, Read},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use codexfs_core::{idmap::IdMap, inode::PseudoEntry, mode_t, new_encode_dev};
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const CRC_MAGIC: &[u8] = b"070702";
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFIFO: u32 = 0o010000;
+const S_IFSOCK: u32 = 0o140000;
+
+// fields of a newc header after the magic, in order
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    filesize: u32,
+    rdevmajor: u32,
+    rdevminor: u32,
+    namesize: u32,
+}
+
+fn read_header(r: &mut dyn Read) -> Result<Header> {
+    let mut buf = [0; 110];
+    r.read_exact(&mut buf)?;
+    ensure!(
+        &buf[..6] == NEWC_MAGIC || &buf[..6] == CRC_MAGIC,
+        "not a newc cpio archive"
+    );
+    let field = |i: usize| -> Result<u32> {
+        let hex = std::str::from_utf8(&buf[6 + i * 8..6 + (i + 1) * 8])?;
+        Ok(u32::from_str_radix(hex, 16)?)
+    };
+    Ok(Header {
+        ino: field(0)?,
+        mode: field(1)?,
+        uid: field(2)?,
+        gid: field(3)?,
+        nlink: field(4)?,
+        filesize: field(6)?,
+        rdevmajor: field(9)?,
+        rdevminor: field(10)?,
+        namesize: field(11)?,
+    })
+}
+
+// skips the padding after a header, name or data of len bytes
+fn skip_pad(r: &mut dyn Read, len: u64) -> io::Result<()> {
+    io::copy(&mut r.take(len.next_multiple_of(4) - len), &mut io::sink())?;
+    Ok(())
+}
+
+// Reads a newc (initramfs) archive into pseudo entries below root, so the
+// tree is built from memory without unpacking it. Hardlinked files share
+// their contents, which newc stores with the last name. Owners are mapped by
+// the uid and gid maps.
+pub fn read(
+    r: &mut dyn Read,
+    root: &Path,
+    id_maps: &(IdMap, IdMap),
+) -> Result<BTreeMap<PathBuf, PseudoEntry>> {
+    let mut entries = BTreeMap::new();
+    // names and data of every hardlinked inode
+    let mut links: HashMap<u32, (Vec<PathBuf>, Vec<u8>)> = HashMap::new();
+    loop {
+        let header = read_header(r)?;
+        let mut name = vec![0; header.namesize as usize];
+        r.read_exact(&mut name)?;
+        skip_pad(r, 110 + header.namesize as u64)?;
+        let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        name.truncate(name_len);
+        if name == TRAILER.as_bytes() {
+            break;
+        }
+        let name = Path::new(OsStr::from_bytes(&name));
+        let mut data = Vec::new();
+        r.take(header.filesize as u64).read_to_end(&mut data)?;
+        ensure!(
+            data.len() == header.filesize as usize,
+            "truncated data of {}",
+            name.display()
+        );
+        skip_pad(r, header.filesize as u64)?;
+
+        let mut path = root.to_path_buf();
+        for component in name.components() {
+            match component {
+                Component::Normal(c) => path.push(c),
+                Component::CurDir | Component::RootDir => {}
+                _ => bail!("unsafe path {} in archive", name.display()),
+            }
+        }
+        let mut entry = PseudoEntry {
+            mode: mode_t::try_from(header.mode)
+                .with_context(|| format!("{}: invalid mode {:o}", name.display(), header.mode))?,
+            uid: id_maps
+                .0
+                .map_to_image(header.uid)
+                .with_context(|| format!("{}: uid", name.display()))?,
+            gid: id_maps
+                .1
+                .map_to_image(header.gid)
+                .with_context(|| format!("{}: gid", name.display()))?,
+            rdev: 0,
+            target: None,
+            contents: None,
+        };
+        match header.mode & S_IFMT {
+            S_IFDIR => {}
+            S_IFREG if header.nlink > 1 => {
+                let (names, link_data) = links.entry(header.ino).or_default();
+                names.push(path.clone());
+                if !data.is_empty() {
+                    *link_data = data;
+                }
+            }
+            S_IFREG => entry.contents = Some(Rc::from(data)),
+            S_IFLNK => entry.target = Some(OsString::from_vec(data).into()),
+            S_IFCHR | S_IFBLK => {
+                entry.rdev = new_encode_dev(header.rdevmajor, header.rdevminor);
+            }
+            S_IFIFO | S_IFSOCK => {}
+            _ => bail!(
+                "{}: unknown file type, mode {:o}",
+                name.display(),
+                header.mode
+            ),
+        }
+        entries.insert(path, entry);
+    }
+    for (names, data) in links.into_values() {
+        let contents: Rc<[u8]> = Rc::from(data);
+        for name in names {
+            if let Some(entry) = entries.get_mut(&name) {
+                entry.contents = Some(contents.clone());
+            }
+        }
+    }
+    // an archive without a "." entry gets a root owned by root
+    let root_entry = entries
+        .entry(root.to_path_buf())
+        .or_insert_with(|| PseudoEntry {
+            mode: (S_IFDIR | 0o755) as _,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            target: None,
+            contents: None,
+        });
+    ensure!(is_dir(root_entry), "the archive root is not a directory");
+    for path in entries.keys().filter(|&path| path != root) {
+        ensure!(
+            entries.get(path.parent().unwrap()).is_some_and(is_dir),
+            "parent directory of {} is missing from the archive",
+            path.strip_prefix(root).unwrap().display()
+        );
+    }
+    Ok(entries)
+}
+
+fn is_dir(entry: &PseudoEntry) -> bool {
+    entry.mode as u32 & S_IFMT == S_IFDIR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a newc entry with the given header fields, in header order
+    fn entry(
+        name: &str,
+        ino: u32,
+        mode: u32,
+        uid: u32,
+        nlink: u32,
+        rdev: (u32, u32),
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut buf = NEWC_MAGIC.to_vec();
+        let fields = [
+            ino,
+            mode,
+            uid,
+            0,
+            nlink,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            rdev.0,
+            rdev.1,
+            name.len() as u32 + 1,
+            0,
+        ];
+        for field in fields {
+            buf.extend(format!("{field:08x}").as_bytes());
+        }
+        buf.extend(name.as_bytes());
+        buf.push(0);
+        buf.resize(buf.len().next_multiple_of(4), 0);
+        buf.extend(data);
+        buf.resize(buf.len().next_multiple_of(4), 0);
+        buf
+    }
+
+    #[test]
+    fn check_read() {
+        let mut archive = Vec::new();
+        archive.extend(entry(".", 1, S_IFDIR | 0o755, 0, 2, (0, 0), b""));
+        archive.extend(entry("dev", 2, S_IFDIR | 0o755, 0, 2, (0, 0), b""));
+        archive.extend(entry("dev/null", 3, S_IFCHR | 0o666, 0, 1, (1, 3), b""));
+        archive.extend(entry("dev/fifo", 4, S_IFIFO | 0o644, 0, 1, (0, 0), b""));
+        archive.extend(entry("a", 5, S_IFREG | 0o644, 1000, 2, (0, 0), b""));
+        archive.extend(entry("b", 5, S_IFREG | 0o644, 1000, 2, (0, 0), b"data"));
+        archive.extend(entry("c", 6, S_IFLNK | 0o777, 0, 1, (0, 0), b"a"));
+        archive.extend(entry(TRAILER, 0, 0, 0, 1, (0, 0), b""));
+        let root = Path::new("/archive");
+        let entries = read(&mut &archive[..], root, &Default::default()).unwrap();
+        assert_eq!(entries.len(), 7);
+        assert_eq!(entries[&root.join("dev/null")].rdev, new_encode_dev(1, 3));
+        assert_eq!(entries[&root.join("dev/fifo")].mode as u32, S_IFIFO | 0o644);
+        let (a, b) = (&entries[&root.join("a")], &entries[&root.join("b")]);
+        assert_eq!(a.uid, 1000);
+        assert_eq!(a.contents.as_deref(), Some(&b"data"[..]));
+        assert!(Rc::ptr_eq(
+            a.contents.as_ref().unwrap(),
+            b.contents.as_ref().unwrap()
+        ));
+        assert_eq!(
+            entries[&root.join("c")].target.as_deref(),
+            Some(Path::new("a"))
+        );
+    }
+
+    #[test]
+    fn check_read_errors() {
+        let mut id_maps = (IdMap::default(), IdMap::default());
+        let read_entries = |entries: &[Vec<u8>], id_maps: &(IdMap, IdMap)| {
+            let mut archive = entries.concat();
+            archive.extend(entry(TRAILER, 0, 0, 0, 1, (0, 0), b""));
+            read(&mut &archive[..], Path::new("/archive"), id_maps)
+        };
+        let file = |name, uid| entry(name, 1, S_IFREG | 0o644, uid, 1, (0, 0), b"");
+        assert!(read_entries(&[file("a", 0)], &id_maps).is_ok());
+        assert!(read_entries(&[file("a", 70000)], &id_maps).is_err());
+        assert!(read_entries(&[file("missing/a", 0)], &id_maps).is_err());
+        assert!(read_entries(&[file("../a", 0)], &id_maps).is_err());
+
+        // an id is only checked once mapped
+        id_maps.0.push(70000, 1000, 1);
+        let entries = read_entries(&[file("a", 70000)], &id_maps).unwrap();
+        assert_eq!(entries[Path::new("/archive/a")].uid, 1000);
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::BufRead,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use codexfs_core::{gid_t, inode::PseudoEntry, mode_t, new_encode_dev, uid_t};
+
+const S_IFREG: mode_t = 0o100000;
+pub(crate) const S_IFDIR: mode_t = 0o040000;
+const S_IFCHR: mode_t = 0o020000;
+const S_IFBLK: mode_t = 0o060000;
+const S_IFIFO: mode_t = 0o010000;
+
+// One line of a genext2fs style device table:
+//   <path> <type> <mode> <uid> <gid> <major> <minor> <start> <inc> <count>
+// type is f, d, c, b or p, unused numbers are "-". With a count, the line
+// stands for <path><start + i> with minor + i * inc for i in 0..count.
+#[derive(Debug, PartialEq, Eq)]
+struct Line {
+    path: String,
+    mode: mode_t, // with the file type
+    uid: uid_t,
+    gid: gid_t,
+    major: u32,
+    minor: u32,
+    start: u32,
+    inc: u32,
+    count: u32,
+}
+
+fn parse_line(line: &str) -> Result<Option<Line>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    ensure!(
+        fields.len() == 10,
+        "expected 10 fields, got {}",
+        fields.len()
+    );
+    let num = |i: usize| -> Result<u32> {
+        match fields[i] {
+            "-" => Ok(0),
+            field => Ok(field.parse()?),
+        }
+    };
+    let file_type = match fields[1] {
+        "f" => S_IFREG,
+        "d" => S_IFDIR,
+        "c" => S_IFCHR,
+        "b" => S_IFBLK,
+        "p" => S_IFIFO,
+        t => bail!("unknown type {t}"),
+    };
+    Ok(Some(Line {
+        path: fields[0].to_owned(),
+        mode: file_type | mode_t::from_str_radix(fields[2], 8)?,
+        uid: uid_t::try_from(num(3)?)
+            .with_context(|| format!("{}: uid does not fit in 16 bits", fields[0]))?,
+        gid: gid_t::try_from(num(4)?)
+            .with_context(|| format!("{}: gid does not fit in 16 bits", fields[0]))?,
+        major: num(5)?,
+        minor: num(6)?,
+        start: num(7)?,
+        inc: num(8)?,
+        count: num(9)?,
+    }))
+}
+
+pub(crate) fn file_type_of(path: &Path) -> Option<mode_t> {
+    let file_type = path.symlink_metadata().ok()?.file_type();
+    Some(if file_type.is_dir() {
+        S_IFDIR
+    } else if file_type.is_file() {
+        S_IFREG
+    } else if file_type.is_char_device() {
+        S_IFCHR
+    } else if file_type.is_block_device() {
+        S_IFBLK
+    } else if file_type.is_fifo() {
+        S_IFIFO
+    } else {
+        0
+    })
+}
+
+// Reads a device table for the tree at src_root. Paths that exist get their
+// mode and owner replaced, missing directories and special files are created.
+pub fn load(
+    r: &mut dyn BufRead,
+    src_root: &Path,
+    attrs: &mut HashMap<PathBuf, (mode_t, uid_t, gid_t)>,
+    pseudo_entries: &mut BTreeMap<PathBuf, PseudoEntry>,
+) -> Result<()> {
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        let Some(line) =
+            parse_line(&line).with_context(|| format!("device table line {}", i + 1))?
+        else {
+            continue;
+        };
+        let names = match line.count {
+            0 => vec![(line.path.clone(), line.minor)],
+            count => (0..count)
+                .map(|i| {
+                    let name = format!("{}{}", line.path, line.start + i);
+                    (name, line.minor + i * line.inc)
+                })
+                .collect(),
+        };
+        for (name, minor) in names {
+            let path = src_root.join(name.trim_start_matches('/'));
+            if let Some(entry) = pseudo_entries.get_mut(&path) {
+                ensure!(
+                    entry.mode & 0o170000 == line.mode & 0o170000,
+                    "{name} exists with another file type"
+                );
+                (entry.mode, entry.uid, entry.gid) = (line.mode, line.uid, line.gid);
+                continue;
+            }
+            match file_type_of(&path) {
+                Some(file_type) => {
+                    ensure!(
+                        file_type == line.mode & 0o170000,
+                        "{name} exists with another file type"
+                    );
+                    attrs.insert(path, (line.mode, line.uid, line.gid));
+                }
+                None if line.mode & 0o170000 == S_IFREG => {
+                    bail!("{name} does not exist, regular files can not be created")
+                }
+                None => {
+                    let entry = PseudoEntry {
+                        mode: line.mode,
+                        uid: line.uid,
+                        gid: line.gid,
+                        rdev: new_encode_dev(line.major, minor),
+                        target: None,
+                        contents: None,
+                    };
+                    pseudo_entries.insert(path, entry);
+                }
+            }
+        }
+    }
+    for path in pseudo_entries.keys().filter(|&path| path != src_root) {
+        let parent = path.parent().unwrap();
+        ensure!(
+            file_type_of(parent) == Some(S_IFDIR)
+                || pseudo_entries
+                    .get(parent)
+                    .is_some_and(|e| e.mode & 0o170000 == S_IFDIR),
+            "parent directory of {} is missing",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_parse_line() {
+        assert_eq!(parse_line("  # comment").unwrap(), None);
+        assert_eq!(
+            parse_line("/dev/tty c 666 0 5 5 0 0 1 4").unwrap(),
+            Some(Line {
+                path: "/dev/tty".into(),
+                mode: S_IFCHR | 0o666,
+                uid: 0,
+                gid: 5,
+                major: 5,
+                minor: 0,
+                start: 0,
+                inc: 1,
+                count: 4,
+            })
+        );
+        assert_eq!(
+            parse_line("/dev d 755 0 0 - - - - -")
+                .unwrap()
+                .unwrap()
+                .mode,
+            S_IFDIR | 0o755
+        );
+        assert!(parse_line("/dev x 755 0 0 - - - - -").is_err());
+        assert!(parse_line("/dev d 755").is_err());
+        assert!(parse_line("/dev d 755 70000 0 - - - - -").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::io::Read;
+
+use anyhow::Result;
+use codexfs_core::{
+    CodexFsDelta, CodexFsExtent, CodexFsFileType, CodexFsInode,
+    compress::{FileDataReader, get_cmpr_mgr},
+    inode::get_inode_vec_mut,
+    sb::get_sb,
+    utils::round_up,
+};
+
+const SAMPLE_SIZE: u64 = 8 << 20;
+
+// Estimates the image for the scanned and reordered tree without compressing
+// or writing anything. The compression ratio comes from the start of the data
+// in layout order, the extent count of every file from the average cluster.
+pub fn mkfs_dry_run() -> Result<()> {
+    let blksz = get_sb().blksz() as u64;
+    let cmpr_mgr = get_cmpr_mgr();
+    let compress = get_sb().compress;
+
+    let data_size = match compress {
+        true => cmpr_mgr.data_size(),
+        false => 0,
+    };
+    let plain_files = match compress {
+        true => &cmpr_mgr.plain_files,
+        false => &cmpr_mgr.files,
+    };
+    let plain_size: u64 = plain_files.iter().map(|f| f.data_size() as u64).sum();
+
+    // decompressed bytes per cluster in the sample
+    let (sample_len, in_per_blk) = match data_size {
+        0 => (0, blksz),
+        _ => {
+            let mut sample = Vec::new();
+            FileDataReader::new(&cmpr_mgr.segments)
+                .take(SAMPLE_SIZE)
+                .read_to_end(&mut sample)?;
+            let blocks = compress_sample(&sample)?;
+            (sample.len() as u64, sample.len() as u64 / blocks)
+        }
+    };
+    let zdata_blocks = data_size.div_ceil(in_per_blk);
+
+    let mut extents = 0;
+    for segment in cmpr_mgr.segments.iter() {
+        extents += segment.places().count() as u64 * segment.len.div_ceil(in_per_blk);
+    }
+    let (mut files, mut dirs, mut others) = (0, 0, 0);
+    let mut meta_size = extents * size_of::<CodexFsExtent>() as u64;
+    for inode in get_inode_vec_mut().iter() {
+        let extra = match inode.file_type() {
+            CodexFsFileType::File => {
+                files += 1;
+                match inode.downcast_file_ref().unwrap().is_delta() {
+                    true => size_of::<CodexFsDelta>() as u64,
+                    false => 0,
+                }
+            }
+            CodexFsFileType::Dir => {
+                dirs += 1;
+                inode.meta().meta_size() as u64
+            }
+            CodexFsFileType::Symlink => {
+                others += 1;
+                inode.meta().meta_size() as u64
+            }
+            _ => {
+                others += 1;
+                0
+            }
+        };
+        let inode_size = size_of::<CodexFsInode>() as u64;
+        meta_size += round_up(inode_size + extra, inode_size);
+    }
+
+    let zdata_off = blksz;
+    let plain_off = zdata_off + zdata_blocks * blksz;
+    let meta_off = plain_off + plain_size;
+    let img_size = round_up(meta_off + meta_size, blksz);
+
+    println!("dry run, nothing is written");
+    println!("blksz {blksz}, {files} files, {dirs} directories, {others} other inodes");
+    println!("{:<16}{:>14}{:>14}", "region", "offset", "size");
+    println!("{:<16}{:>14}{:>14}", "superblock", 0, blksz);
+    if compress {
+        println!(
+            "{:<16}{:>14}{:>14}  {} blocks for {} bytes",
+            "compressed",
+            zdata_off,
+            zdata_blocks * blksz,
+            zdata_blocks,
+            data_size
+        );
+    }
+    println!(
+        "{:<16}{:>14}{:>14}  {} files",
+        "uncompressed",
+        plain_off,
+        plain_size,
+        plain_files.len()
+    );
+    println!(
+        "{:<16}{:>14}{:>14}  {} extents",
+        "metadata", meta_off, meta_size, extents
+    );
+    println!("image size {img_size}");
+    if sample_len > 0 {
+        println!(
+            "compression estimated from the first {sample_len} bytes: {:.1}% of the input",
+            blksz as f64 * 100.0 / in_per_blk as f64
+        );
+    }
+    Ok(())
+}
+
+// number of blocks the sample compresses into, each cluster with the codec
+// that packs the most of it like the real build
+fn compress_sample(sample: &[u8]) -> Result<u64> {
+    let mut output = vec![0; get_sb().blksz() as usize];
+    let mut blocks = 0;
+    let mut off = 0;
+    while off < sample.len() {
+        let mut best = 0;
+        for codec in get_cmpr_mgr().codecs.iter() {
+            let (total_in, _) = codec.compress_cluster(&sample[off..], &mut output)?;
+            best = best.max(total_in as usize);
+        }
+        off += best;
+        blocks += 1;
+    }
+    Ok(blocks)
+}
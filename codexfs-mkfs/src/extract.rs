@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{PermissionsExt, lchown, symlink},
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+use clap::Args;
+use codexfs_core::{
+    cluster_cache,
+    inode::{self, InodeHandle},
+    mode_t, new_decode_dev,
+    sb::{self, get_sb},
+    xattr::{self, Xattrs},
+};
+
+use crate::stage;
+
+const READ_CHUNK: u32 = 1 << 20;
+const EXTRACT_CACHE_SIZE: u64 = 8 << 20;
+
+/// Unpack an image into a directory
+#[derive(Debug, Args)]
+pub struct ExtractArgs {
+    /// Leave modes, owners, xattrs and special files off the unpacked tree
+    /// and list every entry in FILE as "MODE UID GID RDEV XATTRS PATH" lines
+    /// instead, for merging into another build
+    #[arg(long, value_name = "FILE")]
+    pub attrs: Option<String>,
+    pub img_path: String,
+    pub dest: String,
+}
+
+struct Extractor {
+    attrs: Option<BufWriter<File>>,
+    is_root: bool,
+    // first path of every hardlinked inode
+    links: HashMap<u32, PathBuf>,
+    // directories get their mode once their entries are written
+    dirs: Vec<(PathBuf, mode_t)>,
+}
+
+pub fn extract(args: &ExtractArgs) -> Result<()> {
+    sb::fuse_load_super_block(File::open(&args.img_path)?)?;
+    // tails of several files may share a cluster
+    cluster_cache::set_cluster_cache(EXTRACT_CACHE_SIZE);
+    let nid = get_sb().root().meta().inner.borrow().nid;
+    let root = inode::fuse_load_inode(nid)?;
+    let dest = Path::new(&args.dest);
+    let mut extractor = Extractor {
+        attrs: match &args.attrs {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        },
+        is_root: unsafe { libc::geteuid() } == 0,
+        links: HashMap::new(),
+        dirs: Vec::new(),
+    };
+    extractor.extract_inode(&root, dest, Path::new(""))?;
+    for (path, mode) in extractor.dirs.iter().rev() {
+        fs::set_permissions(path, fs::Permissions::from_mode(*mode as u32 & 0o7777))?;
+    }
+    if let Some(attrs) = &mut extractor.attrs {
+        attrs.flush()?;
+    }
+    Ok(())
+}
+
+impl Extractor {
+    fn extract_inode(&mut self, inode: &InodeHandle, dest: &Path, name: &Path) -> Result<()> {
+        let path = dest.join(name);
+        let meta = inode.meta();
+        let xattrs = inode::fuse_read_xattrs(inode)?;
+        let mut rdev = 0;
+        if inode.downcast_dir_ref().is_none() {
+            stage::make_room(&path, false)?;
+        }
+        if let Some(file) = inode.downcast_file_ref() {
+            match self.links.get(&meta.ino) {
+                Some(first) => fs::hard_link(first, &path)?,
+                None => {
+                    let mut out = File::create(&path)?;
+                    let mut off = 0;
+                    while off < file.itype.size {
+                        let len = READ_CHUNK.min(file.itype.size - off);
+                        let data = inode::fuse_read_inode_file_data(file, off, len)?;
+                        out.write_all(&data[..len as usize])?;
+                        off += len;
+                    }
+                    if meta.inner.borrow().nlink > 1 {
+                        self.links.insert(meta.ino, path.clone());
+                    }
+                }
+            }
+        } else if inode.downcast_dir_ref().is_some() {
+            if !stage::make_room(&path, true)? {
+                fs::create_dir(&path)?;
+            }
+            if self.attrs.is_none() {
+                self.dirs.push((path.clone(), meta.mode));
+            }
+        } else if let Some(special) = inode.downcast_special_ref() {
+            rdev = special.itype.rdev;
+        } else if let Some(link) = inode.downcast_symlink_ref() {
+            symlink(link.itype.target.as_ref().unwrap(), &path)?;
+        }
+
+        match &mut self.attrs {
+            Some(attrs) => {
+                let name = name.as_os_str().as_bytes();
+                if name.contains(&b'\n') {
+                    bail!("{} has a newline in its name", path.display());
+                }
+                write!(
+                    attrs,
+                    "{:o} {} {} {rdev} {} ",
+                    meta.mode,
+                    meta.uid,
+                    meta.gid,
+                    encode_xattrs(&xattrs)
+                )?;
+                attrs.write_all(name)?;
+                attrs.write_all(b"\n")?;
+            }
+            None => self.apply(inode, &path, rdev, &xattrs)?,
+        }
+
+        if let Some(dir) = inode.downcast_dir_ref() {
+            for dentry in dir.itype.inner.borrow().dentries.iter() {
+                self.extract_inode(&dentry.inode, dest, &name.join(&dentry.file_name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // what an unprivileged extraction can apply is applied, the rest is
+    // reported and left out
+    fn apply(&self, inode: &InodeHandle, path: &Path, rdev: u32, xattrs: &Xattrs) -> Result<()> {
+        let meta = inode.meta();
+        if inode.downcast_special_ref().is_some() {
+            let (major, minor) = new_decode_dev(rdev);
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            let ret = unsafe {
+                libc::mknod(c_path.as_ptr(), meta.mode as _, libc::makedev(major, minor))
+            };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                log::warn!("skipping {}: {e}", path.display());
+                return Ok(());
+            }
+        }
+        if self.is_root {
+            lchown(path, Some(meta.uid as _), Some(meta.gid as _))?;
+        }
+        if let Err(e) = xattr::write(path, xattrs) {
+            log::warn!("{}: xattrs left out: {e}", path.display());
+        }
+        if !inode.file_type().is_symlink() && inode.downcast_dir_ref().is_none() {
+            fs::set_permissions(path, fs::Permissions::from_mode(meta.mode as u32 & 0o7777))?;
+        }
+        Ok(())
+    }
+}
+
+// "-" for none, otherwise NAME=VALUE pairs in hex joined by commas
+fn encode_xattrs(xattrs: &Xattrs) -> String {
+    if xattrs.is_empty() {
+        return "-".to_owned();
+    }
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    xattrs
+        .iter()
+        .map(|(name, value)| format!("{}={}", hex(name.as_bytes()), hex(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn decode_xattrs(s: &str) -> Result<Xattrs> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+    let unhex = |s: &str| -> Result<Vec<u8>> {
+        if s.len() % 2 != 0 {
+            bail!("odd hex length");
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+            .collect()
+    };
+    s.split(',')
+        .map(|pair| {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected NAME=VALUE"))?;
+            Ok((String::from_utf8(unhex(name)?)?, unhex(value)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_xattrs_encoding() {
+        let xattrs = vec![
+            ("user.a".to_owned(), b"x=y,z".to_vec()),
+            ("security.selinux".to_owned(), Vec::new()),
+        ];
+        assert_eq!(decode_xattrs(&encode_xattrs(&xattrs)).unwrap(), xattrs);
+        assert_eq!(encode_xattrs(&Vec::new()), "-");
+        assert!(decode_xattrs("-").unwrap().is_empty());
+        assert!(decode_xattrs("757").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::BufRead,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use codexfs_core::{
+    gid_t,
+    inode::PseudoEntry,
+    mode_t, uid_t,
+    xattr::{self, XATTR_SECURITY_CAPABILITY, XATTR_SECURITY_SELINUX, Xattrs},
+};
+
+// One line of an Android fs_config file:
+//   <path> <uid> <gid> <mode> [selabel=<label>] [capabilities=<mask>]
+// with the path relative to the source root and an octal mode without the
+// file type, e.g. "system/bin/ping 0 2000 0750 capabilities=0x2000".
+#[derive(Debug, PartialEq, Eq)]
+struct Line {
+    path: String,
+    uid: uid_t,
+    gid: gid_t,
+    mode: mode_t,
+    selabel: Option<String>,
+    capabilities: u64,
+}
+
+fn parse_line(line: &str) -> Result<Option<Line>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    ensure!(
+        fields.len() >= 4,
+        "expected at least 4 fields, got {}",
+        fields.len()
+    );
+    let mut parsed = Line {
+        path: fields[0].to_owned(),
+        uid: fields[1].parse()?,
+        gid: fields[2].parse()?,
+        mode: mode_t::from_str_radix(fields[3], 8)?,
+        selabel: None,
+        capabilities: 0,
+    };
+    ensure!(
+        parsed.mode <= 0o7777,
+        "mode {} has file type bits",
+        fields[3]
+    );
+    for field in fields[4..].iter() {
+        match field.split_once('=') {
+            Some(("selabel", label)) => parsed.selabel = Some(label.to_owned()),
+            Some(("capabilities", mask)) => {
+                parsed.capabilities = match mask.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16)?,
+                    None => mask.parse()?,
+                }
+            }
+            _ => bail!("unknown attribute {field}"),
+        }
+    }
+    Ok(Some(parsed))
+}
+
+// Reads an fs_config file for the tree at src_root, replacing the mode and
+// owner of every listed path and giving it an SELinux label and file
+// capabilities if the line has them. Pseudo entries are changed right away.
+pub fn load(
+    r: &mut dyn BufRead,
+    src_root: &Path,
+    attrs: &mut HashMap<PathBuf, (mode_t, uid_t, gid_t)>,
+    pseudo_entries: &mut BTreeMap<PathBuf, PseudoEntry>,
+    xattrs: &mut HashMap<PathBuf, Xattrs>,
+) -> Result<()> {
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        let Some(line) = parse_line(&line).with_context(|| format!("fs_config line {}", i + 1))?
+        else {
+            continue;
+        };
+        let path = match line.path.trim_start_matches('/') {
+            "" | "." => src_root.to_path_buf(),
+            rel => src_root.join(rel),
+        };
+        if let Some(entry) = pseudo_entries.get_mut(&path) {
+            entry.mode = entry.mode & 0o170000 | line.mode;
+            (entry.uid, entry.gid) = (line.uid, line.gid);
+        } else {
+            let metadata = path
+                .symlink_metadata()
+                .with_context(|| format!("fs_config line {}: {}", i + 1, line.path))?;
+            let file_type = metadata.mode() as mode_t & 0o170000;
+            attrs.insert(path.clone(), (file_type | line.mode, line.uid, line.gid));
+        }
+
+        let path_xattrs = xattrs.entry(path).or_default();
+        path_xattrs.retain(|(name, _)| {
+            name != XATTR_SECURITY_SELINUX && name != XATTR_SECURITY_CAPABILITY
+        });
+        if let Some(label) = line.selabel {
+            let mut value = label.into_bytes();
+            value.push(0);
+            path_xattrs.push((XATTR_SECURITY_SELINUX.to_owned(), value));
+        }
+        if line.capabilities != 0 {
+            let value = xattr::capability(line.capabilities);
+            path_xattrs.push((XATTR_SECURITY_CAPABILITY.to_owned(), value));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_parse_line() {
+        assert_eq!(parse_line("# comment").unwrap(), None);
+        assert_eq!(
+            parse_line("system/bin/ping 0 2000 0750 capabilities=0x2000").unwrap(),
+            Some(Line {
+                path: "system/bin/ping".into(),
+                uid: 0,
+                gid: 2000,
+                mode: 0o750,
+                selabel: None,
+                capabilities: 0x2000,
+            })
+        );
+        let line = parse_line("/ 0 0 755 selabel=u:object_r:rootfs:s0")
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.selabel.as_deref(), Some("u:object_r:rootfs:s0"));
+        assert!(parse_line("system 0 0").is_err());
+        assert!(parse_line("system 0 0 40755").is_err());
+        assert!(parse_line("system 0 0 755 foo=1").is_err());
+    }
+}
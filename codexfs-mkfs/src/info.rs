@@ -0,0 +1,61 @@
+use std::{
+    env,
+    fs::File,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, bail};
+use clap::Args;
+use codexfs_core::{buildinfo, compress::get_cmpr_mgr, sb, sb::get_sb};
+use serde_json::json;
+
+/// Print how an image was built, as recorded by mkfs
+#[derive(Debug, Args)]
+pub struct InfoArgs {
+    pub img_path: String,
+}
+
+pub fn info(args: &InfoArgs) -> Result<()> {
+    sb::fuse_load_super_block(File::open(&args.img_path)?)?;
+    let Some(buf) = buildinfo::fuse_load_build_info()? else {
+        bail!("{} has no build info", args.img_path);
+    };
+    let mut info: serde_json::Value = serde_json::from_slice(&buf)?;
+    // kept in the superblock, not the record
+    if let Some(info) = info.as_object_mut() {
+        if get_sb().label != [0; 16] {
+            info.insert("label".into(), sb::label_to_string(&get_sb().label).into());
+        }
+        if get_sb().uuid != [0; 16] {
+            info.insert("uuid".into(), sb::uuid_to_string(&get_sb().uuid).into());
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+// The record of this build, once the data is written. The time is
+// SOURCE_DATE_EPOCH if set, for reproducible images.
+pub fn build_info(command_line: &[String]) -> Result<Vec<u8>> {
+    let timestamp = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse::<u64>()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    let codecs: Vec<_> = get_cmpr_mgr()
+        .codecs
+        .iter()
+        .map(|codec| codec.to_string())
+        .collect();
+    let info = json!({
+        "tool": "mkfs.codexfs",
+        "version": env!("CARGO_PKG_VERSION"),
+        "timestamp": timestamp,
+        "command_line": command_line,
+        "block_size": get_sb().blksz(),
+        "compressed": get_sb().compress,
+        "codecs": codecs,
+        "dict_size": get_sb().dict_size,
+        "max_cluster_size": get_sb().max_cluster_size,
+    });
+    Ok(serde_json::to_vec(&info)?)
+}
@@ -0,0 +1,68 @@
+use std::{io::Write, str::FromStr};
+
+use anyhow::{Result, bail};
+use env_logger::{Builder, Env};
+use log::LevelFilter;
+
+// how log records are written to stderr
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json, // one object per line with level, target and message
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("unknown log format {s:?}, expected text or json"),
+        }
+    }
+}
+
+// warnings by default, each -v one level more, -q only errors
+fn level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+// RUST_LOG still overrides the level given on the command line
+pub fn init(verbose: u8, quiet: bool, format: LogFormat) {
+    let level = level(verbose, quiet).to_string().to_lowercase();
+    let mut builder = Builder::from_env(Env::default().default_filter_or(level));
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_level() {
+        assert_eq!(level(0, false), LevelFilter::Warn);
+        assert_eq!(level(2, false), LevelFilter::Debug);
+        assert_eq!(level(9, false), LevelFilter::Trace);
+        assert_eq!(level(2, true), LevelFilter::Error);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
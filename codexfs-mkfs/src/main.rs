@@ -1,28 +1,382 @@
 #![allow(static_mut_refs)]
 
-use std::{cell::OnceCell, fs::File, path::Path};
+mod bench;
+mod blkdev;
+mod check;
+mod config;
+mod cpio;
+mod devtable;
+mod dryrun;
+mod extract;
+mod fsconfig;
+mod info;
+mod logging;
+mod overrides;
+mod provenance;
+mod pseudo;
+mod sparse;
+mod stage;
 
-use clap::Parser;
+use std::{
+    cell::OnceCell,
+    env,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    iter,
+    path::Path,
+    process,
+    rc::Rc,
+    thread,
+};
+
+use bench::BenchArgs;
+use check::CheckArgs;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use codexfs_core::{
-    blk_size_t,
-    compress::{get_cmpr_mgr_mut, set_cmpr_mgr},
-    inode,
+    blk_size_t, buildinfo,
+    cache::FingerprintCache,
+    compress::{
+        self, Codec, DataOrder, PolicyRule, ReorderMode, get_cmpr_mgr, get_cmpr_mgr_mut,
+        set_cmpr_mgr,
+    },
+    gid_t, idmap,
+    inode::{self, CaseCollisions, Inode},
+    mode_t,
+    options::{InoMode, MkfsOptions, get_mkfs_opts_mut, set_mkfs_opts},
+    pattern::{self, PathFilter, PathPatterns},
+    progress::{Unit, get_progress_mut},
+    report,
     sb::{self, SuperBlock, get_sb, get_sb_mut, set_sb},
+    scan::{self, SymlinkPolicy},
+    uid_t,
+    utils::parse_size,
+    xattr::XattrFilter,
 };
+use extract::ExtractArgs;
+use info::InfoArgs;
+use logging::LogFormat;
+use provenance::ProvenanceArgs;
 
 #[derive(Debug, Parser)]
 #[command(name = "mkfs.codexfs")]
 #[command(version("1.0"))]
 #[command(about = "A command-line tool to create an CODEX filesystem")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(args_override_self = true)]
 struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(skip)]
+    pub options: String, // what the image depends on besides the source tree
+    #[arg(skip)]
+    pub command_line: Vec<String>, // after the config file's options
+    /// Read options from a TOML file, one key per long option, e.g.
+    /// `codecs = ["lzma:9e"]` or `per-file = true`. Options on the command
+    /// line override it
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<String>,
     #[arg(short, long, action)]
     pub uncompress: bool,
     #[arg(short, long, default_value_t = 4096)]
     pub blksz: blk_size_t,
-    #[arg(index(1))]
-    pub img_path: String,
-    #[arg(index(2))]
-    pub src_path: String,
+    /// Pick the block size by compressing a sample of the source with the
+    /// first codec, instead of --blksz
+    #[arg(long)]
+    pub auto_blksz: bool,
+    /// Codecs tried on every cluster, keeping the one that fits the most
+    /// data: lzma, lzma:<0-9>[e], xz, xz:<0-9>[e], store
+    #[arg(long, value_delimiter = ',')]
+    pub codecs: Vec<Codec>,
+    /// Compress files matching PATTERN with the given codecs instead, e.g.
+    /// "*.png=store" or "*.txt=lzma:9e" (repeatable, first match wins)
+    #[arg(long, value_name = "PATTERN=CODECS")]
+    pub policy: Vec<PolicyRule>,
+    /// Start a new cluster for every file instead of one solid stream
+    #[arg(long)]
+    pub per_file: bool,
+    /// Make root the owner of every inode
+    #[arg(long, conflicts_with = "owner")]
+    pub all_root: bool,
+    /// Make UID:GID the owner of every inode
+    #[arg(long, value_name = "UID:GID", value_parser = parse_owner)]
+    pub owner: Option<(uid_t, gid_t)>,
+    /// Set the permission bits of the image root, in octal like 755
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub root_mode: Option<mode_t>,
+    /// Make UID:GID the owner of the image root, even with --owner
+    #[arg(long, value_name = "UID:GID", value_parser = parse_owner)]
+    pub root_owner: Option<(uid_t, gid_t)>,
+    /// Name the image, up to 16 bytes, for codexfsfuse LABEL=NAME. Kept from
+    /// the image with --append unless given
+    #[arg(long, value_name = "NAME", value_parser = sb::parse_label)]
+    pub label: Option<[u8; 16]>,
+    /// Record UUID in the image, for codexfsfuse UUID=UUID, or "random" for
+    /// a new one. Kept from the image with --append unless given
+    #[arg(long, value_name = "UUID", value_parser = parse_uuid)]
+    pub uuid: Option<[u8; 16]>,
+    /// Remap source uids and gids, one "u|g|b SOURCE IMAGE COUNT" range per
+    /// line (b maps both)
+    #[arg(long, value_name = "FILE")]
+    pub id_map: Option<String>,
+    /// Set mode and owner of paths, or create missing directories, device
+    /// nodes and fifos, from a genext2fs style device table
+    #[arg(short = 'D', long, value_name = "FILE")]
+    pub device_table: Option<String>,
+    /// Override mode, owner or group of paths from FILE, one
+    /// "PATH MODE UID GID [MTIME]" line each, "-" keeping the source's value.
+    /// The image stores no timestamps, MTIME is ignored
+    #[arg(long, value_name = "FILE")]
+    pub override_list: Option<String>,
+    /// Create the directory PATH, and missing ones above it, unless the
+    /// source has it, e.g. "/proc:555" or "/tmp:1777" for mount points.
+    /// MODE defaults to 755 (repeatable)
+    #[arg(long, value_name = "PATH[:MODE]", value_parser = pseudo::parse_dir)]
+    pub mkdir: Vec<(String, mode_t)>,
+    /// Create the symlink PATH pointing to TARGET, e.g. "/bin=usr/bin"; PATH
+    /// must not exist in the source (repeatable)
+    #[arg(long, value_name = "PATH=TARGET", value_parser = pseudo::parse_symlink)]
+    pub symlink: Vec<(String, String)>,
+    /// Set mode, owner, SELinux label and capabilities of paths from an
+    /// Android fs_config file
+    #[arg(long, value_name = "FILE")]
+    pub fs_config: Option<String>,
+    /// Store the extended attributes of the source files, the default
+    #[arg(long, overrides_with = "no_xattrs")]
+    pub xattrs: bool,
+    /// Do not read extended attributes from the source, only those from
+    /// --fs-config are stored
+    #[arg(long, overrides_with = "xattrs")]
+    pub no_xattrs: bool,
+    /// Leave out source extended attributes whose name matches PATTERN, e.g.
+    /// "user.*" (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub xattr_exclude: Vec<String>,
+    /// Keep source extended attributes matching PATTERN even if excluded,
+    /// e.g. --xattr-exclude '*' --xattr-include 'security.*' (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub xattr_include: Vec<String>,
+    /// Leave out entries matching PATTERN, a directory with everything below
+    /// it, e.g. ".git" or "/build/**" (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+    /// Keep entries matching PATTERN even if excluded. When given, files
+    /// matching no --include are left out (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub include: Vec<String>,
+    /// Build the image from exactly the paths in FILE ("-" for stdin), a
+    /// NUL-delimited list relative to SRC_PATH as "find . -print0" prints it.
+    /// Directories leading to listed paths are added, .codexfsignore files
+    /// are not read
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<String>,
+    /// Do not descend into directories on other filesystems than SRC_PATH,
+    /// mount points such as /proc are stored as empty directories
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+    /// Do not leave out what .codexfsignore files (gitignore syntax) in the
+    /// source directories list
+    #[arg(long)]
+    pub no_ignore_files: bool,
+    /// Store what symlinks point to instead of the links, dangling ones are
+    /// kept as links
+    #[arg(short = 'L', long, overrides_with = "no_follow_symlinks")]
+    pub follow_symlinks: bool,
+    /// Store symlinks as links, the default
+    #[arg(short = 'P', long, overrides_with = "follow_symlinks")]
+    pub no_follow_symlinks: bool,
+    /// Follow symlinks matching PATTERN like -L does (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub follow: Vec<String>,
+    /// Fail on symlinks pointing to nothing
+    #[arg(long)]
+    pub reject_dangling_symlinks: bool,
+    /// Fail on symlinks with an absolute target, which would point outside
+    /// the image
+    #[arg(long)]
+    pub reject_absolute_symlinks: bool,
+    /// Store files matching PATTERN uncompressed, e.g. "/boot/**"
+    /// (repeatable, compressed images only)
+    #[arg(long, value_name = "PATTERN")]
+    pub uncompressed_pattern: Vec<String>,
+    /// Use per-file compression only for paths matching this pattern
+    #[arg(long, value_name = "PATTERN")]
+    pub per_file_pattern: Vec<String>,
+    /// Lay out file data in the order listed in this file (one path relative
+    /// to the source root per line) instead of reordering by similarity
+    #[arg(long, value_name = "FILE")]
+    pub order_file: Option<String>,
+    /// Lay out the data of the files in this boot or access trace first, in
+    /// the order they were first read (one path per line, absolute in the
+    /// image or relative to the source root). The rest follows, reordered by
+    /// similarity or by --data-order
+    #[arg(long, value_name = "FILE", conflicts_with = "order_file")]
+    pub access_profile: Option<String>,
+    /// Write the final file data order, reusable with --order-file
+    #[arg(long, value_name = "FILE")]
+    pub write_order: Option<String>,
+    /// How to order file data by similarity: off (input order), fast
+    /// (nearest neighbor) or full (nearest neighbor refined by 2-opt)
+    #[arg(long, value_name = "MODE", default_value = "full")]
+    pub reorder: ReorderMode,
+    /// How to lay out data stored uncompressed: input (scan order, or the
+    /// --order-file list), name, size, extension or access-profile (first
+    /// access order of the --access-profile trace)
+    #[arg(
+        long,
+        value_name = "ORDER",
+        default_value = "input",
+        requires_if("access-profile", "access_profile")
+    )]
+    pub data_order: DataOrder,
+    /// Store files within this TLSH distance of another file as binary deltas
+    /// (compressed images only)
+    #[arg(long, value_name = "MAX_DIFF")]
+    pub delta: Option<usize>,
+    /// Split files larger than twice this size into content-defined segments
+    /// of about this size, reordered and deduplicated independently
+    #[arg(long, value_name = "BYTES")]
+    pub segment_size: Option<u64>,
+    /// Threads reading and fingerprinting the source tree, the number of
+    /// CPUs by default
+    #[arg(long, value_name = "N")]
+    pub scan_threads: Option<usize>,
+    /// Threads writing the inodes once they are laid out, the number of CPUs
+    /// by default
+    #[arg(long, value_name = "N")]
+    pub dump_threads: Option<usize>,
+    /// Leave out source entries that can not be read or stat'ed, store
+    /// unreadable files and directories empty and list them at the end
+    #[arg(long, overrides_with = "strict")]
+    pub skip_errors: bool,
+    /// Abort on the first unreadable source entry, the default
+    #[arg(long, overrides_with = "skip_errors")]
+    pub strict: bool,
+    /// Store files that can not be read for lack of permission as empty
+    /// files with their metadata and warn about them, even without
+    /// --skip-errors. Other read errors still abort the build
+    #[arg(long)]
+    pub placeholder_unreadable: bool,
+    /// Store files with the same content, mode, owner and xattrs as
+    /// hardlinks of one inode
+    #[arg(long)]
+    pub hardlink_dedupe: bool,
+    /// How inode numbers are given out: counter, in the order the tree is
+    /// loaded; path, a hash of each path that stays the same across
+    /// rebuilds; source, the source's own. A number given out already moves
+    /// to the next free one
+    #[arg(
+        long,
+        value_name = "MODE",
+        default_value = "counter",
+        conflicts_with = "append"
+    )]
+    pub ino_mode: InoMode,
+    /// What to do about names in one directory that differ only in case,
+    /// which break on casefold mounts and case-insensitive filesystems:
+    /// ignore, warn or fail
+    #[arg(long, value_name = "MODE", default_value = "ignore")]
+    pub case_collisions: CaseCollisions,
+    /// Reuse file fingerprints from this file for files whose path, size and
+    /// mtime are unchanged, and update it afterwards
+    #[arg(long, value_name = "FILE")]
+    pub tlsh_cache: Option<String>,
+    /// Decode every written cluster again and compare it with the source data
+    /// (compressed images only)
+    #[arg(long)]
+    pub verify_data: bool,
+    /// Scan and reorder the source, then print the estimated layout and size
+    /// of the image instead of building it
+    #[arg(long, conflicts_with_all = ["verify_data", "write_order", "report", "stats", "manifest"])]
+    pub dry_run: bool,
+    /// Pad the image with zeros to SIZE bytes, e.g. 64M for a partition of
+    /// that size (K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub pad_to: Option<u64>,
+    /// Pad the end of the image to a multiple of SIZE bytes, e.g. 1M or the
+    /// flash erase block size (K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub align_end: Option<u64>,
+    /// Write the image in Android's sparse format, which fastboot flashes
+    /// directly. Zero blocks, such as --pad-to padding, take no space
+    #[arg(long, conflicts_with_all = ["append", "check"])]
+    pub sparse: bool,
+    /// Fail as soon as the image needs more than SIZE bytes, e.g. the size of
+    /// the partition it goes to (K, M and G suffixes)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+    /// Only warn at the end when the image is larger than --max-size
+    #[arg(long, requires = "max_size")]
+    pub max_size_warn: bool,
+    /// Record the sha256 and source path of every file in the image, listed
+    /// by the provenance subcommand
+    #[arg(long, conflicts_with = "append")]
+    pub provenance: bool,
+    /// Record a crc32 of every cluster and every block of uncompressed file
+    /// data, which codexfsfuse --verify checks reads against
+    #[arg(long, conflicts_with = "append")]
+    pub checksums: bool,
+    /// Load the finished image like the FUSE driver and compare it with the
+    /// source, failing on any difference
+    #[arg(long, conflicts_with = "dry_run")]
+    pub check: bool,
+    /// Leave IMG_PATH alone if it was built from the same source tree with
+    /// the same options, going by a digest of both recorded in the image
+    #[arg(long, conflicts_with_all = ["append", "dry_run"])]
+    pub check_unchanged: bool,
+    /// Do not print progress while building or the summary at the end, and
+    /// log only errors
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// Log more, -v for every source and image step, -vv and -vvv for
+    /// detail. RUST_LOG overrides it
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Write log records as text, or json with one object per line, in which
+    /// case no progress is printed
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    pub log_format: LogFormat,
+    /// Write a per-file and per-directory compression report ("-" for stdout)
+    #[arg(long)]
+    pub report: Option<String>,
+    /// Write every path with its nid, inode and data offsets, extent count
+    /// and compressed size, as CSV if FILE ends in .csv and JSON otherwise
+    /// ("-" for JSON on stdout)
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<String>,
+    /// Write compression statistics as JSON ("-" for stdout)
+    #[arg(long)]
+    pub stats: Option<String>,
+    /// Add the source to an existing image instead of creating one. Files
+    /// replace those of the same path, directories are merged, and the data
+    /// already in the image is not written again
+    #[arg(long, conflicts_with_all = ["dry_run", "check", "report", "manifest", "auto_blksz"])]
+    pub append: bool,
+    /// Image file, "-" streams it to stdout
+    #[arg(index(1), required = true)]
+    pub img_path: Option<String>,
+    /// Source directory or codexfs image, or archive with --cpio
+    #[arg(index(2), required = true)]
+    pub src_path: Option<String>,
+    /// More directories or images merged over SRC_PATH in order, a path that
+    /// a later source also has is replaced by it, directories are merged.
+    /// Overlayfs whiteouts (0:0 character devices) and opaque directories in
+    /// a later source delete what is below them
+    #[arg(index(3), conflicts_with = "cpio")]
+    pub more_sources: Vec<String>,
+    /// SRC_PATH is a newc cpio (initramfs) archive, "-" for stdin. It is read
+    /// into memory, never unpacked, so there is no source tree to check the
+    /// image against or sample a block size from
+    #[arg(long, conflicts_with_all = ["check", "auto_blksz"])]
+    pub cpio: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    Bench(BenchArgs),
+    Check(CheckArgs),
+    Extract(ExtractArgs),
+    Info(InfoArgs),
+    Provenance(ProvenanceArgs),
 }
 
 static mut ARGS: OnceCell<Args> = OnceCell::new();
@@ -38,36 +392,601 @@ fn set_args(args: Args) {
 }
 
 fn parse_args() -> &'static Args {
-    let args = Args::parse();
+    let mut argv: Vec<String> = env::args().collect();
+    let config_path = argv
+        .iter()
+        .enumerate()
+        .find_map(|(i, arg)| match arg.as_str() {
+            "--config" => argv.get(i + 1).cloned(),
+            arg => arg.strip_prefix("--config=").map(str::to_owned),
+        });
+    if let Some(config_path) = config_path {
+        let config_args = config::load_args(&fs::read_to_string(config_path).unwrap()).unwrap();
+        argv.splice(1..1, config_args);
+    }
+    let matches = Args::command().get_matches_from(&argv);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    args.options = build_options(&matches);
+    args.command_line = argv;
     set_args(args);
     get_args()
 }
 
-fn main() {
-    env_logger::init();
+// The options that make up the input digest of an image with their values,
+// leaving out sources, whose tree is hashed instead, and those only about
+// output besides the image. Files the build reads count by their contents,
+// and the build itself by its version.
+fn build_options(matches: &ArgMatches) -> String {
+    const INPUT_FILES: &[&str] = &[
+        "id_map",
+        "device_table",
+        "override_list",
+        "fs_config",
+        "files_from",
+        "order_file",
+        "access_profile",
+    ];
+    const IGNORED: &[&str] = &[
+        "img_path",
+        "src_path",
+        "more_sources",
+        "config",
+        "quiet",
+        "verbose",
+        "log_format",
+        "check",
+        "check_unchanged",
+        "case_collisions",
+        "report",
+        "manifest",
+        "stats",
+        "write_order",
+        "tlsh_cache",
+        "scan_threads",
+        "dump_threads",
+    ];
+    let mut options = format!("version={};", env!("CARGO_PKG_VERSION"));
+    for arg in Args::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        if IGNORED.contains(&id) {
+            continue;
+        }
+        let Ok(Some(values)) = matches.try_get_raw(id) else {
+            continue;
+        };
+        let values: Vec<_> = values
+            .map(|v| match INPUT_FILES.contains(&id) {
+                true => match codexfs_core::provenance::mkfs_file_digest(Path::new(v)) {
+                    Ok(digest) => digest.iter().map(|b| format!("{b:02x}")).collect(),
+                    // stdin, or a missing file failing the build later
+                    Err(_) => v.to_string_lossy().into_owned(),
+                },
+                false => v.to_string_lossy().into_owned(),
+            })
+            .collect();
+        options += &format!("{id}={};", values.join(","));
+    }
+    options
+}
 
+fn main() {
     let args = parse_args();
-    let img_file = File::create(&args.img_path).unwrap();
-    set_sb(SuperBlock::new(img_file, args.blksz.ilog2() as _));
-    get_sb_mut().compress = !args.uncompress;
-    assert_eq!(get_sb().blksz(), args.blksz, "invalid blksz");
+    logging::init(args.verbose, args.quiet, args.log_format);
+    match &args.command {
+        Some(Command::Bench(bench_args)) => return bench::bench(bench_args).unwrap(),
+        Some(Command::Check(check_args)) => return check::check(check_args).unwrap(),
+        Some(Command::Extract(extract_args)) => return extract::extract(extract_args).unwrap(),
+        Some(Command::Info(info_args)) => return info::info(info_args).unwrap(),
+        Some(Command::Provenance(provenance_args)) => {
+            return provenance::provenance(provenance_args).unwrap();
+        }
+        None => {}
+    }
+    let img_path = args.img_path.as_deref().unwrap();
+    let src_path = args.src_path.as_deref().unwrap();
+    // owners are mapped as sources are read, an id that does not fit the
+    // image fails there with its path
+    let id_maps = match &args.id_map {
+        Some(map_path) => {
+            idmap::load_id_maps(&mut BufReader::new(File::open(map_path).unwrap())).unwrap()
+        }
+        None => Default::default(),
+    };
+    // an image or merge of sources is unpacked here until the image is built
+    let stage_dir;
+    let mut staged = None;
+    let mut archive = None;
+    let src_path = if args.cpio {
+        // entries of the archive are pseudo entries below a root that does
+        // not exist on the host, nothing under it is looked up there
+        let root = Path::new(match src_path {
+            "-" => "/dev/stdin",
+            path => path,
+        });
+        archive = Some(cpio::read(&mut open_input(src_path), root, &id_maps).unwrap());
+        root
+    } else if is_staged() {
+        stage_dir = tempfile::tempdir().unwrap();
+        let sources: Vec<_> = iter::once(src_path.to_owned())
+            .chain(args.more_sources.iter().cloned())
+            .collect();
+        staged =
+            Some(stage::stage(&sources, stage_dir.path(), args.one_file_system, &id_maps).unwrap());
+        stage_dir.path()
+    } else {
+        Path::new(src_path)
+    };
+    let blksz = if args.auto_blksz && !args.uncompress {
+        let codec = args.codecs.first().copied().unwrap_or(Codec::Lzma(6));
+        bench::auto_blksz(src_path, codec).unwrap()
+    } else {
+        args.blksz
+    };
+    // a streamed image is built in an unnamed temporary file and copied out
+    // once complete, the layout is only final after every write
+    let to_stdout = img_path == "-";
+    if to_stdout {
+        for path in [&args.write_order, &args.report, &args.stats, &args.manifest]
+            .into_iter()
+            .flatten()
+        {
+            assert_ne!(path, "-", "stdout already holds the image");
+        }
+        assert!(!args.check, "a streamed image can not be checked");
+        assert!(!args.check_unchanged, "a streamed image is always built");
+        assert!(!args.append, "a streamed image can not be appended to");
+    }
+    let old_digest = match args.check_unchanged {
+        true => File::open(img_path)
+            .ok()
+            .and_then(|img_file| sb::read_input_digest(&img_file).ok()),
+        false => None,
+    };
+    // an image for a block device is built aside and only written once it is
+    // complete, the device is never truncated
+    let to_device = !to_stdout && blkdev::is_block_device(img_path);
+    let device_size = to_device.then(|| blkdev::device_size(img_path).unwrap());
+    if to_device {
+        assert!(
+            !args.append,
+            "an image on a block device can not be appended to"
+        );
+        assert!(
+            !args.sparse,
+            "a sparse image is for a file, not a block device"
+        );
+    }
+    // a dry run leaves an existing image alone, a sparse one is converted
+    // once complete
+    let img_file = if to_stdout || to_device || args.dry_run || args.sparse {
+        tempfile::tempfile().unwrap()
+    } else {
+        File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(!args.append && !args.check_unchanged)
+            .open(img_path)
+            .unwrap()
+    };
+    if args.append {
+        // block size and compression come from the image
+        sb::mkfs_load_image_for_append(img_file).unwrap();
+    } else {
+        set_sb(SuperBlock::new(img_file, blksz.ilog2() as _));
+        get_sb_mut().compress = !args.uncompress;
+        assert_eq!(get_sb().blksz(), blksz, "invalid blksz");
+    }
+    let (uid_map, gid_map) = id_maps;
+    set_mkfs_opts(MkfsOptions {
+        owner: if args.all_root {
+            Some((0, 0))
+        } else {
+            args.owner
+        },
+        uid_map,
+        gid_map,
+        root_mode: args.root_mode,
+        root_owner: args.root_owner,
+        hardlink_dedupe: args.hardlink_dedupe,
+        skip_errors: args.skip_errors,
+        placeholder_unreadable: args.placeholder_unreadable,
+        one_file_system: args.one_file_system,
+        ino_mode: args.ino_mode,
+        ..Default::default()
+    });
+    let opts = get_mkfs_opts_mut();
+    if let Some(staged) = &mut staged {
+        opts.attrs.extend(staged.attrs.drain());
+        opts.pseudo_entries.append(&mut staged.pseudo_entries);
+    }
+    if let Some(mut entries) = archive {
+        opts.pseudo_entries.append(&mut entries);
+    }
+    if let Some(table_path) = &args.device_table {
+        devtable::load(
+            &mut BufReader::new(File::open(table_path).unwrap()),
+            src_path,
+            &mut opts.attrs,
+            &mut opts.pseudo_entries,
+        )
+        .unwrap();
+    }
+    pseudo::add(
+        src_path,
+        &args.mkdir,
+        &args.symlink,
+        &mut opts.pseudo_entries,
+    )
+    .unwrap();
+    if let Some(list_path) = &args.override_list {
+        let mtimes = overrides::load(
+            &mut BufReader::new(File::open(list_path).unwrap()),
+            src_path,
+            &mut opts.pseudo_entries,
+            &mut opts.overrides,
+        )
+        .unwrap();
+        if mtimes > 0 {
+            log::warn!("{mtimes} mtime overrides ignored, the image stores no timestamps");
+        }
+    }
+    if let Some(config_path) = &args.fs_config {
+        fsconfig::load(
+            &mut BufReader::new(File::open(config_path).unwrap()),
+            src_path,
+            &mut opts.attrs,
+            &mut opts.pseudo_entries,
+            &mut opts.xattrs,
+        )
+        .unwrap();
+    }
+    if let Some(label) = args.label {
+        get_sb_mut().label = label;
+    }
+    if let Some(uuid) = args.uuid {
+        get_sb_mut().uuid = uuid;
+    }
+    if args.checksums {
+        get_sb_mut().checksums = Some(Vec::new());
+    }
+    if !args.max_size_warn {
+        opts.max_size = args.max_size;
+    }
+    // the build stops as soon as the image outgrows the device
+    if let Some(device_size) = device_size {
+        let max_size = opts
+            .max_size
+            .map_or(device_size, |size| size.min(device_size));
+        opts.max_size = Some(max_size);
+    }
+    if !args.no_xattrs {
+        let filter = XattrFilter::new(&args.xattr_exclude, &args.xattr_include).unwrap();
+        // xattrs set by --fs-config win over those of staged sources
+        if let Some(staged) = staged {
+            for (path, mut xattrs) in staged.xattrs {
+                xattrs.retain(|(name, _)| filter.is_kept(name));
+                if !xattrs.is_empty() {
+                    opts.xattrs.entry(path).or_insert(xattrs);
+                }
+            }
+        }
+        opts.source_xattrs = Some(filter);
+    }
     set_cmpr_mgr(6);
-    let root = inode::mkfs_load_inode(Path::new(&args.src_path), None).unwrap();
+    get_cmpr_mgr_mut().delta_threshold = args.delta;
+    get_cmpr_mgr_mut().segment_size = args.segment_size;
+    get_cmpr_mgr_mut().per_file = args.per_file;
+    get_cmpr_mgr_mut().per_file_patterns = PathPatterns::new(&args.per_file_pattern).unwrap();
+    get_cmpr_mgr_mut().plain_patterns = PathPatterns::new(&args.uncompressed_pattern).unwrap();
+    if let Some(order_file) = &args.order_file {
+        let order = compress::read_order(BufReader::new(File::open(order_file).unwrap())).unwrap();
+        get_cmpr_mgr_mut().order = Some(order);
+    }
+    if let Some(profile_path) = &args.access_profile {
+        let profile =
+            compress::read_access_profile(BufReader::new(File::open(profile_path).unwrap()))
+                .unwrap();
+        get_cmpr_mgr_mut().access_profile = profile;
+    }
+    if !args.codecs.is_empty() {
+        get_cmpr_mgr_mut().codecs = args.codecs.clone();
+    }
+    get_cmpr_mgr_mut().policies = args.policy.clone();
+    get_cmpr_mgr_mut().reorder_mode = args.reorder;
+    get_cmpr_mgr_mut().data_order = args.data_order;
+    if let Some(cache_path) = &args.tlsh_cache {
+        let cache = FingerprintCache::load(Path::new(cache_path)).unwrap();
+        get_cmpr_mgr_mut().fingerprint_cache = Some(cache);
+    }
+    let listed = args.files_from.as_ref().map(|path| {
+        let stdin_taken = args.cpio && args.src_path.as_deref() == Some("-");
+        assert!(
+            !(stdin_taken && path == "-"),
+            "stdin already holds the archive"
+        );
+        let mut list = Vec::new();
+        open_input(path).read_to_end(&mut list).unwrap();
+        pattern::parse_file_list(&list).unwrap()
+    });
+    pattern::set_path_filter(PathFilter {
+        root: src_path.into(),
+        exclude: PathPatterns::new(&args.exclude).unwrap(),
+        include: PathPatterns::new(&args.include).unwrap(),
+        ignore_files: !args.no_ignore_files && listed.is_none(),
+        listed,
+    });
+    scan::set_symlink_policy(SymlinkPolicy {
+        root: src_path.into(),
+        follow_all: args.follow_symlinks,
+        follow: PathPatterns::new(&args.follow).unwrap(),
+        reject_dangling: args.reject_dangling_symlinks,
+        reject_absolute: args.reject_absolute_symlinks,
+    });
+    get_progress_mut().enabled = !args.quiet && args.log_format == LogFormat::Text;
+    get_progress_mut().begin("scanning", Unit::Entries, None);
+    let scan_threads = args
+        .scan_threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    if !args.cpio {
+        scan::mkfs_scan(src_path, scan_threads).unwrap();
+    }
+    get_progress_mut().begin("loading", Unit::Entries, None);
+    let load_source = || match args.cpio {
+        true => inode::mkfs_load_pseudo_root(src_path).unwrap(),
+        false => inode::mkfs_load_inode(src_path, None).unwrap(),
+    };
+    let mut merge = None;
+    let root = if args.append {
+        let nid = get_sb().root().meta().inner.borrow().nid;
+        let image_root = inode::fuse_load_inode(nid).unwrap();
+        inode::mkfs_forget_image_inodes();
+        let root = load_source();
+        merge = Some(inode::mkfs_merge_tree(&root, &image_root));
+        root
+    } else {
+        load_source()
+    };
+    get_progress_mut().finish();
     get_sb_mut().set_root(root);
+    inode::mkfs_check_limits().unwrap();
+    inode::mkfs_check_case_collisions(args.case_collisions).unwrap();
+    if let Some(cache_path) = &args.tlsh_cache {
+        let cache = get_cmpr_mgr().fingerprint_cache.as_ref().unwrap();
+        cache.save(Path::new(cache_path)).unwrap();
+    }
+    if !args.append {
+        let digest =
+            codexfs_core::provenance::mkfs_input_digest(get_sb().root(), &args.options).unwrap();
+        if old_digest == Some(digest) {
+            println!("{img_path} is up to date");
+            return;
+        }
+        get_sb_mut().input_digest = digest;
+        // kept until now in case it was up to date
+        if args.check_unchanged {
+            get_sb().img_file.as_ref().unwrap().set_len(0).unwrap();
+        }
+    }
 
-    sb::mkfs_balloc_super_block();
-    inode::get_inode_vec_mut()
-        .iter()
-        .for_each(|i| println!("{:?}", i.meta().path));
+    if !args.append {
+        sb::mkfs_balloc_super_block();
+    }
 
+    let file_count = get_cmpr_mgr().files.len() as u64;
+    get_progress_mut().begin("reordering", Unit::Entries, Some(file_count));
     if get_sb().compress {
-        get_cmpr_mgr_mut().reorder();
+        get_cmpr_mgr_mut().reorder().unwrap();
+    } else {
+        get_cmpr_mgr_mut().sort_files();
+    }
+    get_progress_mut().advance(file_count);
+    get_progress_mut().finish();
+    if args.dry_run {
+        dryrun::mkfs_dry_run().unwrap();
+        return;
+    }
+
+    if get_sb().compress {
+        let data_size = get_cmpr_mgr().data_size();
+        get_progress_mut().begin("compressing", Unit::Bytes, Some(data_size));
         inode::mkfs_dump_inode_file_data_z().unwrap();
+        get_progress_mut().finish();
+        sb::mkfs_set_decoder_limits();
+        if args.verify_data {
+            inode::mkfs_verify_file_data_z().unwrap();
+        }
+        if !get_cmpr_mgr().plain_files.is_empty() {
+            mkfs_dump_plain_data(&get_cmpr_mgr().plain_files);
+        }
     } else {
-        inode::mkfs_dump_inode_file_data().unwrap();
+        mkfs_dump_plain_data(&get_cmpr_mgr().files);
+    }
+    if let Some(order_path) = &args.write_order {
+        get_cmpr_mgr()
+            .write_order(&mut create_output(order_path))
+            .unwrap();
+    }
+    let inode_count = inode::get_inode_vec_mut().len() as u64;
+    get_progress_mut().begin("writing metadata", Unit::Entries, Some(inode_count));
+    let meta_size = inode::mkfs_balloc_inode().unwrap();
+    let provenance_buf = args
+        .provenance
+        .then(|| codexfs_core::provenance::mkfs_balloc_provenance(get_sb().root()).unwrap());
+    let checksums_buf = codexfs_core::checksum::mkfs_balloc_checksums().unwrap();
+    let build_info = info::build_info(&args.command_line).unwrap();
+    buildinfo::mkfs_balloc_build_info(&build_info).unwrap();
+    let dump_threads = args
+        .dump_threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    inode::mkfs_dump_inode(dump_threads).unwrap();
+    if let Some(buf) = &provenance_buf {
+        codexfs_core::provenance::mkfs_dump_provenance(buf).unwrap();
+    }
+    if let Some(buf) = &checksums_buf {
+        codexfs_core::checksum::mkfs_dump_checksums(buf).unwrap();
+    }
+    buildinfo::mkfs_dump_build_info(&build_info).unwrap();
+    if let Some(merge) = &merge {
+        merge.mkfs_dump().unwrap();
     }
-    inode::mkfs_balloc_inode();
-    inode::mkfs_dump_inode().unwrap();
     sb::mkfs_dump_super_block().unwrap();
     sb::mkfs_align_block_size().unwrap();
+    sb::mkfs_pad_image(args.pad_to, args.align_end).unwrap();
+    let img_len = get_sb()
+        .img_file
+        .as_ref()
+        .unwrap()
+        .metadata()
+        .unwrap()
+        .len();
+    if let Some(max_size) = args.max_size
+        && img_len > max_size
+    {
+        assert!(
+            args.max_size_warn,
+            "image is {img_len} bytes, more than {max_size}"
+        );
+        log::warn!("image is {img_len} bytes, more than {max_size}");
+    }
+    get_progress_mut().advance(inode_count);
+    get_progress_mut().finish();
+    if args.sparse {
+        let mut img_file = get_sb().img_file.as_ref().unwrap();
+        let mut w = BufWriter::new(create_output(img_path));
+        sparse::write(&mut img_file, get_sb().blksz() as _, &mut w).unwrap();
+        w.flush().unwrap();
+    } else if to_device {
+        blkdev::write_image(get_sb().img_file.as_ref().unwrap(), img_path).unwrap();
+    } else if to_stdout {
+        let mut img_file = get_sb().img_file.as_ref().unwrap();
+        img_file.seek(SeekFrom::Start(0)).unwrap();
+        io::copy(&mut img_file, &mut io::stdout().lock()).unwrap();
+    }
+
+    if let Some(report_path) = &args.report {
+        report::mkfs_report(&mut create_output(report_path)).unwrap();
+    }
+    if let Some(manifest_path) = &args.manifest {
+        let mut w = create_output(manifest_path);
+        if manifest_path.ends_with(".csv") {
+            report::mkfs_dump_manifest_csv(&mut w).unwrap();
+        } else {
+            report::mkfs_dump_manifest(&mut w).unwrap();
+        }
+    }
+    if let Some(stats_path) = &args.stats {
+        report::mkfs_dump_stats(&mut create_output(stats_path)).unwrap();
+    }
+    let skipped = scan::mkfs_skipped();
+    if !skipped.is_empty() {
+        log::warn!("{} source errors skipped:", skipped.len());
+        for (_, e) in skipped.iter() {
+            log::warn!("  {e}");
+        }
+    }
+    if !args.quiet {
+        let phases = &get_progress_mut().phases;
+        match to_stdout {
+            true => report::mkfs_summary(&mut io::stderr(), meta_size, phases),
+            false => report::mkfs_summary(&mut io::stdout(), meta_size, phases),
+        }
+        .unwrap();
+    }
+    if args.check {
+        mkfs_check(img_path, src_path);
+    }
+}
+
+fn mkfs_dump_plain_data(files: &[Rc<Inode<inode::File>>]) {
+    let data_size = files.iter().map(|file| file.data_size() as u64).sum();
+    get_progress_mut().begin("writing", Unit::Bytes, Some(data_size));
+    inode::mkfs_dump_inode_file_data(files).unwrap();
+    get_progress_mut().finish();
+}
+
+fn parse_mode(s: &str) -> anyhow::Result<mode_t> {
+    let mode = mode_t::from_str_radix(s, 8)?;
+    anyhow::ensure!(mode <= 0o7777, "mode {s} has bits beyond 7777");
+    Ok(mode)
+}
+
+fn parse_uuid(s: &str) -> anyhow::Result<[u8; 16]> {
+    if s != "random" {
+        return sb::parse_uuid(s);
+    }
+    let mut uuid = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    // version 4, variant 1
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+fn parse_owner(s: &str) -> anyhow::Result<(uid_t, gid_t)> {
+    let (uid, gid) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("expected UID:GID"))?;
+    Ok((uid.parse()?, gid.parse()?))
+}
+
+// The image is loaded in a new process, this one's global state still holds
+// the build. Mode and owner are only compared if nothing overrode them.
+fn mkfs_check(img_path: &str, src_path: &Path) {
+    let args = get_args();
+    let relaxed = is_staged()
+        || args.all_root
+        || args.owner.is_some()
+        || args.root_mode.is_some()
+        || args.root_owner.is_some()
+        || args.id_map.is_some()
+        || args.device_table.is_some()
+        || args.override_list.is_some()
+        || !args.mkdir.is_empty()
+        || !args.symlink.is_empty()
+        || args.fs_config.is_some()
+        || args.skip_errors
+        || args.placeholder_unreadable;
+    // and source entries may be left out of the image
+    let partial = args.one_file_system
+        || args.skip_errors
+        || args.files_from.is_some()
+        || !args.exclude.is_empty()
+        || !args.include.is_empty();
+    let mut cmd = process::Command::new(env::current_exe().unwrap());
+    cmd.arg("check");
+    if relaxed {
+        cmd.arg("--relaxed");
+    }
+    if partial {
+        cmd.arg("--partial");
+    }
+    if args.no_ignore_files {
+        cmd.arg("--no-ignore-files");
+    }
+    let status = cmd.arg(img_path).arg(src_path).status().unwrap();
+    assert!(status.success(), "image check failed");
+}
+
+// several sources, or an image, are merged into a staging directory first
+fn is_staged() -> bool {
+    let args = get_args();
+    let src_path = args.src_path.as_deref().unwrap();
+    !args.cpio && (!args.more_sources.is_empty() || stage::is_image(Path::new(src_path)))
+}
+
+fn open_input(path: &str) -> Box<dyn Read> {
+    if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(path).unwrap()))
+    }
+}
+
+fn create_output(path: &str) -> Box<dyn Write> {
+    if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(path).unwrap())
+    }
 }
@@ -0,0 +1,123 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::BufRead,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, ensure};
+use codexfs_core::{
+    gid_t,
+    inode::{AttrOverride, PseudoEntry},
+    uid_t,
+};
+
+use crate::parse_mode;
+
+// One line of an override list:
+//   <path> <mode> <uid> <gid> [<mtime>]
+// mode is octal permission bits, "-" keeps what the source has. The image
+// stores no timestamps, an mtime is only counted so that it can be reported.
+#[derive(Debug, PartialEq, Eq)]
+struct Line {
+    path: String,
+    attrs: AttrOverride,
+    mtime: Option<i64>,
+}
+
+fn parse_line(line: &str) -> Result<Option<Line>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    ensure!(
+        fields.len() == 4 || fields.len() == 5,
+        "expected 4 or 5 fields, got {}",
+        fields.len()
+    );
+    let field = |i: usize| fields.get(i).copied().filter(|&field| field != "-");
+    Ok(Some(Line {
+        path: fields[0].to_owned(),
+        attrs: AttrOverride {
+            mode: field(1).map(parse_mode).transpose()?,
+            uid: field(2).map(str::parse::<uid_t>).transpose()?,
+            gid: field(3).map(str::parse::<gid_t>).transpose()?,
+        },
+        mtime: field(4).map(str::parse).transpose()?,
+    }))
+}
+
+// Reads an override list for the tree at src_root, whose paths must exist
+// there or be created by a device table or --mkdir; those entries are changed
+// right away. Returns how many lines gave an mtime.
+pub fn load(
+    r: &mut dyn BufRead,
+    src_root: &Path,
+    pseudo_entries: &mut BTreeMap<PathBuf, PseudoEntry>,
+    overrides: &mut HashMap<PathBuf, AttrOverride>,
+) -> Result<usize> {
+    let mut mtimes = 0;
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        let Some(line) =
+            parse_line(&line).with_context(|| format!("override list line {}", i + 1))?
+        else {
+            continue;
+        };
+        let path = src_root.join(line.path.trim_start_matches('/'));
+        if let Some(entry) = pseudo_entries.get_mut(&path) {
+            let attrs = line.attrs;
+            entry.mode = attrs
+                .mode
+                .map_or(entry.mode, |bits| entry.mode & 0o170000 | bits);
+            entry.uid = attrs.uid.unwrap_or(entry.uid);
+            entry.gid = attrs.gid.unwrap_or(entry.gid);
+        } else {
+            ensure!(
+                path.symlink_metadata().is_ok(),
+                "{} does not exist",
+                line.path
+            );
+            overrides.insert(path, line.attrs);
+        }
+        mtimes += line.mtime.is_some() as usize;
+    }
+    Ok(mtimes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_parse_line() {
+        assert_eq!(parse_line("  # comment").unwrap(), None);
+        assert_eq!(
+            parse_line("/usr/bin/su 4755 0 0").unwrap(),
+            Some(Line {
+                path: "/usr/bin/su".into(),
+                attrs: AttrOverride {
+                    mode: Some(0o4755),
+                    uid: Some(0),
+                    gid: Some(0),
+                },
+                mtime: None,
+            })
+        );
+        assert_eq!(
+            parse_line("/var/log - - 4 1700000000").unwrap(),
+            Some(Line {
+                path: "/var/log".into(),
+                attrs: AttrOverride {
+                    mode: None,
+                    uid: None,
+                    gid: Some(4),
+                },
+                mtime: Some(1700000000),
+            })
+        );
+        assert!(parse_line("/a 644 0").is_err());
+        assert!(parse_line("/a 10644 0 0").is_err());
+        assert!(parse_line("/a 644 root 0").is_err());
+    }
+}
@@ -0,0 +1,36 @@
+use std::fs::File;
+
+use anyhow::{Result, bail};
+use clap::Args;
+use codexfs_core::{provenance, sb};
+
+/// Print the provenance records of an image as "SHA256  PATH" lines, which
+/// "sha256sum -c" can check against the source tree
+#[derive(Debug, Args)]
+pub struct ProvenanceArgs {
+    /// Also read each recorded file from the image and compare its digest
+    #[arg(long)]
+    pub verify: bool,
+    pub img_path: String,
+}
+
+pub fn provenance(args: &ProvenanceArgs) -> Result<()> {
+    sb::fuse_load_super_block(File::open(&args.img_path)?)?;
+    let records = provenance::fuse_load_provenance()?;
+    if records.is_empty() {
+        bail!("{} has no provenance records", args.img_path);
+    }
+    let mut mismatches = 0;
+    for record in records.iter() {
+        let sha256: String = record.sha256.iter().map(|b| format!("{b:02x}")).collect();
+        println!("{sha256}  {}", record.path.display());
+        if args.verify && !provenance::fuse_verify(record)? {
+            eprintln!("{}: content differs from the record", record.path.display());
+            mismatches += 1;
+        }
+    }
+    if mismatches > 0 {
+        bail!("{mismatches} files differ from their records");
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow, bail, ensure};
+use codexfs_core::{inode::PseudoEntry, mode_t};
+
+use crate::{
+    devtable::{S_IFDIR, file_type_of},
+    parse_mode,
+};
+
+const S_IFLNK: mode_t = 0o120000;
+
+// PATH[:MODE] of --mkdir, mode 755 if not given
+pub fn parse_dir(s: &str) -> Result<(String, mode_t)> {
+    match s.rsplit_once(':') {
+        Some((path, mode)) => Ok((path.to_owned(), parse_mode(mode)?)),
+        None => Ok((s.to_owned(), 0o755)),
+    }
+}
+
+// PATH=TARGET of --symlink
+pub fn parse_symlink(s: &str) -> Result<(String, String)> {
+    let (path, target) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected PATH=TARGET"))?;
+    ensure!(!target.is_empty(), "empty symlink target");
+    Ok((path.to_owned(), target.to_owned()))
+}
+
+// Adds directories and symlinks given on the command line to the tree at
+// src_root, owned by root. Missing parent directories are created with mode
+// 755, a directory that already exists is kept as it is.
+pub fn add(
+    src_root: &Path,
+    dirs: &[(String, mode_t)],
+    symlinks: &[(String, String)],
+    pseudo_entries: &mut BTreeMap<PathBuf, PseudoEntry>,
+) -> Result<()> {
+    for (name, mode) in dirs {
+        let path = src_root.join(name.trim_start_matches('/'));
+        add_parents(src_root, &path, pseudo_entries)?;
+        if !is_dir(&path, pseudo_entries)? {
+            pseudo_entries.insert(path, entry(S_IFDIR | mode, None));
+        }
+    }
+    for (name, target) in symlinks {
+        let path = src_root.join(name.trim_start_matches('/'));
+        ensure!(path != src_root, "{name} is the root directory");
+        add_parents(src_root, &path, pseudo_entries)?;
+        ensure!(
+            file_type_of(&path).is_none() && !pseudo_entries.contains_key(&path),
+            "{name} already exists"
+        );
+        pseudo_entries.insert(path, entry(S_IFLNK | 0o777, Some(target.into())));
+    }
+    Ok(())
+}
+
+fn entry(mode: mode_t, target: Option<PathBuf>) -> PseudoEntry {
+    PseudoEntry {
+        mode,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        target,
+        contents: None,
+    }
+}
+
+fn add_parents(
+    src_root: &Path,
+    path: &Path,
+    pseudo_entries: &mut BTreeMap<PathBuf, PseudoEntry>,
+) -> Result<()> {
+    let parents = path
+        .ancestors()
+        .skip(1)
+        .take_while(|parent| parent.starts_with(src_root) && *parent != src_root);
+    for parent in parents.collect::<Vec<_>>().into_iter().rev() {
+        if !is_dir(parent, pseudo_entries)? {
+            pseudo_entries.insert(parent.into(), entry(S_IFDIR | 0o755, None));
+        }
+    }
+    Ok(())
+}
+
+// whether path is a directory of the source or a pseudo one, fails if it is
+// something else
+fn is_dir(path: &Path, pseudo_entries: &BTreeMap<PathBuf, PseudoEntry>) -> Result<bool> {
+    let file_type = match pseudo_entries.get(path) {
+        Some(entry) => Some(entry.mode & 0o170000),
+        None => file_type_of(path),
+    };
+    match file_type {
+        None => Ok(false),
+        Some(S_IFDIR) => Ok(true),
+        Some(_) => bail!("{} exists and is not a directory", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_parse() {
+        assert_eq!(parse_dir("/tmp:1777").unwrap(), ("/tmp".into(), 0o1777));
+        assert_eq!(parse_dir("/proc").unwrap(), ("/proc".into(), 0o755));
+        assert!(parse_dir("/tmp:999").is_err());
+        assert_eq!(
+            parse_symlink("/bin=usr/bin").unwrap(),
+            ("/bin".into(), "usr/bin".into())
+        );
+        assert!(parse_symlink("/bin").is_err());
+    }
+}
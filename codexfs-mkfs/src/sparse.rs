@@ -0,0 +1,116 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const SPARSE_MAGIC: u32 = 0xed26ff3a;
+const FILE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+
+// Copies the image in img to w in Android's sparse format, runs of zero blocks
+// become fill chunks. A partial last block is padded with zeros.
+pub fn write(img: &mut (impl Read + Seek), blksz: u32, w: &mut impl Write) -> io::Result<()> {
+    // (zero, blocks) of every run of blocks
+    let mut runs: Vec<(bool, u32)> = Vec::new();
+    let mut block = vec![0; blksz as usize];
+    let max_raw_blocks = max_raw_blocks(blksz);
+    img.seek(SeekFrom::Start(0))?;
+    while read_block(img, &mut block)? {
+        let zero = block.iter().all(|&b| b == 0);
+        match runs.last_mut() {
+            Some((run_zero, blocks)) if *run_zero == zero && (zero || *blocks < max_raw_blocks) => {
+                *blocks += 1
+            }
+            _ => runs.push((zero, 1)),
+        }
+    }
+    let total_blocks: u32 = runs.iter().map(|(_, blocks)| blocks).sum();
+
+    w.write_all(&SPARSE_MAGIC.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?; // major version
+    w.write_all(&0u16.to_le_bytes())?; // minor version
+    w.write_all(&FILE_HEADER_SIZE.to_le_bytes())?;
+    w.write_all(&CHUNK_HEADER_SIZE.to_le_bytes())?;
+    w.write_all(&blksz.to_le_bytes())?;
+    w.write_all(&total_blocks.to_le_bytes())?;
+    w.write_all(&(runs.len() as u32).to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?; // no checksum
+
+    img.seek(SeekFrom::Start(0))?;
+    for (zero, blocks) in runs {
+        let (chunk_type, data_size) = match zero {
+            true => (CHUNK_TYPE_FILL, 4),
+            false => (CHUNK_TYPE_RAW, blocks * blksz),
+        };
+        w.write_all(&chunk_type.to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?;
+        w.write_all(&blocks.to_le_bytes())?;
+        w.write_all(&(CHUNK_HEADER_SIZE as u32 + data_size).to_le_bytes())?;
+        if zero {
+            w.write_all(&0u32.to_le_bytes())?; // fill value
+            img.seek_relative(blocks as i64 * blksz as i64)?;
+        } else {
+            for _ in 0..blocks {
+                read_block(img, &mut block)?;
+                w.write_all(&block)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// the size of a raw chunk, header and data, is a u32, longer runs of data
+// are split
+fn max_raw_blocks(blksz: u32) -> u32 {
+    (u32::MAX - CHUNK_HEADER_SIZE as u32) / blksz
+}
+
+// reads a block, zero padded at the end of the image, false past it
+fn read_block(img: &mut impl Read, block: &mut [u8]) -> io::Result<bool> {
+    let mut len = 0;
+    while len < block.len() {
+        match img.read(&mut block[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    block[len..].fill(0);
+    Ok(len > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn check_write() {
+        let mut img = vec![1; 8];
+        img.extend([0; 12]);
+        img.extend([2; 2]);
+        let mut out = Vec::new();
+        write(&mut Cursor::new(img), 4, &mut out).unwrap();
+        let u32_at = |off: usize| u32::from_le_bytes(out[off..off + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), SPARSE_MAGIC);
+        assert_eq!((u32_at(12), u32_at(16), u32_at(20)), (4, 6, 3));
+        // raw 2 blocks, fill 3 blocks, raw 1 padded block
+        assert_eq!(&out[28..40], &[0xc1, 0xca, 0, 0, 2, 0, 0, 0, 20, 0, 0, 0]);
+        assert_eq!(
+            &out[48..64],
+            &[0xc2, 0xca, 0, 0, 3, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            &out[64..],
+            &[0xc1, 0xca, 0, 0, 1, 0, 0, 0, 16, 0, 0, 0, 2, 2, 0, 0]
+        );
+    }
+
+    #[test]
+    fn check_max_raw_blocks() {
+        // a raw chunk of 4 GiB does not fit
+        let blocks = max_raw_blocks(4096);
+        assert!(blocks < 1 << 20);
+        assert!(CHUNK_HEADER_SIZE as u64 + blocks as u64 * 4096 <= u32::MAX as u64);
+        assert!(CHUNK_HEADER_SIZE as u64 + (blocks as u64 + 1) * 4096 > u32::MAX as u64);
+    }
+}
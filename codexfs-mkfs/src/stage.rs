@@ -0,0 +1,316 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, MetadataExt, symlink},
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{Context, Result, ensure};
+use codexfs_core::{
+    CODEXFS_MAGIC, CODEXFS_SUPERBLK_OFF, gid_t,
+    idmap::IdMap,
+    inode::PseudoEntry,
+    mode_t, new_encode_dev, uid_t,
+    xattr::{self, Xattrs},
+};
+
+use crate::{devtable::S_IFDIR, extract::decode_xattrs};
+
+const S_IFMT: mode_t = 0o170000;
+const S_IFREG: mode_t = 0o100000;
+const S_IFLNK: mode_t = 0o120000;
+const S_IFCHR: mode_t = 0o020000;
+const OPAQUE_XATTRS: [&str; 2] = ["trusted.overlay.opaque", "user.overlay.opaque"];
+
+// what a staged tree can not carry on disk, keyed by staged path
+#[derive(Debug, Default)]
+pub struct Staged {
+    pub attrs: HashMap<PathBuf, (mode_t, uid_t, gid_t)>,
+    pub xattrs: HashMap<PathBuf, Xattrs>,
+    pub pseudo_entries: BTreeMap<PathBuf, PseudoEntry>, // device nodes, fifos and sockets
+}
+
+// a regular file starting with a codexfs super block
+pub fn is_image(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let mut magic = [0; 4];
+    file.metadata().is_ok_and(|m| m.is_file())
+        && file.read_exact_at(&mut magic, CODEXFS_SUPERBLK_OFF).is_ok()
+        && u32::from_le_bytes(magic) == CODEXFS_MAGIC
+}
+
+// Merges source directories and images into dest, each one over the ones
+// before it: a path a later source also has is replaced, directories are
+// merged. As with overlayfs, a 0:0 character device in a later source deletes
+// the path, and a directory marked opaque hides what is below it. Files are
+// hardlinked where possible, so nothing is ever written through an existing
+// path. Modes and owners of every path are returned, mapped by the uid and
+// gid maps, an unprivileged build can not apply them.
+pub fn stage(
+    sources: &[String],
+    dest: &Path,
+    one_file_system: bool,
+    id_maps: &(IdMap, IdMap),
+) -> Result<Staged> {
+    let mut staged = Staged::default();
+    for (i, source) in sources.iter().enumerate() {
+        let path = Path::new(source);
+        let layer = i > 0;
+        if is_image(path) {
+            stage_image(path, dest, layer, id_maps, &mut staged)
+        } else {
+            ensure!(path.is_dir(), "not a directory or codexfs image");
+            let root_dev = one_file_system.then(|| path.metadata().map(|m| m.dev()));
+            let root_dev = root_dev.transpose()?;
+            stage_dir(path, dest, root_dev, layer, id_maps, &mut staged)
+        }
+        .with_context(|| format!("staging {source}"))?;
+    }
+    Ok(staged)
+}
+
+// Clears path for a new entry, keeping an existing directory if a directory
+// goes there. Returns whether one was kept.
+pub fn make_room(path: &Path, dir: bool) -> io::Result<bool> {
+    match path.symlink_metadata() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+        Ok(metadata) if metadata.is_dir() && dir => Ok(true),
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).map(|_| false),
+        Ok(_) => fs::remove_file(path).map(|_| false),
+    }
+}
+
+impl Staged {
+    // records a new entry at path, dropping what an earlier source had there
+    fn insert(&mut self, path: &Path, attrs: (mode_t, uid_t, gid_t), xattrs: Xattrs, rdev: u32) {
+        let mode = attrs.0;
+        if mode & S_IFMT == S_IFDIR {
+            self.pseudo_entries.remove(path);
+        } else {
+            self.pseudo_entries.retain(|p, _| !p.starts_with(path));
+        }
+        if !matches!(mode & S_IFMT, S_IFDIR | S_IFREG | S_IFLNK) {
+            let (mode, uid, gid) = attrs;
+            let entry = PseudoEntry {
+                mode,
+                uid,
+                gid,
+                rdev,
+                target: None,
+                contents: None,
+            };
+            self.pseudo_entries.insert(path.into(), entry);
+        }
+        self.attrs.insert(path.into(), attrs);
+        match xattrs.is_empty() {
+            true => self.xattrs.remove(path),
+            false => self.xattrs.insert(path.into(), xattrs),
+        };
+    }
+
+    // forgets path and everything below it
+    fn remove(&mut self, path: &Path) {
+        self.attrs.retain(|p, _| !p.starts_with(path));
+        self.xattrs.retain(|p, _| !p.starts_with(path));
+        self.pseudo_entries.retain(|p, _| !p.starts_with(path));
+    }
+}
+
+fn is_whiteout(mode: mode_t, rdev: u32) -> bool {
+    mode & S_IFMT == S_IFCHR && rdev == 0
+}
+
+// drops the opaque mark, returning whether it was there
+fn take_opaque(xattrs: &mut Xattrs) -> bool {
+    let len = xattrs.len();
+    xattrs.retain(|(name, value)| !(OPAQUE_XATTRS.contains(&name.as_str()) && value == b"y"));
+    xattrs.len() != len
+}
+
+fn stage_dir(
+    src: &Path,
+    dest: &Path,
+    root_dev: Option<u64>,
+    layer: bool,
+    id_maps: &(IdMap, IdMap),
+    staged: &mut Staged,
+) -> Result<()> {
+    let metadata = src.symlink_metadata()?;
+    let mode = metadata.mode() as mode_t;
+    let mut rdev = 0;
+    if layer && is_whiteout(mode, metadata.rdev() as _) {
+        make_room(dest, false)?;
+        staged.remove(dest);
+        return Ok(());
+    }
+    let mut xattrs = xattr::read(src, false).unwrap_or_else(|e| {
+        log::warn!("{}: xattrs left out: {e}", src.display());
+        Vec::new()
+    });
+    match mode & S_IFMT {
+        S_IFDIR => {
+            if take_opaque(&mut xattrs) && layer {
+                make_room(dest, false)?;
+                staged.remove(dest);
+            }
+            if !make_room(dest, true)? {
+                fs::create_dir(dest)?;
+            }
+        }
+        S_IFREG => {
+            make_room(dest, false)?;
+            if fs::hard_link(src, dest).is_err() {
+                fs::copy(src, dest)?;
+            }
+        }
+        S_IFLNK => {
+            make_room(dest, false)?;
+            symlink(fs::read_link(src)?, dest)?;
+        }
+        _ => {
+            make_room(dest, false)?;
+            let (major, minor) =
+                unsafe { (libc::major(metadata.rdev()), libc::minor(metadata.rdev())) };
+            rdev = new_encode_dev(major, minor);
+        }
+    }
+    let (uid, gid) = map_owner(id_maps, metadata.uid(), metadata.gid())
+        .with_context(|| src.display().to_string())?;
+    staged.insert(dest, (mode, uid, gid), xattrs, rdev);
+
+    // with -x a directory on another filesystem is staged empty
+    if metadata.is_dir() && root_dev.is_none_or(|dev| dev == metadata.dev()) {
+        for entry in fs::read_dir(src)? {
+            let name = entry?.file_name();
+            let (src, dest) = (src.join(&name), dest.join(&name));
+            stage_dir(&src, &dest, root_dev, layer, id_maps, staged)?;
+        }
+    }
+    Ok(())
+}
+
+// An image is unpacked by the extract subcommand in a new process, this
+// one's global state is for the build. Whiteouts already replaced what they
+// delete as it was unpacked, what opaque directories hide is pruned after.
+fn stage_image(
+    img_path: &Path,
+    dest: &Path,
+    layer: bool,
+    id_maps: &(IdMap, IdMap),
+    staged: &mut Staged,
+) -> Result<()> {
+    let attrs_file = tempfile::NamedTempFile::new()?;
+    let status = process::Command::new(env::current_exe()?)
+        .arg("extract")
+        .arg("--attrs")
+        .arg(attrs_file.path())
+        .arg(img_path)
+        .arg(dest)
+        .status()?;
+    ensure!(status.success(), "extracting {} failed", img_path.display());
+    let mut listed = HashSet::new();
+    let mut opaque_dirs = Vec::new();
+    for line in fs::read(attrs_file.path())?.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let (mode, uid, gid, rdev, mut xattrs, name) = parse_attrs_line(line)?;
+        let path = match name.as_os_str().is_empty() {
+            true => dest.to_path_buf(),
+            false => dest.join(&name),
+        };
+        if layer && is_whiteout(mode, rdev) {
+            staged.remove(&path);
+            continue;
+        }
+        if take_opaque(&mut xattrs) && layer {
+            opaque_dirs.push(path.clone());
+        }
+        let (uid, gid) = map_owner(id_maps, uid.into(), gid.into())
+            .with_context(|| format!("{}: {}", img_path.display(), name.display()))?;
+        listed.insert(path.clone());
+        staged.insert(&path, (mode, uid, gid), xattrs, rdev);
+    }
+    for dir in opaque_dirs {
+        prune(&dir, &listed, staged)?;
+    }
+    Ok(())
+}
+
+// owner ids as the image stores them
+fn map_owner(id_maps: &(IdMap, IdMap), uid: u32, gid: u32) -> Result<(uid_t, gid_t)> {
+    let uid = id_maps.0.map_to_image(uid).context("uid")?;
+    let gid = id_maps.1.map_to_image(gid).context("gid")?;
+    Ok((uid, gid))
+}
+
+// removes what is below dir but not listed
+fn prune(dir: &Path, listed: &HashSet<PathBuf>, staged: &mut Staged) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !listed.contains(&path) {
+            make_room(&path, false)?;
+            staged.remove(&path);
+        } else if path.symlink_metadata()?.is_dir() {
+            prune(&path, listed, staged)?;
+        }
+    }
+    Ok(())
+}
+
+// "MODE UID GID RDEV XATTRS PATH" as listed by extract --attrs
+fn parse_attrs_line(line: &[u8]) -> Result<(mode_t, uid_t, gid_t, u32, Xattrs, PathBuf)> {
+    let mut fields = line.splitn(6, |&b| b == b' ');
+    let mut next = || -> Result<&str> {
+        let field = fields.next().context("truncated attrs line")?;
+        Ok(std::str::from_utf8(field)?)
+    };
+    let mode = mode_t::from_str_radix(next()?, 8)?;
+    let uid = next()?.parse()?;
+    let gid = next()?.parse()?;
+    let rdev = next()?.parse()?;
+    let xattrs = decode_xattrs(next()?)?;
+    let name = fields.next().context("truncated attrs line")?;
+    let name = PathBuf::from(OsStr::from_bytes(name));
+    Ok((mode, uid, gid, rdev, xattrs, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_parse_attrs_line() {
+        let (mode, uid, gid, rdev, xattrs, name) =
+            parse_attrs_line(b"20644 0 6 259 - dev/my disk").unwrap();
+        assert_eq!((mode, uid, gid, rdev), (0o20644, 0, 6, 259));
+        assert!(xattrs.is_empty());
+        assert_eq!(name, Path::new("dev/my disk"));
+        let (.., name) = parse_attrs_line(b"40755 0 0 0 - ").unwrap();
+        assert!(name.as_os_str().is_empty());
+        assert!(parse_attrs_line(b"40755 0 0 0").is_err());
+    }
+
+    #[test]
+    fn check_overlay_marks() {
+        assert!(is_whiteout(0o20000, 0));
+        assert!(!is_whiteout(0o20644, 259));
+        let mut xattrs = vec![
+            ("trusted.overlay.opaque".to_owned(), b"y".to_vec()),
+            ("user.a".to_owned(), b"y".to_vec()),
+        ];
+        assert!(take_opaque(&mut xattrs));
+        assert_eq!(xattrs.len(), 1);
+        assert!(!take_opaque(&mut xattrs));
+    }
+}